@@ -0,0 +1,360 @@
+//! A local HTTP API for controlling a running machine, for editors and
+//! dashboards that would rather not scrape `up`'s output.
+//!
+//! Every request needs an `Authorization: Bearer <token>` header carrying the
+//! token from the machine's `api-token` file. The machine itself can only be
+//! touched from its dispatch queue, so state-changing requests are handed to
+//! the `up` loop as `Control` messages rather than performed here.
+//!
+//! ```text
+//! GET  /machines                  the project's machines and their states
+//! POST /machines/<name>/start     start a stopped machine with vagrantx up
+//! GET  /machine                   this machine
+//! POST /machine/stop
+//! POST /machine/exec              run {"command": [...], "user", "workdir"}
+//!                                 over SSH, answering with its status and
+//!                                 output
+//! GET  /machine/console           the console, streamed
+//! GET  /machine/events
+//! GET  /machine/snapshots
+//! GET  /metrics
+//! ```
+
+use crate::config;
+use crate::console::ConsoleOutput;
+use crate::control::Control;
+use crate::events::EventLog;
+use crate::exec;
+use crate::http;
+use crate::machine::Machine;
+use crate::metrics;
+use crate::output;
+use crate::prune;
+use crate::snapshot::Snapshots;
+use crate::ssh::Session;
+use crate::status::{self, SharedStatus};
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Returns the machine's API token, generating one the first time so
+/// clients keep working across restarts. An empty one, which any client
+/// could send, is replaced.
+fn token(machine: &Machine) -> io::Result<String> {
+    let path = machine.dir.join("api-token");
+    if let Ok(token) = fs::read_to_string(&path) {
+        if !token.trim().is_empty() {
+            return Ok(token.trim().to_string());
+        }
+    }
+
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    fs::create_dir_all(&machine.dir)?;
+    let partial = path.with_extension("partial");
+    let _ = fs::remove_file(&partial);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&partial)?
+        .write_all(token.as_bytes())?;
+    fs::rename(&partial, &path)?;
+    Ok(token)
+}
+
+/// The body of `POST /machine/exec`.
+#[derive(Deserialize)]
+struct ExecRequest {
+    command: Vec<String>,
+    user: Option<String>,
+    workdir: Option<String>,
+}
+
+struct Api {
+    token: String,
+    config_file: PathBuf,
+    machine: Machine,
+    status: SharedStatus,
+    console: ConsoleOutput,
+    events: EventLog,
+    control: Sender<Control>,
+}
+
+fn json_response(stream: &mut TcpStream, status: &str, body: serde_json::Value) -> io::Result<()> {
+    http::respond(stream, status, "application/json", &format!("{}\n", body))
+}
+
+fn error_response(stream: &mut TcpStream, status: &str, message: &str) -> io::Result<()> {
+    json_response(stream, status, json!({ "error": message }))
+}
+
+/// Compares in time that depends only on the lengths, so a token can't be
+/// guessed a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Api {
+    fn authorized(&self, request: &http::Request) -> bool {
+        match request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) => {
+                !token.trim().is_empty()
+                    && constant_time_eq(token.trim().as_bytes(), self.token.as_bytes())
+            }
+            None => false,
+        }
+    }
+
+    /// The configs beside this machine's, with the machines they define.
+    fn project(&self) -> Vec<(PathBuf, Machine)> {
+        let dir = self.config_file.parent().unwrap_or_else(|| Path::new("."));
+        prune::configs(dir)
+            .into_iter()
+            .map(|(path, config)| {
+                let machine = Machine::new(&path, config.name.as_deref());
+                (path, machine)
+            })
+            .collect()
+    }
+
+    fn machines(&self) -> serde_json::Value {
+        let machines: Vec<serde_json::Value> = self
+            .project()
+            .iter()
+            .map(|(path, machine)| {
+                let state = status::published(machine).map(|p| p.state);
+                json!({
+                    "name": machine.name,
+                    "config": path,
+                    "state": state.as_deref().unwrap_or("stopped"),
+                })
+            })
+            .collect();
+        json!(machines)
+    }
+
+    /// Starts a stopped machine of the project with a `vagrantx up` of its
+    /// own, which outlives this one.
+    fn start(&self, stream: &mut TcpStream, name: &str) -> io::Result<()> {
+        let (config_file, machine) = match self.project().into_iter().find(|(_, m)| m.name == name)
+        {
+            Some(found) => found,
+            None => return error_response(stream, "404 Not Found", "no such machine"),
+        };
+        if status::published(&machine).is_some() {
+            return error_response(stream, "409 Conflict", "machine is already running");
+        }
+        let exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("vagrantx"));
+        // Its own process group, so a Ctrl-C meant for this up spares it.
+        let started = Command::new(exe)
+            .arg("up")
+            .arg(&config_file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn();
+        match started {
+            Ok(_) => json_response(stream, "202 Accepted", json!({ "starting": true })),
+            Err(e) => error_response(
+                stream,
+                "500 Internal Server Error",
+                &format!("could not run vagrantx up: {}", e),
+            ),
+        }
+    }
+
+    fn snapshots(&self) -> Result<serde_json::Value, Box<dyn error::Error>> {
+        let config = config::load_config(&self.config_file)?;
+        Ok(json!(Snapshots::unlocked(&config, &self.machine)?.all()?))
+    }
+
+    fn machine(&self) -> serde_json::Value {
+        let status = self.status.lock().unwrap();
+        json!({
+            "name": status.name,
+            "state": status.state,
+            "uptime_seconds": status.uptime_seconds(),
+            "cpus": status.cpu_count,
+            "memory_bytes": status.memory_size,
+            "balloon_target_bytes": status.balloon_target,
+            "boots": status.boots,
+            "boot_phase": status.boot_phase,
+        })
+    }
+
+    fn stop(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let (reply, result) = mpsc::channel();
+        if self
+            .control
+            .send(Control::Stop(reply, "requested over the API"))
+            .is_err()
+        {
+            return error_response(
+                stream,
+                "503 Service Unavailable",
+                "machine is shutting down",
+            );
+        }
+        match result.recv() {
+            Ok(Ok(())) => json_response(stream, "202 Accepted", json!({ "stopping": true })),
+            Ok(Err(e)) => error_response(stream, "409 Conflict", &e.to_string()),
+            Err(_) => error_response(stream, "503 Service Unavailable", "machine is not running"),
+        }
+    }
+
+    /// Runs a command in the guest over SSH, as `vagrantx exec` does, and
+    /// answers once it has finished.
+    fn exec(&self, stream: &mut TcpStream, body: &[u8]) -> io::Result<()> {
+        let request: ExecRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return error_response(stream, "400 Bad Request", &e.to_string()),
+        };
+        if request.command.is_empty() {
+            return error_response(stream, "400 Bad Request", "command is empty");
+        }
+        let session = config::load_config(&self.config_file)
+            .and_then(|config| Session::new(&config, &self.machine));
+        let mut session = match session {
+            Ok(session) => session,
+            Err(e) => return error_response(stream, "409 Conflict", &e.to_string()),
+        };
+        // Nobody's there to answer a prompt.
+        session.options.push("BatchMode=yes".to_string());
+        let output = session
+            .command()
+            .arg("-T")
+            .arg("--")
+            .arg(exec::remote_command(
+                &request.command,
+                request.user.as_deref(),
+                request.workdir.as_deref(),
+            ))
+            .stdin(Stdio::null())
+            .output();
+        match output {
+            Ok(output) => json_response(
+                stream,
+                "200 OK",
+                json!({
+                    "exit_code": output.status.code(),
+                    "stdout": String::from_utf8_lossy(&output.stdout),
+                    "stderr": String::from_utf8_lossy(&output.stderr),
+                }),
+            ),
+            Err(e) => error_response(
+                stream,
+                "500 Internal Server Error",
+                &format!("could not run ssh: {}", e),
+            ),
+        }
+    }
+
+    /// Sends the buffered console history, then new output as it arrives,
+    /// until the client hangs up.
+    fn console(&self, stream: &mut TcpStream) -> io::Result<()> {
+        http::respond_streaming(stream, "200 OK", "application/octet-stream")?;
+        let mut position = 0;
+        loop {
+            let (data, next) = self.console.read_from(position, Duration::from_secs(1));
+            position = next;
+            stream.write_all(&data)?;
+            stream.flush()?;
+        }
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        let request = match http::read_request(&stream) {
+            Ok(request) => request,
+            Err(e) => return error_response(&mut stream, "400 Bad Request", &e.to_string()),
+        };
+        if !self.authorized(&request) {
+            return error_response(&mut stream, "401 Unauthorized", "missing or wrong token");
+        }
+
+        let start = request
+            .path
+            .strip_prefix("/machines/")
+            .and_then(|rest| rest.strip_suffix("/start"));
+        if let ("POST", Some(name)) = (request.method.as_str(), start) {
+            return self.start(&mut stream, name);
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/machines") => json_response(&mut stream, "200 OK", self.machines()),
+            ("GET", "/machine") => json_response(&mut stream, "200 OK", self.machine()),
+            ("POST", "/machine/stop") => self.stop(&mut stream),
+            ("POST", "/machine/exec") => self.exec(&mut stream, &request.body),
+            ("GET", "/machine/console") => self.console(&mut stream),
+            ("GET", "/machine/events") => match self.events.read() {
+                Ok(events) => json_response(&mut stream, "200 OK", json!(events)),
+                Err(e) => error_response(&mut stream, "500 Internal Server Error", &e.to_string()),
+            },
+            ("GET", "/machine/snapshots") => match self.snapshots() {
+                Ok(snapshots) => json_response(&mut stream, "200 OK", snapshots),
+                Err(e) => error_response(&mut stream, "500 Internal Server Error", &e.to_string()),
+            },
+            ("GET", "/metrics") => http::respond(
+                &mut stream,
+                "200 OK",
+                "text/plain; version=0.0.4",
+                &metrics::render(&self.status, &self.machine),
+            ),
+            _ => error_response(&mut stream, "404 Not Found", "not found"),
+        }
+    }
+}
+
+/// Serves the API on `address`, one thread per connection since console
+/// streams stay open.
+pub fn serve(
+    address: &str,
+    config_file: &Path,
+    machine: &Machine,
+    status: SharedStatus,
+    console: ConsoleOutput,
+    control: Sender<Control>,
+) -> io::Result<()> {
+    let api = Arc::new(Api {
+        token: token(machine)?,
+        config_file: config_file.to_path_buf(),
+        machine: machine.clone(),
+        status,
+        console,
+        events: EventLog::new(machine),
+        control,
+    });
+
+    let listener = TcpListener::bind(address)?;
+    output::message(&format!(
+        "serving the API on http://{} (token in {})",
+        listener.local_addr()?,
+        machine.dir.join("api-token").display()
+    ));
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let api = api.clone();
+            thread::spawn(move || {
+                let _ = api.handle(stream);
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Which CPU architecture a machine's guest is built for.
+//!
+//! Virtualization.framework only runs guests of the host's own
+//! architecture; anything else has to be emulated by QEMU. The guest's is
+//! the config's `arch` if set, then the box's, then whatever the kernel
+//! image turns out to be built for, and the host's if none of those say.
+
+use crate::boxes;
+use crate::config::Config;
+use std::env::consts::ARCH;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The name vagrantx uses for `arch`, accepting the Debian-style names too.
+pub fn normalize(arch: &str) -> Option<&'static str> {
+    match arch {
+        "aarch64" | "arm64" => Some("aarch64"),
+        "x86_64" | "amd64" | "x64" => Some("x86_64"),
+        _ => None,
+    }
+}
+
+/// What an uncompressed kernel image is built for, from its boot header.
+pub fn kernel_arch(path: &Path) -> Option<&'static str> {
+    let mut header = [0u8; 0x206];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if &header[56..60] == b"ARM\x64" {
+        Some("aarch64")
+    } else if &header[0x202..0x206] == b"HdrS" {
+        Some("x86_64")
+    } else {
+        None
+    }
+}
+
+fn declared(config: &Config) -> Option<&str> {
+    config.arch.as_deref().or(config.qemu.arch.as_deref())
+}
+
+fn box_arch(config: &Config) -> Option<&'static str> {
+    let name = config.box_name.as_ref()?;
+    normalize(boxes::load(name).ok()?.arch.as_deref()?)
+}
+
+fn configured_kernel_arch(config: &Config) -> Option<&'static str> {
+    kernel_arch(config.boot.kernel.as_deref()?)
+}
+
+/// The architecture of `config`'s guest.
+pub fn guest(config: &Config) -> &'static str {
+    declared(config)
+        .and_then(normalize)
+        .or_else(|| box_arch(config))
+        .or_else(|| configured_kernel_arch(config))
+        .unwrap_or(ARCH)
+}
+
+pub fn is_foreign(config: &Config) -> bool {
+    guest(config) != ARCH
+}
+
+/// Fails if the config, box and kernel disagree about the architecture,
+/// which otherwise shows up as a guest that hangs without a word.
+pub fn check(config: &Config) -> Result<(), String> {
+    if let Some(arch) = declared(config) {
+        if normalize(arch).is_none() {
+            return Err(format!(
+                "unknown architecture {}; use aarch64 or x86_64",
+                arch
+            ));
+        }
+    }
+    let guest = guest(config);
+    let found = [
+        (
+            config.box_name.as_ref().map(|name| format!("box {}", name)),
+            box_arch(config),
+        ),
+        (
+            config
+                .boot
+                .kernel
+                .as_ref()
+                .map(|kernel| kernel.display().to_string()),
+            configured_kernel_arch(config),
+        ),
+    ];
+    for (what, arch) in found {
+        if let (Some(what), Some(arch)) = (what, arch) {
+            if arch != guest {
+                return Err(format!(
+                    "{} is built for {}, but the machine is {}; set \"arch\" to match",
+                    what, arch, guest
+                ));
+            }
+        }
+    }
+    Ok(())
+}
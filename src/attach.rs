@@ -0,0 +1,121 @@
+//! `vagrantx console`: attaching to a running machine's serial console from
+//! another terminal, interactively or through an expect script.
+
+use crate::cast::Cast;
+use crate::console::{self, ConsoleOutput};
+use crate::expect::Script;
+use crate::machine::Machine;
+use libc::{isatty, tcgetattr, tcsetattr, termios, ECHO, ICANON, ICRNL, ISIG, TCSANOW};
+use std::error;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+
+/// Ctrl-], as with telnet.
+const DETACH: u8 = 0x1d;
+
+/// Puts stdin in raw mode for as long as it's kept, so keystrokes (Ctrl-C
+/// included) go to the guest.
+struct RawMode(termios);
+
+impl RawMode {
+    fn enable() -> Option<RawMode> {
+        unsafe {
+            if isatty(0) == 0 {
+                return None;
+            }
+            let mut attributes = MaybeUninit::uninit();
+            if tcgetattr(0, attributes.as_mut_ptr()) != 0 {
+                return None;
+            }
+            let saved = attributes.assume_init();
+            let mut raw = saved;
+            raw.c_iflag &= !ICRNL;
+            raw.c_lflag &= !(ICANON | ECHO | ISIG);
+            tcsetattr(0, TCSANOW, &raw);
+            Some(RawMode(saved))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            tcsetattr(0, TCSANOW, &self.0);
+        }
+    }
+}
+
+/// Attaches to the console of the machine `config_file` defines. With a
+/// script, runs it and detaches; otherwise stays attached until Ctrl-].
+/// Everything the console prints meanwhile is recorded to `record`, if
+/// given.
+pub fn attach(
+    config_file: &Path,
+    script: Option<&Path>,
+    record: Option<&Path>,
+) -> Result<(), Box<dyn error::Error>> {
+    let machine = Machine::load(config_file)?;
+    let script = script.map(Script::load).transpose()?;
+    let mut cast = record
+        .map(|path| {
+            Cast::create(path).map_err(|e| format!("could not create {}: {}", path.display(), e))
+        })
+        .transpose()?;
+    let mut stream = UnixStream::connect(console::socket_path(&machine))
+        .map_err(|e| format!("could not attach to {}: {}", machine.name, e))?;
+    if script.is_none() {
+        println!("attached to {}; press Ctrl-] to detach", machine.name);
+    }
+
+    let output = ConsoleOutput::new();
+    let mut reader = stream.try_clone()?;
+    let follower = output.clone();
+    thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let mut buf = [0; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let _ = stdout.write_all(&buf[..n]);
+                    let _ = stdout.flush();
+                    follower.push(&buf[..n]);
+                    if let Some(recording) = &mut cast {
+                        if let Err(e) = recording.output(&buf[..n]) {
+                            println!("warning: stopped recording: {}", e);
+                            cast = None;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    if let Some(script) = script {
+        // The history is replayed on attach, so a prompt the guest printed
+        // before we got here still counts.
+        script.run(&output, 0, &mut |data| stream.write_all(data))?;
+        return Ok(());
+    }
+
+    let _raw = RawMode::enable();
+    let mut stdin = io::stdin();
+    let mut buf = [0; 1024];
+    loop {
+        let n = stdin.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match buf[..n].iter().position(|&b| b == DETACH) {
+            Some(i) => {
+                stream.write_all(&buf[..i])?;
+                break;
+            }
+            None => stream.write_all(&buf[..n])?,
+        }
+    }
+    Ok(())
+}
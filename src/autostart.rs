@@ -0,0 +1,169 @@
+//! Starts machines at login through a per-machine launchd agent.
+
+use crate::machine::Machine;
+use crate::paths;
+use std::env;
+use std::error;
+use std::fs::{self, canonicalize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Machine names are only unique within a project, so the label also
+/// includes a hash of the config's location.
+fn label(machine: &Machine, config_file: &Path) -> String {
+    let hash = paths::fnv1a(config_file.as_os_str().to_string_lossy().as_bytes());
+    format!("vagrantx.{}.{:08x}", machine.name, hash as u32)
+}
+
+fn plist_path(label: &str) -> PathBuf {
+    let home = env::var_os("HOME").expect("HOME is not set");
+    PathBuf::from(home)
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", label))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn plist(label: &str, program: &Path, config_file: &Path, log: &Path) -> String {
+    let working_dir = config_file.parent().unwrap_or_else(|| Path::new("/"));
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{program}</string>
+        <string>up</string>
+        <string>{config}</string>
+    </array>
+    <key>WorkingDirectory</key>
+    <string>{working_dir}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#,
+        label = escape(label),
+        program = escape(&program.to_string_lossy()),
+        config = escape(&config_file.to_string_lossy()),
+        working_dir = escape(&working_dir.to_string_lossy()),
+        log = escape(&log.to_string_lossy()),
+    )
+}
+
+fn launchctl(args: &[&str]) -> Result<bool, Box<dyn error::Error>> {
+    Ok(Command::new("launchctl").args(args).status()?.success())
+}
+
+fn domain() -> String {
+    format!("gui/{}", unsafe { libc::getuid() })
+}
+
+fn locate(config_file: &Path) -> Result<(PathBuf, Machine, String), Box<dyn error::Error>> {
+    let config_file = canonicalize(config_file)?;
+    let machine = Machine::load(&config_file)?;
+    let label = label(&machine, &config_file);
+    Ok((config_file, machine, label))
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// An agent some earlier `enable` installed.
+pub struct Agent {
+    pub label: String,
+    pub plist: PathBuf,
+    pub config_file: Option<PathBuf>,
+}
+
+/// Every vagrantx agent installed for this user, whichever project it's
+/// for.
+pub fn agents() -> Vec<Agent> {
+    let dir = plist_path("vagrantx").parent().unwrap().to_path_buf();
+    let mut agents: Vec<Agent> = fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|e| e.path()).collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|plist| {
+            let label = plist.file_stem()?.to_string_lossy().into_owned();
+            if !label.starts_with("vagrantx.") || plist.extension() != Some("plist".as_ref()) {
+                return None;
+            }
+            // The config is the argument after `up`, as `plist` writes it.
+            let contents = fs::read_to_string(&plist).unwrap_or_default();
+            let config_file = contents
+                .split_once("<string>up</string>")
+                .and_then(|(_, rest)| rest.split_once("<string>"))
+                .and_then(|(_, rest)| rest.split_once("</string>"))
+                .map(|(config, _)| PathBuf::from(unescape(config)));
+            Some(Agent {
+                label,
+                plist,
+                config_file,
+            })
+        })
+        .collect();
+    agents.sort_by(|a, b| a.label.cmp(&b.label));
+    agents
+}
+
+/// Unloads and deletes `agent`.
+pub fn remove(agent: &Agent) -> Result<(), Box<dyn error::Error>> {
+    let _ = launchctl(&["bootout", &format!("{}/{}", domain(), agent.label)]);
+    fs::remove_file(&agent.plist)?;
+    Ok(())
+}
+
+/// Whether `config_file`'s machine has a launchd agent.
+pub fn enabled(config_file: &Path) -> bool {
+    locate(config_file).is_ok_and(|(_, _, label)| plist_path(&label).exists())
+}
+
+pub fn enable(config_file: &Path) -> Result<(), Box<dyn error::Error>> {
+    let (config_file, machine, label) = locate(config_file)?;
+    let path = plist_path(&label);
+    let program = env::current_exe()?;
+    let log = machine.dir.join("autostart.log");
+
+    fs::create_dir_all(&machine.dir)?;
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    // Re-enabling replaces whatever agent was loaded before, e.g. one
+    // pointing at an older vagrantx binary.
+    let _ = launchctl(&["bootout", &format!("{}/{}", domain(), label)]);
+    fs::write(&path, plist(&label, &program, &config_file, &log))?;
+    if !launchctl(&["bootstrap", &domain(), &path.to_string_lossy()])? {
+        return Err(format!("launchctl could not load {}", path.display()).into());
+    }
+
+    println!("{} will start at login ({})", machine.name, label);
+    Ok(())
+}
+
+pub fn disable(config_file: &Path) -> Result<(), Box<dyn error::Error>> {
+    let (_, machine, label) = locate(config_file)?;
+    let path = plist_path(&label);
+    if !path.exists() {
+        return Err(format!("autostart is not enabled for {}", machine.name).into());
+    }
+
+    let _ = launchctl(&["bootout", &format!("{}/{}", domain(), label)]);
+    fs::remove_file(&path)?;
+
+    println!("{} will no longer start at login", machine.name);
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! The hypervisors a machine can run on. Virtualization.framework is the
+//! default; QEMU covers guests of another architecture and hosts where the
+//! framework isn't available.
+
+use crate::arch;
+use crate::config::{BackendKind, Config, ResolvedBoot};
+use crate::console::Console;
+use crate::machine::Machine;
+use crate::qemu::Qemu;
+use crate::resources::{self, Limits};
+use crate::vm::{self, Vm, VmError};
+use libc::pid_t;
+use objc::runtime::Class;
+use std::env::consts::ARCH;
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+
+/// Why a machine is no longer running.
+#[derive(Debug)]
+pub enum Exit {
+    /// The guest powered off.
+    Stopped,
+    /// The hypervisor gave up on the machine.
+    Error,
+    /// The guest restarted itself, which stops a framework machine rather
+    /// than resetting it. Backends never report this themselves; `up`
+    /// tells it apart from a shutdown by what the guest printed.
+    Rebooted,
+}
+
+/// One boot of a machine. A fresh one is created for every restart.
+pub trait Backend {
+    /// Starts the machine, blocking until it's known whether it started.
+    fn start(&self) -> Result<(), VmError>;
+
+    /// The machine's current state, named as in `status::Status`, and how it
+    /// exited if it has stopped running.
+    fn poll(&self) -> (&'static str, Option<Exit>);
+
+    /// How much memory the balloon device is currently targeting, if the
+    /// backend can tell.
+    fn balloon_target(&self) -> Option<u64>;
+
+    /// Asks the guest to shut down, as if its power button were pressed.
+    fn request_stop(&self) -> Result<(), VmError>;
+
+    /// Stops the machine at once, as if its power were cut.
+    fn force_stop(&self) -> Result<(), VmError>;
+
+    /// Has the balloon device give the guest `bytes` of its memory.
+    fn set_balloon_target(&self, bytes: u64) -> Result<(), VmError>;
+
+    /// The host processes running the guest.
+    fn processes(&self) -> Vec<pid_t>;
+}
+
+pub fn virtualization_supported() -> bool {
+    // Before macOS 11 the framework doesn't exist at all.
+    Class::get("VZVirtualMachine").is_some() && VZVirtualMachine::supported()
+}
+
+/// Resolves `auto` to the backend that will actually run `config`'s guest.
+pub fn select(config: &Config) -> Result<BackendKind, String> {
+    let foreign_arch = arch::is_foreign(config);
+    match config.backend {
+        BackendKind::Auto if foreign_arch || !virtualization_supported() => Ok(BackendKind::Qemu),
+        BackendKind::Auto => Ok(BackendKind::Virtualization),
+        // Rosetta would only help with x86_64 programs inside an aarch64
+        // guest, not with an x86_64 kernel.
+        BackendKind::Virtualization if foreign_arch => Err(format!(
+            "Virtualization.framework can't run {} guests on {}; use \"backend\": \"qemu\" to emulate one, or a box built for {}",
+            arch::guest(config),
+            ARCH,
+            ARCH
+        )),
+        BackendKind::Virtualization if !virtualization_supported() => {
+            Err("Virtualization.framework is not supported on this host".to_string())
+        }
+        kind => Ok(kind),
+    }
+}
+
+/// The resources `kind` allows a machine.
+pub fn limits(kind: BackendKind) -> Limits {
+    match kind {
+        BackendKind::Qemu => resources::host_limits(),
+        _ => resources::limits(),
+    }
+}
+
+/// Builds the machine for one boot on the backend `select` chose.
+pub fn create(
+    kind: BackendKind,
+    config: &Config,
+    boot: &ResolvedBoot,
+    cpu_count: usize,
+    memory_size: usize,
+    console: &Console,
+    machine: &Machine,
+) -> Result<Box<dyn Backend>, VmError> {
+    match kind {
+        BackendKind::Qemu => Ok(Box::new(Qemu::new(
+            config,
+            arch::guest(config),
+            boot,
+            cpu_count,
+            memory_size,
+            console,
+            machine,
+        ))),
+        _ => {
+            let conf =
+                vm::build_configuration(config, boot, cpu_count, memory_size, console, machine)?;
+            Ok(Box::new(Vm::new(
+                conf,
+                &machine.name,
+                config.host_priority.qos,
+            )))
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! `vagrantx bench`: how long a machine takes to boot, over several boots.
+//!
+//! Each run is a `vagrantx up` of its own, with `--machine-readable`, timed
+//! from when it's run: to the kernel starting, to the machine being ready,
+//! and for however long provisioning takes. Once it's done, the machine is
+//! halted for the next run.
+
+use crate::config;
+use crate::halt;
+use crate::machine::Machine;
+use crate::output;
+use crate::status;
+use serde_json::Value;
+use std::env;
+use std::error;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long one run took to reach each point, where it did.
+#[derive(Default)]
+struct Run {
+    kernel: Option<Duration>,
+    ready: Option<Duration>,
+    provisioning: Option<Duration>,
+}
+
+/// What `Run::times` measures, in order.
+const MEASURES: [&str; 3] = ["time to kernel", "time to ready", "provisioning"];
+
+impl Run {
+    fn times(&self) -> [Option<Duration>; 3] {
+        [self.kernel, self.ready, self.provisioning]
+    }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
+
+fn median(sorted: &[Duration]) -> Duration {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Boots the machine once, returning when it's been provisioned, or is
+/// ready if there's nothing to provision, and halted again.
+fn run(
+    config_file: &Path,
+    machine: &Machine,
+    provisions: bool,
+    grace: Duration,
+) -> Result<Run, Box<dyn error::Error>> {
+    let started = Instant::now();
+    let mut child = Command::new(env::current_exe().unwrap_or_else(|_| "vagrantx".into()))
+        .args(["--machine-readable", "up"])
+        .arg(config_file)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run vagrantx up: {}", e))?;
+    let stdout = child.stdout.take().unwrap();
+
+    let mut timings = Run::default();
+    let mut provisioning = None;
+    let mut failure = None;
+    let mut lines = BufReader::new(stdout).lines().map_while(Result::ok);
+    for line in lines.by_ref() {
+        let record: Value = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        let data = &record["data"];
+        let elapsed = started.elapsed();
+        match (record["type"].as_str(), data["event"].as_str()) {
+            // As announced by phases.
+            (Some("message"), _)
+                if data.as_str() == Some(&format!("{}: kernel started", machine.name)) =>
+            {
+                timings.kernel.get_or_insert(elapsed);
+            }
+            (Some("event"), Some("ready")) => timings.ready = Some(elapsed),
+            (Some("event"), Some("provisioning")) => {
+                provisioning.get_or_insert(elapsed);
+            }
+            (Some("event"), Some("provisioned")) => {
+                let from = provisioning.or(timings.ready).unwrap_or_default();
+                timings.provisioning = Some(elapsed - from);
+            }
+            (Some("event"), Some(event @ ("unready" | "unprovisioned" | "crashed"))) => {
+                failure = Some(match data["detail"].as_str() {
+                    Some(detail) => format!("{}: {}", event, detail),
+                    None => event.to_string(),
+                });
+            }
+            (Some("error"), _) => {
+                failure = Some(data["message"].as_str().unwrap_or_default().to_string());
+            }
+            _ => {}
+        }
+        let done = if provisions {
+            timings.provisioning.is_some()
+        } else {
+            timings.ready.is_some()
+        };
+        if done || failure.is_some() {
+            break;
+        }
+    }
+
+    if failure.is_none() {
+        // up only says it's running once it's done with what was measured.
+        while status::published(machine).is_none() && child.try_wait()?.is_none() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        halt::stop(machine, false, grace)?;
+    }
+    // Read to the end, so the child isn't stopped writing to a full pipe.
+    lines.for_each(drop);
+    child.wait()?;
+    match failure {
+        Some(failure) => Err(failure.into()),
+        None if timings.ready.is_none() => Err("vagrantx up exited before it was ready".into()),
+        None => Ok(timings),
+    }
+}
+
+/// Boots the machine `config_file` defines `runs` times, then reports the
+/// fastest, median and slowest time to each point.
+pub fn bench(config_file: &Path, runs: usize) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    if status::published(&machine).is_some() {
+        return Err(format!("{} is running; halt it to benchmark it", machine.name).into());
+    }
+    if runs == 0 {
+        return Err("there has to be at least one run".into());
+    }
+    let provisions = !config.provisioners.is_empty();
+    let grace = Duration::from_secs(config.halt_timeout);
+
+    let mut results = Vec::new();
+    for i in 1..=runs {
+        output::message(&format!("run {} of {}", i, runs));
+        let timings = run(config_file, &machine, provisions, grace)
+            .map_err(|e| format!("run {} failed: {}", i, e))?;
+        let show = |d: Option<Duration>| d.map_or_else(|| "-".to_string(), seconds);
+        output::message(&format!(
+            "kernel {}, ready {}, provisioning {}",
+            show(timings.kernel),
+            show(timings.ready),
+            show(timings.provisioning)
+        ));
+        results.push(timings);
+    }
+
+    println!("{:<16} {:>8} {:>8} {:>8}", "", "MIN", "MEDIAN", "MAX");
+    for (i, name) in MEASURES.iter().enumerate() {
+        let mut times: Vec<Duration> = results.iter().filter_map(|r| r.times()[i]).collect();
+        if times.is_empty() {
+            continue;
+        }
+        times.sort();
+        println!(
+            "{:<16} {:>8} {:>8} {:>8}",
+            name,
+            seconds(times[0]),
+            seconds(median(&times)),
+            seconds(times[times.len() - 1])
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,251 @@
+use crate::arch;
+use crate::extract;
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const METADATA_FILE: &str = "box.json";
+const DISK_FILE: &str = "disk.img";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// A box's `box.json`. Paths are relative to the box directory on disk and
+/// absolute once loaded.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoxMetadata {
+    pub kernel: PathBuf,
+    pub initrd: PathBuf,
+    pub disk: PathBuf,
+
+    /// Default kernel command line for machines using this box.
+    #[serde(default)]
+    pub command_line: Option<String>,
+
+    #[serde(default)]
+    pub root_partition: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshCredentials>,
+
+    /// What the kernel is built for, `aarch64` or `x86_64`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+}
+
+/// How to log in to a fresh machine made from the box.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SshCredentials {
+    pub username: String,
+
+    /// Relative to the box directory, like the other paths.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<PathBuf>,
+}
+
+/// A box archive's `manifest.json`: the SHA-256 of every other file in it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub files: BTreeMap<String, String>,
+}
+
+pub fn box_dir(name: &str) -> PathBuf {
+    paths::boxes_dir().join(name)
+}
+
+pub fn load(name: &str) -> Result<BoxMetadata, Box<dyn error::Error>> {
+    let dir = box_dir(name);
+    let mut file = File::open(dir.join(METADATA_FILE))
+        .map_err(|e| format!("could not open box {}: {}", name, e))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    let mut metadata: BoxMetadata = serde_json::from_str(&contents)?;
+    metadata.kernel = dir.join(&metadata.kernel);
+    metadata.initrd = dir.join(&metadata.initrd);
+    metadata.disk = dir.join(&metadata.disk);
+    if let Some(key) = metadata
+        .ssh
+        .as_mut()
+        .and_then(|ssh| ssh.private_key.as_mut())
+    {
+        *key = dir.join(&*key);
+    }
+    Ok(metadata)
+}
+
+pub fn list() -> Result<Vec<String>, Box<dyn error::Error>> {
+    let dir = paths::boxes_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().join(METADATA_FILE).is_file() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn populate(
+    dir: &Path,
+    disk: &Path,
+    kernel: Option<&Path>,
+    initrd: Option<&Path>,
+    command_line: Option<String>,
+    root_partition: Option<u32>,
+) -> Result<(), Box<dyn error::Error>> {
+    let disk_dest = dir.join(DISK_FILE);
+    fs::copy(disk, &disk_dest)?;
+
+    let root_partition = match (kernel, initrd) {
+        (Some(kernel), Some(initrd)) => {
+            fs::copy(kernel, dir.join("vmlinuz"))?;
+            fs::copy(initrd, dir.join("initrd"))?;
+            root_partition
+        }
+        (None, None) => extract::boot_artifacts(&disk_dest, dir)?,
+        _ => return Err("--kernel and --initrd must be given together".into()),
+    };
+
+    let metadata = BoxMetadata {
+        kernel: PathBuf::from("vmlinuz"),
+        initrd: PathBuf::from("initrd"),
+        disk: PathBuf::from(DISK_FILE),
+        command_line,
+        root_partition,
+        ssh: None,
+        arch: arch::kernel_arch(&dir.join("vmlinuz")).map(String::from),
+    };
+    write_metadata(dir, &metadata)
+}
+
+/// Records who to log in to machines made from box `name` as.
+pub fn set_ssh_username(name: &str, username: &str) -> Result<(), Box<dyn error::Error>> {
+    let dir = box_dir(name);
+    let mut metadata: BoxMetadata = serde_json::from_reader(File::open(dir.join(METADATA_FILE))?)?;
+    let private_key = metadata.ssh.and_then(|ssh| ssh.private_key);
+    metadata.ssh = Some(SshCredentials {
+        username: username.to_string(),
+        private_key,
+    });
+    write_metadata(&dir, &metadata)
+}
+
+/// Writes `metadata`, whose paths must already be relative to `dir`.
+pub fn write_metadata(dir: &Path, metadata: &BoxMetadata) -> Result<(), Box<dyn error::Error>> {
+    let file = File::create(dir.join(METADATA_FILE))?;
+    serde_json::to_writer_pretty(file, metadata)?;
+    Ok(())
+}
+
+pub fn sha256(path: &Path) -> Result<String, Box<dyn error::Error>> {
+    let output = Command::new("shasum")
+        .arg("-a")
+        .arg("256")
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("could not checksum {}", path.display()).into());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Box archives are gzipped tarballs, as written by `package`.
+fn is_archive(path: &Path) -> bool {
+    let mut magic = [0; 2];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| magic == [0x1f, 0x8b])
+        .unwrap_or(false)
+}
+
+/// Unpacks a box archive into `dir` and checks it against its manifest.
+fn unpack(dir: &Path, archive: &Path) -> Result<(), Box<dyn error::Error>> {
+    let status = Command::new("tar")
+        .arg("-xzf")
+        .arg(archive)
+        .arg("-C")
+        .arg(dir)
+        .status()?;
+    if !status.success() {
+        return Err(format!("could not unpack {}", archive.display()).into());
+    }
+
+    let manifest: Manifest = serde_json::from_reader(
+        File::open(dir.join(MANIFEST_FILE)).map_err(|_| "box archive has no manifest")?,
+    )?;
+    for (name, expected) in &manifest.files {
+        if Path::new(name).components().count() != 1 {
+            return Err(format!("box archive manifest names {}", name).into());
+        }
+        if &sha256(&dir.join(name))? != expected {
+            return Err(format!("{} does not match the archive's manifest", name).into());
+        }
+    }
+
+    let metadata: BoxMetadata = serde_json::from_reader(File::open(dir.join(METADATA_FILE))?)?;
+    for path in [&metadata.kernel, &metadata.initrd, &metadata.disk] {
+        if !dir.join(path).is_file() {
+            return Err(format!("box archive is missing {}", path.display()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Imports `disk` as a new box. `disk` may also be a box archive from
+/// `package`, which brings everything else with it. Without an explicit kernel
+/// and initrd, they're extracted from the image's /boot, which also finds the
+/// root partition.
+pub fn add(
+    name: &str,
+    disk: &Path,
+    kernel: Option<&Path>,
+    initrd: Option<&Path>,
+    command_line: Option<String>,
+    root_partition: Option<u32>,
+) -> Result<(), Box<dyn error::Error>> {
+    let dir = box_dir(name);
+    if dir.exists() {
+        return Err(format!("box {} already exists", name).into());
+    }
+
+    // Build the box next to its final location so a failed add leaves
+    // nothing behind that `list` would pick up.
+    let staging = paths::boxes_dir().join(format!(".{}.partial", name));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let result = if is_archive(disk) {
+        unpack(&staging, disk)
+    } else {
+        populate(&staging, disk, kernel, initrd, command_line, root_partition)
+    };
+    match result {
+        Ok(()) => {
+            fs::rename(&staging, &dir)?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Builds new boxes by booting a config's box, provisioning it, and saving
+//! the resulting disk.
+
+use crate::backend::{self, Exit};
+use crate::boxes;
+use crate::config;
+use crate::console::Console;
+use crate::lock;
+use crate::machine::Machine;
+use crate::network;
+use crate::plugins;
+use crate::profiles;
+use crate::readiness;
+use crate::resources;
+use std::error;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the guest gets to power off after provisioning.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(300);
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Rewrites `disk` as a sparse file, leaving holes wherever a whole chunk
+/// is zero. Only space the guest has zeroed is reclaimed, so a provisioner
+/// that fills free space with zeroes first gets the smallest box.
+fn compact(disk: &Path) -> io::Result<()> {
+    let compacted = disk.with_extension("compacting");
+    let mut input = File::open(disk)?;
+    let mut output = File::create(&compacted)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut len = 0;
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if buf[..n].iter().all(|b| *b == 0) {
+            output.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            output.write_all(&buf[..n])?;
+        }
+        len += n as u64;
+    }
+    output.set_len(len)?;
+    output.sync_all()?;
+    fs::rename(&compacted, disk)
+}
+
+/// Boots the box `config_file` uses, runs its provisioners, and saves the
+/// result as the box `box_name`. The new box keeps the base box's kernel
+/// and initrd.
+pub fn build(config_file: &PathBuf, box_name: &str) -> Result<(), Box<dyn error::Error>> {
+    let mut config = config::load_config(config_file)?;
+    profiles::apply(&mut config, None)?;
+    let base_name = config
+        .box_name
+        .as_deref()
+        .ok_or("build needs a config with a box to start from")?;
+    if !config.boot.disks.is_empty() {
+        return Err("build needs the root disk to come from the box, not boot.disks".into());
+    }
+    if boxes::box_dir(box_name).exists() {
+        return Err(format!("box {} already exists", box_name).into());
+    }
+    let base = boxes::load(base_name)?;
+
+    // A scratch machine, so building never touches the config's own.
+    let machine = Machine::new(config_file, Some(&format!("build-{}", box_name)));
+    let _lock = lock::acquire(&machine, "build", false)?;
+    if machine.root_disk().exists() {
+        fs::remove_file(machine.root_disk())?;
+    }
+
+    let kind = backend::select(&config)?;
+    let (cpu_count, memory_size) = resources::check(
+        config.cpu_count,
+        config.memory_size,
+        &backend::limits(kind),
+        config.clamp_resources,
+    )?;
+    let probe = config
+        .readiness
+        .as_ref()
+        .map(readiness::Probe::new)
+        .transpose()?;
+    let boot = config.resolve_boot(&machine)?;
+    let console = Console::new();
+
+    println!("building {} from {}", box_name, base_name);
+    let vm = backend::create(
+        kind,
+        &config,
+        &boot,
+        cpu_count,
+        memory_size,
+        &console,
+        &machine,
+    )?;
+    // Making the machine gave it its address.
+    let mac = network::saved_mac_address(&machine)
+        .ok_or_else(|| format!("{} has no MAC address", machine.name))?;
+    let booted_at = console.output().position();
+    vm.start()?;
+    if let Some(probe) = &probe {
+        probe.wait(console.output(), booted_at, &mac)?;
+    }
+    plugins::provision_all(&config.provisioners, &machine, config_file, &mac)?;
+
+    println!("shutting down {}", machine.name);
+    vm.request_stop()?;
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    loop {
+        match vm.poll() {
+            (_, Some(Exit::Stopped)) => break,
+            (_, Some(Exit::Error)) => return Err("machine failed while shutting down".into()),
+            _ if Instant::now() >= deadline => {
+                return Err(format!(
+                    "machine did not power off within {}s",
+                    SHUTDOWN_TIMEOUT.as_secs()
+                )
+                .into())
+            }
+            _ => thread::sleep(Duration::from_secs(1)),
+        }
+    }
+    drop(vm);
+
+    println!("compacting {}", machine.root_disk().display());
+    compact(&machine.root_disk())?;
+    boxes::add(
+        box_name,
+        &machine.root_disk(),
+        Some(&base.kernel),
+        Some(&base.initrd),
+        base.command_line.clone(),
+        base.root_partition,
+    )?;
+
+    fs::remove_dir_all(&machine.dir)?;
+    println!("built box {}", box_name);
+    Ok(())
+}
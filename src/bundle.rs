@@ -0,0 +1,258 @@
+//! `vagrantx export` and `import`: a whole machine in one file, to move it
+//! to another Mac or hand it to someone.
+//!
+//! A bundle is a gzipped tarball of
+//!
+//! ```text
+//! bundle.json     what's in it
+//! config/<file>   the config, with any includes merged in
+//! machine/        the machine directory: disks, snapshots, keys, events
+//! box/            the box it was made from, if it has one
+//! ```
+//!
+//! What only means something while `up` runs, its lock, sockets, status and
+//! forwards, is left out.
+
+use crate::boxes;
+use crate::cache;
+use crate::config;
+use crate::events;
+use crate::lock;
+use crate::machine::Machine;
+use crate::output;
+use crate::paths;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+const METADATA_FILE: &str = "bundle.json";
+
+/// Files in the machine directory that don't outlive the `up` using them.
+const TRANSIENT: &[&str] = &[
+    "lock",
+    "status.json",
+    "running.json",
+    "forwards",
+    "api-token",
+    "autostart.log",
+    "console.pty",
+    "health.json",
+];
+
+/// A bundle's `bundle.json`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Metadata {
+    name: String,
+    /// The config's file name, under `config/`.
+    config: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    box_name: Option<String>,
+    created: String,
+}
+
+fn transient(name: &str) -> bool {
+    TRANSIENT.contains(&name) || name.ends_with(".sock") || name.ends_with(".partial")
+}
+
+/// Copies the tree at `from` to `to`, leaving out the top-level entries
+/// `skip` picks. On APFS the files are clones.
+fn copy_tree(
+    from: &Path,
+    to: &Path,
+    skip: &dyn Fn(&str) -> bool,
+) -> Result<(), Box<dyn error::Error>> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if skip(&name.to_string_lossy()) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &to.join(&name), &|_| false)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), to.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+/// A staging directory at `path`, emptied of anything an earlier attempt
+/// left.
+fn fresh_dir(path: PathBuf) -> Result<PathBuf, Box<dyn error::Error>> {
+    if path.exists() {
+        fs::remove_dir_all(&path)?;
+    }
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Writes the machine `config_file` defines to a bundle at `output`. The
+/// machine must be halted, which holding its lock guarantees.
+pub fn export(config_file: &PathBuf, output: &Path) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(config_file)?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    if !machine.dir.exists() {
+        return Err(format!("{} has never been started", machine.name).into());
+    }
+    let _lock = lock::acquire(&machine, "export", false)?;
+
+    let dir = fs::canonicalize(&machine.dir)?;
+    let outside: Vec<&PathBuf> = config
+        .boot
+        .disks
+        .iter()
+        .chain(&config.additional_disks)
+        .chain(&config.boot.kernel)
+        .chain(&config.boot.initrd)
+        .chain(&config.boot.cdrom)
+        .filter(|path| !path.to_str().is_some_and(cache::is_url))
+        .filter(|path| fs::canonicalize(path).map_or(true, |path| !path.starts_with(&dir)))
+        .collect();
+    for path in outside {
+        output::warning(&format!(
+            "{} is outside the machine directory and won't be in the bundle",
+            path.display()
+        ));
+    }
+
+    output::message(&format!("exporting {}", machine.name));
+    // Beside the machine directory rather than in it, so it isn't copied
+    // into itself.
+    let staging = fresh_dir(
+        machine
+            .dir
+            .with_file_name(format!(".{}.export.partial", machine.name)),
+    )?;
+    let result = (|| -> Result<(), Box<dyn error::Error>> {
+        let file_name = config_file
+            .file_name()
+            .ok_or("the config has no file name")?
+            .to_string_lossy()
+            .into_owned();
+        fs::create_dir_all(staging.join("config"))?;
+        match config::standalone(config_file)? {
+            Some(merged) => fs::write(
+                staging.join("config").join(&file_name),
+                serde_json::to_vec_pretty(&merged)?,
+            )?,
+            None => {
+                fs::copy(config_file, staging.join("config").join(&file_name))?;
+            }
+        }
+
+        copy_tree(&machine.dir, &staging.join("machine"), &transient)?;
+        if let Some(name) = &config.box_name {
+            copy_tree(&boxes::box_dir(name), &staging.join("box"), &|_| false)?;
+        }
+
+        let metadata = Metadata {
+            name: machine.name.clone(),
+            config: file_name,
+            box_name: config.box_name.clone(),
+            created: events::timestamp(SystemTime::now()),
+        };
+        fs::write(
+            staging.join(METADATA_FILE),
+            serde_json::to_vec_pretty(&metadata)?,
+        )?;
+
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(output)
+            .arg("-C")
+            .arg(&staging)
+            .arg(".")
+            .status()?;
+        if !status.success() {
+            return Err(format!("could not write {}", output.display()).into());
+        }
+        Ok(())
+    })();
+    fs::remove_dir_all(&staging)?;
+    result?;
+
+    output::message(&format!("wrote {}", output.display()));
+    Ok(())
+}
+
+/// Unpacks the bundle at `bundle` into the project at `dir`: its config
+/// beside any others there, its machine directory under `.vagrantx`, and
+/// its box, unless there's already one by that name.
+pub fn import(bundle: &Path, dir: &Path) -> Result<(), Box<dyn error::Error>> {
+    let project = dir.join(".vagrantx");
+    fs::create_dir_all(&project)?;
+    let staging = fresh_dir(project.join(".import.partial"))?;
+    let result = install(bundle, dir, &staging);
+    fs::remove_dir_all(&staging)?;
+    result
+}
+
+fn install(bundle: &Path, dir: &Path, staging: &Path) -> Result<(), Box<dyn error::Error>> {
+    let status = Command::new("tar")
+        .arg("-xzf")
+        .arg(bundle)
+        .arg("-C")
+        .arg(staging)
+        .status()?;
+    if !status.success() {
+        return Err(format!("could not unpack {}", bundle.display()).into());
+    }
+    let metadata: Metadata = serde_json::from_slice(
+        &fs::read(staging.join(METADATA_FILE))
+            .map_err(|_| format!("{} is not a vagrantx bundle", bundle.display()))?,
+    )?;
+    let names = [
+        Some(&metadata.name),
+        Some(&metadata.config),
+        metadata.box_name.as_ref(),
+    ];
+    for name in names.into_iter().flatten() {
+        if Path::new(name).components().count() != 1 {
+            return Err(format!("the bundle names a file {}", name).into());
+        }
+    }
+
+    let config_file = dir.join(&metadata.config);
+    let machine = Machine::new(&config_file, Some(&metadata.name));
+    if config_file.exists() {
+        return Err(format!("{} already exists", config_file.display()).into());
+    }
+    if machine.dir.exists() {
+        return Err(format!("there is already a machine named {}", machine.name).into());
+    }
+
+    if let Some(name) = &metadata.box_name {
+        let dest = boxes::box_dir(name);
+        if dest.exists() {
+            output::message(&format!("using the box {} already here", name));
+        } else {
+            // Staged next to its final location, as `box add` does, since
+            // the bundle may be on another volume.
+            fs::create_dir_all(paths::boxes_dir())?;
+            let partial = fresh_dir(paths::boxes_dir().join(format!(".{}.partial", name)))?;
+            let copied = copy_tree(&staging.join("box"), &partial, &|_| false)
+                .and_then(|()| Ok(fs::rename(&partial, &dest)?));
+            if let Err(e) = copied {
+                let _ = fs::remove_dir_all(&partial);
+                return Err(e);
+            }
+            output::message(&format!("added the box {}", name));
+        }
+    }
+
+    fs::create_dir_all(machine.dir.parent().unwrap())?;
+    fs::rename(staging.join("machine"), &machine.dir)?;
+    fs::rename(staging.join("config").join(&metadata.config), &config_file)?;
+    output::message(&format!(
+        "imported {}; vagrantx up {} starts it",
+        machine.name,
+        config_file.display()
+    ));
+    Ok(())
+}
@@ -0,0 +1,373 @@
+//! Downloads, kept in `~/.vagrantx/cache` so the same kernel, initrd, ISO
+//! or box archive is only fetched once.
+//!
+//! Files are stored by the SHA-256 of their contents, and `index.json`
+//! records which URL each came from and when it was last used. Once the
+//! cache grows past its limit, the least recently used files go first. The
+//! limit is `VAGRANTX_CACHE_LIMIT`, e.g. `20G`, and defaults to 10 GiB.
+//!
+//! Wherever a config or command takes a URL, a `#sha256=<hex>` on the end
+//! gives the checksum the download must have. A download that's cut short
+//! is picked up where it left off next time.
+
+use crate::boxes;
+use crate::output;
+use crate::paths;
+use crate::stats::format_bytes;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::error;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::slice;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_LIMIT: u64 = 10 * 1024 * 1024 * 1024;
+
+/// How often a download's progress is shown.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Seconds since the epoch.
+    pub last_used: u64,
+}
+
+fn index_path() -> PathBuf {
+    paths::cache_dir().join("index.json")
+}
+
+fn blob_path(sha256: &str) -> PathBuf {
+    paths::cache_dir().join(sha256)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Entries whose file is still there, most recently used first.
+fn load_index() -> Vec<Entry> {
+    let mut entries: Vec<Entry> = fs::read(index_path())
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+    entries.retain(|e| blob_path(&e.sha256).is_file());
+    entries.sort_by_key(|e| Reverse(e.last_used));
+    entries
+}
+
+fn save_index(entries: &[Entry]) -> Result<(), Box<dyn error::Error>> {
+    fs::create_dir_all(paths::cache_dir())?;
+    let partial = index_path().with_extension("partial");
+    fs::write(&partial, serde_json::to_vec_pretty(entries)?)?;
+    fs::rename(&partial, index_path())?;
+    Ok(())
+}
+
+/// A size like `512M` or `20G`, or a plain number of bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("invalid size {}", s)),
+    };
+    number
+        .parse::<u64>()
+        .map(|n| n.saturating_mul(multiplier))
+        .map_err(|_| format!("invalid size {}", s))
+}
+
+fn limit() -> Result<u64, String> {
+    match env::var("VAGRANTX_CACHE_LIMIT") {
+        Ok(limit) => parse_size(&limit),
+        Err(_) => Ok(DEFAULT_LIMIT),
+    }
+}
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// `location` as a local path: itself if it's a path, or its cached
+/// download if it's a URL.
+pub fn resolve(location: &Path) -> Result<PathBuf, Box<dyn error::Error>> {
+    match location.to_str() {
+        Some(location) if is_url(location) => {
+            let (url, sha256) = split_checksum(location);
+            fetch(url, sha256)
+        }
+        _ => Ok(location.to_path_buf()),
+    }
+}
+
+/// A URL without any `#sha256=` on the end, and the checksum that was.
+fn split_checksum(location: &str) -> (&str, Option<&str>) {
+    match location.rsplit_once("#sha256=") {
+        Some((url, sha256)) => (url, Some(sha256)),
+        None => (location, None),
+    }
+}
+
+/// Where the download of `url` goes until it's done. The same each time,
+/// so an interrupted download can be resumed.
+fn partial_path(url: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    paths::cache_dir().join(format!(".{:016x}.partial", hasher.finish()))
+}
+
+fn find(entries: &[Entry], url: &str, sha256: Option<&str>) -> Option<usize> {
+    entries
+        .iter()
+        .position(|e| sha256.map_or(e.url == url, |sha256| e.sha256.eq_ignore_ascii_case(sha256)))
+}
+
+/// How big the download at `url` will be, if the server says.
+fn content_length(url: &str) -> Option<u64> {
+    let output = Command::new("curl").args(["-fsIL", url]).output().ok()?;
+    // Each redirect has its own headers; the last are the file's.
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())
+                .flatten()
+        })
+        .next_back()
+}
+
+/// The cached copy of `url`, downloading it first if there isn't one.
+/// With `sha256`, a copy with different contents doesn't count.
+pub fn fetch(url: &str, sha256: Option<&str>) -> Result<PathBuf, Box<dyn error::Error>> {
+    let mut entries = load_index();
+    if let Some(i) = find(&entries, url, sha256) {
+        entries[i].last_used = now();
+        let path = blob_path(&entries[i].sha256);
+        save_index(&entries)?;
+        return Ok(path);
+    }
+
+    fs::create_dir_all(paths::cache_dir())?;
+    let partial = partial_path(url);
+    // Machines brought up together often share a kernel; only one of them
+    // downloads it.
+    let lock = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial)?;
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(format!("could not lock {}", partial.display()).into());
+    }
+    entries = load_index();
+    if let Some(i) = find(&entries, url, sha256) {
+        let _ = fs::remove_file(&partial);
+        entries[i].last_used = now();
+        save_index(&entries)?;
+        return Ok(blob_path(&entries[i].sha256));
+    }
+
+    let total = if output::interactive() {
+        content_length(url)
+    } else {
+        None
+    };
+    let mut status = download(url, &partial, total)?;
+    // 33: the server can't resume, so start again from the beginning.
+    if status.code() == Some(33) {
+        fs::File::create(&partial)?;
+        status = download(url, &partial, total)?;
+    }
+    if !status.success() {
+        // What did arrive is kept, to resume from.
+        return Err(format!("could not download {} ({})", url, status).into());
+    }
+
+    let actual = boxes::sha256(&partial)?;
+    if let Some(expected) = sha256 {
+        if !actual.eq_ignore_ascii_case(expected) {
+            let _ = fs::remove_file(&partial);
+            return Err(format!(
+                "{} has SHA-256 {}, not the expected {}",
+                url, actual, expected
+            )
+            .into());
+        }
+    }
+    let path = blob_path(&actual);
+    // The same file from another URL is only stored once.
+    if path.exists() {
+        fs::remove_file(&partial)?;
+    } else {
+        fs::rename(&partial, &path)?;
+    }
+    entries.retain(|e| e.url != url);
+    entries.insert(
+        0,
+        Entry {
+            url: url.to_string(),
+            sha256: actual,
+            size: fs::metadata(&path)?.len(),
+            last_used: now(),
+        },
+    );
+    save_index(&entries)?;
+    gc(limit()?, slice::from_ref(&path))?;
+    Ok(path)
+}
+
+/// Downloads `url` into `partial`, carrying on from whatever is already
+/// there.
+fn download(
+    url: &str,
+    partial: &Path,
+    total: Option<u64>,
+) -> Result<ExitStatus, Box<dyn error::Error>> {
+    let progress = output::progress(&format!("downloading {}", url));
+    let mut curl = Command::new("curl")
+        .args(["-fLsS", "--retry", "3", "-C", "-", "-o"])
+        .arg(partial)
+        .arg(url)
+        .spawn()?;
+    loop {
+        if let Some(status) = curl.try_wait()? {
+            return Ok(status);
+        }
+        let done = fs::metadata(partial).map_or(0, |m| m.len());
+        progress.set(done, total);
+        thread::sleep(PROGRESS_INTERVAL);
+    }
+}
+
+/// Removes the least recently used files until the cache fits in `limit`,
+/// never removing any of `keep`. Returns how many bytes were freed.
+fn gc(limit: u64, keep: &[PathBuf]) -> Result<u64, Box<dyn error::Error>> {
+    let mut entries = load_index();
+    let mut blobs: Vec<(String, u64, u64)> = Vec::new();
+    for entry in &entries {
+        match blobs
+            .iter_mut()
+            .find(|(sha256, _, _)| sha256 == &entry.sha256)
+        {
+            Some(blob) => blob.2 = blob.2.max(entry.last_used),
+            None => blobs.push((entry.sha256.clone(), entry.size, entry.last_used)),
+        }
+    }
+    blobs.sort_by_key(|(_, _, last_used)| *last_used);
+
+    let mut total: u64 = blobs.iter().map(|(_, size, _)| size).sum();
+    let mut freed = 0;
+    for (sha256, size, _) in blobs {
+        if total <= limit {
+            break;
+        }
+        let path = blob_path(&sha256);
+        if keep.contains(&path) {
+            continue;
+        }
+        fs::remove_file(&path)?;
+        entries.retain(|e| e.sha256 != sha256);
+        total -= size;
+        freed += size;
+    }
+    save_index(&entries)?;
+    Ok(freed)
+}
+
+/// What's wrong with the cache, if anything: an index that won't load,
+/// files it lists that are missing or the wrong size, and downloads that
+/// never finished.
+pub fn problems() -> Vec<String> {
+    let mut problems = Vec::new();
+    let entries: Vec<Entry> = match fs::read(index_path()) {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(entries) => entries,
+            Err(e) => {
+                problems.push(format!("{} is unreadable: {}", index_path().display(), e));
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    };
+    for entry in &entries {
+        match fs::metadata(blob_path(&entry.sha256)) {
+            Ok(metadata) if metadata.len() == entry.size => {}
+            Ok(_) => problems.push(format!("the download of {} is the wrong size", entry.url)),
+            Err(_) => problems.push(format!("the download of {} is missing", entry.url)),
+        }
+    }
+    for entry in fs::read_dir(paths::cache_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        if entry.file_name().to_string_lossy().ends_with(".partial") {
+            problems.push(format!(
+                "{} is an unfinished download",
+                entry.path().display()
+            ));
+        }
+    }
+    problems
+}
+
+/// Prints what's cached, most recently used first.
+pub fn list(json: bool) -> Result<(), Box<dyn error::Error>> {
+    let entries = load_index();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("the download cache is empty");
+        return Ok(());
+    }
+    println!("{:<12} {:>10}  URL", "SHA256", "SIZE");
+    for entry in &entries {
+        println!(
+            "{:<12} {:>10}  {}",
+            &entry.sha256[..entry.sha256.len().min(12)],
+            format_bytes(entry.size as f64),
+            entry.url
+        );
+    }
+    let total: u64 = entries.iter().map(|e| e.size).sum();
+    println!(
+        "{} of {} used",
+        format_bytes(total as f64),
+        format_bytes(limit()? as f64)
+    );
+    Ok(())
+}
+
+/// Cleans the cache down to `limit`, or the configured limit. A limit of 0
+/// empties it.
+pub fn clean(limit_override: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+    let limit = match limit_override {
+        Some(limit) => parse_size(limit)?,
+        None => limit()?,
+    };
+    let freed = gc(limit, &[])?;
+    println!("freed {}", format_bytes(freed as f64));
+    Ok(())
+}
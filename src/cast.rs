@@ -0,0 +1,86 @@
+//! Recording console sessions as asciinema (asciicast v2) cast files.
+
+use libc::{ioctl, winsize, STDOUT_FILENO, TIOCGWINSZ};
+use serde_json::json;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem::MaybeUninit;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The terminal's size, or the traditional 80x24 if it doesn't have one.
+pub fn terminal_size() -> (u16, u16) {
+    unsafe {
+        let mut size = MaybeUninit::<winsize>::zeroed();
+        if ioctl(STDOUT_FILENO, TIOCGWINSZ, size.as_mut_ptr()) == 0 {
+            let size = size.assume_init();
+            if size.ws_col > 0 && size.ws_row > 0 {
+                return (size.ws_col, size.ws_row);
+            }
+        }
+    }
+    (80, 24)
+}
+
+pub struct Cast {
+    file: BufWriter<File>,
+    started: Instant,
+    /// The start of a UTF-8 sequence split across reads, held back until
+    /// the rest of it arrives.
+    pending: Vec<u8>,
+}
+
+impl Cast {
+    pub fn create(path: &Path) -> io::Result<Cast> {
+        let (width, height) = terminal_size();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut file = BufWriter::new(File::create(path)?);
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "env": {
+                "SHELL": env::var("SHELL").unwrap_or_default(),
+                "TERM": env::var("TERM").unwrap_or_default(),
+            },
+        });
+        writeln!(file, "{}", header)?;
+        file.flush()?;
+        Ok(Cast {
+            file,
+            started: Instant::now(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Records `data` as printed to the terminal now.
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        let text = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.to_string(),
+            Err(e) if e.error_len().is_none() => {
+                let valid = e.valid_up_to();
+                let text = String::from_utf8_lossy(&self.pending[..valid]).into_owned();
+                self.pending.drain(..valid);
+                return self.event(&text);
+            }
+            Err(_) => String::from_utf8_lossy(&self.pending).into_owned(),
+        };
+        self.pending.clear();
+        self.event(&text)
+    }
+
+    fn event(&mut self, text: &str) -> io::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let time = self.started.elapsed().as_secs_f64();
+        writeln!(self.file, "{}", json!([time, "o", text]))?;
+        self.file.flush()
+    }
+}
@@ -0,0 +1,140 @@
+use std::error;
+use std::fmt;
+
+/// The command line used when neither the box nor the config gives one.
+/// It only names a root device if there's a disk to be it, so a machine
+/// that runs from its initrd alone still boots.
+pub fn default_command_line(disk_count: usize) -> &'static str {
+    if disk_count == 0 {
+        "console=hvc0"
+    } else {
+        "console=hvc0 root={root_disk}"
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid command line template: {}", self.0)
+    }
+}
+
+impl error::Error for TemplateError {}
+
+/// Returns the name the guest kernel gives the virtio block device at
+/// `index`, following the same scheme as Linux: vda..vdz, vdaa, vdab, ...
+pub fn virtio_block_device(index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        suffix.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    suffix.reverse();
+    format!("/dev/vd{}", String::from_utf8(suffix).unwrap())
+}
+
+fn root_device(
+    disk_count: usize,
+    root_disk: usize,
+    root_partition: Option<u32>,
+) -> Result<String, TemplateError> {
+    if root_disk >= disk_count {
+        return Err(TemplateError(format!(
+            "root_disk is {} but only {} disk(s) are attached",
+            root_disk, disk_count
+        )));
+    }
+
+    let device = virtio_block_device(root_disk);
+    Ok(match root_partition {
+        Some(partition) => format!("{}{}", device, partition),
+        None => device,
+    })
+}
+
+fn lookup(
+    name: &str,
+    disk_count: usize,
+    root_disk: usize,
+    root_partition: Option<u32>,
+) -> Result<String, TemplateError> {
+    if name == "root_disk" {
+        return root_device(disk_count, root_disk, root_partition);
+    }
+
+    if let Some(index) = name.strip_prefix("disk") {
+        let index: usize = index
+            .parse()
+            .map_err(|_| TemplateError(format!("unknown variable {{{}}}", name)))?;
+        if index >= disk_count {
+            return Err(TemplateError(format!(
+                "{{{}}} refers to a disk that is not attached",
+                name
+            )));
+        }
+        return Ok(virtio_block_device(index));
+    }
+
+    Err(TemplateError(format!("unknown variable {{{}}}", name)))
+}
+
+/// Expands `{root_disk}` and `{diskN}` in a kernel command line. Literal
+/// braces are written as `{{` and `}}`.
+pub fn render(
+    template: &str,
+    disk_count: usize,
+    root_disk: usize,
+    root_partition: Option<u32>,
+) -> Result<String, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => {
+                            return Err(TemplateError(format!("unterminated {{{}", name)));
+                        }
+                    }
+                }
+                out.push_str(&lookup(name.trim(), disk_count, root_disk, root_partition)?);
+            }
+            '}' => return Err(TemplateError("unmatched }".to_string())),
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Combines a base command line with the user's override and extra
+/// arguments: `command_line`, when set, replaces the base entirely, and
+/// `append` is added to whichever one wins.
+pub fn merge(base: &str, command_line: Option<&str>, append: &str) -> String {
+    let mut merged = command_line.unwrap_or(base).trim().to_string();
+    let append = append.trim();
+    if !append.is_empty() {
+        if !merged.is_empty() {
+            merged.push(' ');
+        }
+        merged.push_str(append);
+    }
+    merged
+}
@@ -0,0 +1,110 @@
+//! Shell completions and a man page, both generated from the CLI definition.
+
+use std::io::{self, Write};
+use structopt::clap::{App, ErrorKind, Shell};
+
+/// bash: complete box and snapshot names, and defer to clap's completion for
+/// everything else.
+const BASH_DYNAMIC: &str = r#"
+_vagrantx_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local words=("${COMP_WORDS[@]:1:COMP_CWORD-1}")
+    case "${words[*]}" in
+        init)
+            COMPREPLY=($(compgen -W "$(vagrantx complete boxes 2>/dev/null)" -- "$cur"))
+            return 0
+            ;;
+        "snapshot restore "*|"snapshot delete "*)
+            if [[ ${#words[@]} -eq 3 ]]; then
+                COMPREPLY=($(compgen -W "$(vagrantx complete snapshots "${words[2]}" 2>/dev/null)" -- "$cur"))
+                return 0
+            fi
+            ;;
+    esac
+    _vagrantx "$@"
+}
+complete -F _vagrantx_dynamic -o bashdefault -o default vagrantx
+"#;
+
+const FISH_DYNAMIC: &str = r#"
+complete -c vagrantx -n "__fish_seen_subcommand_from init" -f -a "(vagrantx complete boxes 2>/dev/null)"
+complete -c vagrantx -n "__fish_seen_subcommand_from restore delete; and test (count (commandline -opc)) -eq 4" -f -a "(vagrantx complete snapshots (commandline -opc)[4] 2>/dev/null)"
+"#;
+
+pub fn completions(mut app: App, shell: Shell) {
+    let mut stdout = io::stdout();
+    app.gen_completions_to("vagrantx", shell, &mut stdout);
+    let _ = match shell {
+        Shell::Bash => stdout.write_all(BASH_DYNAMIC.as_bytes()),
+        Shell::Fish => stdout.write_all(FISH_DYNAMIC.as_bytes()),
+        _ => Ok(()),
+    };
+}
+
+/// The help clap shows for `vagrantx <path> --help`.
+fn help(app: &App, path: &[&str]) -> String {
+    let args = ["vagrantx"]
+        .iter()
+        .chain(path.iter())
+        .chain(["--help"].iter())
+        .copied();
+    match app.clone().get_matches_from_safe(args) {
+        Err(e) if e.kind == ErrorKind::HelpDisplayed => e.message,
+        _ => String::new(),
+    }
+}
+
+/// The subcommands listed in a help screen's SUBCOMMANDS section.
+fn subcommands(help: &str) -> Vec<String> {
+    help.lines()
+        .skip_while(|l| !l.starts_with("SUBCOMMANDS:"))
+        .skip(1)
+        .take_while(|l| l.starts_with(' '))
+        .filter(|l| l.starts_with("    ") && !l.starts_with("     "))
+        .filter_map(|l| l.split_whitespace().next())
+        .filter(|name| *name != "help")
+        .map(str::to_string)
+        .collect()
+}
+
+fn roff_escape(text: &str) -> String {
+    text.lines()
+        .map(|l| {
+            let l = l.replace('\\', "\\e");
+            if l.starts_with('.') || l.starts_with('\'') {
+                format!("\\&{}", l)
+            } else {
+                l
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn man_section(out: &mut String, app: &App, path: &mut Vec<String>) {
+    let args: Vec<&str> = path.iter().map(String::as_str).collect();
+    let help = help(app, &args);
+    out.push_str(&format!(".SS \"vagrantx {}\"\n.nf\n", path.join(" ")));
+    out.push_str(&roff_escape(help.trim_end()));
+    out.push_str("\n.fi\n");
+
+    for subcommand in subcommands(&help) {
+        path.push(subcommand);
+        man_section(out, app, path);
+        path.pop();
+    }
+}
+
+pub fn man_page(app: App) {
+    let help = help(&app, &[]);
+    let mut out = String::new();
+    out.push_str(".TH VAGRANTX 1\n");
+    out.push_str(".SH NAME\nvagrantx \\- run Linux virtual machines on macOS\n");
+    out.push_str(".SH DESCRIPTION\n.nf\n");
+    out.push_str(&roff_escape(help.trim_end()));
+    out.push_str("\n.fi\n.SH COMMANDS\n");
+    for subcommand in subcommands(&help) {
+        man_section(&mut out, &app, &mut vec![subcommand]);
+    }
+    print!("{}", out);
+}
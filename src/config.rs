@@ -0,0 +1,980 @@
+use crate::boxes;
+use crate::cache;
+use crate::cmdline;
+use crate::error::{existing, Error};
+use crate::interpolate;
+use crate::machine::Machine;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+fn default_cpu() -> usize {
+    2
+}
+
+fn default_mem_size() -> usize {
+    2147483648
+}
+
+fn default_time_sync() -> bool {
+    true
+}
+
+fn default_readiness_timeout() -> u64 {
+    300
+}
+
+fn default_health_interval() -> u64 {
+    30
+}
+
+fn default_health_timeout() -> u64 {
+    5
+}
+
+fn default_halt_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Boot {
+    /// Defaults to the box's kernel when `box` is set. It, the initrd, the
+    /// cdrom and any disk may be an http(s) URL, downloaded into the cache
+    /// and optionally checksummed with `#sha256=<hex>`.
+    #[serde(default)]
+    pub kernel: Option<PathBuf>,
+
+    #[serde(default)]
+    pub initrd: Option<PathBuf>,
+
+    /// Replaces the box's (or vagrantx's) default command line. May reference
+    /// `{root_disk}` and `{diskN}`, which expand to the guest's virtio block
+    /// device paths.
+    #[serde(default)]
+    pub command_line: Option<String>,
+
+    /// Extra arguments added after the (default or overridden) command line.
+    #[serde(default)]
+    pub command_line_append: String,
+
+    /// Index of the root filesystem among all attached disks, counting
+    /// `disks` first and then `additional_disks`.
+    #[serde(default)]
+    pub root_disk: usize,
+
+    #[serde(default)]
+    pub root_partition: Option<u32>,
+
+    /// Defaults to a copy of the box's disk when `box` is set.
+    #[serde(default)]
+    pub disks: Vec<PathBuf>,
+
+    /// Read-only install media, such as a distro's installer ISO.
+    #[serde(default)]
+    pub cdrom: Option<PathBuf>,
+
+    /// What to boot. `up --boot-from` overrides it for one run.
+    #[serde(default)]
+    pub from: BootFrom,
+}
+
+/// Where a machine boots from: its kernel, loaded directly, or through
+/// UEFI firmware booting `cdrom` or the disks. The firmware needs neither
+/// a kernel nor an initrd.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootFrom {
+    #[default]
+    Kernel,
+    Cdrom,
+    Disk,
+}
+
+impl FromStr for BootFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BootFrom, String> {
+        match s {
+            "kernel" => Ok(BootFrom::Kernel),
+            "cdrom" => Ok(BootFrom::Cdrom),
+            "disk" => Ok(BootFrom::Disk),
+            _ => Err(format!("{} is not kernel, cdrom or disk", s)),
+        }
+    }
+}
+
+impl fmt::Display for BootFrom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BootFrom::Kernel => "kernel",
+            BootFrom::Cdrom => "cdrom",
+            BootFrom::Disk => "disk",
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Platform {
+    #[serde(default)]
+    pub nested_virtualization: bool,
+}
+
+/// What to do when a running guest stops without being asked to.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Restart only if the machine hit an error, not on a clean poweroff.
+    OnFailure,
+    Always,
+}
+
+/// A named resource preset. Whatever a profile sets replaces the machine's
+/// own value.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub cpu_count: Option<usize>,
+
+    #[serde(default)]
+    pub memory_size: Option<usize>,
+
+    #[serde(default)]
+    pub disk_size: Option<u64>,
+}
+
+/// Which hypervisor runs the machine.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Virtualization.framework when it can run the guest, QEMU otherwise.
+    #[default]
+    Auto,
+    Virtualization,
+    Qemu,
+}
+
+/// Where the guest's serial console goes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Serial {
+    /// The terminal `up` runs in, when it's run in one.
+    #[default]
+    Stdio,
+    /// A PTY of its own, for `screen` or `minicom`, leaving the terminal
+    /// alone. Its path is printed and kept in the machine's `console.pty`.
+    Pty,
+}
+
+/// Tuning for the machine's network device.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Nic {
+    /// The guest interface's MTU. Set in the guest through cloud-init,
+    /// and under QEMU also advertised by the device. Virtualization.framework's
+    /// NAT carries at most 1500 bytes.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+
+    /// Off to stop the device offloading checksums. QEMU only; the
+    /// framework doesn't let it be changed.
+    #[serde(default)]
+    pub checksum_offload: Option<bool>,
+
+    /// Off to stop the device offloading TCP segmentation. QEMU only.
+    #[serde(default)]
+    pub tso: Option<bool>,
+}
+
+/// Where else the guest's console goes, besides `serial`, and who may type
+/// into it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsoleSinks {
+    /// Copy what the guest prints to up's stdout. Defaults to on unless
+    /// `serial` is `pty`.
+    #[serde(default)]
+    pub stdio: Option<bool>,
+
+    /// Serve the socket `vagrantx console` attaches to.
+    #[serde(default = "default_console_socket")]
+    pub socket: bool,
+
+    /// A file to append everything the guest prints to.
+    #[serde(default)]
+    pub log: Option<PathBuf>,
+
+    #[serde(default)]
+    pub input: ConsoleInput,
+}
+
+fn default_console_socket() -> bool {
+    true
+}
+
+impl Default for ConsoleSinks {
+    fn default() -> Self {
+        ConsoleSinks {
+            stdio: None,
+            socket: default_console_socket(),
+            log: None,
+            input: ConsoleInput::default(),
+        }
+    }
+}
+
+/// Who may type into the guest's console. Console scripts and the API
+/// always can.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConsoleInput {
+    /// The terminal or PTY `up` runs with, and every `vagrantx console`.
+    #[default]
+    All,
+    /// Only the terminal or PTY; attached consoles just watch.
+    Serial,
+    /// Only consoles attached with `vagrantx console`.
+    Attached,
+    /// Nobody.
+    ReadOnly,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Qemu {
+    /// The same as the top-level `arch`, which wins if both are set.
+    #[serde(default)]
+    pub arch: Option<String>,
+
+    /// Defaults to `qemu-system-<arch>` on the `PATH`.
+    #[serde(default)]
+    pub binary: Option<PathBuf>,
+}
+
+/// The macOS quality-of-service class to run a machine at.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Qos {
+    /// Whatever the host gives a process started from the terminal.
+    #[default]
+    Default,
+    /// Lower priority, for work the user isn't waiting on.
+    Utility,
+    /// Lowest priority, with the guest's CPU and disk I/O throttled whenever
+    /// the host is busy.
+    Background,
+}
+
+/// How hard a machine competes with the rest of the host for CPU, so a
+/// background build doesn't make the desktop sluggish.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostPriority {
+    #[serde(default)]
+    pub qos: Qos,
+
+    /// Nice value for the processes running the guest, 0 to 20. Defaults to
+    /// 10 for the `utility` and `background` classes.
+    #[serde(default)]
+    pub nice: Option<i32>,
+
+    /// Most host CPU the guest may use, as a percentage of one core, e.g.
+    /// 150 for one and a half cores.
+    #[serde(default)]
+    pub cpu_limit: Option<u32>,
+}
+
+fn default_forward_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// A host port relayed to a port on the guest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortForward {
+    pub guest: u16,
+    pub host: u16,
+
+    /// The host address to listen on.
+    #[serde(default = "default_forward_address")]
+    pub address: String,
+}
+
+impl FromStr for PortForward {
+    type Err = String;
+
+    /// Parses `host:guest` or `address:host:guest`, as with `ssh -L`.
+    fn from_str(s: &str) -> Result<PortForward, String> {
+        let invalid = || format!("{} is not [address:]host-port:guest-port", s);
+        let (rest, guest) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let (address, host) = match rest.rsplit_once(':') {
+            Some((address, host)) => (address.to_string(), host),
+            None => (default_forward_address(), rest),
+        };
+        Ok(PortForward {
+            guest: guest.parse().map_err(|_| invalid())?,
+            host: host.parse().map_err(|_| invalid())?,
+            address,
+        })
+    }
+}
+
+/// How `vagrantx ssh` logs in. Anything unset falls back to the box's
+/// credentials.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ssh {
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+
+    /// Forward the host's SSH agent, as with `ssh -A`.
+    #[serde(default)]
+    pub forward_agent: bool,
+
+    /// Extra OpenSSH options, as passed to `ssh -o`, e.g.
+    /// `ProxyJump=bastion`.
+    #[serde(default)]
+    pub options: Vec<String>,
+
+    /// Generate a key for the machine and authorize it through cloud-init,
+    /// unless `identity_file` is set.
+    #[serde(default = "default_generate_key")]
+    pub generate_key: bool,
+}
+
+fn default_generate_key() -> bool {
+    true
+}
+
+impl Default for Ssh {
+    fn default() -> Self {
+        Ssh {
+            username: None,
+            identity_file: None,
+            forward_agent: false,
+            options: Vec::new(),
+            generate_key: default_generate_key(),
+        }
+    }
+}
+
+/// How a synced folder gets to the guest. Only rsync is supported, since
+/// there's no shared filesystem a guest can be relied on to mount.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncedFolderKind {
+    #[default]
+    Rsync,
+}
+
+/// A host directory copied into the guest by `up` and `vagrantx rsync`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncedFolder {
+    /// The host directory, relative to the config file.
+    pub host: PathBuf,
+
+    /// Where it goes on the guest. Files there that aren't on the host are
+    /// removed.
+    pub guest: String,
+
+    #[serde(default, rename = "type")]
+    pub kind: SyncedFolderKind,
+
+    /// rsync patterns for files to leave out, e.g. `.git/` or `*.log`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+fn default_guest_env_path() -> String {
+    "/etc/vagrantx/env".to_string()
+}
+
+/// Variables `up` writes into the guest over SSH each time it becomes
+/// ready, so secrets never have to be baked into an image.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestEnv {
+    /// Host environment variables to pass on, as `NAME` or
+    /// `GUEST_NAME=HOST_NAME`.
+    #[serde(default)]
+    pub from_host: Vec<String>,
+
+    /// A host file of `NAME=value` lines, e.g. one kept out of version
+    /// control.
+    #[serde(default)]
+    pub secrets_file: Option<PathBuf>,
+
+    /// Where the guest gets them, as `NAME='value'` lines only root can
+    /// read. Shells can source it and systemd units can use it as an
+    /// `EnvironmentFile`.
+    #[serde(default = "default_guest_env_path")]
+    pub path: String,
+}
+
+impl Default for GuestEnv {
+    fn default() -> Self {
+        GuestEnv {
+            from_host: Vec::new(),
+            secrets_file: None,
+            path: default_guest_env_path(),
+        }
+    }
+}
+
+impl GuestEnv {
+    pub fn is_empty(&self) -> bool {
+        self.from_host.is_empty() && self.secrets_file.is_none()
+    }
+}
+
+/// Conditions `up` waits for before reporting the machine ready. All that
+/// are set must pass.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Readiness {
+    /// Regex to wait for on the serial console, e.g. `login:`.
+    #[serde(default)]
+    pub console: Option<String>,
+
+    /// Guest TCP port that must accept connections.
+    #[serde(default)]
+    pub tcp_port: Option<u16>,
+
+    /// Seconds to wait before giving up.
+    #[serde(default = "default_readiness_timeout")]
+    pub timeout: u64,
+}
+
+/// Something `up` keeps checking for as long as the machine is running.
+/// Exactly one of `tcp_port`, `http_port` and `command` is set.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Defaults to a description of the check, e.g. `tcp 5432`.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Guest TCP port that must accept connections.
+    #[serde(default)]
+    pub tcp_port: Option<u16>,
+
+    /// Guest port to GET `path` from, which must answer with a 2xx or 3xx.
+    #[serde(default)]
+    pub http_port: Option<u16>,
+
+    #[serde(default = "default_health_path")]
+    pub path: String,
+
+    /// Run over SSH, since there's no guest agent; must exit 0.
+    #[serde(default)]
+    pub command: Option<String>,
+
+    /// Seconds between checks.
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+
+    /// Seconds a check may take before it counts as failed.
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Defaults to the config file's name.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(rename = "box", default)]
+    pub box_name: Option<String>,
+
+    #[serde(default)]
+    pub boot: Boot,
+
+    #[serde(default = "default_cpu")]
+    pub cpu_count: usize,
+
+    #[serde(default)]
+    pub additional_disks: Vec<PathBuf>,
+
+    #[serde(default = "default_mem_size")]
+    pub memory_size: usize,
+
+    /// Grow the machine's root disk to this many bytes. Only disks cloned
+    /// from a box are grown, and never shrunk; the guest has to grow its
+    /// filesystem itself.
+    #[serde(default)]
+    pub disk_size: Option<u64>,
+
+    /// Profile to apply when `up` isn't given `--profile`.
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// Profiles for this project, on top of the built-in and global ones.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// Pull cpu_count and memory_size into the range the host supports,
+    /// with a warning, instead of refusing to start.
+    #[serde(default)]
+    pub clamp_resources: bool,
+
+    /// Setting this opts into a generic platform configuration with a
+    /// persistent machine identifier.
+    #[serde(default)]
+    pub platform: Option<Platform>,
+
+    /// An expect script (see `vagrantx console --script`) to drive the
+    /// console through after each boot, before readiness is checked.
+    #[serde(default)]
+    pub console_script: Option<PathBuf>,
+
+    #[serde(default)]
+    pub serial: Serial,
+
+    #[serde(default)]
+    pub console_sinks: ConsoleSinks,
+
+    #[serde(default)]
+    pub readiness: Option<Readiness>,
+
+    /// Checked while the machine runs, and shown by `vagrantx status`.
+    /// Each change is recorded as a `healthy` or `unhealthy` event.
+    #[serde(default)]
+    pub health_checks: Vec<HealthCheck>,
+
+    #[serde(default)]
+    pub restart: RestartPolicy,
+
+    /// Seconds `vagrantx halt` gives the guest to shut down before forcing
+    /// it off.
+    #[serde(default = "default_halt_timeout")]
+    pub halt_timeout: u64,
+
+    /// Where to serve Prometheus metrics while the machine runs, e.g.
+    /// `127.0.0.1:9464`.
+    #[serde(default)]
+    pub metrics_address: Option<String>,
+
+    /// Where to serve the control API while the machine runs. Requests must
+    /// carry the token `up` writes to the machine's `api-token` file.
+    #[serde(default)]
+    pub api_address: Option<String>,
+
+    #[serde(default)]
+    pub backend: BackendKind,
+
+    #[serde(default)]
+    pub ssh: Ssh,
+
+    /// Guest architecture, `aarch64` or `x86_64`. Defaults to the box's,
+    /// then the kernel's, then the host's; a guest that isn't the host's
+    /// runs emulated under QEMU.
+    #[serde(default)]
+    pub arch: Option<String>,
+
+    /// Set the guest's clock over SSH when it resumes or the host wakes
+    /// from sleep.
+    #[serde(default = "default_time_sync")]
+    pub time_sync: bool,
+
+    /// Names to point at the guest in the host's `/etc/hosts` while it
+    /// runs, e.g. `web.test`. Updating the file needs sudo.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+
+    /// An address on the NAT network for the guest to keep, e.g.
+    /// `192.168.64.50`, instead of whatever DHCP leases it. It's configured
+    /// through cloud-init, so the guest needs it.
+    #[serde(default)]
+    pub static_ip: Option<Ipv4Addr>,
+
+    #[serde(default)]
+    pub nic: Nic,
+
+    #[serde(default)]
+    pub host_priority: HostPriority,
+
+    #[serde(default)]
+    pub guest_env: GuestEnv,
+
+    /// Host directories to copy into the guest once it's ready.
+    #[serde(default)]
+    pub synced_folders: Vec<SyncedFolder>,
+
+    /// An Ignition config, or a Butane one ending in `.bu`, for Fedora
+    /// CoreOS and Flatcar guests to provision themselves from on first boot.
+    #[serde(default)]
+    pub ignition: Option<PathBuf>,
+
+    /// Machines that have to be ready before this one starts, when several
+    /// are brought up together.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    /// Host ports `up` relays to the guest while it runs.
+    #[serde(default)]
+    pub forwarded_ports: Vec<PortForward>,
+
+    #[serde(default)]
+    pub qemu: Qemu,
+
+    /// Config fragments keyed by environment name. The one named by
+    /// `VAGRANTX_ENV` (or `--env`) is merged over the rest of the config.
+    #[serde(default, skip_serializing)]
+    pub overrides: BTreeMap<String, serde_json::Value>,
+
+    /// Run in order once the machine first becomes ready.
+    #[serde(default)]
+    pub provisioners: Vec<Provisioner>,
+
+    /// Events to post a Notification Center alert for, e.g. `ready`,
+    /// `unprovisioned` or `crashed`.
+    #[serde(default)]
+    pub notify: Vec<String>,
+
+    /// Save the disks as the `pre-provision` snapshot before provisioners
+    /// run, so a run that breaks the machine can be undone.
+    #[serde(default)]
+    pub snapshot_before_provision: bool,
+}
+
+/// A provisioner supplied by a plugin. Everything besides `type` is passed
+/// to the plugin untouched.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Provisioner {
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    #[serde(flatten)]
+    pub options: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Everything needed to boot, after filling in defaults from the box. The
+/// paths have been checked and made absolute.
+#[derive(Clone)]
+pub struct ResolvedBoot {
+    /// Only for booting `BootFrom::Kernel`.
+    pub kernel: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    pub command_line: String,
+    pub disks: Vec<PathBuf>,
+    /// A read-only disk for the guest to configure itself from; see `seed`.
+    pub seed: Option<PathBuf>,
+    /// An Ignition config for QEMU to pass through fw_cfg; see `ignition`.
+    pub ignition: Option<PathBuf>,
+    pub cdrom: Option<PathBuf>,
+    pub from: BootFrom,
+}
+
+/// Blanks out `//` comments, which configs may use even though JSON doesn't
+/// allow them. Comments become spaces so error positions stay right.
+fn strip_comments(contents: &str) -> String {
+    let mut out = String::with_capacity(contents.len());
+    let mut chars = contents.chars().peekable();
+    let (mut in_string, mut escaped) = (false, false);
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '/' && chars.peek() == Some(&'/') {
+            out.push(' ');
+            while let Some(&next) = chars.peek() {
+                if next == '\n' {
+                    break;
+                }
+                out.push(' ');
+                chars.next();
+            }
+        } else {
+            in_string = c == '"';
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// `config_file`'s contents, minus comments.
+fn read_config(config_file: &PathBuf) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(config_file)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| Error::path(config_file, e))?;
+    Ok(strip_comments(&contents))
+}
+
+fn parse<T: serde::de::DeserializeOwned>(config_file: &Path, contents: &str) -> Result<T, Error> {
+    serde_json::from_str(contents)
+        .map_err(|e| Error::Config(format!("{}: {}", config_file.display(), e)))
+}
+
+/// `config_file` merged over the configs listed in its `include`, which
+/// are relative to it. Each included config is merged over the one before
+/// it. `including` holds the files whose includes are being followed, to
+/// catch cycles.
+fn with_includes(
+    config_file: &Path,
+    mut value: serde_json::Value,
+    including: &mut Vec<PathBuf>,
+) -> Result<serde_json::Value, Error> {
+    let includes: Vec<PathBuf> = match value.get("include") {
+        Some(include) => serde_json::from_value(include.clone()).map_err(|_| {
+            Error::Config(format!(
+                "{}: include must be a list of paths",
+                config_file.display()
+            ))
+        })?,
+        None => return Ok(value),
+    };
+    let canonical = existing(config_file)?;
+    if including.contains(&canonical) {
+        return Err(Error::Config(format!(
+            "{} includes itself",
+            config_file.display()
+        )));
+    }
+    including.push(canonical);
+
+    let dir = config_file.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = serde_json::Value::Object(Default::default());
+    for include in includes {
+        let path = dir.join(include);
+        let included = with_includes(&path, parse(&path, &read_config(&path)?)?, including)?;
+        merge(&mut merged, included);
+    }
+    including.pop();
+
+    if let Some(object) = value.as_object_mut() {
+        object.remove("include");
+    }
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// `config_file` with its includes merged in, as a config that needs no
+/// other file, or `None` if it includes nothing. Variables are left for
+/// wherever it's loaded.
+pub fn standalone(config_file: &PathBuf) -> Result<Option<serde_json::Value>, Error> {
+    let value: serde_json::Value = parse(config_file, &read_config(config_file)?)?;
+    if value.get("include").is_none() {
+        return Ok(None);
+    }
+    with_includes(config_file, value, &mut Vec::new()).map(Some)
+}
+
+/// `location` as a file that exists, downloading it into the cache first
+/// if it's a URL.
+fn local(location: &Path) -> Result<PathBuf, Box<dyn error::Error>> {
+    Ok(existing(&cache::resolve(location)?)?)
+}
+
+/// The machine's own copy of the disk at `url`, the `index`th it has, so
+/// the download in the cache stays pristine.
+fn downloaded_disk(
+    machine: &Machine,
+    index: usize,
+    url: &Path,
+    clone_disk: bool,
+) -> Result<PathBuf, Box<dyn error::Error>> {
+    let disk = machine.dir.join(format!("disk-{}.img", index));
+    if !disk.exists() {
+        let downloaded = cache::resolve(url)?;
+        if !clone_disk {
+            return Ok(existing(&downloaded)?);
+        }
+        fs::create_dir_all(&machine.dir)?;
+        fs::copy(&downloaded, &disk)?;
+    }
+    Ok(existing(&disk)?)
+}
+
+pub fn load_config(config_file: &PathBuf) -> Result<Config, Box<dyn error::Error>> {
+    let contents = read_config(config_file)?;
+    let value: serde_json::Value = parse(config_file, &contents)?;
+    // Included configs may have variables even if this one doesn't.
+    let mut config: Config = if value.get("include").is_some() || contents.contains("${") {
+        let mut value = if value.get("include").is_some() {
+            with_includes(config_file, value, &mut Vec::new())?
+        } else {
+            value
+        };
+        interpolate::expand(config_file, &mut value)
+            .map_err(|e| Error::Config(format!("{}: {}", config_file.display(), e)))?;
+        serde_json::from_value(value)
+            .map_err(|e| Error::Config(format!("{}: {}", config_file.display(), e)))?
+    } else {
+        // From the text, so errors say where in the file they are.
+        parse(config_file, &contents)?
+    };
+    let environment = match env::var("VAGRANTX_ENV") {
+        Ok(environment) => environment,
+        Err(_) => return Ok(config),
+    };
+    if let Some(overlay) = config.overrides.remove(&environment) {
+        let mut value = serde_json::to_value(&config)?;
+        merge(&mut value, overlay);
+        config = serde_json::from_value(value)
+            .map_err(|e| Error::Config(format!("overrides.{}: {}", environment, e)))?;
+    }
+    Ok(config)
+}
+
+/// Merges `overlay` into `base`. Objects are merged key by key; anything
+/// else, arrays included, is replaced outright.
+fn merge(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl Config {
+    pub fn resolve_boot(&self, machine: &Machine) -> Result<ResolvedBoot, Box<dyn error::Error>> {
+        self.resolve(machine, true)
+    }
+
+    /// Like `resolve_boot`, but leaves the machine directory alone: a
+    /// machine that doesn't have its own disk yet is given the box's.
+    pub fn preview_boot(&self, machine: &Machine) -> Result<ResolvedBoot, Box<dyn error::Error>> {
+        self.resolve(machine, false)
+    }
+
+    fn resolve(
+        &self,
+        machine: &Machine,
+        clone_disk: bool,
+    ) -> Result<ResolvedBoot, Box<dyn error::Error>> {
+        let boot_box = match &self.box_name {
+            Some(name) => Some(boxes::load(name)?),
+            None => None,
+        };
+
+        // Firmware finds its own way to a kernel, so none is passed.
+        let direct = self.boot.from == BootFrom::Kernel;
+        let kernel = self
+            .boot
+            .kernel
+            .clone()
+            .or_else(|| boot_box.as_ref().map(|b| b.kernel.clone()))
+            .filter(|_| direct);
+        let initrd = self
+            .boot
+            .initrd
+            .clone()
+            .or_else(|| boot_box.as_ref().map(|b| b.initrd.clone()))
+            .filter(|_| direct);
+        if direct && kernel.is_none() {
+            return Err(Error::Config("boot.kernel is required when no box is set".into()).into());
+        }
+        if direct && initrd.is_none() {
+            return Err(Error::Config("boot.initrd is required when no box is set".into()).into());
+        }
+        if self.boot.from == BootFrom::Cdrom && self.boot.cdrom.is_none() {
+            return Err(Error::Config("booting from the cdrom needs boot.cdrom".into()).into());
+        }
+
+        let mut boot_disks = self.boot.disks.clone();
+        if let (true, Some(boot_box)) = (boot_disks.is_empty(), &boot_box) {
+            // Machines get their own copy so the box stays pristine. On APFS
+            // this is a clone, so it's cheap regardless of image size.
+            let disk = machine.root_disk();
+            if !clone_disk {
+                boot_disks.push(if disk.exists() {
+                    disk
+                } else {
+                    boot_box.disk.clone()
+                });
+            } else {
+                if !disk.exists() {
+                    fs::create_dir_all(&machine.dir)?;
+                    fs::copy(&boot_box.disk, &disk)?;
+                }
+                if let Some(size) = self.disk_size {
+                    let file = fs::OpenOptions::new().write(true).open(&disk)?;
+                    if file.metadata()?.len() < size {
+                        file.set_len(size)?;
+                    }
+                }
+                boot_disks.push(disk);
+            }
+        }
+
+        // Checked here so a missing file is reported as such, before it
+        // reaches a hypervisor.
+        let kernel = kernel.as_deref().map(local).transpose()?;
+        let initrd = initrd.as_deref().map(local).transpose()?;
+        let cdrom = self.boot.cdrom.as_deref().map(local).transpose()?;
+        let disks = boot_disks
+            .iter()
+            .chain(self.additional_disks.iter())
+            .enumerate()
+            .map(|(i, disk)| match disk.to_str() {
+                Some(url) if cache::is_url(url) => downloaded_disk(machine, i, disk, clone_disk),
+                _ => Ok(existing(disk)?),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let base = boot_box
+            .as_ref()
+            .and_then(|b| b.command_line.as_deref())
+            .unwrap_or_else(|| cmdline::default_command_line(disks.len()));
+        let root_partition = self
+            .boot
+            .root_partition
+            .or_else(|| boot_box.as_ref().and_then(|b| b.root_partition));
+        let command_line = cmdline::render(
+            &cmdline::merge(
+                base,
+                self.boot.command_line.as_deref(),
+                &self.boot.command_line_append,
+            ),
+            disks.len(),
+            self.boot.root_disk,
+            root_partition,
+        )?;
+
+        Ok(ResolvedBoot {
+            kernel,
+            initrd,
+            command_line,
+            disks,
+            seed: None,
+            ignition: None,
+            cdrom,
+            from: self.boot.from,
+        })
+    }
+}
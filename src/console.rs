@@ -0,0 +1,504 @@
+use crate::config::{ConsoleInput, ConsoleSinks, Serial};
+use crate::machine::Machine;
+use crate::output;
+use libc::{
+    cfmakeraw, dup, grantpt, isatty, pipe, posix_openpt, ptsname, tcgetattr, tcsetattr, termios,
+    unlockpt, ECHO, ICANON, ICRNL, O_NOCTTY, O_RDWR, STDIN_FILENO, TCSANOW,
+};
+use objc::rc::StrongPtr;
+use objc::runtime::YES;
+use objc::{class, msg_send, sel, sel_impl};
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use virtualization_rs::{
+    base::{Id, NSFileHandle},
+    virtualization::serial_port::{
+        VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
+    },
+};
+
+/// How much recent guest output to keep for probes and diagnostics.
+const HISTORY_SIZE: usize = 64 * 1024;
+
+/// Reads of guest output to hold for a PTY nobody is reading before
+/// dropping them.
+const PTY_BACKLOG: usize = 64;
+
+struct History {
+    data: Vec<u8>,
+    /// Bytes ever pushed, so followers can tell what they've already seen.
+    total: usize,
+}
+
+/// Recent guest console output, shared with whoever is waiting on it.
+#[derive(Clone)]
+pub struct ConsoleOutput {
+    inner: Arc<(Mutex<History>, Condvar)>,
+}
+
+impl ConsoleOutput {
+    pub fn new() -> ConsoleOutput {
+        ConsoleOutput {
+            inner: Arc::new((
+                Mutex::new(History {
+                    data: Vec::new(),
+                    total: 0,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    pub fn push(&self, data: &[u8]) {
+        let (history, changed) = &*self.inner;
+        let mut history = history.lock().unwrap();
+        history.data.extend_from_slice(data);
+        history.total += data.len();
+        if history.data.len() > HISTORY_SIZE {
+            let excess = history.data.len() - HISTORY_SIZE;
+            history.data.drain(..excess);
+        }
+        changed.notify_all();
+    }
+
+    /// How many bytes the guest has printed so far.
+    pub fn position(&self) -> usize {
+        self.inner.0.lock().unwrap().total
+    }
+
+    /// Blocks until `pattern` matches output past `position` or `timeout`
+    /// passes, returning the position just after the match.
+    pub fn expect(
+        &self,
+        pattern: &regex::bytes::Regex,
+        position: usize,
+        timeout: Duration,
+    ) -> Option<usize> {
+        let deadline = Instant::now() + timeout;
+        let (history, changed) = &*self.inner;
+        let mut history = history.lock().unwrap();
+        loop {
+            let kept_from = history.total - history.data.len();
+            let start = position.max(kept_from) - kept_from;
+            if let Some(m) = pattern.find(&history.data[start..]) {
+                return Some(kept_from + start + m.end());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            history = changed.wait_timeout(history, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Waits up to `timeout` for output past `position`, returning it with
+    /// the position to continue from. Output that has already fallen out of
+    /// the history is skipped.
+    pub fn read_from(&self, position: usize, timeout: Duration) -> (Vec<u8>, usize) {
+        let (history, changed) = &*self.inner;
+        let mut history = history.lock().unwrap();
+        if history.total <= position {
+            history = changed.wait_timeout(history, timeout).unwrap().0;
+        }
+        let kept_from = history.total - history.data.len();
+        let start = position.max(kept_from) - kept_from;
+        (history.data[start..].to_vec(), history.total)
+    }
+
+    /// The last `count` lines the guest printed.
+    pub fn tail(&self, count: usize) -> Vec<String> {
+        let history = self.inner.0.lock().unwrap();
+        let text = String::from_utf8_lossy(&history.data);
+        let lines: Vec<&str> = text.lines().collect();
+        lines[lines.len().saturating_sub(count)..]
+            .iter()
+            .map(|l| l.trim_end_matches('\r').to_string())
+            .collect()
+    }
+}
+
+/// Somewhere guest output is copied to, besides the history every console
+/// keeps and attached consoles follow.
+enum Sink {
+    /// Our stdout, as machine-readable records if that's asked for.
+    Stdout,
+    /// A PTY, through a backlog that's full when nobody's reading.
+    Pty(SyncSender<Vec<u8>>),
+    Log(PathBuf, File),
+}
+
+impl Sink {
+    /// Whether the sink can still take output.
+    fn write(&mut self, data: &[u8]) -> bool {
+        match self {
+            Sink::Stdout => output::console(data),
+            // Dropped when the backlog is full; probes still see it in the
+            // history.
+            Sink::Pty(pty) => drop(pty.try_send(data.to_vec())),
+            Sink::Log(path, file) => {
+                if let Err(e) = file.write_all(data) {
+                    output::warning(&format!(
+                        "stopped logging the console to {}: {}",
+                        path.display(),
+                        e
+                    ));
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Copies guest output to each of `sinks` while keeping recent history.
+fn spawn_tee(mut reader: File, output: ConsoleOutput, mut sinks: Vec<Sink>) {
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    sinks.retain_mut(|sink| sink.write(&buf[..n]));
+                    output.push(&buf[..n]);
+                }
+            }
+        }
+    });
+}
+
+/// Copies everything from `reader` into `writer` until either end closes.
+fn spawn_copy(mut reader: impl Read + Send + 'static, mut writer: impl Write + Send + 'static) {
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if writer.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn make_pipe(what: &str) -> (RawFd, RawFd) {
+    let mut fds = [0; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        panic!(
+            "could not create console {} pipe: {}",
+            what,
+            io::Error::last_os_error()
+        );
+    }
+    (fds[0], fds[1])
+}
+
+/// A new PTY, as its controlling side and the path of the side to attach
+/// to.
+fn open_pty() -> io::Result<(File, PathBuf)> {
+    unsafe {
+        let fd = posix_openpt(O_RDWR | O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let controller = File::from_raw_fd(fd);
+        if grantpt(fd) != 0 || unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let name = ptsname(fd);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned());
+        Ok((controller, path))
+    }
+}
+
+/// Sends an attached client everything the guest prints until it goes away.
+fn spawn_follow(mut stream: UnixStream, output: ConsoleOutput) {
+    thread::spawn(move || {
+        let mut position = 0;
+        loop {
+            let (data, next) = output.read_from(position, Duration::from_secs(1));
+            position = next;
+            if stream.write_all(&data).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// The socket `vagrantx console` attaches to.
+pub fn socket_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("console.sock")
+}
+
+/// Where `up` notes the path of the machine's PTY, when it has one.
+pub fn pty_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("console.pty")
+}
+
+fn file_handle_with_descriptor(fd: i32) -> NSFileHandle {
+    unsafe {
+        let alloc: Id = msg_send![class!(NSFileHandle), alloc];
+        let p = StrongPtr::new(msg_send![alloc, initWithFileDescriptor: fd closeOnDealloc: YES]);
+        NSFileHandle(p)
+    }
+}
+
+/// The host side of the guest's serial console. It outlives any one boot so
+/// that restarts keep writing to the same terminal and history.
+pub struct Console {
+    output: ConsoleOutput,
+    write_fd: RawFd,
+    /// What the guest reads: our stdin, attached clients and scripts all
+    /// write into this pipe.
+    input_read_fd: RawFd,
+    input_write_fd: RawFd,
+    input: ConsoleInput,
+    /// With a PTY, its path and our own hold on it. Without one open, reads
+    /// of the controlling side fail once whoever attached goes away.
+    pty: Option<(PathBuf, File)>,
+    /// The terminal's settings from before we made it raw, if we did.
+    terminal: Option<RawTerminal>,
+}
+
+/// Puts the terminal back how it was when dropped.
+struct RawTerminal {
+    fd: RawFd,
+    saved: termios,
+}
+
+impl RawTerminal {
+    fn restore(&self) {
+        unsafe { tcsetattr(self.fd, TCSANOW, &self.saved) };
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Makes the terminal pass keys through as they're typed, until the
+/// returned guard is dropped.
+fn raw_terminal() -> io::Result<RawTerminal> {
+    let file_handle_for_reading = NSFileHandle::file_handle_with_standard_input();
+    let fd: RawFd = unsafe { msg_send![*file_handle_for_reading.0, fileDescriptor] };
+
+    let mut attributes = MaybeUninit::uninit();
+    if unsafe { tcgetattr(fd, attributes.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let saved = unsafe { attributes.assume_init() };
+    let mut attributes = saved;
+    attributes.c_iflag &= !ICRNL;
+    attributes.c_lflag &= !(ICANON | ECHO);
+    if unsafe { tcsetattr(fd, TCSANOW, &attributes) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(RawTerminal { fd, saved })
+}
+
+impl Console {
+    pub fn new() -> Console {
+        let mut console = Console::buffered();
+        if unsafe { isatty(STDIN_FILENO) } == 1 {
+            match raw_terminal() {
+                Ok(terminal) => console.terminal = Some(terminal),
+                Err(e) => {
+                    output::warning(&format!("could not put the terminal in raw mode: {}", e))
+                }
+            }
+        }
+        spawn_copy(io::stdin(), console.input());
+        console
+    }
+
+    /// A console that leaves the terminal as it is, for machines nobody is
+    /// typing into.
+    pub fn buffered() -> Console {
+        Console::with_sinks(vec![Sink::Stdout], ConsoleInput::All, None)
+    }
+
+    /// The console `up` gives a machine: on the terminal, if `terminal`
+    /// says there is one, or a PTY of its own, and copied to whatever else
+    /// `sinks` asks for.
+    pub fn open(serial: Serial, sinks: &ConsoleSinks, terminal: bool) -> io::Result<Console> {
+        let mut outputs = Vec::new();
+        let stdio = sinks.stdio.unwrap_or(serial == Serial::Stdio);
+        if stdio {
+            outputs.push(Sink::Stdout);
+        }
+        if let Some(path) = &sinks.log {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            outputs.push(Sink::Log(path.clone(), file));
+        }
+        let typed = matches!(sinks.input, ConsoleInput::All | ConsoleInput::Serial);
+
+        if serial == Serial::Pty {
+            let (console, controller) = Console::pty(outputs, sinks.input)?;
+            if typed {
+                spawn_copy(controller, console.input());
+            } else {
+                spawn_copy(controller, io::sink());
+            }
+            return Ok(console);
+        }
+        let mut console = Console::with_sinks(outputs, sinks.input, None);
+        if terminal && stdio && typed {
+            console.terminal = Some(raw_terminal()?);
+            spawn_copy(io::stdin(), console.input());
+        }
+        Ok(console)
+    }
+
+    /// A console on a PTY of its own, so it can be attached to with
+    /// `screen` or `minicom` while the terminal is left alone. Returns its
+    /// controlling side, for what's typed there.
+    fn pty(mut outputs: Vec<Sink>, input: ConsoleInput) -> io::Result<(Console, File)> {
+        let (controller, path) = open_pty()?;
+        let attached = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NOCTTY)
+            .open(&path)?;
+        // Passed through as is, for whatever terminal attaches.
+        unsafe {
+            let mut attributes = MaybeUninit::uninit();
+            if tcgetattr(attached.as_raw_fd(), attributes.as_mut_ptr()) == 0 {
+                let mut attributes = attributes.assume_init();
+                cfmakeraw(&mut attributes);
+                tcsetattr(attached.as_raw_fd(), TCSANOW, &attributes);
+            }
+        }
+
+        let (sender, backlog) = mpsc::sync_channel::<Vec<u8>>(PTY_BACKLOG);
+        let mut writer = controller.try_clone()?;
+        thread::spawn(move || {
+            for data in backlog {
+                if writer.write_all(&data).is_err() {
+                    break;
+                }
+            }
+        });
+        outputs.push(Sink::Pty(sender));
+        let console = Console::with_sinks(outputs, input, Some((path, attached)));
+        Ok((console, controller))
+    }
+
+    fn with_sinks(sinks: Vec<Sink>, input: ConsoleInput, pty: Option<(PathBuf, File)>) -> Console {
+        // The guest writes into a pipe rather than straight to stdout so we
+        // can watch what it prints.
+        let (read_fd, write_fd) = make_pipe("output");
+        let output = ConsoleOutput::new();
+        spawn_tee(unsafe { File::from_raw_fd(read_fd) }, output.clone(), sinks);
+        let (input_read_fd, input_write_fd) = make_pipe("input");
+
+        Console {
+            output,
+            write_fd,
+            input_read_fd,
+            input_write_fd,
+            input,
+            pty,
+            terminal: None,
+        }
+    }
+
+    /// Puts the terminal back how it was, for handing it to another
+    /// program. The console keeps on reading it.
+    pub fn restore_terminal(&self) {
+        if let Some(terminal) = &self.terminal {
+            terminal.restore();
+        }
+    }
+
+    /// The PTY to attach to, if the console is on one.
+    pub fn pty_path(&self) -> Option<&Path> {
+        self.pty.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    pub fn output(&self) -> &ConsoleOutput {
+        &self.output
+    }
+
+    /// Somewhere for a guest running in another process to write its
+    /// output.
+    pub fn writer(&self) -> File {
+        unsafe { File::from_raw_fd(dup(self.write_fd)) }
+    }
+
+    /// Somewhere for a guest running in another process to read its input.
+    pub fn reader(&self) -> File {
+        unsafe { File::from_raw_fd(dup(self.input_read_fd)) }
+    }
+
+    fn input(&self) -> File {
+        unsafe { File::from_raw_fd(dup(self.input_write_fd)) }
+    }
+
+    /// Types `data` into the guest's console.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.input().write_all(data)
+    }
+
+    /// Lets `vagrantx console` attach at `path`. Each client is sent the
+    /// console history and then follows it, and unless the console's input
+    /// says otherwise, whatever it writes is typed into the guest.
+    pub fn serve(&self, path: &PathBuf) -> io::Result<()> {
+        // A socket left behind by an earlier run would stop us binding.
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let output = self.output.clone();
+        let input_write_fd = self.input_write_fd;
+        let typed = matches!(self.input, ConsoleInput::All | ConsoleInput::Attached);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(reader) = stream.try_clone() {
+                    // Read regardless, so a client isn't left blocked
+                    // writing to us.
+                    if typed {
+                        let input = unsafe { File::from_raw_fd(dup(input_write_fd)) };
+                        spawn_copy(reader, input);
+                    } else {
+                        spawn_copy(reader, io::sink());
+                    }
+                }
+                spawn_follow(stream, output.clone());
+            }
+        });
+        Ok(())
+    }
+
+    pub fn serial_port(&self) -> VZVirtioConsoleDeviceSerialPortConfiguration {
+        // Each machine's file handles close their descriptors when it's torn
+        // down, so give it duplicates of ours.
+        let file_handle_for_reading =
+            file_handle_with_descriptor(unsafe { dup(self.input_read_fd) });
+        let file_handle_for_writing = file_handle_with_descriptor(unsafe { dup(self.write_fd) });
+        let attachement = VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(file_handle_for_reading)
+            .file_handle_for_writing(file_handle_for_writing)
+            .build();
+
+        VZVirtioConsoleDeviceSerialPortConfiguration::new(attachement)
+    }
+}
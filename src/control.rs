@@ -0,0 +1,88 @@
+//! Requests other vagrantx commands make of the `up` running a machine.
+//!
+//! The machine can only be touched from `up`'s own loop, so requests
+//! arriving over the API or on the machine's `control.sock` are handed to
+//! it as `Control` messages. On the socket each request is a line, `stop`,
+//! `force-stop`, `reload` or `balloon <bytes>`, answered with `ok` or
+//! `error: <reason>`.
+
+use crate::machine::Machine;
+use crate::vm::VmError;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// A request the `up` loop carries out, replying on the enclosed channel.
+/// Stops say who asked, for the event log.
+pub enum Control {
+    /// Ask the guest to shut down.
+    Stop(Sender<Result<(), VmError>>, &'static str),
+    /// Stop the machine at once, as if its power were cut.
+    ForceStop(Sender<Result<(), VmError>>, &'static str),
+    /// Shut the guest down, then start it again with the config as it now
+    /// is.
+    Reload(Sender<Result<(), VmError>>),
+    /// Have the balloon device give the guest this many bytes.
+    SetBalloon(Sender<Result<(), VmError>>, u64),
+}
+
+fn socket_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("control.sock")
+}
+
+fn handle(stream: UnixStream, control: &Sender<Control>) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let (reply, result) = mpsc::channel();
+    let request = match line.trim() {
+        "stop" => Control::Stop(reply, "requested by vagrantx halt"),
+        "force-stop" => Control::ForceStop(reply, "forced by vagrantx halt"),
+        "reload" => Control::Reload(reply),
+        other => match other.strip_prefix("balloon ").map(str::parse) {
+            Some(Ok(bytes)) => Control::SetBalloon(reply, bytes),
+            _ => return writeln!(&stream, "error: unknown request {}", other),
+        },
+    };
+    if control.send(request).is_err() {
+        return writeln!(&stream, "error: the machine is shutting down");
+    }
+    match result.recv() {
+        Ok(Ok(())) => writeln!(&stream, "ok"),
+        Ok(Err(e)) => writeln!(&stream, "error: {}", e),
+        Err(_) => writeln!(&stream, "error: the machine is not running"),
+    }
+}
+
+/// Listens on the machine's control socket, passing requests on to
+/// `control`.
+pub fn serve(machine: &Machine, control: Sender<Control>) -> io::Result<()> {
+    let path = socket_path(machine);
+    // A socket left behind by an earlier run would stop us binding.
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = handle(stream, &control);
+        }
+    });
+    Ok(())
+}
+
+/// Sends `request` to the `up` running `machine`, returning once it has
+/// been carried out or refused.
+pub fn request(machine: &Machine, request: &str) -> Result<(), String> {
+    let mut stream = UnixStream::connect(socket_path(machine))
+        .map_err(|e| format!("could not reach the vagrantx up running it: {}", e))?;
+    writeln!(stream, "{}", request).map_err(|e| e.to_string())?;
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .map_err(|e| e.to_string())?;
+    match reply.trim() {
+        "ok" => Ok(()),
+        reply => Err(reply.strip_prefix("error: ").unwrap_or(reply).to_string()),
+    }
+}
@@ -0,0 +1,275 @@
+//! `vagrantx doctor`: whether this Mac can run machines, and what to do
+//! about it if not.
+
+use crate::backend;
+use crate::boxes;
+use crate::cache;
+use crate::paths;
+use crate::resources;
+use crate::stats::format_bytes;
+use std::env;
+use std::env::consts::ARCH;
+use std::error;
+use std::ffi::CString;
+use std::fs;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Below this much free memory, machines of the default size may not fit.
+const LOW_MEMORY: u64 = 2 * GIB;
+
+/// Below this much free disk, a box or two may not fit.
+const LOW_DISK: u64 = 10 * GIB;
+
+enum Outcome {
+    Ok(String),
+    /// Works, but not as well as it could. With a suggested fix.
+    Warning(String, String),
+    /// Machines won't run until it's fixed. With the fix.
+    Failed(String, String),
+}
+
+fn macos_version() -> Option<String> {
+    let output = Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn virtualization() -> Outcome {
+    let version = macos_version().unwrap_or_else(|| "an unknown macOS".to_string());
+    let major: u32 = version
+        .split('.')
+        .next()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    if !backend::virtualization_supported() {
+        return Outcome::Failed(
+            format!("not available on macOS {}", version),
+            "it needs macOS 11 or later on a Mac with virtualization support; until then only QEMU machines run".to_string(),
+        );
+    }
+    if major < 13 {
+        return Outcome::Warning(
+            format!("available on macOS {}", version),
+            "macOS 13 or later brings Rosetta, shared directories and EFI boot".to_string(),
+        );
+    }
+    Outcome::Ok(format!("available on macOS {}", version))
+}
+
+fn architecture() -> Outcome {
+    // 1 when an x86_64 build is being translated by Rosetta.
+    if resources::sysctl_u64("sysctl.proc_translated") == Some(1) {
+        return Outcome::Failed(
+            format!("an {} build running under Rosetta", ARCH),
+            "install the arm64 build, which can run guests natively".to_string(),
+        );
+    }
+    Outcome::Ok(ARCH.to_string())
+}
+
+/// The entitlements `exe` is signed with, as codesign prints them.
+fn entitlements(exe: &Path) -> Result<String, String> {
+    let output = Command::new("codesign")
+        .args(["-d", "--entitlements", "-", "--xml"])
+        .arg(exe)
+        .output()
+        .map_err(|e| format!("could not run codesign: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn signing(exe: &Path, entitlements: &Result<String, String>) -> Outcome {
+    let fix = format!(
+        "codesign --entitlements virtualization_rs.entitlements -s - {}",
+        exe.display()
+    );
+    match entitlements {
+        Ok(e) if e.contains("com.apple.security.virtualization") => {
+            Outcome::Ok("signed with com.apple.security.virtualization".to_string())
+        }
+        Ok(_) => Outcome::Failed(
+            "not entitled to com.apple.security.virtualization".to_string(),
+            fix,
+        ),
+        Err(e) => Outcome::Failed(format!("not signed: {}", e), fix),
+    }
+}
+
+fn bridged(entitlements: &Result<String, String>) -> Outcome {
+    match entitlements {
+        Ok(e) if e.contains("com.apple.vm.networking") => {
+            Outcome::Ok("entitled to com.apple.vm.networking".to_string())
+        }
+        _ => Outcome::Warning(
+            "not entitled to com.apple.vm.networking, so machines use NAT".to_string(),
+            "Apple only grants the entitlement to builds signed with a provisioning profile that includes it".to_string(),
+        ),
+    }
+}
+
+fn memory() -> Outcome {
+    let total = resources::host_memory_size().unwrap_or(0);
+    let free = match (
+        resources::sysctl_u64("vm.page_free_count"),
+        resources::sysctl_u64("hw.pagesize"),
+    ) {
+        (Some(pages), Some(size)) => pages * size,
+        _ => return Outcome::Ok(format!("{} in all", format_bytes(total as f64))),
+    };
+    let detail = format!(
+        "{} free of {}",
+        format_bytes(free as f64),
+        format_bytes(total as f64)
+    );
+    if free < LOW_MEMORY {
+        return Outcome::Warning(
+            detail,
+            "quit something, or give machines a smaller memory_size".to_string(),
+        );
+    }
+    Outcome::Ok(detail)
+}
+
+/// Free bytes on the volume holding `path`, or the nearest directory
+/// above it that exists.
+fn free_space(path: &Path) -> Option<u64> {
+    let dir = path.ancestors().find(|dir| dir.exists())?;
+    let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+fn disk() -> Outcome {
+    let home = paths::vagrantx_home();
+    let free = match free_space(&home) {
+        Some(free) => free,
+        None => {
+            return Outcome::Warning(
+                format!("could not tell how much space {} has", home.display()),
+                "check that VAGRANTX_HOME is somewhere that exists".to_string(),
+            )
+        }
+    };
+    let detail = format!("{} free for {}", format_bytes(free as f64), home.display());
+    if free < LOW_DISK {
+        return Outcome::Warning(
+            detail,
+            "vagrantx cache gc and vagrantx prune remove what isn't used".to_string(),
+        );
+    }
+    Outcome::Ok(detail)
+}
+
+fn box_problems() -> Vec<String> {
+    let mut problems = Vec::new();
+    for name in boxes::list().unwrap_or_default() {
+        match boxes::load(&name) {
+            Ok(metadata) => {
+                for path in [&metadata.kernel, &metadata.initrd, &metadata.disk] {
+                    if !path.is_file() {
+                        problems.push(format!("box {} is missing {}", name, path.display()));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("box {} won't load: {}", name, e)),
+        }
+    }
+    for entry in fs::read_dir(paths::boxes_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        if entry.file_name().to_string_lossy().ends_with(".partial") {
+            problems.push(format!(
+                "{} is a box that was never added",
+                entry.path().display()
+            ));
+        }
+    }
+    problems
+}
+
+fn storage() -> Outcome {
+    let mut problems = box_problems();
+    problems.extend(cache::problems());
+    if problems.is_empty() {
+        return Outcome::Ok(format!(
+            "{} boxes and the download cache look sound",
+            boxes::list().map_or(0, |names| names.len())
+        ));
+    }
+    Outcome::Warning(
+        problems.join("; "),
+        format!(
+            "delete broken boxes from {} and add them again with vagrantx box add; vagrantx cache gc --limit 0 empties the cache",
+            paths::boxes_dir().display()
+        ),
+    )
+}
+
+fn qemu() -> Outcome {
+    let missing: Vec<String> = ["aarch64", "x86_64"]
+        .iter()
+        .map(|arch| format!("qemu-system-{}", arch))
+        .filter(|binary| {
+            !env::var_os("PATH")
+                .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(binary).is_file()))
+        })
+        .collect();
+    if missing.is_empty() {
+        return Outcome::Ok("installed for aarch64 and x86_64 guests".to_string());
+    }
+    Outcome::Warning(
+        format!("{} not found", missing.join(" and ")),
+        "brew install qemu, if machines of another architecture are wanted".to_string(),
+    )
+}
+
+/// Checks what machines need of the host, printing each check with a fix
+/// for any that fail. Fails if a machine couldn't run as things are.
+pub fn doctor() -> Result<(), Box<dyn error::Error>> {
+    let exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("vagrantx"));
+    let entitlements = entitlements(&exe);
+    let checks = [
+        ("Virtualization.framework", virtualization()),
+        ("architecture", architecture()),
+        ("code signing", signing(&exe, &entitlements)),
+        ("bridged networking", bridged(&entitlements)),
+        ("memory", memory()),
+        ("disk", disk()),
+        ("boxes and cache", storage()),
+        ("QEMU", qemu()),
+    ];
+
+    let mut failed = 0;
+    for (name, outcome) in &checks {
+        match outcome {
+            Outcome::Ok(detail) => println!("ok    {}: {}", name, detail),
+            Outcome::Warning(detail, fix) => {
+                println!("warn  {}: {}", name, detail);
+                println!("      fix: {}", fix);
+            }
+            Outcome::Failed(detail, fix) => {
+                println!("FAIL  {}: {}", name, detail);
+                println!("      fix: {}", fix);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} checks failed", failed, checks.len()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! The error `main` reports. Each kind of failure exits with its own status,
+//! so scripts can tell a bad config from a machine that never came up:
+//!
+//! | status | failure |
+//! |--------|---------|
+//! | 1 | anything not listed below |
+//! | 2 | the command line didn't parse |
+//! | 3 | the config is invalid |
+//! | 4 | a file it names doesn't exist or can't be used |
+//! | 5 | another process has the machine locked |
+//! | 6 | the hypervisor refused or failed to run the machine |
+//! | 7 | the machine didn't become ready |
+//! | 8 | the machine crashed |
+
+use crate::lock::LockError;
+use crate::readiness::ReadinessError;
+use crate::vm::VmError;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const USAGE_EXIT_CODE: i32 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Path { path: PathBuf, source: io::Error },
+    Locked(LockError),
+    Vm(VmError),
+    NotReady(String),
+    Crashed(String),
+    Other(Box<dyn error::Error>),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Config(_) => 3,
+            Error::Path { .. } => 4,
+            Error::Locked(_) => 5,
+            Error::Vm(_) => 6,
+            Error::NotReady(_) => 7,
+            Error::Crashed(_) => 8,
+        }
+    }
+
+    pub fn path(path: &Path, source: io::Error) -> Error {
+        Error::Path {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(message) => write!(f, "invalid config: {}", message),
+            Error::Path { path, source } if source.kind() == io::ErrorKind::NotFound => write!(
+                f,
+                "{} does not exist (relative paths are relative to the directory vagrantx is run in)",
+                path.display()
+            ),
+            Error::Path { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Locked(e) => e.fmt(f),
+            Error::Vm(e) if e.domain == "VZErrorDomain" => write!(
+                f,
+                "Virtualization.framework failed: {}; `vagrantx validate` checks the config in more detail",
+                e
+            ),
+            Error::Vm(e) => e.fmt(f),
+            Error::NotReady(message) | Error::Crashed(message) => f.write_str(message),
+            Error::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<LockError> for Error {
+    fn from(err: LockError) -> Self {
+        Error::Locked(err)
+    }
+}
+
+impl From<VmError> for Error {
+    fn from(err: VmError) -> Self {
+        Error::Vm(err)
+    }
+}
+
+impl From<ReadinessError> for Error {
+    fn from(err: ReadinessError) -> Self {
+        Error::NotReady(err.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Other(Box::new(err))
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message.into())
+    }
+}
+
+/// Most of the crate returns `Box<dyn Error>`; this recovers what kind of
+/// failure is inside.
+impl From<Box<dyn error::Error>> for Error {
+    fn from(err: Box<dyn error::Error>) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<LockError>() {
+            Ok(err) => return Error::Locked(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<VmError>() {
+            Ok(err) => return Error::Vm(*err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ReadinessError>() {
+            Ok(err) => return (*err).into(),
+            Err(err) => err,
+        };
+        match err.downcast::<serde_json::Error>() {
+            Ok(err) => Error::Config(err.to_string()),
+            Err(err) => Error::Other(err),
+        }
+    }
+}
+
+/// The absolute form of `path`, which has to exist.
+pub fn existing(path: &Path) -> Result<PathBuf, Error> {
+    fs::canonicalize(path).map_err(|e| Error::path(path, e))
+}
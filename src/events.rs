@@ -0,0 +1,184 @@
+//! Per-machine append-only log of lifecycle events, one JSON object per
+//! line.
+
+use crate::machine::Machine;
+use crate::notify;
+use crate::output;
+use crate::vm::VmError;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Event {
+    pub time: String,
+    pub event: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<VmError>,
+}
+
+/// Formats a time as RFC 3339 in UTC.
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[derive(Clone)]
+pub struct EventLog {
+    machine: String,
+    path: PathBuf,
+    notify: Vec<String>,
+}
+
+impl EventLog {
+    pub fn new(machine: &Machine) -> EventLog {
+        EventLog {
+            machine: machine.name.clone(),
+            path: machine.dir.join("events.ndjson"),
+            notify: Vec::new(),
+        }
+    }
+
+    /// Also posts a notification whenever one of `events` is recorded.
+    pub fn notifying(mut self, events: &[String]) -> EventLog {
+        self.notify = events.to_vec();
+        self
+    }
+
+    fn append(&self, event: Event) {
+        let result = fs::create_dir_all(self.path.parent().unwrap())
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+            })
+            .and_then(|mut file| {
+                let mut line = serde_json::to_string(&event).unwrap();
+                line.push('\n');
+                // One write per event keeps lines whole even if two
+                // processes append at once.
+                file.write_all(line.as_bytes())
+            });
+        if let Err(e) = result {
+            output::warning(&format!("could not write {}: {}", self.path.display(), e));
+        }
+        output::event(&self.machine, &event);
+        if self.notify.contains(&event.event) {
+            notify::post(&self.machine, &event);
+        }
+    }
+
+    pub fn record(&self, event: &str, detail: Option<String>) {
+        self.append(Event {
+            time: timestamp(SystemTime::now()),
+            event: event.to_string(),
+            detail,
+            error: None,
+        });
+    }
+
+    pub fn record_error(&self, event: &str, error: &VmError) {
+        self.append(Event {
+            time: timestamp(SystemTime::now()),
+            event: event.to_string(),
+            detail: None,
+            error: Some(error.clone()),
+        });
+    }
+
+    /// Every event recorded so far, skipping lines that don't parse.
+    pub fn read(&self) -> io::Result<Vec<Event>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut events = Vec::new();
+        for line in reader.lines() {
+            if let Ok(event) = serde_json::from_str(&line?) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    fn print(line: &str) {
+        match serde_json::from_str::<Event>(line) {
+            Ok(event) => {
+                let detail = match (&event.detail, &event.error) {
+                    (_, Some(error)) => error.to_string(),
+                    (Some(detail), None) => detail.clone(),
+                    (None, None) => String::new(),
+                };
+                println!("{}  {:<10}  {}", event.time, event.event, detail);
+            }
+            Err(_) => println!("{}", line),
+        }
+    }
+
+    /// Prints the log, then keeps printing new events as they're appended if
+    /// `follow` is set.
+    pub fn show(&self, follow: bool) -> Result<(), Box<dyn error::Error>> {
+        if !self.path.exists() && !follow {
+            return Ok(());
+        }
+        while !self.path.exists() {
+            thread::sleep(Duration::from_millis(500));
+        }
+
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                if !follow {
+                    return Ok(());
+                }
+                thread::sleep(Duration::from_millis(500));
+                continue;
+            }
+            // A partial line means the writer isn't done with it yet.
+            if !line.ends_with('\n') {
+                let mut rest = String::new();
+                while !rest.ends_with('\n') {
+                    thread::sleep(Duration::from_millis(100));
+                    reader.read_line(&mut rest)?;
+                }
+                line.push_str(&rest);
+            }
+            Self::print(line.trim_end());
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! `vagrantx exec`: running one command in the guest, for scripts and CI.
+//!
+//! There's no guest agent, so this goes over SSH like `vagrantx ssh`.
+
+use crate::config;
+use crate::machine::Machine;
+use crate::remote::shell_quote;
+use crate::ssh::Session;
+use libc::isatty;
+use std::error;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+
+/// The shell command that runs `command` as `user`, in `workdir`.
+pub fn remote_command(command: &[String], user: Option<&str>, workdir: Option<&str>) -> String {
+    let mut script = command
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Some(workdir) = workdir {
+        script = format!("cd {} && exec {}", shell_quote(workdir), script);
+    }
+    match user {
+        Some(user) => format!(
+            "sudo -n -H -u {} sh -c {}",
+            shell_quote(user),
+            shell_quote(&script)
+        ),
+        None => script,
+    }
+}
+
+/// Runs `command` in the machine `config_file` defines, with our stdin,
+/// stdout and stderr, and exits with its status. Only returns if ssh can't
+/// be run.
+pub fn exec(
+    config_file: &Path,
+    command: &[String],
+    user: Option<&str>,
+    workdir: Option<&str>,
+) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let mut session = Session::new(&config, &machine)?;
+    session.options.push("BatchMode=yes".to_string());
+
+    let mut ssh = session.command();
+    // Only ask for a terminal when there is one, so piped input and output
+    // pass through untouched.
+    ssh.arg(if unsafe { isatty(0) } == 1 {
+        "-t"
+    } else {
+        "-T"
+    });
+    ssh.arg("--").arg(remote_command(command, user, workdir));
+    Err(format!("could not run ssh: {}", ssh.exec()).into())
+}
@@ -0,0 +1,136 @@
+//! Scripted console interaction, for images that have to be driven through
+//! an installer or a login prompt.
+//!
+//! A script has one directive per line; blank lines and lines starting with
+//! `#` are ignored.
+//!
+//! - `expect <regex>` waits for the guest to print something matching.
+//! - `send <text>` types text, with `\n`, `\r`, `\t` and `\\` escapes.
+//! - `timeout <seconds>` changes how long later `expect`s wait (60s at
+//!   first).
+
+use crate::console::ConsoleOutput;
+use regex::bytes::Regex;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct ExpectError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for ExpectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "console script line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for ExpectError {}
+
+enum Step {
+    Expect(Regex),
+    Send(Vec<u8>),
+    Timeout(Duration),
+}
+
+pub struct Script {
+    steps: Vec<(usize, Step)>,
+}
+
+fn unescape(text: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some(other) => other,
+                None => '\\',
+            }
+        } else {
+            c
+        };
+        let mut buf = [0; 4];
+        out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    out
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, ExpectError> {
+        let mut steps = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |message: String| ExpectError {
+                line: line_number,
+                message,
+            };
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (directive, argument) = line.split_once(' ').unwrap_or((line, ""));
+            let step = match directive {
+                "expect" => Step::Expect(Regex::new(argument).map_err(|e| error(e.to_string()))?),
+                "send" => Step::Send(unescape(argument)),
+                "timeout" => Step::Timeout(Duration::from_secs(
+                    argument
+                        .trim()
+                        .parse()
+                        .map_err(|_| error(format!("invalid timeout {}", argument)))?,
+                )),
+                _ => return Err(error(format!("unknown directive {}", directive))),
+            };
+            steps.push((line_number, step));
+        }
+        Ok(Script { steps })
+    }
+
+    pub fn load(path: &Path) -> Result<Script, Box<dyn error::Error>> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        Ok(Script::parse(&source)?)
+    }
+
+    /// Runs the script against `output`, starting at console position
+    /// `from`, and typing through `send`. Each `expect` only matches output
+    /// after whatever the previous one matched.
+    pub fn run(
+        &self,
+        output: &ConsoleOutput,
+        from: usize,
+        send: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> Result<(), ExpectError> {
+        let mut position = from;
+        let mut timeout = DEFAULT_TIMEOUT;
+        for (line, step) in &self.steps {
+            let error = |message: String| ExpectError {
+                line: *line,
+                message,
+            };
+            match step {
+                Step::Expect(pattern) => {
+                    position = output.expect(pattern, position, timeout).ok_or_else(|| {
+                        error(format!(
+                            "console never printed /{}/ within {}s",
+                            pattern,
+                            timeout.as_secs()
+                        ))
+                    })?;
+                }
+                Step::Send(data) => send(data).map_err(|e| error(e.to_string()))?,
+                Step::Timeout(duration) => timeout = *duration,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+//! Pulls the kernel and initrd out of a disk image's /boot.
+//!
+//! macOS can't mount ext filesystems, so rather than mounting the image we
+//! drive e2fsprogs' `debugfs`, which reads the filesystem directly from the
+//! image file.
+
+use crate::output;
+use std::cmp::Ordering;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const SECTOR_SIZE: u64 = 512;
+const EXT_MAGIC_OFFSET: u64 = 1080;
+const EXT_MAGIC: [u8; 2] = [0x53, 0xef];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Homebrew's e2fsprogs is keg-only, so its sbin isn't on PATH by default.
+const DEBUGFS_FALLBACKS: &[&str] = &[
+    "/opt/homebrew/opt/e2fsprogs/sbin/debugfs",
+    "/usr/local/opt/e2fsprogs/sbin/debugfs",
+];
+
+#[derive(Debug)]
+pub struct ExtractError(String);
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "could not extract boot files: {}", self.0)
+    }
+}
+
+impl error::Error for ExtractError {}
+
+impl From<io::Error> for ExtractError {
+    fn from(err: io::Error) -> Self {
+        ExtractError(err.to_string())
+    }
+}
+
+struct Partition {
+    number: Option<u32>,
+    offset: u64,
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn is_ext(file: &mut File, offset: u64) -> bool {
+    let mut magic = [0; 2];
+    read_at(file, offset + EXT_MAGIC_OFFSET, &mut magic).is_ok() && magic == EXT_MAGIC
+}
+
+fn gpt_partitions(file: &mut File) -> io::Result<Option<Vec<Partition>>> {
+    let mut header = [0; 92];
+    read_at(file, SECTOR_SIZE, &mut header)?;
+    if &header[..8] != b"EFI PART" {
+        return Ok(None);
+    }
+
+    let entries_lba = le_u64(&header[72..]);
+    let entry_count = le_u32(&header[80..]);
+    let entry_size = le_u32(&header[84..]) as usize;
+    // The spec wants 128 times a power of two. Entries are read by offset,
+    // so ones that span sectors are fine.
+    if entry_size < 128 || !entry_size.is_power_of_two() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("GPT partition entries are {} bytes", entry_size),
+        ));
+    }
+
+    let mut partitions = Vec::new();
+    let mut entry = vec![0; entry_size];
+    for i in 0..entry_count {
+        read_at(
+            file,
+            entries_lba * SECTOR_SIZE + i as u64 * entry_size as u64,
+            &mut entry,
+        )?;
+        // An all-zero type GUID marks an unused entry.
+        if entry[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        partitions.push(Partition {
+            number: Some(i + 1),
+            offset: le_u64(&entry[32..]) * SECTOR_SIZE,
+        });
+    }
+    Ok(Some(partitions))
+}
+
+fn mbr_partitions(file: &mut File) -> io::Result<Vec<Partition>> {
+    let mut mbr = [0; 512];
+    read_at(file, 0, &mut mbr)?;
+    if mbr[510..] != [0x55, 0xaa] {
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        if entry[4] == 0 {
+            continue;
+        }
+        partitions.push(Partition {
+            number: Some(i as u32 + 1),
+            offset: le_u32(&entry[8..]) as u64 * SECTOR_SIZE,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Every ext filesystem in the image: the whole image if it's a bare
+/// filesystem, otherwise each GPT or MBR partition that holds one.
+fn ext_filesystems(image: &Path) -> io::Result<Vec<Partition>> {
+    let mut file = File::open(image)?;
+    if is_ext(&mut file, 0) {
+        return Ok(vec![Partition {
+            number: None,
+            offset: 0,
+        }]);
+    }
+
+    let partitions = match gpt_partitions(&mut file)? {
+        Some(partitions) => partitions,
+        None => mbr_partitions(&mut file)?,
+    };
+    Ok(partitions
+        .into_iter()
+        .filter(|p| is_ext(&mut file, p.offset))
+        .collect())
+}
+
+fn find_debugfs() -> Result<PathBuf, ExtractError> {
+    if let Some(path) = env::var_os("PATH") {
+        for dir in env::split_paths(&path) {
+            let candidate = dir.join("debugfs");
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+    }
+
+    DEBUGFS_FALLBACKS
+        .iter()
+        .map(PathBuf::from)
+        .find(|p| p.is_file())
+        .ok_or_else(|| {
+            ExtractError(
+                "debugfs not found; install it with `brew install e2fsprogs` \
+                 or pass --kernel and --initrd"
+                    .to_string(),
+            )
+        })
+}
+
+struct DebugFs {
+    program: PathBuf,
+    image: String,
+}
+
+impl DebugFs {
+    fn new(program: &Path, image: &Path, offset: u64) -> DebugFs {
+        DebugFs {
+            program: program.to_path_buf(),
+            image: format!("{}?offset={}", image.display(), offset),
+        }
+    }
+
+    fn run(&self, request: &str) -> Result<String, ExtractError> {
+        let output = Command::new(&self.program)
+            .arg("-R")
+            .arg(request)
+            .arg(&self.image)
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(ExtractError(format!(
+                "debugfs `{}` failed: {}",
+                request,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Names of the regular files in `dir`.
+    fn files(&self, dir: &str) -> Result<Vec<String>, ExtractError> {
+        // `ls -p` prints one `/inode/mode/uid/gid/name/size/` record per entry.
+        Ok(self
+            .run(&format!("ls -p {}", dir))?
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split('/').collect();
+                if fields.len() < 7 || !fields[2].starts_with("10") {
+                    return None;
+                }
+                Some(fields[5].to_string())
+            })
+            .collect())
+    }
+
+    fn dump(&self, path: &str, dest: &Path) -> Result<(), ExtractError> {
+        self.run(&format!(
+            "dump -p {} {}",
+            quote(path),
+            quote(&dest.display().to_string())
+        ))?;
+        match fs::metadata(dest) {
+            Ok(m) if m.len() > 0 => Ok(()),
+            _ => Err(ExtractError(format!("debugfs could not read {}", path))),
+        }
+    }
+}
+
+/// Quotes an argument for debugfs, whose parser takes `""` inside double
+/// quotes for a quote and nothing else as special.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('"', "\"\""))
+}
+
+/// Orders version strings so that `5.15.0-91` sorts after `5.4.0-42`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let chunks = |s: &str| -> Vec<(u64, String)> {
+        s.split(|c: char| !c.is_ascii_alphanumeric())
+            .map(|part| (part.parse().unwrap_or(0), part.to_string()))
+            .collect()
+    };
+    chunks(a).cmp(&chunks(b))
+}
+
+const INITRD_NAMES: &[(&str, &str)] = &[
+    ("initrd.img-", ""),
+    ("initramfs-", ".img"),
+    ("initrd-", ""),
+    ("initrd-", ".img"),
+];
+
+/// Picks the newest kernel in `files` that has a matching initrd.
+fn select_pair(files: &[String]) -> Option<(String, String)> {
+    let mut versions: Vec<&str> = files
+        .iter()
+        .filter_map(|f| f.strip_prefix("vmlinuz-"))
+        .filter(|v| !v.contains("rescue"))
+        .collect();
+    versions.sort_by(|a, b| compare_versions(b, a));
+
+    versions.into_iter().find_map(|version| {
+        INITRD_NAMES
+            .iter()
+            .map(|(prefix, suffix)| format!("{}{}{}", prefix, version, suffix))
+            .find(|initrd| files.contains(initrd))
+            .map(|initrd| (format!("vmlinuz-{}", version), initrd))
+    })
+}
+
+/// Virtualization.framework only boots uncompressed arm64 kernels, but
+/// distributions ship them gzipped.
+fn decompress_kernel(path: &Path) -> Result<(), ExtractError> {
+    let mut magic = [0; 2];
+    File::open(path)?.read_exact(&mut magic)?;
+    if magic != GZIP_MAGIC {
+        return Ok(());
+    }
+
+    let compressed = path.with_extension("gz");
+    fs::rename(path, &compressed)?;
+    let status = Command::new("gzip")
+        .arg("-dc")
+        .arg(&compressed)
+        .stdout(File::create(path)?)
+        .status()?;
+    fs::remove_file(&compressed)?;
+    if !status.success() {
+        return Err(ExtractError("could not decompress kernel".to_string()));
+    }
+    Ok(())
+}
+
+/// Copies the newest kernel and matching initrd found in `image` into
+/// `dest_dir` as `vmlinuz` and `initrd`, returning the partition holding the
+/// root filesystem if the image is partitioned and it could be identified.
+pub fn boot_artifacts(image: &Path, dest_dir: &Path) -> Result<Option<u32>, ExtractError> {
+    let debugfs = find_debugfs()?;
+    let filesystems = ext_filesystems(image)?;
+    if filesystems.is_empty() {
+        return Err(ExtractError(format!(
+            "no ext filesystem found in {}",
+            image.display()
+        )));
+    }
+
+    // Look for a root filesystem with /boot first, and only then for a
+    // separate boot partition, which holds the same files at its top level.
+    for dir in ["/boot", "/"] {
+        for fs in &filesystems {
+            let debugfs = DebugFs::new(&debugfs, image, fs.offset);
+            let files = match debugfs.files(dir) {
+                Ok(files) => files,
+                Err(_) => continue,
+            };
+            let (kernel, initrd) = match select_pair(&files) {
+                Some(pair) => pair,
+                None => continue,
+            };
+
+            let prefix = dir.trim_end_matches('/');
+            let kernel_dest = dest_dir.join("vmlinuz");
+            let initrd_dest = dest_dir.join("initrd");
+            debugfs.dump(&format!("{}/{}", prefix, kernel), &kernel_dest)?;
+            debugfs.dump(&format!("{}/{}", prefix, initrd), &initrd_dest)?;
+            decompress_kernel(&kernel_dest)?;
+
+            output::message(&format!("extracted {} and {}", kernel, initrd));
+
+            // A separate boot partition tells us nothing about where root
+            // lives, so leave that to the user in that case.
+            return Ok(if dir == "/boot" { fs.number } else { None });
+        }
+    }
+
+    Err(ExtractError(format!(
+        "no kernel and initrd found under /boot in {}",
+        image.display()
+    )))
+}
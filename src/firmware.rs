@@ -0,0 +1,107 @@
+//! VZEFIBootLoader and USB mass storage, for booting installers and disks
+//! through firmware. virtualization-rs doesn't bind either yet.
+
+use crate::machine::Machine;
+use objc::rc::StrongPtr;
+use objc::runtime::Class;
+use objc::{class, msg_send, sel, sel_impl};
+use std::path::{Path, PathBuf};
+use virtualization_rs::base::{Id, NSError, NIL, NSURL};
+use virtualization_rs::virtualization::boot_loader::VZBootLoader;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZStorageDeviceAttachment,
+    VZStorageDeviceConfiguration, VZVirtioBlockDeviceConfiguration,
+};
+
+/// VZEFIVariableStoreInitializationOptionAllowOverwrite.
+const ALLOW_OVERWRITE: usize = 1;
+
+/// EFI needs macOS 13 or later.
+pub fn supported() -> bool {
+    Class::get("VZEFIBootLoader").is_some()
+}
+
+/// Where the firmware keeps its variables, boot entries among them, so an
+/// installed system's entry survives restarts.
+fn variable_store_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("efi-variables")
+}
+
+pub struct EfiBootLoader(StrongPtr);
+
+impl EfiBootLoader {
+    pub fn new(machine: &Machine) -> Result<EfiBootLoader, NSError> {
+        let path = variable_store_path(machine);
+        let url = NSURL::file_url_with_path(&path.to_string_lossy(), false);
+        unsafe {
+            let alloc: Id = msg_send![class!(VZEFIVariableStore), alloc];
+            let store = if path.exists() {
+                StrongPtr::new(msg_send![alloc, initWithURL:*url.0])
+            } else {
+                let error = NSError::nil();
+                let store = StrongPtr::new(msg_send![
+                    alloc,
+                    initCreatingVariableStoreAtURL:*url.0
+                    options:ALLOW_OVERWRITE
+                    error:&(*error.0)
+                ]);
+                if error.code() != 0 {
+                    return Err(error);
+                }
+                store
+            };
+            let loader = StrongPtr::new(msg_send![class!(VZEFIBootLoader), new]);
+            let _: () = msg_send![*loader, setVariableStore:*store];
+            Ok(EfiBootLoader(loader))
+        }
+    }
+}
+
+impl VZBootLoader for EfiBootLoader {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// A storage device of whichever kind, so disks and install media can go
+/// in the one list the configuration takes.
+pub struct StorageDevice(StrongPtr);
+
+impl StorageDevice {
+    pub fn block(device: VZVirtioBlockDeviceConfiguration) -> StorageDevice {
+        StorageDevice(unsafe { StrongPtr::retain(device.id()) })
+    }
+
+    /// `image` attached read-only, as USB mass storage where the host has
+    /// it, since that's where installers expect to find themselves.
+    pub fn install_media(image: &Path) -> Result<StorageDevice, NSError> {
+        let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(image.to_string_lossy().into_owned())
+            .read_only(true)
+            .build()?;
+        let class = match Class::get("VZUSBMassStorageDeviceConfiguration") {
+            Some(class) => class,
+            None => {
+                return Ok(StorageDevice::block(VZVirtioBlockDeviceConfiguration::new(
+                    attachment,
+                )))
+            }
+        };
+        unsafe {
+            let alloc: Id = msg_send![class, alloc];
+            let device: Id = msg_send![alloc, initWithAttachment:attachment.id()];
+            if device == NIL {
+                return Ok(StorageDevice::block(VZVirtioBlockDeviceConfiguration::new(
+                    attachment,
+                )));
+            }
+            Ok(StorageDevice(StrongPtr::new(device)))
+        }
+    }
+}
+
+impl VZStorageDeviceConfiguration for StorageDevice {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
@@ -0,0 +1,129 @@
+//! Watching host directories for changes with FSEvents.
+
+use libc::{c_char, c_void};
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::Duration;
+
+type CFRef = *const c_void;
+
+const UTF8_ENCODING: u32 = 0x0800_0100;
+const SINCE_NOW: u64 = u64::MAX;
+const FLAG_NO_DEFER: u32 = 0x02;
+const FLAG_FILE_EVENTS: u32 = 0x10;
+
+type Callback =
+    extern "C" fn(CFRef, *mut c_void, usize, *const *const c_char, *const u32, *const u64);
+
+#[repr(C)]
+struct StreamContext {
+    version: isize,
+    info: *mut c_void,
+    retain: CFRef,
+    release: CFRef,
+    copy_description: CFRef,
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    static kCFRunLoopDefaultMode: CFRef;
+    static kCFTypeArrayCallBacks: c_void;
+    fn CFStringCreateWithCString(allocator: CFRef, string: *const c_char, encoding: u32) -> CFRef;
+    fn CFArrayCreate(
+        allocator: CFRef,
+        values: *const CFRef,
+        count: isize,
+        callbacks: *const c_void,
+    ) -> CFRef;
+    fn CFRunLoopGetCurrent() -> CFRef;
+    fn CFRunLoopRun();
+}
+
+#[link(name = "CoreServices", kind = "framework")]
+extern "C" {
+    fn FSEventStreamCreate(
+        allocator: CFRef,
+        callback: Callback,
+        context: *mut StreamContext,
+        paths: CFRef,
+        since_when: u64,
+        latency: f64,
+        flags: u32,
+    ) -> CFRef;
+    fn FSEventStreamScheduleWithRunLoop(stream: CFRef, run_loop: CFRef, mode: CFRef);
+    fn FSEventStreamStart(stream: CFRef) -> u8;
+}
+
+type Handler = Box<dyn FnMut(Vec<PathBuf>)>;
+
+extern "C" fn changed(
+    _stream: CFRef,
+    info: *mut c_void,
+    count: usize,
+    paths: *const *const c_char,
+    _flags: *const u32,
+    _ids: *const u64,
+) {
+    let handler = unsafe { &mut *(info as *mut Handler) };
+    let paths = (0..count)
+        .map(|i| {
+            let path = unsafe { CStr::from_ptr(*paths.add(i)) };
+            PathBuf::from(path.to_string_lossy().into_owned())
+        })
+        .collect();
+    handler(paths);
+}
+
+/// Calls `handler` with the paths that changed under any of `dirs`,
+/// batching changes that come within `latency` of each other. Only returns
+/// if the stream can't be started.
+pub fn watch(
+    dirs: &[PathBuf],
+    latency: Duration,
+    handler: impl FnMut(Vec<PathBuf>) + 'static,
+) -> Result<(), String> {
+    let strings: Vec<CFRef> = dirs
+        .iter()
+        .map(|dir| {
+            let path = CString::new(dir.as_os_str().as_bytes()).unwrap();
+            unsafe { CFStringCreateWithCString(ptr::null(), path.as_ptr(), UTF8_ENCODING) }
+        })
+        .collect();
+    let handler: *mut Handler = Box::into_raw(Box::new(Box::new(handler)));
+    let mut context = StreamContext {
+        version: 0,
+        info: handler as *mut c_void,
+        retain: ptr::null(),
+        release: ptr::null(),
+        copy_description: ptr::null(),
+    };
+
+    unsafe {
+        let paths = CFArrayCreate(
+            ptr::null(),
+            strings.as_ptr(),
+            strings.len() as isize,
+            &kCFTypeArrayCallBacks,
+        );
+        let stream = FSEventStreamCreate(
+            ptr::null(),
+            changed,
+            &mut context,
+            paths,
+            SINCE_NOW,
+            latency.as_secs_f64(),
+            FLAG_NO_DEFER | FLAG_FILE_EVENTS,
+        );
+        if stream.is_null() {
+            return Err("could not create an FSEvents stream".to_string());
+        }
+        FSEventStreamScheduleWithRunLoop(stream, CFRunLoopGetCurrent(), kCFRunLoopDefaultMode);
+        if FSEventStreamStart(stream) == 0 {
+            return Err("could not start watching for changes".to_string());
+        }
+        CFRunLoopRun();
+    }
+    Ok(())
+}
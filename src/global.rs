@@ -0,0 +1,91 @@
+//! Machines that belong to no project, made with `vagrantx up --name`.
+//!
+//! Each lives in `~/.vagrantx/machines/<name>`, config and all, and any
+//! command that takes a config can be given its name instead, from
+//! anywhere.
+
+use crate::config;
+use crate::init;
+use crate::paths;
+use crate::rename;
+use std::error;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const CONFIG_FILE: &str = "config.json";
+
+/// Where the config of the machine named `name` is.
+pub fn config_path(name: &str) -> PathBuf {
+    paths::machines_dir().join(name).join(CONFIG_FILE)
+}
+
+/// The name of the machine `config_file` defines, if it's one of these.
+pub fn name_of(config_file: &Path) -> Option<String> {
+    if config_file.file_name()? != CONFIG_FILE {
+        return None;
+    }
+    let dir = config_file.parent()?;
+    let machines = dir.parent()?;
+    let global = paths::machines_dir();
+    let same = machines == global
+        || matches!(
+            (fs::canonicalize(machines), fs::canonicalize(&global)),
+            (Ok(a), Ok(b)) if a == b
+        );
+    same.then(|| dir.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .flatten()
+}
+
+/// A config given on the command line. A bare name that isn't a file but
+/// is one of these machines is its config.
+pub fn resolve(arg: &OsStr) -> PathBuf {
+    let path = PathBuf::from(arg);
+    let bare = matches!(
+        path.components().collect::<Vec<_>>().as_slice(),
+        [Component::Normal(_)]
+    );
+    if bare && !path.exists() {
+        let global = config_path(&path.to_string_lossy());
+        if global.exists() {
+            return global;
+        }
+    }
+    path
+}
+
+/// The config of the machine named `name`, written for `box_name` unless
+/// there's one already.
+pub fn ensure(name: &str, box_name: Option<&str>) -> Result<PathBuf, Box<dyn error::Error>> {
+    if !rename::valid_name(name) {
+        return Err(format!(
+            "{} is not a valid machine name; use letters, numbers, '-', '_' and '.'",
+            name
+        )
+        .into());
+    }
+    let config_file = config_path(name);
+    if config_file.exists() {
+        let existing = config::load_config(&config_file)?.box_name;
+        match (box_name, existing) {
+            (Some(wanted), Some(existing)) if wanted != existing => Err(format!(
+                "{} already exists with the box {}; use another name",
+                name, existing
+            )
+            .into()),
+            _ => Ok(config_file),
+        }
+    } else {
+        let box_name = box_name.ok_or_else(|| {
+            format!(
+                "there is no machine named {}; --box says what to make it from",
+                name
+            )
+        })?;
+        let box_name = init::installed_box(box_name)?;
+        fs::create_dir_all(config_file.parent().unwrap())?;
+        fs::write(&config_file, init::template(&box_name))?;
+        println!("wrote {}", config_file.display());
+        Ok(config_file)
+    }
+}
@@ -0,0 +1,131 @@
+//! Passing environment variables and secrets from the host into a guest.
+//!
+//! They're gathered fresh on every boot and written to a root-only file on
+//! the guest over SSH, so nothing secret ends up in a box, the config or
+//! the kernel command line.
+
+use crate::config::{Config, GuestEnv};
+use crate::machine::Machine;
+use crate::output;
+use crate::remote::shell_quote;
+use crate::ssh::Session;
+use std::env;
+use std::error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a guest that's ready on the console has to start answering SSH.
+const SSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The `NAME=value` lines of a secrets file. Blank lines and `#` comments
+/// are skipped, and a value may be quoted.
+fn read_secrets(path: &Path) -> Result<Vec<(String, String)>, Box<dyn error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let mut secrets = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) if valid_name(name.trim()) => (name.trim(), value.trim()),
+            _ => {
+                return Err(
+                    format!("{}:{}: expected NAME=value", path.display(), number + 1).into(),
+                )
+            }
+        };
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|&q| value.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+            .unwrap_or(value);
+        secrets.push((name.to_string(), value.to_string()));
+    }
+    Ok(secrets)
+}
+
+/// Everything `guest_env` asks for, in the order it's declared; secrets
+/// come last and so win.
+pub fn gather(guest_env: &GuestEnv) -> Result<Vec<(String, String)>, Box<dyn error::Error>> {
+    let mut variables: Vec<(String, String)> = Vec::new();
+    for spec in &guest_env.from_host {
+        let (guest, host) = spec.split_once('=').unwrap_or((spec, spec));
+        if !valid_name(guest) {
+            return Err(format!("{} is not a valid variable name", guest).into());
+        }
+        match env::var(host) {
+            Ok(value) => variables.push((guest.to_string(), value)),
+            Err(_) => output::warning(&format!(
+                "{} is not set on the host, so not passing it",
+                host
+            )),
+        }
+    }
+    if let Some(path) = &guest_env.secrets_file {
+        variables.extend(read_secrets(path)?);
+    }
+    Ok(variables)
+}
+
+fn file_contents(variables: &[(String, String)]) -> String {
+    let mut contents = String::from("# Written by vagrantx at boot.\n");
+    for (name, value) in variables {
+        contents.push_str(&format!("{}={}\n", name, shell_quote(value)));
+    }
+    contents
+}
+
+/// Writes the guest's environment file, waiting for SSH to come up if it
+/// hasn't yet.
+pub fn inject(config: &Config, machine: &Machine) -> Result<(), Box<dyn error::Error>> {
+    let contents = file_contents(&gather(&config.guest_env)?);
+    let deadline = Instant::now() + SSH_TIMEOUT;
+    let mut session = Session::wait(config, machine, SSH_TIMEOUT)?;
+    session.options.push("BatchMode=yes".to_string());
+    session.options.push("ConnectTimeout=5".to_string());
+
+    let path = &config.guest_env.path;
+    let dir = Path::new(path)
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "/".to_string());
+    let script = format!(
+        "sudo -n sh -c {}",
+        shell_quote(&format!(
+            "umask 077 && mkdir -p {} && cat > {}",
+            shell_quote(&dir),
+            shell_quote(path)
+        ))
+    );
+
+    loop {
+        let mut child = session
+            .command()
+            .args(["--", &script])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run ssh: {}", e))?;
+        let _ = child.stdin.take().unwrap().write_all(contents.as_bytes());
+        let status = child.wait()?;
+        if status.success() {
+            return Ok(());
+        }
+        // 255 is ssh failing to connect rather than the command failing.
+        if status.code() != Some(255) || Instant::now() > deadline {
+            return Err(format!("could not write {} on the guest ({})", path, status).into());
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+}
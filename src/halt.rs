@@ -0,0 +1,86 @@
+//! `vagrantx halt`: stopping a machine `up` is running. The guest is asked
+//! to shut down first, as if its power button were pressed, and only forced
+//! off if it hasn't within the grace period.
+
+use crate::config;
+use crate::control;
+use crate::machine::Machine;
+use crate::output;
+use crate::status;
+use std::error;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a machine has to go once forced off.
+const FORCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a machine was stopped.
+pub enum Halt {
+    NotRunning,
+    /// The guest shut itself down.
+    Graceful,
+    Forced,
+}
+
+/// Whether the `up` running `machine` exited within `timeout`.
+fn wait(machine: &Machine, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while status::published(machine).is_some() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    true
+}
+
+/// Stops `machine`, giving the guest `grace` to shut down unless `force`.
+pub fn stop(
+    machine: &Machine,
+    force: bool,
+    grace: Duration,
+) -> Result<Halt, Box<dyn error::Error>> {
+    if status::published(machine).is_none() {
+        return Ok(Halt::NotRunning);
+    }
+    if !force {
+        match control::request(machine, "stop") {
+            Ok(()) if wait(machine, grace) => return Ok(Halt::Graceful),
+            Ok(()) => output::warning(&format!(
+                "{} did not shut down within {}s, forcing it off",
+                machine.name,
+                grace.as_secs()
+            )),
+            Err(e) => output::warning(&format!(
+                "could not ask {} to shut down ({}), forcing it off",
+                machine.name, e
+            )),
+        }
+    }
+    control::request(machine, "force-stop")
+        .map_err(|e| format!("could not force {} off: {}", machine.name, e))?;
+    if !wait(machine, FORCE_TIMEOUT) {
+        return Err(format!("{} did not stop", machine.name).into());
+    }
+    Ok(Halt::Forced)
+}
+
+/// Halts the machine `config_file` defines. `timeout` overrides the
+/// config's `halt_timeout`.
+pub fn halt(
+    config_file: &Path,
+    force: bool,
+    timeout: Option<u64>,
+) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let grace = Duration::from_secs(timeout.unwrap_or(config.halt_timeout));
+    let message = match stop(&machine, force, grace)? {
+        Halt::NotRunning => format!("{} is not running", machine.name),
+        Halt::Graceful => format!("{} shut down", machine.name),
+        Halt::Forced => format!("forced {} off", machine.name),
+    };
+    output::message(&message);
+    Ok(())
+}
@@ -0,0 +1,318 @@
+//! The health checks a config declares, which `up` runs for as long as the
+//! machine is running.
+//!
+//! Results are published in the machine's `health.json` for `vagrantx
+//! status`, and each change in a check is recorded as a `healthy` or
+//! `unhealthy` event, so `notify` can alert on it.
+
+use crate::config::{Config, HealthCheck};
+use crate::events::{self, EventLog};
+use crate::machine::Machine;
+use crate::network;
+use crate::ssh::Session;
+use libc::{kill, pid_t, SIGKILL};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone)]
+enum Probe {
+    Tcp(u16),
+    Http(u16, String),
+    Command(String),
+}
+
+/// A check from the config, made sense of.
+#[derive(Clone)]
+pub struct Check {
+    name: String,
+    probe: Probe,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Check {
+    fn new(check: &HealthCheck) -> Result<Check, String> {
+        let probe = match (check.tcp_port, check.http_port, &check.command) {
+            (Some(port), None, None) => Probe::Tcp(port),
+            (None, Some(port), None) => {
+                if !check.path.starts_with('/') {
+                    return Err(format!(
+                        "health check path {} does not start with /",
+                        check.path
+                    ));
+                }
+                Probe::Http(port, check.path.clone())
+            }
+            (None, None, Some(command)) => Probe::Command(command.clone()),
+            _ => {
+                return Err(
+                    "a health check needs exactly one of tcp_port, http_port and command"
+                        .to_string(),
+                )
+            }
+        };
+        if check.interval == 0 || check.timeout == 0 {
+            return Err("a health check's interval and timeout must be at least 1".to_string());
+        }
+        let name = check.name.clone().unwrap_or_else(|| match &probe {
+            Probe::Tcp(port) => format!("tcp {}", port),
+            Probe::Http(port, path) => format!("http {}{}", port, path),
+            Probe::Command(command) => command.clone(),
+        });
+        Ok(Check {
+            name,
+            probe,
+            interval: Duration::from_secs(check.interval),
+            timeout: Duration::from_secs(check.timeout),
+        })
+    }
+
+    /// Runs the check once, saying why it failed if it did.
+    fn run(&self, mac: &str, session: &Result<Session, String>) -> Result<(), String> {
+        match &self.probe {
+            Probe::Tcp(port) => {
+                connect(mac, *port, self.timeout)?;
+                Ok(())
+            }
+            Probe::Http(port, path) => http_get(connect(mac, *port, self.timeout)?, path),
+            Probe::Command(command) => match session {
+                Ok(session) => run_command(session, command, self.timeout),
+                Err(e) => Err(format!("can't log in: {}", e)),
+            },
+        }
+    }
+}
+
+/// The checks `config` declares. Fails on any that don't make sense.
+pub fn checks(config: &Config) -> Result<Vec<Check>, String> {
+    config.health_checks.iter().map(Check::new).collect()
+}
+
+fn connect(mac: &str, port: u16, timeout: Duration) -> Result<TcpStream, String> {
+    let ip = network::guest_ip(mac).ok_or("the guest has no address")?;
+    let stream = TcpStream::connect_timeout(&SocketAddr::from((ip, port)), timeout)
+        .map_err(|e| format!("could not connect to {}:{}: {}", ip, port, e))?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    Ok(stream)
+}
+
+fn http_get(mut stream: TcpStream, path: &str) -> Result<(), String> {
+    let host = stream
+        .peer_addr()
+        .map_or_else(|_| "localhost".to_string(), |addr| addr.to_string());
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    )
+    .map_err(|e| format!("could not send the request: {}", e))?;
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| format!("no response: {}", e))?;
+    let status_line = status_line.trim_end();
+    match status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+    {
+        Some(200..=399) => Ok(()),
+        Some(_) => Err(format!("GET {} answered {}", path, status_line)),
+        None => Err(format!("GET {} got no HTTP response", path)),
+    }
+}
+
+fn run_command(session: &Session, command: &str, timeout: Duration) -> Result<(), String> {
+    let child = session
+        .command()
+        .args(["--", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run ssh: {}", e))?;
+    let pid = child.id() as pid_t;
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = done.send(child.wait_with_output());
+    });
+    let output = match finished.recv_timeout(timeout) {
+        Ok(output) => output.map_err(|e| e.to_string())?,
+        Err(_) => {
+            unsafe { kill(pid, SIGKILL) };
+            return Err(format!("still running after {}s", timeout.as_secs()));
+        }
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => Err(format!("{}: {}", output.status, line.trim())),
+        None => Err(output.status.to_string()),
+    }
+}
+
+/// How one check last went.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CheckHealth {
+    pub name: String,
+    /// Unset until the check has run.
+    pub healthy: Option<bool>,
+    /// Why it last failed, even if it's passed since.
+    pub last_failure: Option<String>,
+    pub checked: Option<String>,
+}
+
+/// What `health.json` holds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Health {
+    /// `healthy`, `unhealthy`, or `starting` until every check has run.
+    pub status: String,
+    pub checks: Vec<CheckHealth>,
+}
+
+impl Health {
+    fn new(checks: Vec<CheckHealth>) -> Health {
+        let status = if checks.iter().any(|c| c.healthy == Some(false)) {
+            "unhealthy"
+        } else if checks.iter().all(|c| c.healthy == Some(true)) {
+            "healthy"
+        } else {
+            "starting"
+        };
+        Health {
+            status: status.to_string(),
+            checks,
+        }
+    }
+
+    /// Why the machine is unhealthy, naming the first failing check.
+    pub fn reason(&self) -> Option<String> {
+        let failing = self.checks.iter().find(|c| c.healthy == Some(false))?;
+        Some(match &failing.last_failure {
+            Some(failure) => format!("{}: {}", failing.name, failure),
+            None => failing.name.clone(),
+        })
+    }
+}
+
+fn path(machine: &Machine) -> PathBuf {
+    machine.dir.join("health.json")
+}
+
+fn publish(machine: &Machine, health: &Health) {
+    let path = path(machine);
+    let partial = path.with_extension("partial");
+    let _ = serde_json::to_vec(health)
+        .map_err(io::Error::from)
+        .and_then(|data| fs::write(&partial, data))
+        .and_then(|()| fs::rename(&partial, &path));
+}
+
+/// How `machine`'s checks last went, if it's running and has any.
+pub fn read(machine: &Machine) -> Option<Health> {
+    serde_json::from_slice(&fs::read(path(machine)).ok()?).ok()
+}
+
+/// Runs checks in the background until dropped.
+pub struct Monitor {
+    stop: Arc<AtomicBool>,
+    path: PathBuf,
+}
+
+impl Monitor {
+    /// Starts running `checks` against the machine with `mac`, each every
+    /// interval, the first straight away.
+    pub fn start(
+        checks: Vec<Check>,
+        config: &Config,
+        machine: &Machine,
+        mac: &str,
+        events: &EventLog,
+    ) -> Monitor {
+        let stop = Arc::new(AtomicBool::new(false));
+        let path = path(machine);
+        let session = if checks.iter().any(|c| matches!(c.probe, Probe::Command(_))) {
+            Session::new(config, machine)
+                .map(|mut session| {
+                    // Nobody's there to answer a prompt.
+                    session.options.push("BatchMode=yes".to_string());
+                    session
+                })
+                .map_err(|e| e.to_string())
+        } else {
+            Err("no command checks".to_string())
+        };
+        let machine = machine.clone();
+        let mac = mac.to_string();
+        let events = events.clone();
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            let mut results: Vec<CheckHealth> = checks
+                .iter()
+                .map(|c| CheckHealth {
+                    name: c.name.clone(),
+                    healthy: None,
+                    last_failure: None,
+                    checked: None,
+                })
+                .collect();
+            let mut due = vec![Instant::now(); checks.len()];
+            publish(&machine, &Health::new(results.clone()));
+            while !stopped.load(Ordering::Relaxed) {
+                let mut changed = false;
+                for (i, check) in checks.iter().enumerate() {
+                    if due[i] > Instant::now() {
+                        continue;
+                    }
+                    let outcome = check.run(&mac, &session);
+                    due[i] = Instant::now() + check.interval;
+                    if stopped.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let result = &mut results[i];
+                    let was = result.healthy;
+                    result.healthy = Some(outcome.is_ok());
+                    result.checked = Some(events::timestamp(SystemTime::now()));
+                    match outcome {
+                        Ok(()) if was == Some(false) => {
+                            events.record("healthy", Some(check.name.clone()))
+                        }
+                        Ok(()) => {}
+                        Err(e) => {
+                            if was != Some(false) {
+                                events.record("unhealthy", Some(format!("{}: {}", check.name, e)));
+                            }
+                            result.last_failure = Some(e);
+                        }
+                    }
+                    changed = true;
+                }
+                if changed {
+                    publish(&machine, &Health::new(results.clone()));
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+        Monitor { stop, path }
+    }
+}
+
+impl Drop for Monitor {
+    /// Stops checking, without waiting for a check under way, and forgets
+    /// the results, which only hold while the machine runs.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,165 @@
+//! Keeping `/etc/hosts` pointing a machine's `hostnames` at its address.
+//!
+//! Entries live in a block vagrantx owns, each tagged with the machine it's
+//! for, so several machines can share the block and nothing else in the
+//! file is touched. `/etc/hosts` belongs to root, so rewriting it goes
+//! through sudo.
+
+use crate::machine::Machine;
+use crate::network;
+use crate::output;
+use crate::paths;
+use std::error;
+use std::fs;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const HOSTS: &str = "/etc/hosts";
+const BEGIN: &str = "# BEGIN vagrantx";
+const END: &str = "# END vagrantx";
+
+/// How often to check whether the guest's address has changed.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Identifies a machine's entries.
+fn tag(machine: &Machine) -> String {
+    let dir = fs::canonicalize(&machine.dir).unwrap_or_else(|_| machine.dir.clone());
+    let hash = paths::fnv1a(dir.as_os_str().to_string_lossy().as_bytes());
+    format!("# vagrantx {} {:08x}", machine.name, hash as u32)
+}
+
+/// `current` with `machine`'s entries replaced by `hostnames` at `ip`, or
+/// removed if there's no `ip`.
+fn rewrite(current: &str, tag: &str, hostnames: &[String], ip: Option<Ipv4Addr>) -> String {
+    let mut before = Vec::new();
+    let mut block = Vec::new();
+    let mut after = Vec::new();
+    let mut section = 0;
+    for line in current.lines() {
+        match (section, line.trim()) {
+            (0, BEGIN) => section = 1,
+            (1, END) => section = 2,
+            (0, _) => before.push(line),
+            (1, _) => block.push(line.to_string()),
+            _ => after.push(line),
+        }
+    }
+
+    block.retain(|l| !l.ends_with(tag));
+    if let Some(ip) = ip {
+        block.push(format!("{} {} {}", ip, hostnames.join(" "), tag));
+    }
+
+    let mut lines: Vec<String> = before.iter().map(|l| l.to_string()).collect();
+    if !block.is_empty() {
+        lines.push(BEGIN.to_string());
+        lines.extend(block);
+        lines.push(END.to_string());
+    }
+    lines.extend(after.iter().map(|l| l.to_string()));
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    contents
+}
+
+/// Points `hostnames` at `ip` for `machine`, or removes its entries if
+/// there's no `ip`.
+pub fn update(
+    machine: &Machine,
+    hostnames: &[String],
+    ip: Option<Ipv4Addr>,
+) -> Result<(), Box<dyn error::Error>> {
+    write(machine, hostnames, ip, true)
+}
+
+/// `update`, letting sudo ask for a password only if `prompt`.
+fn write(
+    machine: &Machine,
+    hostnames: &[String],
+    ip: Option<Ipv4Addr>,
+    prompt: bool,
+) -> Result<(), Box<dyn error::Error>> {
+    let current = fs::read_to_string(HOSTS)?;
+    let updated = rewrite(&current, &tag(machine), hostnames, ip);
+    if updated == current {
+        return Ok(());
+    }
+
+    let mut sudo = Command::new("sudo");
+    if !prompt {
+        sudo.arg("-n");
+    }
+    let mut tee = sudo
+        .args(["tee", HOSTS])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    tee.stdin.take().unwrap().write_all(updated.as_bytes())?;
+    let status = tee.wait()?;
+    if !status.success() {
+        return Err(format!("could not update {} ({})", HOSTS, status).into());
+    }
+    Ok(())
+}
+
+/// Asks for sudo credentials up front, while the terminal is still usable
+/// for a password prompt.
+pub fn authorize() {
+    let _ = Command::new("sudo").arg("-v").status();
+}
+
+/// Keeps a machine's entries up to date with its address until dropped,
+/// then removes them.
+pub struct Watch {
+    machine: Machine,
+    hostnames: Vec<String>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Keeps `machine`'s entries up to date with the guest's address from now
+/// on. The sudo credentials `authorize` got will long since have expired
+/// by the time the address changes, so no password is asked for then; if
+/// one would be, the change is only warned about.
+pub fn watch(machine: &Machine, mac: &str, hostnames: Vec<String>) -> Watch {
+    let stop = Arc::new(AtomicBool::new(false));
+    let watched = machine.clone();
+    let names = hostnames.clone();
+    let stopped = stop.clone();
+    let mac = mac.to_string();
+    thread::spawn(move || {
+        let mut current = None;
+        while !stopped.load(Ordering::Relaxed) {
+            let ip = network::guest_ip(&mac);
+            if ip.is_some() && ip != current {
+                if let Err(e) = write(&watched, &names, ip, false) {
+                    output::warning(&format!(
+                        "{}; run sudo -v so vagrantx can update it again",
+                        e
+                    ));
+                }
+                current = ip;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+    Watch {
+        machine: machine.clone(),
+        hostnames,
+        stop,
+    }
+}
+
+impl Drop for Watch {
+    /// However `up` ends, the machine's entries go with it.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Err(e) = update(&self.machine, &self.hostnames, None) {
+            output::warning(&e.to_string());
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! First-boot provisioning of Fedora CoreOS and Flatcar guests with
+//! Ignition.
+//!
+//! The config's `ignition` file is an Ignition config, or a Butane one
+//! (`.bu`) that `butane` translates first. QEMU hands it to the guest
+//! through fw_cfg, where Ignition's `qemu` platform looks. The framework
+//! has nothing like fw_cfg, so there the guest boots as the `metal`
+//! platform and fetches the config from a one-off HTTP server on the
+//! host's vmnet address, at a path nobody else can guess.
+//!
+//! Ignition only runs on a machine's first boot, which is told apart by a
+//! marker written once the machine is first ready.
+
+use crate::config::{BackendKind, Config, ResolvedBoot};
+use crate::http;
+use crate::machine::Machine;
+use crate::seed;
+use serde_json::{json, Map, Value};
+use std::error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+/// The framework's NAT network hands out addresses from this host address's
+/// /24 unless it's been reconfigured.
+const DEFAULT_VMNET_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 64, 1);
+
+fn applied_marker(machine: &Machine) -> PathBuf {
+    machine.dir.join("ignition-applied")
+}
+
+/// The host's address on the framework's NAT network.
+fn vmnet_address() -> Ipv4Addr {
+    Command::new("defaults")
+        .args([
+            "read",
+            "/Library/Preferences/SystemConfiguration/com.apple.vmnet",
+            "Shared_Net_Address",
+        ])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse().ok())
+        .unwrap_or(DEFAULT_VMNET_ADDRESS)
+}
+
+/// `source` as an Ignition config, translating Butane if need be.
+fn load(source: &Path) -> Result<Value, Box<dyn error::Error>> {
+    let butane = matches!(
+        source.extension().and_then(|e| e.to_str()),
+        Some("bu" | "butane" | "yaml" | "yml")
+    );
+    let data = if butane {
+        let output = Command::new("butane")
+            .arg("--strict")
+            .arg(source)
+            .output()
+            .map_err(|e| {
+                format!(
+                    "could not run butane to translate {}: {}",
+                    source.display(),
+                    e
+                )
+            })?;
+        if !output.status.success() {
+            return Err(format!(
+                "butane could not translate {}: {}",
+                source.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        output.stdout
+    } else {
+        fs::read(source).map_err(|e| format!("could not read {}: {}", source.display(), e))?
+    };
+    let value: Value = serde_json::from_slice(&data)
+        .map_err(|e| format!("{} is not an Ignition config: {}", source.display(), e))?;
+    if value.pointer("/ignition/version").is_none() {
+        return Err(format!("{} has no ignition.version", source.display()).into());
+    }
+    Ok(value)
+}
+
+/// The array at `key` of `object`, added empty if it isn't there. Fails
+/// naming `path` if something other than an array is.
+fn array_at<'a>(
+    object: &'a mut Map<String, Value>,
+    key: &str,
+    path: &str,
+) -> Result<&'a mut Vec<Value>, String> {
+    object
+        .entry(key)
+        .or_insert_with(|| json!([]))
+        .as_array_mut()
+        .ok_or_else(|| format!("{} is not an array", path))
+}
+
+/// Adds the machine's generated key to `username`'s authorized keys, so
+/// `vagrantx ssh` works as it does with cloud-init. Fails naming the key
+/// that isn't the shape Ignition has it.
+fn authorize(ignition: &mut Value, username: &str, public_key: &str) -> Result<(), String> {
+    let passwd = ignition
+        .as_object_mut()
+        .ok_or("the config is not an object")?
+        .entry("passwd")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or("passwd is not an object")?;
+    let users = array_at(passwd, "users", "passwd.users")?;
+    let index = match users.iter().position(|u| u["name"] == username) {
+        Some(index) => index,
+        None => {
+            users.push(json!({ "name": username }));
+            users.len() - 1
+        }
+    };
+    let user = users[index]
+        .as_object_mut()
+        .ok_or_else(|| format!("passwd.users[{}] is not an object", index))?;
+    let path = format!("passwd.users[{}].sshAuthorizedKeys", index);
+    array_at(user, "sshAuthorizedKeys", &path)?.push(json!(public_key));
+    Ok(())
+}
+
+fn random_token() -> Result<String, Box<dyn error::Error>> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Serves `body` at `/<token>.ign` to guests on the NAT network, returning
+/// its URL.
+fn serve(body: String) -> Result<String, Box<dyn error::Error>> {
+    let host = vmnet_address();
+    // The bridge only exists while a machine is running, so listen
+    // everywhere and turn away anyone not on it.
+    let listener = TcpListener::bind("0.0.0.0:0")?;
+    let port = listener.local_addr()?.port();
+    let path = format!("/{}.ign", random_token()?);
+    let url = format!("http://{}:{}{}", host, port, path);
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let on_bridge = match stream.peer_addr().map(|a| a.ip()) {
+                Ok(IpAddr::V4(peer)) => peer.octets()[..3] == host.octets()[..3],
+                _ => false,
+            };
+            let request = match http::read_request(&stream) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            let _ = if on_bridge && request.method == "GET" && request.path == path {
+                http::respond(&mut stream, "200 OK", "application/json", &body)
+            } else {
+                http::respond(&mut stream, "404 Not Found", "text/plain", "not found\n")
+            };
+        }
+    });
+    Ok(url)
+}
+
+/// Sets up Ignition for this boot of `config`'s machine on `kind`, if it
+/// has an Ignition config and hasn't booted with it before.
+pub fn prepare(
+    config: &Config,
+    machine: &Machine,
+    kind: BackendKind,
+    boot: &mut ResolvedBoot,
+) -> Result<(), Box<dyn error::Error>> {
+    let source = match &config.ignition {
+        Some(source) if !applied_marker(machine).exists() => source,
+        _ => return Ok(()),
+    };
+    let mut ignition = load(source)?;
+    if config.ssh.generate_key && config.ssh.identity_file.is_none() {
+        let username = config.ssh.username.as_deref().unwrap_or("core");
+        authorize(&mut ignition, username, &seed::ensure_key(machine)?)
+            .map_err(|e| format!("{}: {}", source.display(), e))?;
+    }
+    let body = serde_json::to_string(&ignition)?;
+
+    boot.command_line.push_str(" ignition.firstboot");
+    if kind == BackendKind::Qemu {
+        let path = machine.dir.join("config.ign");
+        fs::write(&path, body)?;
+        boot.command_line.push_str(" ignition.platform.id=qemu");
+        boot.ignition = Some(path);
+    } else {
+        let url = serve(body)?;
+        boot.command_line.push_str(&format!(
+            " ignition.platform.id=metal ignition.config.url={}",
+            url
+        ));
+    }
+    Ok(())
+}
+
+/// Notes that the machine has booted with its Ignition config, so later
+/// boots don't run Ignition again.
+pub fn applied(config: &Config, machine: &Machine) {
+    if config.ignition.is_some() {
+        let _ = fs::write(applied_marker(machine), "");
+    }
+}
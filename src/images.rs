@@ -0,0 +1,117 @@
+//! Official distro cloud images by name, e.g. `ubuntu:24.04`.
+//!
+//! The first time one is used it's downloaded into the cache, converted
+//! from qcow2 to a raw image with `qemu-img`, and added as a box named like
+//! `ubuntu-24.04`. These images configure themselves with cloud-init, which
+//! the machine's seed disk feeds its SSH key to.
+
+use crate::boxes;
+use crate::cache;
+use crate::init;
+use crate::output;
+use crate::paths;
+use std::env::consts::ARCH;
+use std::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub struct Image {
+    pub id: &'static str,
+    /// `{arch}` is replaced with the distro's name for the host's
+    /// architecture.
+    url: &'static str,
+    /// The user cloud-init sets up.
+    username: &'static str,
+}
+
+pub const IMAGES: &[Image] = &[
+    Image {
+        id: "ubuntu:24.04",
+        url: "https://cloud-images.ubuntu.com/releases/24.04/release/ubuntu-24.04-server-cloudimg-{arch}.img",
+        username: "ubuntu",
+    },
+    Image {
+        id: "ubuntu:22.04",
+        url: "https://cloud-images.ubuntu.com/releases/22.04/release/ubuntu-22.04-server-cloudimg-{arch}.img",
+        username: "ubuntu",
+    },
+    Image {
+        id: "debian:12",
+        url: "https://cloud.debian.org/images/cloud/bookworm/latest/debian-12-genericcloud-{arch}.qcow2",
+        username: "debian",
+    },
+    Image {
+        id: "debian:11",
+        url: "https://cloud.debian.org/images/cloud/bullseye/latest/debian-11-genericcloud-{arch}.qcow2",
+        username: "debian",
+    },
+];
+
+impl Image {
+    pub fn find(id: &str) -> Option<&'static Image> {
+        IMAGES.iter().find(|image| image.id == id)
+    }
+
+    pub fn box_name(&self) -> String {
+        self.id.replace(':', "-")
+    }
+
+    fn url(&self) -> Result<String, String> {
+        let arch = match ARCH {
+            "aarch64" => "arm64",
+            "x86_64" => "amd64",
+            arch => return Err(format!("{} has no image for {}", self.id, arch)),
+        };
+        Ok(self.url.replace("{arch}", arch))
+    }
+
+    /// Adds the image as a box, unless that's already been done.
+    pub fn ensure_box(&self) -> Result<String, Box<dyn error::Error>> {
+        let name = self.box_name();
+        if boxes::box_dir(&name).exists() {
+            return Ok(name);
+        }
+        let qcow2 = cache::fetch(&self.url()?, None)?;
+
+        let raw = paths::boxes_dir().join(format!(".{}.raw.partial", name));
+        fs::create_dir_all(paths::boxes_dir())?;
+        output::message(&format!("converting {} to a raw disk image", self.id));
+        let status = Command::new("qemu-img")
+            .args(["convert", "-O", "raw"])
+            .arg(&qcow2)
+            .arg(&raw)
+            .status()
+            .map_err(|e| {
+                format!(
+                    "could not run qemu-img, which converting cloud images needs: {}",
+                    e
+                )
+            })?;
+        if !status.success() {
+            let _ = fs::remove_file(&raw);
+            return Err(format!("could not convert {} ({})", self.id, status).into());
+        }
+        let result = boxes::add(&name, &raw, None, None, None, None)
+            .and_then(|()| boxes::set_ssh_username(&name, self.username));
+        let _ = fs::remove_file(&raw);
+        result?;
+        Ok(name)
+    }
+}
+
+/// `path` as a config to use. An image ID that isn't also a file is
+/// replaced by a config for it in the current directory, which is written
+/// along with the image's box the first time.
+pub fn config_for(path: &Path) -> Result<PathBuf, Box<dyn error::Error>> {
+    let image = match path.to_str().and_then(Image::find) {
+        Some(image) if !path.exists() => image,
+        _ => return Ok(path.to_path_buf()),
+    };
+    let config_file = PathBuf::from(format!("{}.json", image.box_name()));
+    if !config_file.exists() {
+        let name = image.ensure_box()?;
+        init::init(&name, &config_file)?;
+    }
+    Ok(config_file)
+}
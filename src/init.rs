@@ -0,0 +1,99 @@
+//! Scaffolding for a new project.
+
+use crate::boxes;
+use crate::images::Image;
+use std::error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+pub const DEFAULT_CONFIG_FILE: &str = "XVagrantfile.json";
+
+pub fn template(box_name: &str) -> String {
+    format!(
+        r#"{{
+    // The box to boot; `vagrantx box list` shows what's installed.
+    "box": "{box_name}",
+
+    // Virtual CPUs, and memory in bytes (a multiple of 1 MiB). Or pick a
+    // preset with `vagrantx up --profile small|medium|large`.
+    "cpu_count": 2,
+    "memory_size": 2147483648,
+
+    "boot": {{
+        // Extra kernel arguments, added after the box's own.
+        "command_line_append": ""
+    }},
+
+    // `up` reports the machine ready once the console prints this.
+    "readiness": {{
+        "console": "login:",
+        "timeout": 300
+    }},
+
+    // What to do when the guest stops on its own: never, on-failure or
+    // always.
+    "restart": "never"
+}}
+"#,
+        box_name = box_name.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// Makes sure `.vagrantx/` is in the project's `.gitignore`.
+fn ignore_state(project: &Path) -> Result<(), Box<dyn error::Error>> {
+    let path = project.join(".gitignore");
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    if existing.lines().any(|l| {
+        matches!(
+            l.trim(),
+            ".vagrantx" | ".vagrantx/" | "/.vagrantx" | "/.vagrantx/"
+        )
+    }) {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, ".vagrantx/")?;
+    Ok(())
+}
+
+/// The box `box_name` names, adding it first if it's a cloud image ID.
+/// Warns if it isn't installed.
+pub fn installed_box(box_name: &str) -> Result<String, Box<dyn error::Error>> {
+    let box_name = match Image::find(box_name) {
+        Some(image) => image.ensure_box()?,
+        None => box_name.to_string(),
+    };
+    if !boxes::box_dir(&box_name).exists() {
+        println!(
+            "warning: box {} is not installed; add it with `vagrantx box add {} <disk>`",
+            box_name, box_name
+        );
+    }
+    Ok(box_name)
+}
+
+/// Writes a starter config for `box_name` to `config_file`. `box_name`
+/// may also be a cloud image ID such as `ubuntu:24.04`, whose box is added
+/// if it isn't already.
+pub fn init(box_name: &str, config_file: &Path) -> Result<(), Box<dyn error::Error>> {
+    if config_file.exists() {
+        return Err(format!("{} already exists", config_file.display()).into());
+    }
+    let box_name = installed_box(box_name)?;
+
+    let project = match config_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(project.join(".vagrantx"))?;
+    fs::write(config_file, template(&box_name))?;
+    ignore_state(project)?;
+
+    println!("wrote {}", config_file.display());
+    Ok(())
+}
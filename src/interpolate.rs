@@ -0,0 +1,105 @@
+//! Variables in config values: `${HOME}` or `${env:HOME}` for the host's
+//! environment, and `${project_dir}`, `${machine}` and `${machine_dir}` for
+//! where the config is and the machine it defines. `$${` is a literal `${`.
+
+use crate::machine::Machine;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+struct Variables {
+    project_dir: String,
+    machine: String,
+    machine_dir: String,
+}
+
+impl Variables {
+    fn lookup(&self, name: &str) -> Result<String, String> {
+        if let Some(var) = name.strip_prefix("env:") {
+            return env::var(var).map_err(|_| format!("{} is not set in the environment", var));
+        }
+        match name {
+            "project_dir" => Ok(self.project_dir.clone()),
+            "machine" => Ok(self.machine.clone()),
+            "machine_dir" => Ok(self.machine_dir.clone()),
+            _ => env::var(name).map_err(|_| {
+                format!(
+                    "{} is neither set in the environment nor one of project_dir, machine and machine_dir",
+                    name
+                )
+            }),
+        }
+    }
+
+    fn expand(&self, s: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            if let Some(after) = rest.strip_prefix("$${") {
+                out.push_str("${");
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("${") {
+                let end = after
+                    .find('}')
+                    .ok_or_else(|| format!("{} has an unclosed ${{", s))?;
+                out.push_str(&self.lookup(&after[..end])?);
+                rest = &after[end + 1..];
+            } else {
+                out.push('$');
+                rest = &rest[1..];
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn walk(&self, path: &str, value: &mut Value) -> Result<(), String> {
+        match value {
+            Value::String(s) if s.contains('$') => {
+                *s = self.expand(s).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            Value::Array(values) => {
+                for (i, value) in values.iter_mut().enumerate() {
+                    self.walk(&format!("{}[{}]", path, i), value)?;
+                }
+            }
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    let path = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    self.walk(&path, value)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the variables in every string in `value`, the parsed contents
+/// of `config_file`, naming the setting an undefined one is in.
+pub fn expand(config_file: &Path, value: &mut Value) -> Result<(), String> {
+    let project_dir = match config_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let project_dir = fs::canonicalize(project_dir).unwrap_or_else(|_| project_dir.to_path_buf());
+    let name = value.get("name").and_then(Value::as_str);
+    // From the absolute path, so machine_dir is too.
+    let machine = Machine::new(
+        &project_dir.join(config_file.file_name().unwrap_or_default()),
+        name,
+    );
+    let variables = Variables {
+        project_dir: project_dir.to_string_lossy().into_owned(),
+        machine: machine.name,
+        machine_dir: machine.dir.to_string_lossy().into_owned(),
+    };
+    variables.walk("", value)
+}
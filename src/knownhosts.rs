@@ -0,0 +1,99 @@
+//! The host keys of a project's machines, in `.vagrantx/known_hosts`.
+//!
+//! Entries are by machine name rather than address, since addresses are
+//! handed out again to whichever machine boots next. Machines seeded
+//! through cloud-init are given their host key, so it's known before they
+//! ever boot; any other machine's key is learned on the first connection
+//! and forgotten when the machine is created again.
+
+use crate::machine::Machine;
+use std::error;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// The project's known_hosts file, beside its machine directories.
+pub fn path(machine: &Machine) -> PathBuf {
+    machine
+        .dir
+        .parent()
+        .and_then(|machines| machines.parent())
+        .map_or_else(
+            || PathBuf::from("known_hosts"),
+            |dir| dir.join("known_hosts"),
+        )
+}
+
+/// The name `machine`'s key is recorded under, for ssh's `HostKeyAlias`.
+pub fn alias(machine: &Machine) -> String {
+    format!("vagrantx-{}", machine.name)
+}
+
+/// Whether `line` is an entry for `alias`.
+fn names(line: &str, alias: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|hosts| hosts.split(',').any(|host| host == alias))
+}
+
+/// Replaces `machine`'s entries with `key`, or just removes them.
+fn rewrite(machine: &Machine, key: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+    let path = path(machine);
+    let alias = alias(machine);
+    let contents = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<String> = contents
+        .lines()
+        .filter(|line| !names(line, &alias))
+        .map(str::to_string)
+        .collect();
+    if let Some(key) = key {
+        lines.push(format!("{} {}", alias, key));
+    }
+    if lines.len() == contents.lines().count() && key.is_none() {
+        return Ok(());
+    }
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("partial");
+    fs::write(&partial, updated)?;
+    fs::rename(&partial, &path)?;
+    Ok(())
+}
+
+/// Forgets `machine`'s host key, for when it's been made anew.
+pub fn forget(machine: &Machine) -> Result<(), Box<dyn error::Error>> {
+    rewrite(machine, None)
+}
+
+fn host_key_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("ssh_host_ed25519_key")
+}
+
+/// The host key `machine` is to have, private and public, generating it if
+/// there isn't one yet. The public half is recorded as `machine`'s.
+pub fn ensure_host_key(machine: &Machine) -> Result<(String, String), Box<dyn error::Error>> {
+    let key = host_key_path(machine);
+    let public = key.with_extension("pub");
+    if !key.exists() {
+        fs::create_dir_all(&machine.dir)?;
+        let _ = fs::remove_file(&public);
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C"])
+            .arg(alias(machine))
+            .arg("-f")
+            .arg(&key)
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|e| format!("could not run ssh-keygen: {}", e))?;
+        if !status.success() {
+            return Err(format!("could not generate a host key ({})", status).into());
+        }
+    }
+    let private = fs::read_to_string(&key)?;
+    let public = fs::read_to_string(&public)?.trim().to_string();
+    rewrite(machine, Some(&public))?;
+    Ok((private, public))
+}
@@ -0,0 +1,83 @@
+//! Advisory per-machine locks, so two vagrantx processes can't mutate the
+//! same machine at once.
+
+use crate::machine::Machine;
+use libc::{flock, EWOULDBLOCK, LOCK_EX, LOCK_NB};
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug)]
+pub enum LockError {
+    Held { machine: String, holder: String },
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockError::Held { machine, holder } => write!(
+                f,
+                "machine {} is locked by {}; if that process is stuck, rerun with --force-unlock",
+                machine, holder
+            ),
+            LockError::Io(e) => write!(f, "could not lock machine: {}", e),
+        }
+    }
+}
+
+impl error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(err: io::Error) -> Self {
+        LockError::Io(err)
+    }
+}
+
+/// Held until dropped; the kernel releases the lock when the file closes,
+/// including when the process dies.
+pub struct MachineLock {
+    _file: File,
+}
+
+/// Locks `machine` for `operation`. With `force`, the existing lock file is
+/// replaced first, which leaves any current holder locking an orphaned file.
+pub fn acquire(machine: &Machine, operation: &str, force: bool) -> Result<MachineLock, LockError> {
+    let path = machine.dir.join("lock");
+    fs::create_dir_all(&machine.dir)?;
+    if force && path.exists() {
+        fs::remove_file(&path)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(EWOULDBLOCK) {
+            return Err(err.into());
+        }
+        let mut holder = String::new();
+        file.read_to_string(&mut holder)?;
+        let holder = match holder.trim().split_once(' ') {
+            Some((pid, op)) => format!("PID {} ({})", pid, op),
+            None => "another process".to_string(),
+        };
+        return Err(LockError::Held {
+            machine: machine.name.clone(),
+            holder,
+        });
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{} {}", std::process::id(), operation)?;
+
+    Ok(MachineLock { _file: file })
+}
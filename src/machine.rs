@@ -0,0 +1,44 @@
+use crate::config;
+use crate::global;
+use std::error;
+use std::path::{Path, PathBuf};
+
+/// Where a machine keeps its state: `.vagrantx/machines/<name>` next to the
+/// config file that defines it, or for a machine that belongs to no
+/// project, the directory its config is in.
+#[derive(Clone)]
+pub struct Machine {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl Machine {
+    pub fn new(config_file: &Path, name: Option<&str>) -> Machine {
+        if let Some(name) = global::name_of(config_file) {
+            let dir = config_file.parent().unwrap().to_path_buf();
+            return Machine { name, dir };
+        }
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => config_file
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "default".to_string()),
+        };
+        let project_dir = config_file.parent().unwrap_or_else(|| Path::new("."));
+        let dir = project_dir.join(".vagrantx").join("machines").join(&name);
+
+        Machine { name, dir }
+    }
+
+    /// The machine defined by `config_file`.
+    pub fn load(config_file: &Path) -> Result<Machine, Box<dyn error::Error>> {
+        let config = config::load_config(&config_file.to_path_buf())?;
+        Ok(Machine::new(config_file, config.name.as_deref()))
+    }
+
+    /// The machine's writable copy of its box's disk image.
+    pub fn root_disk(&self) -> PathBuf {
+        self.dir.join("disk.img")
+    }
+}
@@ -1,246 +1,855 @@
 extern crate virtualization_rs;
 
-use block::{Block, ConcreteBlock};
-use libc::{sleep, tcgetattr, tcsetattr, ECHO, ICANON, ICRNL, TCSANOW};
-use objc::rc::StrongPtr;
-use objc::{msg_send, sel, sel_impl};
-use serde::{Deserialize, Serialize};
-use std::error;
-use std::fs::{canonicalize, File};
-use std::io::Read;
-use std::mem::MaybeUninit;
-use std::sync::{Arc, RwLock};
-use virtualization_rs::virtualization::boot_loader;
-use virtualization_rs::{
-    base::{dispatch_async, dispatch_queue_create, Id, NSError, NSFileHandle, NIL},
-    virtualization::{
-        boot_loader::VZLinuxBootLoaderBuilder,
-        entropy_device::VZVirtioEntropyDeviceConfiguration,
-        memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
-        network_device::{
-            VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
-        },
-        serial_port::{
-            VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
-        },
-        storage_device::{
-            VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
-        },
-        virtual_machine::{VZVirtualMachine, VZVirtualMachineConfigurationBuilder},
-    },
-};
+mod api;
+mod arch;
+mod attach;
+mod autostart;
+mod backend;
+mod bench;
+mod boxes;
+mod build;
+mod bundle;
+mod cache;
+mod cast;
+mod cmdline;
+mod completions;
+mod config;
+mod console;
+mod control;
+mod doctor;
+mod error;
+mod events;
+mod exec;
+mod expect;
+mod extract;
+mod firmware;
+mod fsevents;
+mod global;
+mod guestenv;
+mod halt;
+mod health;
+mod hosts;
+mod http;
+mod ignition;
+mod images;
+mod init;
+mod interpolate;
+mod knownhosts;
+mod lock;
+mod machine;
+mod metrics;
+mod multi;
+mod network;
+mod notify;
+mod observe;
+mod output;
+mod package;
+mod paths;
+mod phases;
+mod platform;
+mod plugins;
+mod priority;
+mod procinfo;
+mod profiles;
+mod provision;
+mod provisionlog;
+mod prune;
+mod qemu;
+mod readiness;
+mod relay;
+mod reload;
+mod remote;
+mod rename;
+mod resources;
+mod restart;
+mod rsync;
+mod seed;
+mod settings;
+mod snapshot;
+mod ssh;
+mod stats;
+mod status;
+mod timesync;
+mod transfer;
+mod up;
+mod validate;
+mod vm;
 
+use error::Error;
+use std::env;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "simplevm")]
+#[structopt(name = "vagrantx")]
 struct Opt {
-    #[structopt(parse(from_os_str))]
-    config: PathBuf,
+    /// Run the command on another Mac over SSH, e.g. `user@mac-mini.local`
+    #[structopt(long, global = true)]
+    host: Option<String>,
+    /// Apply the config's overrides for this environment, e.g. `ci`.
+    /// Defaults to $VAGRANTX_ENV
+    #[structopt(long, global = true)]
+    env: Option<String>,
+    /// Print NDJSON records instead of text, for wrappers and CI
+    #[structopt(long, global = true)]
+    machine_readable: bool,
+    /// Print without color, as does setting $NO_COLOR
+    #[structopt(long, global = true)]
+    no_color: bool,
+    #[structopt(subcommand)]
+    command: Command,
 }
 
-fn default_cpu() -> usize {
-    2
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Write a starter config for a box in the current directory
+    Init {
+        /// A box, or a cloud image such as ubuntu:24.04 or debian:12
+        #[structopt(name = "box")]
+        box_name: String,
+        /// Where to write the config
+        #[structopt(short, long, parse(from_os_str), default_value = init::DEFAULT_CONFIG_FILE)]
+        output: PathBuf,
+    },
+    /// Boot the machine described by a config file
+    Up {
+        /// One or more configs, or cloud images such as ubuntu:24.04; several
+        /// are brought up together
+        #[structopt(
+            name = "config",
+            parse(from_os_str = global::resolve),
+            required_unless = "name"
+        )]
+        configs: Vec<PathBuf>,
+        /// Bring up a machine of this name that belongs to no project,
+        /// kept in ~/.vagrantx/machines, instead of a config
+        #[structopt(long, conflicts_with = "config")]
+        name: Option<String>,
+        /// The box to make the --name machine from, the first time
+        #[structopt(long = "box", requires = "name")]
+        box_name: Option<String>,
+        /// Take the machine's lock even if another process holds it
+        #[structopt(long)]
+        force_unlock: bool,
+        /// Resource profile to use, e.g. small, medium or large
+        #[structopt(long)]
+        profile: Option<String>,
+        /// Boot from kernel, cdrom or disk this time, whatever boot.from says
+        #[structopt(long)]
+        boot_from: Option<config::BootFrom>,
+        /// With several configs, bring them up one at a time
+        #[structopt(long)]
+        no_parallel: bool,
+        /// With several configs, how many to start at once
+        #[structopt(long, default_value = "4")]
+        parallel: usize,
+    },
+    /// Check a config for problems without starting anything
+    Validate {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+    },
+    /// Manage boxes
+    Box(BoxCommand),
+    /// Inspect and clean the download cache
+    Cache(CacheCommand),
+    /// Provision a config's box and save the result as a new box
+    Build {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Name of the box to create
+        name: String,
+    },
+    /// Export a halted machine as a box archive for `box add`
+    Package {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// User to log in as on machines made from the box
+        #[structopt(long)]
+        ssh_username: Option<String>,
+        /// Private key for that user, shipped inside the box
+        #[structopt(long, parse(from_os_str), requires = "ssh-username")]
+        ssh_private_key: Option<PathBuf>,
+    },
+    /// Write a halted machine, its disks, config and box included, to one
+    /// file that `import` brings back
+    Export {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Unpack a machine from `export` into a project
+    Import {
+        #[structopt(parse(from_os_str))]
+        bundle: PathBuf,
+        /// The project to put it in
+        #[structopt(parse(from_os_str), default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Start a machine automatically at login
+    Autostart(AutostartCommand),
+    /// Show a machine's lifecycle events
+    Events {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Keep printing new events as they happen
+        #[structopt(short, long)]
+        follow: bool,
+    },
+    /// Attach to a running machine's serial console
+    Console {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Drive the console with an expect script, then detach
+        #[structopt(long, parse(from_os_str))]
+        script: Option<PathBuf>,
+        /// Record the session to an asciinema cast file
+        #[structopt(long, parse(from_os_str))]
+        record: Option<PathBuf>,
+    },
+    /// Log in to a running machine over SSH, or run a command there
+    Ssh {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Forward the SSH agent
+        #[structopt(short = "A", long)]
+        forward_agent: bool,
+        /// Private key to log in with, instead of the config's or the box's
+        #[structopt(short, long, parse(from_os_str))]
+        identity_file: Option<PathBuf>,
+        /// Extra OpenSSH options, e.g. `-o ProxyJump=bastion`
+        #[structopt(short = "o", long = "option", number_of_values = 1)]
+        options: Vec<String>,
+        /// Command to run instead of a login shell
+        #[structopt(last = true)]
+        command: Vec<String>,
+    },
+    /// Run a command in a running machine and exit with its status
+    Exec {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Run the command as this user, with sudo
+        #[structopt(short, long)]
+        user: Option<String>,
+        /// Directory to run the command in
+        #[structopt(short, long)]
+        workdir: Option<String>,
+        #[structopt(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Copy a file or directory into a running machine
+    Push {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        #[structopt(parse(from_os_str))]
+        host_path: PathBuf,
+        guest_path: String,
+    },
+    /// Copy a file out of a running machine
+    Pull {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        guest_path: String,
+        #[structopt(parse(from_os_str))]
+        host_path: PathBuf,
+        /// Copy a directory
+        #[structopt(short, long)]
+        recursive: bool,
+    },
+    /// Shut a running machine down, forcing it off if the guest doesn't
+    Halt {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Force the machine off without asking the guest
+        #[structopt(short, long)]
+        force: bool,
+        /// Seconds to wait for the guest before forcing it off, instead of
+        /// the config's halt_timeout
+        #[structopt(long)]
+        timeout: Option<u64>,
+    },
+    /// Show how a running machine's config has changed and apply it, restarting
+    /// the machine only if it has to be
+    Reload {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Only show the changes
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Run a running machine's provisioners again
+    Provision {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// If a provisioner fails, stop the machine and restore the disks it
+        /// had before
+        #[structopt(long)]
+        rollback_on_failure: bool,
+        /// Show the output of the last run instead of provisioning
+        #[structopt(long, conflicts_with = "rollback-on-failure")]
+        show_last: bool,
+    },
+    /// Copy a machine's synced folders into it
+    Rsync {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Keep syncing as files on the host change
+        #[structopt(short, long)]
+        watch: bool,
+    },
+    /// List the host ports forwarded to a machine
+    Port {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Give a machine a new name, keeping its disk and other state
+    Rename {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        new_name: String,
+    },
+    /// Remove machines, disks, snapshots and agents nothing uses any more
+    Prune {
+        /// The project whose machines to check
+        #[structopt(parse(from_os_str), default_value = ".")]
+        project: PathBuf,
+        /// List what would be removed without removing it
+        #[structopt(long)]
+        dry_run: bool,
+        /// Remove without asking first
+        #[structopt(short, long, conflicts_with = "dry-run")]
+        yes: bool,
+        /// Also remove boxes no config in the project or started at login uses
+        #[structopt(long)]
+        boxes: bool,
+    },
+    /// Show what machines are doing
+    Status {
+        #[structopt(name = "config", parse(from_os_str = global::resolve), required = true)]
+        configs: Vec<PathBuf>,
+        /// Keep printing state and address changes and new events until
+        /// interrupted
+        #[structopt(short, long)]
+        watch: bool,
+        /// Print JSON, one record per change with --watch
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Boot a machine several times and report how long each part took
+    Bench {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// How many times to boot it
+        #[structopt(short = "n", long, default_value = "5")]
+        runs: usize,
+    },
+    /// Check that this Mac can run machines, suggesting fixes for what it
+    /// can't
+    Doctor,
+    /// Show the host resources running machines are using
+    Stats {
+        #[structopt(name = "config", parse(from_os_str = global::resolve), required = true)]
+        configs: Vec<PathBuf>,
+        /// Keep refreshing until interrupted
+        #[structopt(short, long)]
+        watch: bool,
+        /// Seconds between refreshes with --watch
+        #[structopt(long, default_value = "2")]
+        interval: u64,
+        /// Print a JSON array of stats per refresh instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Forward host ports to a running machine until interrupted
+    #[structopt(setting = AppSettings::SubcommandsNegateReqs)]
+    Tunnel {
+        // Only missing with `close`, as the ports have to come after it.
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: Option<PathBuf>,
+        /// Ports to forward, as [address:]host-port:guest-port
+        #[structopt(required = true)]
+        forwards: Vec<config::PortForward>,
+        #[structopt(subcommand)]
+        close: Option<TunnelCommand>,
+    },
+    /// Set a running machine's clock from the host's
+    Timesync {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+    },
+    /// Save and restore a halted machine's disks
+    Snapshot(SnapshotCommand),
+    /// Look back at what a machine printed
+    Logs(LogsCommand),
+    /// Read or change one of a config's settings
+    Config(ConfigCommand),
+    /// Print a completion script for bash, zsh, fish, powershell or elvish
+    Completions { shell: Shell },
+    /// Print a man page
+    Man,
+    /// Print names for shell completion to offer
+    #[structopt(setting = AppSettings::Hidden)]
+    Complete(CompleteCommand),
+    /// List installed plugins and what they provide
+    Plugins,
+    #[structopt(external_subcommand)]
+    External(Vec<String>),
 }
 
-fn default_mem_size() -> usize {
-    2147483648
+#[derive(StructOpt, Debug)]
+enum CacheCommand {
+    /// List cached downloads, most recently used first
+    Ls {
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Remove the least recently used downloads until the cache fits its limit
+    Gc {
+        /// Size to shrink the cache to instead, e.g. 5G; 0 empties it
+        #[structopt(long)]
+        limit: Option<String>,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-struct Boot {
-    kernel: PathBuf,
-    initrd: PathBuf,
-
-    #[serde(default)]
-    command_line: String,
-
-    #[serde(default)]
-    disks: Vec<PathBuf>,
+#[derive(StructOpt, Debug)]
+enum BoxCommand {
+    /// Import a disk image as a box, extracting its kernel and initrd from
+    /// /boot unless they're given explicitly, or import a box archive. Any
+    /// of the files may be an http(s) URL, which is downloaded into the cache
+    Add {
+        name: String,
+        #[structopt(parse(from_os_str))]
+        disk: PathBuf,
+        #[structopt(long, parse(from_os_str))]
+        kernel: Option<PathBuf>,
+        #[structopt(long, parse(from_os_str))]
+        initrd: Option<PathBuf>,
+        /// Default kernel command line for machines using this box
+        #[structopt(long)]
+        command_line: Option<String>,
+    },
+    /// List installed boxes
+    List,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-struct Config {
-    boot: Boot,
-
-    #[serde(default = "default_cpu")]
-    cpu_count: usize,
-
-    #[serde(default)]
-    additional_disks: Vec<PathBuf>,
-
-    #[serde(default = "default_mem_size")]
-    memory_size: usize,
+#[derive(StructOpt, Debug)]
+enum TunnelCommand {
+    /// Close tunnels opened by `vagrantx tunnel`
+    Close {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Host ports whose tunnels to close; all of them if none are given
+        ports: Vec<u16>,
+    },
 }
 
-fn build_console_configuration() -> VZVirtioConsoleDeviceSerialPortConfiguration {
-    let file_handle_for_reading = NSFileHandle::file_handle_with_standard_input();
-
-    unsafe {
-        let mut attributes = MaybeUninit::uninit();
-        let r = tcgetattr(
-            msg_send![*file_handle_for_reading.0, fileDescriptor],
-            attributes.as_mut_ptr(),
-        );
-        let mut init_attributes = attributes.assume_init_mut();
-
-        init_attributes.c_iflag &= !ICRNL;
-        init_attributes.c_lflag &= !(ICANON | ECHO);
-
-        let r = tcsetattr(
-            msg_send![*file_handle_for_reading.0, fileDescriptor],
-            TCSANOW,
-            attributes.as_ptr(),
-        );
-    };
-
-    let file_handle_for_writing = NSFileHandle::file_handle_with_standard_output();
-    let attachement = VZFileHandleSerialPortAttachmentBuilder::new()
-        .file_handle_for_reading(file_handle_for_reading)
-        .file_handle_for_writing(file_handle_for_writing)
-        .build();
-
-    VZVirtioConsoleDeviceSerialPortConfiguration::new(attachement)
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Print a setting, e.g. memory_size or forwarded_ports[0].host, as the
+    /// machine would use it
+    Get {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        key: String,
+    },
+    /// Change a setting, keeping the rest of the file as it is. The value is
+    /// parsed as JSON, or taken as a string if it isn't
+    Set {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        key: String,
+        value: String,
+    },
 }
 
-fn build_boot_loader(
-    kernel: &Path,
-    initrd: &Path,
-    cmd_line: &str,
-) -> boot_loader::VZLinuxBootLoader {
-    VZLinuxBootLoaderBuilder::new()
-        .kernel_url(
-            canonicalize(&kernel)
-                .unwrap()
-                .into_os_string()
-                .into_string()
-                .unwrap(),
-        )
-        .initial_ramdisk_url(
-            canonicalize(&initrd)
-                .unwrap()
-                .into_os_string()
-                .into_string()
-                .unwrap(),
-        )
-        .command_line(cmd_line)
-        .build()
+#[derive(StructOpt, Debug)]
+enum LogsCommand {
+    /// Show a provisioning run in full, the last one unless given its name
+    Provision {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        run: Option<String>,
+        /// List the runs that are kept instead
+        #[structopt(long, conflicts_with = "run")]
+        list: bool,
+    },
 }
 
-fn build_block_devices(
-    disks: &[PathBuf],
-) -> Result<Vec<VZVirtioBlockDeviceConfiguration>, NSError> {
-    let mut block_devices = Vec::with_capacity(disks.len());
-    for disk in disks {
-        let block_attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
-            .path(
-                canonicalize(disk)
-                    .unwrap()
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-            )
-            .read_only(false)
-            .build()?;
-        let block_device = VZVirtioBlockDeviceConfiguration::new(block_attachment);
-        block_devices.push(block_device);
-    }
-    Ok(block_devices)
+#[derive(StructOpt, Debug)]
+enum SnapshotCommand {
+    /// Snapshot the machine's disks, as a child of the current snapshot
+    Save {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        name: String,
+        #[structopt(short, long)]
+        description: Option<String>,
+    },
+    /// Put the machine's disks back as they were in a snapshot
+    Restore {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        name: String,
+    },
+    /// Delete a snapshot, reattaching its children to its parent
+    Delete {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        name: String,
+    },
+    /// List snapshots; the current one is marked with *
+    List {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+        /// Show how snapshots descend from one another
+        #[structopt(long)]
+        tree: bool,
+    },
 }
 
-fn load_config(config_file: &PathBuf) -> Result<Config, Box<dyn error::Error>> {
-    let mut file = File::open(config_file)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-
-    serde_json::from_str(&contents).map_err(|e| e.into())
+#[derive(StructOpt, Debug)]
+enum CompleteCommand {
+    Boxes,
+    Snapshots {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+    },
 }
 
-fn main() {
-    let opt = Opt::from_args();
-
-    let config = load_config(&opt.config).expect("could not read config");
+#[derive(StructOpt, Debug)]
+enum AutostartCommand {
+    /// Install and load a launchd agent that runs `up` at login
+    Enable {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+    },
+    /// Unload and remove the machine's launchd agent
+    Disable {
+        #[structopt(parse(from_os_str = global::resolve))]
+        config: PathBuf,
+    },
+}
 
-    if !VZVirtualMachine::supported() {
-        println!("not supported");
-        return;
+impl Command {
+    /// The config file the command acts on, if any.
+    fn config(&self) -> Option<&Path> {
+        match self {
+            Command::Up { configs, .. } => configs.first().map(PathBuf::as_path),
+            Command::Stats { configs, .. } | Command::Status { configs, .. } => {
+                configs.first().map(PathBuf::as_path)
+            }
+            Command::Validate { config }
+            | Command::Build { config, .. }
+            | Command::Package { config, .. }
+            | Command::Export { config, .. }
+            | Command::Bench { config, .. }
+            | Command::Events { config, .. }
+            | Command::Console { config, .. }
+            | Command::Ssh { config, .. }
+            | Command::Port { config, .. }
+            | Command::Exec { config, .. }
+            | Command::Push { config, .. }
+            | Command::Pull { config, .. }
+            | Command::Rsync { config, .. }
+            | Command::Provision { config, .. }
+            | Command::Halt { config, .. }
+            | Command::Reload { config, .. }
+            | Command::Timesync { config }
+            | Command::Rename { config, .. }
+            | Command::Tunnel {
+                close: Some(TunnelCommand::Close { config, .. }),
+                ..
+            } => Some(config),
+            Command::Tunnel { config, .. } => config.as_deref(),
+            Command::Autostart(AutostartCommand::Enable { config })
+            | Command::Autostart(AutostartCommand::Disable { config }) => Some(config),
+            Command::Config(ConfigCommand::Get { config, .. })
+            | Command::Config(ConfigCommand::Set { config, .. }) => Some(config),
+            Command::Snapshot(SnapshotCommand::Save { config, .. })
+            | Command::Snapshot(SnapshotCommand::Restore { config, .. })
+            | Command::Snapshot(SnapshotCommand::Delete { config, .. })
+            | Command::Snapshot(SnapshotCommand::List { config, .. }) => Some(config),
+            Command::Logs(LogsCommand::Provision { config, .. }) => Some(config),
+            Command::Init { .. }
+            | Command::Box(_)
+            | Command::Cache(_)
+            | Command::Completions { .. }
+            | Command::Man
+            | Command::Complete(_)
+            | Command::Plugins
+            | Command::Doctor
+            | Command::Prune { .. }
+            | Command::Import { .. }
+            | Command::External(_) => None,
+        }
     }
+}
 
-    let entropy = VZVirtioEntropyDeviceConfiguration::new();
-    let memory_balloon = VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new();
-
-    let network_attachment = VZNATNetworkDeviceAttachment::new();
-    let mut network_device = VZVirtioNetworkDeviceConfiguration::new(network_attachment);
-    network_device.set_mac_address(VZMACAddress::random_locally_administered_address());
-
-    let boot_loader = build_boot_loader(
-        &config.boot.kernel,
-        &config.boot.initrd,
-        &config.boot.command_line,
-    );
-
-    let block_devices = match build_block_devices(
-        &config
-            .boot
-            .disks
-            .iter()
-            .cloned()
-            .chain(config.additional_disks.iter().cloned())
-            .collect::<Vec<_>>(),
-    ) {
-        Ok(devices) => devices,
-        Err(err) => {
-            err.dump();
-            return;
+fn run(command: Command) -> Result<(), Error> {
+    match command {
+        Command::Init { box_name, output } => init::init(&box_name, &output)?,
+        Command::Up {
+            mut configs,
+            name,
+            box_name,
+            force_unlock,
+            profile,
+            boot_from,
+            no_parallel,
+            parallel,
+        } => {
+            if let Some(name) = name {
+                configs.push(global::ensure(&name, box_name.as_deref())?);
+            }
+            let configs = configs
+                .iter()
+                .map(|config| images::config_for(config))
+                .collect::<Result<Vec<_>, _>>()?;
+            match configs.as_slice() {
+                [config] => up::up(config, force_unlock, profile.as_deref(), boot_from)?,
+                _ => multi::up_all(
+                    &configs,
+                    &multi::Options {
+                        parallel: if no_parallel { 1 } else { parallel },
+                        force_unlock,
+                        profile: profile.as_deref(),
+                        boot_from,
+                    },
+                )?,
+            }
         }
-    };
-
-    let conf = VZVirtualMachineConfigurationBuilder::new()
-        .boot_loader(boot_loader)
-        .cpu_count(config.cpu_count)
-        .memory_size(config.memory_size)
-        .entropy_devices(vec![entropy])
-        .memory_balloon_devices(vec![memory_balloon])
-        .network_devices(vec![network_device])
-        .serial_ports(vec![build_console_configuration()])
-        .storage_devices(block_devices)
-        .build();
-
-    match conf.validate_with_error() {
-        Ok(_) => {
-            let label = std::ffi::CString::new("second").unwrap();
-            let queue = unsafe { dispatch_queue_create(label.as_ptr(), NIL) };
-            let vm = Arc::new(RwLock::new(VZVirtualMachine::new(conf, queue)));
-            let dispatch_block = ConcreteBlock::new(move || {
-                let completion_handler = ConcreteBlock::new(|err: Id| {
-                    if err != NIL {
-                        let error = unsafe { NSError(StrongPtr::new(err)) };
-                        error.dump();
-                    }
-                });
-                let completion_handler = completion_handler.copy();
-                let completion_handler: &Block<(Id,), ()> = &completion_handler;
-                vm.write()
-                    .unwrap()
-                    .start_with_completion_handler(completion_handler);
-            });
-            let dispatch_block = dispatch_block.copy();
-            let dispatch_block: &Block<(), ()> = &dispatch_block;
-            unsafe {
-                dispatch_async(queue, dispatch_block);
+        Command::Box(BoxCommand::Add {
+            name,
+            disk,
+            kernel,
+            initrd,
+            command_line,
+        }) => {
+            let kernel = kernel.as_deref().map(cache::resolve).transpose()?;
+            let initrd = initrd.as_deref().map(cache::resolve).transpose()?;
+            boxes::add(
+                &name,
+                &cache::resolve(&disk)?,
+                kernel.as_deref(),
+                initrd.as_deref(),
+                command_line,
+                None,
+            )?
+        }
+        Command::Cache(CacheCommand::Ls { json }) => cache::list(json)?,
+        Command::Cache(CacheCommand::Gc { limit }) => cache::clean(limit.as_deref())?,
+        Command::Validate { config } => {
+            if !validate::validate(&config)? {
+                return Err(Error::Config(format!("{} has problems", config.display())));
             }
-            loop {
-                unsafe {
-                    sleep(1000);
+        }
+        Command::Build { config, name } => build::build(&config, &name)?,
+        Command::Package {
+            config,
+            output,
+            ssh_username,
+            ssh_private_key,
+        } => package::package(&config, &output, ssh_username, ssh_private_key.as_deref())?,
+        Command::Export { config, output } => bundle::export(&config, &output)?,
+        Command::Import { bundle, dir } => bundle::import(&bundle, &dir)?,
+        Command::Box(BoxCommand::List) => {
+            for name in boxes::list()? {
+                match boxes::load(&name).ok().and_then(|b| b.arch) {
+                    Some(arch) => println!("{} ({})", name, arch),
+                    None => println!("{}", name),
                 }
             }
         }
-        Err(e) => {
-            e.dump();
-            return;
+        Command::Autostart(AutostartCommand::Enable { config }) => autostart::enable(&config)?,
+        Command::Autostart(AutostartCommand::Disable { config }) => autostart::disable(&config)?,
+        Command::Events { config, follow } => {
+            let machine = machine::Machine::load(&config)?;
+            events::EventLog::new(&machine).show(follow)?;
         }
+        Command::Console {
+            config,
+            script,
+            record,
+        } => attach::attach(&config, script.as_deref(), record.as_deref())?,
+        Command::Ssh {
+            config,
+            forward_agent,
+            identity_file,
+            options,
+            command,
+        } => ssh::ssh(&config, forward_agent, identity_file, options, &command)?,
+        Command::Exec {
+            config,
+            user,
+            workdir,
+            command,
+        } => exec::exec(&config, &command, user.as_deref(), workdir.as_deref())?,
+        Command::Push {
+            config,
+            host_path,
+            guest_path,
+        } => transfer::push(&config, &host_path, &guest_path)?,
+        Command::Pull {
+            config,
+            guest_path,
+            host_path,
+            recursive,
+        } => transfer::pull(&config, &guest_path, &host_path, recursive)?,
+        Command::Rsync { config, watch } => rsync::rsync(&config, watch)?,
+        Command::Halt {
+            config,
+            force,
+            timeout,
+        } => halt::halt(&config, force, timeout)?,
+        Command::Reload { config, dry_run } => reload::reload(&config, dry_run)?,
+        Command::Provision {
+            config,
+            show_last: true,
+            ..
+        } => provisionlog::show(&machine::Machine::load(&config)?, None)?,
+        Command::Provision {
+            config,
+            rollback_on_failure,
+            ..
+        } => provision::provision(&config, rollback_on_failure)?,
+        Command::Logs(LogsCommand::Provision { config, list, run }) => {
+            let machine = machine::Machine::load(&config)?;
+            if list {
+                provisionlog::list(&machine)?
+            } else {
+                provisionlog::show(&machine, run.as_deref())?
+            }
+        }
+        Command::Prune {
+            project,
+            dry_run,
+            yes,
+            boxes,
+        } => prune::prune(&project, dry_run, yes, boxes)?,
+        Command::Rename { config, new_name } => rename::rename(&config, &new_name)?,
+        Command::Status {
+            configs,
+            watch,
+            json,
+        } => observe::status(&configs, watch, json)?,
+        Command::Bench { config, runs } => bench::bench(&config, runs)?,
+        Command::Doctor => doctor::doctor()?,
+        Command::Stats {
+            configs,
+            watch,
+            interval,
+            json,
+        } => stats::stats(&configs, watch, Duration::from_secs(interval), json)?,
+        Command::Port { config, json } => {
+            let machine = machine::Machine::load(&config)?;
+            relay::list(&machine, json)?;
+        }
+        Command::Tunnel {
+            close: Some(TunnelCommand::Close { config, ports }),
+            ..
+        } => {
+            let machine = machine::Machine::load(&config)?;
+            relay::close(&machine, &ports)?;
+        }
+        Command::Tunnel {
+            config, forwards, ..
+        } => relay::tunnel(config.as_deref().unwrap(), &forwards)?,
+        Command::Timesync { config } => timesync::timesync(&config)?,
+        Command::Config(ConfigCommand::Get { config, key }) => settings::get(&config, &key)?,
+        Command::Config(ConfigCommand::Set { config, key, value }) => {
+            settings::set(&config, &key, &value)?
+        }
+        Command::Snapshot(command) => match command {
+            SnapshotCommand::Save {
+                config,
+                name,
+                description,
+            } => snapshot::Snapshots::open(&config)?.save(&name, description)?,
+            SnapshotCommand::Restore { config, name } => {
+                snapshot::Snapshots::open(&config)?.restore(&name)?
+            }
+            SnapshotCommand::Delete { config, name } => {
+                snapshot::Snapshots::open(&config)?.delete(&name)?
+            }
+            SnapshotCommand::List { config, tree } => {
+                snapshot::Snapshots::open(&config)?.list(tree)?
+            }
+        },
+        Command::Completions { shell } => completions::completions(Opt::clap(), shell),
+        Command::Man => completions::man_page(Opt::clap()),
+        Command::Complete(CompleteCommand::Boxes) => {
+            for name in boxes::list().unwrap_or_default() {
+                println!("{}", name);
+            }
+        }
+        Command::Complete(CompleteCommand::Snapshots { config }) => {
+            if let Ok(machine) = machine::Machine::load(&config) {
+                for name in snapshot::names(&machine) {
+                    println!("{}", name);
+                }
+            }
+        }
+        Command::Plugins => {
+            for plugin in plugins::discover() {
+                println!("{}", plugin.name);
+                for command in &plugin.description.commands {
+                    println!("    command {}  {}", command.name, command.about);
+                }
+                for provisioner in &plugin.description.provisioners {
+                    println!("    provisioner {}", provisioner);
+                }
+            }
+        }
+        Command::External(args) => plugins::run_command(&args)?,
+    }
+    Ok(())
+}
+
+fn main() {
+    let opt = match Opt::from_args_safe() {
+        Ok(opt) => opt,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            process::exit(error::USAGE_EXIT_CODE);
+        }
+        // --help and --version.
+        Err(e) => e.exit(),
+    };
+    if opt.machine_readable {
+        output::set_machine_readable();
+    }
+    if opt.no_color {
+        output::set_no_color();
+    }
+    if let Some(environment) = &opt.env {
+        env::set_var("VAGRANTX_ENV", environment);
+    }
+    if let Some(host) = &opt.host {
+        if let Command::Box(BoxCommand::Add { .. }) = opt.command {
+            println!("box add reads local files; run it on {} instead", host);
+            process::exit(error::USAGE_EXIT_CODE);
+        }
+        match remote::run(host, opt.command.config()) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                println!("could not reach {}: {}", host, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Err(e) = run(opt.command) {
+        output::error(&e.to_string(), e.exit_code());
+        process::exit(e.exit_code());
     }
 }
@@ -0,0 +1,195 @@
+//! Prometheus exposition of a machine's status over plain HTTP.
+
+use crate::http;
+use crate::machine::Machine;
+use crate::output;
+use crate::procinfo;
+use crate::relay::{self, ForwardState, Registration};
+use crate::status::SharedStatus;
+use std::fmt::Write as _;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+const STATES: &[&str] = &[
+    "stopped", "starting", "running", "pausing", "paused", "resuming", "error",
+];
+
+fn gauge(out: &mut String, name: &str, help: &str, machine: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{}{{machine=\"{}\"}} {}", name, machine, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, machine: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{}{{machine=\"{}\"}} {}", name, machine, value);
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// One series per forwarded port, from every process relaying them.
+fn forwards(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    machine: &str,
+    registrations: &[Registration],
+    value: impl Fn(&ForwardState) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for registration in registrations {
+        for forward in &registration.forwards {
+            let _ = writeln!(
+                out,
+                "{}{{machine=\"{}\",source=\"{}\",address=\"{}\",guest_port=\"{}\"}} {}",
+                name,
+                machine,
+                escape(&registration.source),
+                escape(&forward.address),
+                forward.guest_port,
+                value(forward)
+            );
+        }
+    }
+}
+
+pub fn render(status: &SharedStatus, machine: &Machine) -> String {
+    let usage = procinfo::vm_usage();
+    let registrations = relay::registrations(machine).unwrap_or_default();
+    let status = status.lock().unwrap();
+    let machine = escape(&status.name);
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP vagrantx_machine_state Current state of the machine."
+    );
+    let _ = writeln!(out, "# TYPE vagrantx_machine_state gauge");
+    for state in STATES {
+        let _ = writeln!(
+            out,
+            "vagrantx_machine_state{{machine=\"{}\",state=\"{}\"}} {}",
+            machine,
+            state,
+            (status.state == *state) as u8
+        );
+    }
+
+    gauge(
+        &mut out,
+        "vagrantx_machine_uptime_seconds",
+        "Seconds since the machine last booted.",
+        &machine,
+        status.uptime_seconds(),
+    );
+    gauge(
+        &mut out,
+        "vagrantx_machine_cpus",
+        "Configured virtual CPUs.",
+        &machine,
+        status.cpu_count as f64,
+    );
+    gauge(
+        &mut out,
+        "vagrantx_machine_memory_bytes",
+        "Configured guest memory.",
+        &machine,
+        status.memory_size as f64,
+    );
+    if let Some(target) = status.balloon_target {
+        gauge(
+            &mut out,
+            "vagrantx_machine_balloon_target_bytes",
+            "Memory the balloon device currently lets the guest use.",
+            &machine,
+            target as f64,
+        );
+    }
+    counter(
+        &mut out,
+        "vagrantx_machine_boots_total",
+        "Times the machine has been started, including restarts.",
+        &machine,
+        status.boots as f64,
+    );
+    counter(
+        &mut out,
+        "vagrantx_machine_host_cpu_seconds_total",
+        "Host CPU time used by the processes running the guest.",
+        &machine,
+        usage.cpu_seconds,
+    );
+    gauge(
+        &mut out,
+        "vagrantx_machine_host_resident_bytes",
+        "Host memory resident in the processes running the guest.",
+        &machine,
+        usage.resident_bytes as f64,
+    );
+    if !registrations.is_empty() {
+        forwards(
+            &mut out,
+            "vagrantx_forward_connections_active",
+            "Connections open through a forwarded port.",
+            "gauge",
+            &machine,
+            &registrations,
+            |f| f.active,
+        );
+        forwards(
+            &mut out,
+            "vagrantx_forward_connections_total",
+            "Connections ever accepted on a forwarded port.",
+            "counter",
+            &machine,
+            &registrations,
+            |f| f.total,
+        );
+        forwards(
+            &mut out,
+            "vagrantx_forward_bytes_total",
+            "Bytes relayed either way through a forwarded port.",
+            "counter",
+            &machine,
+            &registrations,
+            |f| f.bytes,
+        );
+    }
+
+    out
+}
+
+fn handle(mut stream: TcpStream, status: &SharedStatus, machine: &Machine) -> io::Result<()> {
+    let request = http::read_request(&stream)?;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => http::respond(
+            &mut stream,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            &render(status, machine),
+        ),
+        _ => http::respond(&mut stream, "404 Not Found", "text/plain", "not found\n"),
+    }
+}
+
+/// Serves `/metrics` on `address` from a background thread.
+pub fn serve(address: &str, status: SharedStatus, machine: &Machine) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    output::message(&format!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    ));
+    let machine = machine.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = handle(stream, &status, &machine);
+        }
+    });
+    Ok(())
+}
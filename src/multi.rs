@@ -0,0 +1,277 @@
+//! Bringing up several machines at once, each in its own `vagrantx up`.
+//!
+//! Children run with `--machine-readable`, so their output can be told
+//! apart and prefixed with the machine's name, and so it's known when each
+//! is ready. At most `parallel` are starting at a time; the rest wait for
+//! one to become ready or fail. A machine that fails doesn't stop the
+//! others, except those that list it in `depends_on`, which don't start
+//! until everything they depend on is ready.
+
+use crate::config::{self, BootFrom};
+use crate::error::Error;
+use crate::machine::Machine;
+use crate::output;
+use serde_json::Value;
+use std::env;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+pub struct Options<'a> {
+    pub parallel: usize,
+    pub force_unlock: bool,
+    pub profile: Option<&'a str>,
+    pub boot_from: Option<BootFrom>,
+}
+
+enum Message {
+    Record(usize, Value),
+    /// A line that wasn't a record, e.g. from stderr or a panic.
+    Line(usize, String),
+    Closed(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Waiting,
+    Starting,
+    Ready,
+    Exited,
+}
+
+struct Entry {
+    config: PathBuf,
+    name: String,
+    /// Indexes of the entries this one depends on.
+    depends_on: Vec<usize>,
+    state: State,
+    child: Option<Child>,
+    /// What the child has printed to the console since its last newline.
+    console: String,
+    /// Its stdout and stderr, until both are closed.
+    open_streams: usize,
+}
+
+fn spawn_reader(
+    index: usize,
+    stream: impl Read + Send + 'static,
+    sender: Sender<Message>,
+    records: bool,
+) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            let message = match serde_json::from_str::<Value>(&line) {
+                Ok(record) if records && record.is_object() => Message::Record(index, record),
+                _ => Message::Line(index, line),
+            };
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+        let _ = sender.send(Message::Closed(index));
+    });
+}
+
+impl Entry {
+    fn start(&mut self, index: usize, options: &Options, sender: &Sender<Message>) {
+        let mut command = Command::new(env::current_exe().unwrap_or_else(|_| "vagrantx".into()));
+        command.args(["--machine-readable", "up"]).arg(&self.config);
+        if options.force_unlock {
+            command.arg("--force-unlock");
+        }
+        if let Some(profile) = options.profile {
+            command.args(["--profile", profile]);
+        }
+        if let Some(from) = options.boot_from {
+            command.arg("--boot-from").arg(from.to_string());
+        }
+        let spawned = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        match spawned {
+            Ok(mut child) => {
+                spawn_reader(index, child.stdout.take().unwrap(), sender.clone(), true);
+                spawn_reader(index, child.stderr.take().unwrap(), sender.clone(), false);
+                self.open_streams = 2;
+                self.child = Some(child);
+                self.state = State::Starting;
+            }
+            Err(e) => {
+                self.say(&format!("error: could not run vagrantx: {}", e));
+                self.state = State::Exited;
+            }
+        }
+    }
+
+    fn say(&self, text: &str) {
+        if output::machine_readable() {
+            output::relay(
+                &self.name,
+                serde_json::json!({ "type": "message", "data": text }),
+            );
+        } else {
+            println!("{} | {}", self.name, text);
+        }
+    }
+
+    /// Shows a record from the child, and notes whether it's now ready.
+    fn show(&mut self, record: Value) {
+        let kind = record["type"].as_str().unwrap_or_default().to_string();
+        if kind == "event" && record["data"]["event"] == "ready" {
+            self.state = State::Ready;
+        }
+        if output::machine_readable() {
+            output::relay(&self.name, record);
+            return;
+        }
+        let data = &record["data"];
+        match kind.as_str() {
+            "message" => self.say(data.as_str().unwrap_or_default()),
+            "warning" => self.say(&format!("warning: {}", data.as_str().unwrap_or_default())),
+            "error" => self.say(&format!(
+                "error: {}",
+                data["message"].as_str().unwrap_or_default()
+            )),
+            "console" => {
+                self.console.push_str(data.as_str().unwrap_or_default());
+                while let Some(end) = self.console.find('\n') {
+                    let line: String = self.console.drain(..=end).collect();
+                    self.say(line.trim_end_matches(['\r', '\n']));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Brings up every machine in `configs`, returning once they've all
+/// stopped.
+pub fn up_all(configs: &[PathBuf], options: &Options) -> Result<(), Error> {
+    let mut entries = Vec::new();
+    let mut dependencies = Vec::new();
+    for config_file in configs {
+        let config = config::load_config(config_file)?;
+        let machine = Machine::new(config_file, config.name.as_deref());
+        dependencies.push(config.depends_on);
+        entries.push(Entry {
+            config: config_file.clone(),
+            name: machine.name,
+            depends_on: Vec::new(),
+            state: State::Waiting,
+            child: None,
+            console: String::new(),
+            open_streams: 0,
+        });
+    }
+
+    for (index, names) in dependencies.iter().enumerate() {
+        for name in names {
+            let dependency = entries
+                .iter()
+                .position(|e| &e.name == name)
+                .ok_or_else(|| {
+                    Error::Config(format!(
+                        "{} depends on {}, which isn't one of the machines being brought up",
+                        entries[index].name, name
+                    ))
+                })?;
+            entries[index].depends_on.push(dependency);
+        }
+    }
+    check_cycles(&entries)?;
+
+    let parallel = options.parallel.max(1);
+    let (sender, messages) = mpsc::channel();
+    let mut failed = Vec::new();
+    loop {
+        let starting = entries
+            .iter()
+            .filter(|e| e.state == State::Starting)
+            .count();
+        // Whatever depends on a machine that stopped without becoming ready
+        // never will start.
+        while let Some(index) = entries.iter().position(|e| {
+            e.state == State::Waiting
+                && e.depends_on
+                    .iter()
+                    .any(|&d| entries[d].state == State::Exited)
+        }) {
+            let entry = &mut entries[index];
+            entry.say("skipped, as a machine it depends on failed");
+            failed.push(entry.name.clone());
+            entry.state = State::Exited;
+        }
+        for _ in starting..parallel {
+            let next = entries.iter().position(|e| {
+                e.state == State::Waiting
+                    && e.depends_on
+                        .iter()
+                        .all(|&d| entries[d].state == State::Ready)
+            });
+            match next {
+                Some(index) => entries[index].start(index, options, &sender),
+                None => break,
+            }
+        }
+        if entries.iter().all(|e| e.state == State::Exited) {
+            break;
+        }
+
+        match messages.recv() {
+            Ok(Message::Record(index, record)) => entries[index].show(record),
+            Ok(Message::Line(index, line)) => entries[index].say(&line),
+            Ok(Message::Closed(index)) => {
+                let entry = &mut entries[index];
+                entry.open_streams -= 1;
+                if entry.open_streams > 0 {
+                    continue;
+                }
+                let status = entry.child.take().and_then(|mut child| child.wait().ok());
+                if !status.is_some_and(|s| s.success()) {
+                    failed.push(entry.name.clone());
+                }
+                entry.state = State::Exited;
+            }
+            Err(_) => break,
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} failed", failed.join(", ")).into())
+    }
+}
+
+/// Fails if some machine ends up depending on itself.
+fn check_cycles(entries: &[Entry]) -> Result<(), Error> {
+    // Repeatedly set aside machines whose dependencies are all set aside;
+    // anything left over is in a cycle.
+    let mut ordered = vec![false; entries.len()];
+    loop {
+        let next = (0..entries.len())
+            .find(|&i| !ordered[i] && entries[i].depends_on.iter().all(|&d| ordered[d]));
+        match next {
+            Some(index) => ordered[index] = true,
+            None => break,
+        }
+    }
+    let cycle: Vec<&str> = entries
+        .iter()
+        .zip(&ordered)
+        .filter(|(_, &ordered)| !ordered)
+        .map(|(e, _)| e.name.as_str())
+        .collect();
+    if cycle.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "depends_on forms a cycle between {}",
+            cycle.join(", ")
+        )))
+    }
+}
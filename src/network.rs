@@ -0,0 +1,264 @@
+use crate::config::Nic;
+use crate::machine::Machine;
+use crate::output;
+use crate::paths;
+use objc::rc::StrongPtr;
+use objc::{class, msg_send, sel, sel_impl};
+use std::error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process::Command;
+use virtualization_rs::base::{Id, NSString, NIL};
+use virtualization_rs::virtualization::network_device::VZMACAddress;
+
+/// Where macOS's bootpd records the leases it hands NAT guests.
+const DHCPD_LEASES: &str = "/var/db/dhcpd_leases";
+
+/// Where the shared NAT network can be moved from its default.
+const VMNET_PREFERENCES: &str = "/Library/Preferences/SystemConfiguration/com.apple.vmnet";
+
+fn parse_mac_address(s: &str) -> Option<VZMACAddress> {
+    let string = NSString::new(s);
+    unsafe {
+        let alloc: Id = msg_send![class!(VZMACAddress), alloc];
+        let p: Id = msg_send![alloc, initWithString: *string.0];
+        if p == NIL {
+            None
+        } else {
+            Some(VZMACAddress(StrongPtr::new(p)))
+        }
+    }
+}
+
+/// The framework's form of a MAC address from `mac_address`.
+pub fn vz_mac_address(mac: &str) -> VZMACAddress {
+    parse_mac_address(mac).expect("invalid MAC address")
+}
+
+fn is_mac_address(s: &str) -> bool {
+    let octets: Vec<&str> = s.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// A random unicast, locally administered address.
+fn random_mac_address() -> String {
+    let mut bytes = [0u8; 6];
+    let _ = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    bytes[0] = (bytes[0] & 0xfe) | 0x02;
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The MAC address the machine was given, without generating one for a
+/// machine that's never been up.
+pub fn saved_mac_address(machine: &Machine) -> Option<String> {
+    let saved = fs::read_to_string(machine.dir.join("mac-address")).ok()?;
+    is_mac_address(saved.trim()).then(|| saved.trim().to_lowercase())
+}
+
+/// Returns the machine's MAC address, generating one on first boot. Keeping
+/// it stable means the guest keeps getting the same lease, and so the same
+/// IP, across restarts.
+pub fn mac_address(machine: &Machine) -> String {
+    if let Some(mac) = saved_mac_address(machine) {
+        return mac;
+    }
+
+    let path = machine.dir.join("mac-address");
+    let mac = random_mac_address();
+    if let Err(e) = fs::create_dir_all(&machine.dir).and_then(|_| fs::write(&path, &mac)) {
+        output::warning(&format!("could not save {}: {}", path.display(), e));
+    }
+    mac
+}
+
+/// bootpd writes MAC addresses without leading zeros in each octet.
+fn normalize_mac(mac: &str) -> String {
+    mac.split(':')
+        .map(|octet| {
+            let trimmed = octet.trim_start_matches('0');
+            if trimmed.is_empty() {
+                "0".to_string()
+            } else {
+                trimmed.to_lowercase()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The addresses the host's DHCP server has leased, newest first, with
+/// the normalized MAC address each went to.
+fn leases() -> Vec<(Ipv4Addr, String)> {
+    let leases = fs::read_to_string(DHCPD_LEASES).unwrap_or_default();
+
+    // Leases are `{ key=value ... }` blocks, newest first.
+    let mut found = Vec::new();
+    let mut ip = None;
+    let mut hw = None;
+    for line in leases.lines() {
+        let line = line.trim();
+        if line == "{" {
+            ip = None;
+            hw = None;
+        } else if line == "}" {
+            if let (Some(ip), Some(hw)) = (ip.take(), hw.take()) {
+                found.push((ip, hw));
+            }
+        } else if let Some(addr) = line.strip_prefix("ip_address=") {
+            ip = addr.parse().ok();
+        } else if let Some(addr) = line.strip_prefix("hw_address=") {
+            // The address is prefixed with its hardware type, e.g. `1,`.
+            let addr = addr.split_once(',').map_or(addr, |(_, addr)| addr);
+            hw = Some(normalize_mac(addr));
+        }
+    }
+    found
+}
+
+/// Looks up the guest's address: its `static_ip`, or else the one the
+/// host's DHCP server most recently leased to `mac`.
+pub fn guest_ip(mac: &str) -> Option<Ipv4Addr> {
+    let mac = normalize_mac(mac);
+    if let Some((_, ip, _)) = static_ips().into_iter().find(|(m, _, _)| *m == mac) {
+        return Some(ip);
+    }
+    leases()
+        .into_iter()
+        .find(|(_, hw)| *hw == mac)
+        .map(|(ip, _)| ip)
+}
+
+/// Machines with a `static_ip` never take a lease, so `up` records their
+/// addresses here instead, one `<mac> <ip> <machine dir>` per line.
+fn static_ips_path() -> PathBuf {
+    paths::vagrantx_home().join("static-ips")
+}
+
+/// The recorded static addresses, leaving out machines that are gone.
+fn static_ips() -> Vec<(String, Ipv4Addr, PathBuf)> {
+    fs::read_to_string(static_ips_path())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let mac = fields.next()?.to_string();
+            let ip = fields.next()?.parse().ok()?;
+            let dir = PathBuf::from(fields.next()?);
+            Some((mac, ip, dir))
+        })
+        .filter(|(_, _, dir)| dir.is_dir())
+        .collect()
+}
+
+fn vmnet_preference(key: &str) -> Option<Ipv4Addr> {
+    let output = Command::new("defaults")
+        .args(["read", VMNET_PREFERENCES, key])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// The host's address on the shared NAT network, which is the guests'
+/// gateway and DNS server, and the network's prefix length.
+pub fn nat_network() -> (Ipv4Addr, u32) {
+    let gateway = vmnet_preference("Shared_Net_Address").unwrap_or(Ipv4Addr::new(192, 168, 64, 1));
+    let mask = vmnet_preference("Shared_Net_Mask").unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
+    (gateway, u32::from(mask).count_ones())
+}
+
+/// Whether `ip` can be a guest's: an address on the NAT network that isn't
+/// its gateway.
+pub fn check_static_ip(ip: Ipv4Addr) -> Result<(), String> {
+    let (gateway, prefix) = nat_network();
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    let network = u32::from(gateway) & mask;
+    let host = u32::from(ip);
+    if host & mask != network {
+        return Err(format!(
+            "static_ip {} is not on the NAT network {}/{}",
+            ip,
+            Ipv4Addr::from(network),
+            prefix
+        ));
+    }
+    if ip == gateway || host == network || host == network | !mask {
+        return Err(format!("static_ip {} is reserved on the NAT network", ip));
+    }
+    Ok(())
+}
+
+/// The largest frame vmnet's shared NAT network carries.
+const NAT_MTU: u32 = 1500;
+
+/// Whether `nic` makes sense for a machine run by QEMU, or unless `qemu`,
+/// by Virtualization.framework.
+pub fn check_nic(nic: &Nic, qemu: bool) -> Result<(), String> {
+    match nic.mtu {
+        Some(mtu) if !(68..=65535).contains(&mtu) => {
+            Err(format!("nic.mtu {} is not between 68 and 65535", mtu))
+        }
+        Some(mtu) if !qemu && mtu > NAT_MTU => Err(format!(
+            "nic.mtu {} is more than Virtualization.framework's NAT network carries ({})",
+            mtu, NAT_MTU
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Records `machine`'s static address, or with `None`, that it has none.
+/// Fails if another machine has the address.
+pub fn record_static_ip(
+    machine: &Machine,
+    ip: Option<Ipv4Addr>,
+) -> Result<(), Box<dyn error::Error>> {
+    let mac = normalize_mac(&mac_address(machine));
+    let dir = fs::canonicalize(&machine.dir).unwrap_or_else(|_| machine.dir.clone());
+    let mut entries = static_ips();
+    if let Some(ip) = ip {
+        if let Some((_, _, other)) = entries
+            .iter()
+            .find(|(m, addr, other)| *addr == ip && *m != mac && *other != dir)
+        {
+            return Err(format!(
+                "static_ip {} already belongs to the machine in {}",
+                ip,
+                other.display()
+            )
+            .into());
+        }
+        if leases().iter().any(|(addr, hw)| *addr == ip && *hw != mac) {
+            output::warning(&format!(
+                "{} was leased to another guest, which may still be using it",
+                ip
+            ));
+        }
+    }
+
+    let before = entries.len();
+    entries.retain(|(m, _, _)| *m != mac);
+    if ip.is_none() && entries.len() == before {
+        return Ok(());
+    }
+    let mut lines: Vec<String> = entries
+        .iter()
+        .map(|(mac, ip, dir)| format!("{} {} {}", mac, ip, dir.display()))
+        .collect();
+    if let Some(ip) = ip {
+        lines.push(format!("{} {} {}", mac, ip, dir.display()));
+    }
+    let path = static_ips_path();
+    fs::create_dir_all(paths::vagrantx_home())?;
+    let partial = path.with_extension("partial");
+    fs::write(&partial, lines.join("\n") + "\n")?;
+    fs::rename(&partial, &path)?;
+    Ok(())
+}
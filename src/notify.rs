@@ -0,0 +1,76 @@
+//! Notification Center alerts for the events a config's `notify` asks for,
+//! so a long boot or provisioning run can be left in the background.
+
+use crate::events::Event;
+use std::process::Command;
+use std::thread;
+
+/// Every event a machine records, which are all `notify` can name.
+pub const EVENTS: &[&str] = &[
+    "created",
+    "started",
+    "ready",
+    "unready",
+    "provisioning",
+    "provisioned",
+    "unprovisioned",
+    "stopping",
+    "stopped",
+    "crashed",
+    "rebooted",
+    "restarting",
+    "reloading",
+    "errored",
+    "snapshotted",
+    "restored",
+    "renamed",
+    "timesynced",
+    "healthy",
+    "unhealthy",
+];
+
+/// What happened to the machine, as the end of a sentence naming it.
+fn describe(event: &str) -> String {
+    match event {
+        "ready" => "is ready".to_string(),
+        "unready" => "did not become ready".to_string(),
+        "provisioning" => "is being provisioned".to_string(),
+        "unprovisioned" => "could not be provisioned".to_string(),
+        "stopping" => "is shutting down".to_string(),
+        "errored" => "could not be started".to_string(),
+        "restarting" => "is restarting".to_string(),
+        "timesynced" => "had its clock synced".to_string(),
+        "healthy" => "is healthy again".to_string(),
+        "unhealthy" => "is unhealthy".to_string(),
+        event => event.to_string(),
+    }
+}
+
+/// Quotes `s` as an AppleScript string.
+fn applescript_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Posts an alert about `event`. It's shown in the background, and failing
+/// to show it isn't worth bothering anyone about.
+pub fn post(machine: &str, event: &Event) {
+    let mut text = format!("{} {}", machine, describe(&event.event));
+    let detail = match (&event.detail, &event.error) {
+        (_, Some(error)) => Some(error.to_string()),
+        (detail, None) => detail.clone(),
+    };
+    if let Some(detail) = detail {
+        text.push_str(&format!(": {}", detail));
+    }
+    let script = format!(
+        "display notification {} with title \"vagrantx\"",
+        applescript_quote(&text)
+    );
+    // Started here rather than on the thread, so the alert still goes out
+    // if we're about to exit.
+    if let Ok(mut child) = Command::new("osascript").args(["-e", &script]).spawn() {
+        thread::spawn(move || {
+            let _ = child.wait();
+        });
+    }
+}
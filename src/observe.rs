@@ -0,0 +1,180 @@
+//! `vagrantx status`: what each machine is doing, and with `--watch`, each
+//! change as it happens.
+//!
+//! There's no daemon to subscribe to, so changes are found by polling what
+//! every `up` publishes, the DHCP leases and the event logs. With `--json`,
+//! each change is a line like
+//!
+//! ```text
+//! {"time":"2022-06-01T12:00:01Z","machine":"web","type":"state","data":"running"}
+//! {"time":"2022-06-01T12:00:04Z","machine":"web","type":"ip","data":"192.168.64.5"}
+//! {"time":"2022-06-01T12:00:09Z","machine":"web","type":"event","data":{"event":"provisioning",...}}
+//! ```
+//!
+//! Health check results come from what `health` publishes, and their
+//! changes show up among the events.
+
+use crate::config;
+use crate::events::{self, Event, EventLog};
+use crate::health::{self, Health};
+use crate::machine::Machine;
+use crate::network;
+use crate::status;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Observed {
+    machine: Machine,
+    events: EventLog,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    machine: String,
+    state: String,
+    ip: Option<Ipv4Addr>,
+    /// Only for a running machine with health checks.
+    health: Option<Health>,
+    last_event: Option<String>,
+}
+
+impl Observed {
+    fn state(&self) -> String {
+        status::published(&self.machine).map_or_else(|| "stopped".to_string(), |p| p.state)
+    }
+
+    fn ip(&self, state: &str) -> Option<Ipv4Addr> {
+        // An old lease outlives the machine.
+        (state != "stopped")
+            // Looked up each time, since a machine that's never been up
+            // has no MAC address until it is.
+            .then(|| network::guest_ip(&network::saved_mac_address(&self.machine)?))
+            .flatten()
+    }
+
+    fn summary(&self) -> Summary {
+        let state = self.state();
+        Summary {
+            machine: self.machine.name.clone(),
+            ip: self.ip(&state),
+            health: (state != "stopped")
+                .then(|| health::read(&self.machine))
+                .flatten(),
+            last_event: self
+                .events
+                .read()
+                .ok()
+                .and_then(|events| events.last().map(|e| e.event.clone())),
+            state,
+        }
+    }
+}
+
+fn report(json: bool, machine: &str, kind: &str, data: Value) {
+    let time = events::timestamp(SystemTime::now());
+    if json {
+        let record = json!({ "time": time, "machine": machine, "type": kind, "data": data });
+        println!("{}", record);
+        return;
+    }
+    let text = match (kind, &data) {
+        ("event", data) => {
+            let event: Option<Event> = serde_json::from_value(data.clone()).ok();
+            match event {
+                Some(Event {
+                    detail: Some(detail),
+                    event,
+                    ..
+                }) => format!("{} ({})", event, detail),
+                Some(event) => event.event,
+                None => data.to_string(),
+            }
+        }
+        (_, Value::String(s)) => s.clone(),
+        (_, Value::Null) => "none".to_string(),
+        (_, data) => data.to_string(),
+    };
+    println!("{}  {:<16} {:<6} {}", time, machine, kind, text);
+}
+
+/// Prints what each machine in `configs` is doing, and with `watch`, keeps
+/// printing state and IP changes and new events until interrupted.
+pub fn status(configs: &[PathBuf], watch: bool, json: bool) -> Result<(), Box<dyn error::Error>> {
+    let mut observed = Vec::new();
+    for config_file in configs {
+        let config = config::load_config(config_file)?;
+        let machine = Machine::new(config_file, config.name.as_deref());
+        observed.push(Observed {
+            events: EventLog::new(&machine),
+            machine,
+        });
+    }
+
+    if !watch {
+        let summaries: Vec<Summary> = observed.iter().map(Observed::summary).collect();
+        if json {
+            println!("{}", serde_json::to_string(&summaries)?);
+            return Ok(());
+        }
+        println!(
+            "{:<16} {:<10} {:<16} {:<10} LAST EVENT",
+            "MACHINE", "STATE", "IP", "HEALTH"
+        );
+        for s in &summaries {
+            println!(
+                "{:<16} {:<10} {:<16} {:<10} {}",
+                s.machine,
+                s.state,
+                s.ip.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+                s.health.as_ref().map_or("-", |h| h.status.as_str()),
+                s.last_event.as_deref().unwrap_or("-")
+            );
+        }
+        for s in &summaries {
+            if let Some(reason) = s.health.as_ref().and_then(Health::reason) {
+                println!("{} is unhealthy: {}", s.machine, reason);
+            }
+        }
+        return Ok(());
+    }
+
+    // Where each machine was last seen: state, address and how many events
+    // have been reported. Old events aren't replayed.
+    let mut seen: Vec<(Option<String>, Option<Ipv4Addr>, usize)> = observed
+        .iter()
+        .map(|o| (None, None, o.events.read().map_or(0, |e| e.len())))
+        .collect();
+    loop {
+        for (o, (state, ip, reported)) in observed.iter().zip(seen.iter_mut()) {
+            let name = &o.machine.name;
+            let now = o.state();
+            if state.as_deref() != Some(&now) {
+                report(json, name, "state", json!(now));
+            }
+            let address = o.ip(&now);
+            if address != *ip && (address.is_some() || state.is_some()) {
+                report(json, name, "ip", json!(address.map(|ip| ip.to_string())));
+            }
+            *state = Some(now);
+            *ip = address;
+
+            let events = o.events.read().unwrap_or_default();
+            // A log shorter than before was started over.
+            if events.len() < *reported {
+                *reported = 0;
+            }
+            for event in &events[*reported..] {
+                report(json, name, "event", json!(event));
+            }
+            *reported = events.len();
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
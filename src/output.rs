@@ -0,0 +1,301 @@
+//! What `up` and friends tell the user. With `--machine-readable`, every
+//! line on stdout is instead a JSON record for wrappers and CI to parse:
+//!
+//! ```text
+//! {"time":"2022-06-01T12:00:00Z","type":"message","data":"starting web"}
+//! {"time":"2022-06-01T12:00:01Z","type":"event","machine":"web","data":{"event":"started",...}}
+//! {"time":"2022-06-01T12:00:01Z","type":"console","data":"[    0.000000] Linux version ..."}
+//! {"time":"2022-06-01T12:00:05Z","type":"error","data":{"message":"...","exit_code":7}}
+//! ```
+//!
+//! `type` is one of `message`, `warning`, `event`, `console` or `error`.
+//!
+//! On a terminal, waits show a spinner or progress bar on the last line,
+//! drawn over by the next thing printed, and warnings and errors are in
+//! color unless `--no-color` or `NO_COLOR` says otherwise. Anywhere else
+//! the same things are printed one line after another.
+
+use crate::cast;
+use crate::events::{self, Event};
+use crate::stats::format_bytes;
+use libc::isatty;
+use serde_json::{json, Value};
+use std::env;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+static MACHINE_READABLE: AtomicBool = AtomicBool::new(false);
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const TICK: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 24;
+
+/// What's being waited for, shown on the terminal's last line.
+struct Status {
+    label: String,
+    started: Instant,
+    /// Bytes done and, if known, the total.
+    progress: Option<(u64, Option<u64>)>,
+}
+
+struct Screen {
+    status: Option<Status>,
+    /// Whether the status line is on the screen.
+    drawn: bool,
+    /// Whether the cursor is at the start of a line, where the status line
+    /// can go without breaking into something else's.
+    at_line_start: bool,
+    frame: usize,
+}
+
+static SCREEN: Mutex<Screen> = Mutex::new(Screen {
+    status: None,
+    drawn: false,
+    at_line_start: true,
+    frame: 0,
+});
+static TICKER: Once = Once::new();
+
+pub fn set_machine_readable() {
+    MACHINE_READABLE.store(true, Ordering::Relaxed);
+}
+
+pub fn machine_readable() -> bool {
+    MACHINE_READABLE.load(Ordering::Relaxed)
+}
+
+pub fn set_no_color() {
+    NO_COLOR.store(true, Ordering::Relaxed);
+}
+
+/// Whether stdout is a terminal to draw on, rather than a file, a pipe or
+/// a wrapper reading records.
+pub fn interactive() -> bool {
+    !machine_readable()
+        && unsafe { isatty(1) } == 1
+        && env::var("TERM").map_or(true, |term| term != "dumb")
+}
+
+fn color() -> bool {
+    interactive()
+        && !NO_COLOR.load(Ordering::Relaxed)
+        && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
+/// `text` in the color with ANSI code `code`, if there's color.
+fn paint(code: &str, text: &str) -> String {
+    if color() {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+impl Screen {
+    fn line(&self) -> String {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return String::new(),
+        };
+        let elapsed = status.started.elapsed().as_secs();
+        let line = match status.progress {
+            Some((done, Some(total))) if total > 0 => {
+                let filled = (done.min(total) as f64 / total as f64 * BAR_WIDTH as f64) as usize;
+                format!(
+                    "{} [{}{}] {} of {}",
+                    status.label,
+                    "#".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled),
+                    format_bytes(done as f64),
+                    format_bytes(total as f64)
+                )
+            }
+            Some((done, _)) => format!(
+                "{} {} {}",
+                SPINNER[self.frame % SPINNER.len()],
+                status.label,
+                format_bytes(done as f64)
+            ),
+            None => format!(
+                "{} {} ({}s)",
+                SPINNER[self.frame % SPINNER.len()],
+                status.label,
+                elapsed
+            ),
+        };
+        // One that wrapped couldn't be drawn over.
+        let width = cast::terminal_size().0 as usize;
+        line.chars().take(width.saturating_sub(1)).collect()
+    }
+
+    fn clear(&mut self, out: &mut impl Write) {
+        if self.drawn {
+            let _ = out.write_all(b"\r\x1b[K");
+            self.drawn = false;
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write) {
+        self.clear(out);
+        if self.status.is_some() && self.at_line_start {
+            let _ = out.write_all(self.line().as_bytes());
+            self.drawn = true;
+        }
+        let _ = out.flush();
+    }
+}
+
+/// Writes `data` to a terminal, keeping the status line below it.
+fn write(data: &[u8]) {
+    let mut screen = SCREEN.lock().unwrap();
+    let mut out = io::stdout().lock();
+    screen.clear(&mut out);
+    let _ = out.write_all(data);
+    if let Some(&last) = data.last() {
+        screen.at_line_start = last == b'\n';
+    }
+    screen.draw(&mut out);
+}
+
+fn print_line(text: &str) {
+    if interactive() {
+        write(format!("{}\n", text).as_bytes());
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// A wait shown on the terminal's last line until it's finished or
+/// dropped. Elsewhere, its label is printed when it starts.
+pub struct Progress {
+    shown: bool,
+}
+
+/// Starts showing `label` while something is waited for.
+pub fn progress(label: &str) -> Progress {
+    if !interactive() {
+        message(label);
+        return Progress { shown: false };
+    }
+    TICKER.call_once(|| {
+        thread::spawn(|| loop {
+            thread::sleep(TICK);
+            let mut screen = SCREEN.lock().unwrap();
+            if screen.status.is_some() {
+                screen.frame += 1;
+                screen.draw(&mut io::stdout().lock());
+            }
+        });
+    });
+    let mut screen = SCREEN.lock().unwrap();
+    screen.status = Some(Status {
+        label: label.to_string(),
+        started: Instant::now(),
+        progress: None,
+    });
+    screen.draw(&mut io::stdout().lock());
+    Progress { shown: true }
+}
+
+impl Progress {
+    /// Shows that `done` bytes of `total`, if it's known, are done.
+    pub fn set(&self, done: u64, total: Option<u64>) {
+        if !self.shown {
+            return;
+        }
+        if let Some(status) = SCREEN.lock().unwrap().status.as_mut() {
+            status.progress = Some((done, total));
+        }
+    }
+
+    /// Stops showing the wait, printing `text` in its place.
+    pub fn finish(self, text: &str) {
+        drop(self);
+        message(text);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.shown {
+            let mut screen = SCREEN.lock().unwrap();
+            screen.status = None;
+            screen.draw(&mut io::stdout().lock());
+        }
+    }
+}
+
+fn emit(kind: &str, machine: Option<&str>, data: Value) {
+    let mut record = json!({
+        "time": events::timestamp(SystemTime::now()),
+        "type": kind,
+        "data": data,
+    });
+    if let Some(machine) = machine {
+        record["machine"] = json!(machine);
+    }
+    // One write per record keeps lines whole across threads.
+    let _ = io::stdout().write_all(format!("{}\n", record).as_bytes());
+}
+
+pub fn message(text: &str) {
+    if machine_readable() {
+        emit("message", None, json!(text));
+    } else {
+        print_line(text);
+    }
+}
+
+pub fn warning(text: &str) {
+    if machine_readable() {
+        emit("warning", None, json!(text));
+    } else {
+        print_line(&format!("{} {}", paint("33", "warning:"), text));
+    }
+}
+
+/// Reports an event `machine` just recorded. Only machine-readable output
+/// includes these; people can follow them with `vagrantx events`.
+pub fn event(machine: &str, event: &Event) {
+    if machine_readable() {
+        emit("event", Some(machine), json!(event));
+    }
+}
+
+/// Guest console output, which otherwise goes to stdout as is.
+pub fn console(data: &[u8]) {
+    if machine_readable() {
+        emit("console", None, json!(String::from_utf8_lossy(data)));
+    } else if interactive() {
+        write(data);
+    } else {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(data);
+        let _ = stdout.flush();
+    }
+}
+
+pub fn error(message: &str, exit_code: i32) {
+    if machine_readable() {
+        emit(
+            "error",
+            None,
+            json!({ "message": message, "exit_code": exit_code }),
+        );
+    } else {
+        print_line(&format!("{} {}", paint("31", "error:"), message));
+    }
+}
+
+/// Passes on a record from a child `vagrantx`, marked with the machine it's
+/// about.
+pub fn relay(machine: &str, mut record: Value) {
+    if record.get("machine").is_none() {
+        record["machine"] = json!(machine);
+    }
+    let _ = io::stdout().write_all(format!("{}\n", record).as_bytes());
+}
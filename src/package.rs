@@ -0,0 +1,128 @@
+//! Exports a machine as a box archive that `box add` can import elsewhere.
+
+use crate::arch;
+use crate::boxes::{self, BoxMetadata, Manifest, SshCredentials};
+use crate::cmdline;
+use crate::config;
+use crate::lock;
+use crate::machine::Machine;
+use std::error;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SSH_KEY_FILE: &str = "ssh_key";
+
+/// Writes the machine's root disk, kernel, initrd and boot settings to a
+/// gzipped tarball at `output`. Additional disks aren't included. The
+/// machine must be halted, which holding its lock guarantees.
+pub fn package(
+    config_file: &PathBuf,
+    output: &Path,
+    ssh_username: Option<String>,
+    ssh_private_key: Option<&Path>,
+) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(config_file)?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    if !machine.dir.exists() {
+        return Err(format!("{} has never been started", machine.name).into());
+    }
+    let _lock = lock::acquire(&machine, "package", false)?;
+
+    let boot = config.resolve_boot(&machine)?;
+    let root_disk = boot
+        .disks
+        .get(config.boot.root_disk)
+        .ok_or("boot.root_disk is out of range")?;
+    let (kernel, initrd) = match (&boot.kernel, &boot.initrd) {
+        (Some(kernel), Some(initrd)) => (kernel, initrd),
+        _ => return Err("only machines that boot their kernel can be packaged".into()),
+    };
+    let base = config.box_name.as_deref().map(boxes::load).transpose()?;
+
+    let staging = machine.dir.join("package.partial");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    println!("packaging {}", machine.name);
+    fs::copy(root_disk, staging.join("disk.img"))?;
+    fs::copy(kernel, staging.join("vmlinuz"))?;
+    fs::copy(initrd, staging.join("initrd"))?;
+
+    // Keep the command line as a template, so it still adapts to however
+    // many disks the next machine has.
+    let command_line = cmdline::merge(
+        base.as_ref()
+            .and_then(|b| b.command_line.as_deref())
+            // A package always has its root disk.
+            .unwrap_or_else(|| cmdline::default_command_line(1)),
+        config.boot.command_line.as_deref(),
+        &config.boot.command_line_append,
+    );
+
+    let ssh = match (ssh_username, base.as_ref().and_then(|b| b.ssh.clone())) {
+        (Some(username), _) => Some((username, ssh_private_key.map(Path::to_path_buf))),
+        (None, Some(ssh)) => Some((ssh.username, ssh.private_key)),
+        (None, None) => None,
+    };
+    let ssh = match ssh {
+        Some((username, Some(key))) => {
+            let dest = staging.join(SSH_KEY_FILE);
+            fs::copy(key, &dest)?;
+            fs::set_permissions(&dest, fs::Permissions::from_mode(0o600))?;
+            Some(SshCredentials {
+                username,
+                private_key: Some(PathBuf::from(SSH_KEY_FILE)),
+            })
+        }
+        Some((username, None)) => Some(SshCredentials {
+            username,
+            private_key: None,
+        }),
+        None => None,
+    };
+
+    boxes::write_metadata(
+        &staging,
+        &BoxMetadata {
+            kernel: PathBuf::from("vmlinuz"),
+            initrd: PathBuf::from("initrd"),
+            disk: PathBuf::from("disk.img"),
+            command_line: Some(command_line),
+            root_partition: config
+                .boot
+                .root_partition
+                .or_else(|| base.as_ref().and_then(|b| b.root_partition)),
+            ssh,
+            arch: Some(arch::guest(&config).to_string()),
+        },
+    )?;
+
+    let mut manifest = Manifest::default();
+    for entry in fs::read_dir(&staging)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let checksum = boxes::sha256(&staging.join(&name))?;
+        manifest.files.insert(name, checksum);
+    }
+    let file = fs::File::create(staging.join(boxes::MANIFEST_FILE))?;
+    serde_json::to_writer_pretty(file, &manifest)?;
+
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(output)
+        .arg("-C")
+        .arg(&staging)
+        .arg(boxes::MANIFEST_FILE)
+        .args(manifest.files.keys())
+        .status()?;
+    fs::remove_dir_all(&staging)?;
+    if !status.success() {
+        return Err(format!("could not write {}", output.display()).into());
+    }
+
+    println!("wrote {}", output.display());
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use std::env;
+use std::path::PathBuf;
+
+/// FNV-1a, for labels and IDs that have to stay the same across vagrantx
+/// builds, which std's hasher doesn't promise.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Root of vagrantx's per-user state, `~/.vagrantx` unless overridden by
+/// `VAGRANTX_HOME`.
+pub fn vagrantx_home() -> PathBuf {
+    if let Some(home) = env::var_os("VAGRANTX_HOME") {
+        return PathBuf::from(home);
+    }
+
+    let home = env::var_os("HOME").expect("HOME is not set");
+    PathBuf::from(home).join(".vagrantx")
+}
+
+pub fn boxes_dir() -> PathBuf {
+    vagrantx_home().join("boxes")
+}
+
+pub fn cache_dir() -> PathBuf {
+    vagrantx_home().join("cache")
+}
+
+/// Where machines made with `up --name`, which belong to no project, live.
+pub fn machines_dir() -> PathBuf {
+    vagrantx_home().join("machines")
+}
+
+pub fn plugins_dir() -> PathBuf {
+    vagrantx_home().join("plugins")
+}
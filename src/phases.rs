@@ -0,0 +1,86 @@
+//! Following a boot's progress through the markers Linux and systemd print
+//! on the console, so a boot that hangs can say where.
+
+use crate::console::ConsoleOutput;
+use crate::output;
+use crate::status::SharedStatus;
+use regex::Regex;
+use std::thread;
+use std::time::Duration;
+
+/// Boot phases in the order they happen, with what the console prints on
+/// reaching each. A guest without systemd skips the middle ones.
+const PHASES: &[(&str, &str)] = &[
+    ("kernel started", r"Linux version \d"),
+    (
+        "kernel booted",
+        r"Freeing unused kernel|Run /\S*init as init process",
+    ),
+    ("init started", r"systemd\[1\]: |Welcome to |INIT: version"),
+    ("reached network target", r"Reached target .*Network"),
+    ("login prompt", r"login: *$"),
+];
+
+/// The phase that comes after `phase`, if there is one.
+pub fn next(phase: Option<&str>) -> Option<&'static str> {
+    let index = match phase {
+        Some(phase) => PHASES.iter().position(|(name, _)| *name == phase)? + 1,
+        None => 0,
+    };
+    PHASES.get(index).map(|(name, _)| *name)
+}
+
+/// Follows console output from `position` for the boot numbered `boot`,
+/// recording each phase it reaches in `status` and announcing it. Stops at
+/// the last phase, or once the machine has booted again.
+pub fn watch(output: ConsoleOutput, status: SharedStatus, boot: u64, position: usize) {
+    let patterns: Vec<(&'static str, Regex)> = PHASES
+        .iter()
+        .map(|(name, pattern)| (*name, Regex::new(pattern).unwrap()))
+        .collect();
+
+    thread::spawn(move || {
+        let mut position = position;
+        let mut reached = 0;
+        let mut line = Vec::new();
+        while reached < patterns.len() {
+            let (data, next) = output.read_from(position, Duration::from_secs(1));
+            position = next;
+            let name = {
+                let status = status.lock().unwrap();
+                if status.boots != boot {
+                    return;
+                }
+                status.name.clone()
+            };
+
+            let mut latest = None;
+            let mut check = |line: &[u8], reached: &mut usize| {
+                let line = String::from_utf8_lossy(line);
+                let line = line.trim_end_matches('\r');
+                for (i, (phase, pattern)) in patterns.iter().enumerate().skip(*reached) {
+                    if pattern.is_match(line) {
+                        *reached = i + 1;
+                        latest = Some(*phase);
+                    }
+                }
+            };
+            for &byte in &data {
+                if byte == b'\n' {
+                    check(&line, &mut reached);
+                    line.clear();
+                } else {
+                    line.push(byte);
+                }
+            }
+            // A prompt doesn't end in a newline, so look at the line so far
+            // too.
+            check(&line, &mut reached);
+
+            if let Some(phase) = latest {
+                status.lock().unwrap().boot_phase = Some(phase);
+                output::message(&format!("{}: {}", name, phase));
+            }
+        }
+    });
+}
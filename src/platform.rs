@@ -0,0 +1,120 @@
+//! VZGenericPlatformConfiguration knobs, which virtualization-rs doesn't
+//! bind yet.
+
+use crate::config::Platform;
+use crate::machine::Machine;
+use crate::output;
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, BOOL, YES};
+use objc::{class, msg_send, sel, sel_impl};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::slice;
+use virtualization_rs::base::{Id, NIL};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+
+#[derive(Debug)]
+pub struct PlatformError(String);
+
+impl fmt::Display for PlatformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported platform configuration: {}", self.0)
+    }
+}
+
+impl error::Error for PlatformError {}
+
+/// The Objective-C object behind a configuration. virtualization-rs keeps
+/// the pointer private and has no setter for platforms, so reach through
+/// the newtype, which wraps nothing but the StrongPtr.
+pub fn configuration_id(conf: &VZVirtualMachineConfiguration) -> Id {
+    unsafe { **(conf as *const VZVirtualMachineConfiguration as *const StrongPtr) }
+}
+
+fn generic_platform_class() -> Option<&'static Class> {
+    Class::get("VZGenericPlatformConfiguration")
+}
+
+/// Nested virtualization needs macOS 15 and an M3 or later.
+pub fn nested_virtualization_supported() -> bool {
+    let class = match generic_platform_class() {
+        Some(class) => class,
+        None => return false,
+    };
+    unsafe {
+        let responds: BOOL =
+            msg_send![class, respondsToSelector: sel!(isNestedVirtualizationSupported)];
+        if responds != YES {
+            return false;
+        }
+        let supported: BOOL = msg_send![class, isNestedVirtualizationSupported];
+        supported == YES
+    }
+}
+
+/// Loads the machine's identifier, creating and saving one on first boot so
+/// the guest sees the same machine across restarts.
+fn machine_identifier(machine: &Machine) -> Result<StrongPtr, PlatformError> {
+    let path = machine.dir.join("machine-identifier");
+    let class = class!(VZGenericMachineIdentifier);
+
+    if let Ok(data) = fs::read(&path) {
+        unsafe {
+            let ns_data: Id =
+                msg_send![class!(NSData), dataWithBytes:data.as_ptr() length:data.len()];
+            let alloc: Id = msg_send![class, alloc];
+            let identifier: Id = msg_send![alloc, initWithDataRepresentation: ns_data];
+            if identifier != NIL {
+                return Ok(StrongPtr::new(identifier));
+            }
+        }
+        output::warning(&format!("ignoring unreadable {}", path.display()));
+    }
+
+    unsafe {
+        let identifier = StrongPtr::new(msg_send![class, new]);
+        let ns_data: Id = msg_send![*identifier, dataRepresentation];
+        let bytes: *const u8 = msg_send![ns_data, bytes];
+        let length: usize = msg_send![ns_data, length];
+        fs::create_dir_all(&machine.dir)
+            .and_then(|_| fs::write(&path, slice::from_raw_parts(bytes, length)))
+            .map_err(|e| PlatformError(format!("could not save {}: {}", path.display(), e)))?;
+        Ok(identifier)
+    }
+}
+
+/// Checks that the host supports everything `platform` asks for.
+pub fn check(platform: &Platform) -> Result<(), PlatformError> {
+    if generic_platform_class().is_none() {
+        return Err(PlatformError(
+            "platform options require macOS 12 or later".to_string(),
+        ));
+    }
+    if platform.nested_virtualization && !nested_virtualization_supported() {
+        return Err(PlatformError(
+            "nested virtualization requires macOS 15 and an M3 or later".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn apply(
+    conf: &VZVirtualMachineConfiguration,
+    platform: &Platform,
+    machine: &Machine,
+) -> Result<(), PlatformError> {
+    check(platform)?;
+    let class = generic_platform_class().unwrap();
+
+    let identifier = machine_identifier(machine)?;
+    unsafe {
+        let generic = StrongPtr::new(msg_send![class, new]);
+        let _: () = msg_send![*generic, setMachineIdentifier: *identifier];
+        if platform.nested_virtualization {
+            let _: () = msg_send![*generic, setNestedVirtualizationEnabled: YES];
+        }
+        let _: () = msg_send![configuration_id(conf), setPlatform: *generic];
+    }
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! External plugins that add subcommands and provisioners.
+//!
+//! A plugin is any executable in `~/.vagrantx/plugins`. vagrantx runs it
+//! once per request, writing a single JSON object to its stdin:
+//!
+//! - `{"request": "describe"}` asks what the plugin provides; the result is
+//!   `{"commands": [{"name": ..., "about": ...}], "provisioners": [...]}`.
+//! - `{"request": "command", "command": ..., "args": [...], "cwd": ...}`
+//!   runs a subcommand.
+//! - `{"request": "provision", "provisioner": ..., "options": {...},
+//!   "machine": {...}}` provisions a running machine.
+//!
+//! The plugin answers with JSON lines on stdout: any number of
+//! `{"log": "..."}` lines, which are printed as they arrive, then either
+//! `{"result": ...}` or `{"error": "..."}`. Its stderr goes straight to ours.
+
+use crate::config::Provisioner;
+use crate::events::EventLog;
+use crate::machine::Machine;
+use crate::network;
+use crate::output;
+use crate::paths;
+use crate::provisionlog;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+#[derive(Debug)]
+pub struct PluginError {
+    plugin: String,
+    message: String,
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "plugin {}: {}", self.plugin, self.message)
+    }
+}
+
+impl error::Error for PluginError {}
+
+#[derive(Deserialize, Debug)]
+pub struct CommandInfo {
+    pub name: String,
+    #[serde(default)]
+    pub about: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Description {
+    #[serde(default)]
+    pub commands: Vec<CommandInfo>,
+    #[serde(default)]
+    pub provisioners: Vec<String>,
+}
+
+pub struct Plugin {
+    pub name: String,
+    path: PathBuf,
+    pub description: Description,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Message {
+    Log(String),
+    Result(Value),
+    Error(String),
+}
+
+impl Plugin {
+    fn error(&self, message: String) -> PluginError {
+        PluginError {
+            plugin: self.name.clone(),
+            message,
+        }
+    }
+
+    /// Makes `request` of the plugin. Everything it logs and writes to its
+    /// stderr is also written to `log`, if given.
+    fn call(&self, request: &Value, log: Option<&File>) -> Result<Value, PluginError> {
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(if log.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .spawn()
+            .map_err(|e| self.error(format!("could not run {}: {}", self.path.display(), e)))?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let _ = writeln!(stdin, "{}", request);
+        drop(stdin);
+
+        let stderr = match (child.stderr.take(), log.map(File::try_clone)) {
+            (Some(stderr), Some(Ok(mut file))) => Some(thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    eprintln!("{}", line);
+                    let _ = writeln!(file, "{}", line);
+                }
+            })),
+            _ => None,
+        };
+        let record = |text: &str| {
+            output::message(&format!("{}: {}", self.name, text));
+            if let Some(mut file) = log {
+                let _ = writeln!(file, "{}", text);
+            }
+        };
+
+        let mut outcome = None;
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+            let line = line.map_err(|e| self.error(e.to_string()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(Message::Log(text)) => record(&text),
+                Ok(Message::Result(value)) => outcome = Some(Ok(value)),
+                Ok(Message::Error(message)) => {
+                    if let Some(mut file) = log {
+                        let _ = writeln!(file, "error: {}", message);
+                    }
+                    outcome = Some(Err(self.error(message)))
+                }
+                Err(_) => record(&line),
+            }
+        }
+
+        let status = child.wait().map_err(|e| self.error(e.to_string()))?;
+        if let Some(stderr) = stderr {
+            let _ = stderr.join();
+        }
+        if let Some(mut file) = log {
+            let _ = writeln!(file, "exited with {}", status);
+        }
+        match outcome {
+            Some(outcome) => outcome,
+            None => Err(self.error(format!("exited with {} without a result", status))),
+        }
+    }
+}
+
+/// Every plugin that describes itself successfully. Broken plugins are
+/// reported and skipped so one of them can't take down every command.
+pub fn discover() -> Vec<Plugin> {
+    let entries = match fs::read_dir(paths::plugins_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut plugins = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let executable = fs::metadata(&path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if !executable {
+            continue;
+        }
+
+        let mut plugin = Plugin {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            path,
+            description: Description::default(),
+        };
+        match plugin
+            .call(&json!({ "request": "describe" }), None)
+            .and_then(|v| serde_json::from_value(v).map_err(|e| plugin.error(e.to_string())))
+        {
+            Ok(description) => {
+                plugin.description = description;
+                plugins.push(plugin);
+            }
+            Err(e) => output::warning(&e.to_string()),
+        }
+    }
+    plugins.sort_by(|a, b| a.name.cmp(&b.name));
+    plugins
+}
+
+/// Runs a subcommand vagrantx doesn't know itself; `args[0]` is its name.
+pub fn run_command(args: &[String]) -> Result<(), Box<dyn error::Error>> {
+    let (name, args) = args.split_first().ok_or("no command given")?;
+    let plugins = discover();
+    let plugin = plugins
+        .iter()
+        .find(|p| p.description.commands.iter().any(|c| &c.name == name))
+        .ok_or_else(|| format!("unknown command {}", name))?;
+
+    plugin.call(
+        &json!({
+        "request": "command",
+        "command": name,
+        "args": args,
+        "cwd": env::current_dir()?,
+        }),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Runs the plugin provisioner called `kind` against a machine described
+/// by `machine`, writing what it prints to `log`.
+fn provision(
+    kind: &str,
+    options: &serde_json::Map<String, Value>,
+    machine: Value,
+    log: Option<&File>,
+) -> Result<(), Box<dyn error::Error>> {
+    let plugins = discover();
+    let plugin = plugins
+        .iter()
+        .find(|p| p.description.provisioners.iter().any(|n| n == kind))
+        .ok_or_else(|| format!("no plugin provides the {} provisioner", kind))?;
+
+    plugin.call(
+        &json!({
+        "request": "provision",
+        "provisioner": kind,
+        "options": options,
+        "machine": machine,
+        }),
+        log,
+    )?;
+    Ok(())
+}
+
+/// Runs `provisioners` in order against a running machine, stopping at the
+/// first failure. The run is recorded for `vagrantx logs provision`.
+pub fn provision_all(
+    provisioners: &[Provisioner],
+    machine: &Machine,
+    config_file: &Path,
+    mac: &str,
+) -> Result<(), Box<dyn error::Error>> {
+    let description = json!({
+        "name": machine.name,
+        "dir": machine.dir,
+        "config": fs::canonicalize(config_file).unwrap_or_else(|_| config_file.to_path_buf()),
+        "mac": mac,
+        "ip": network::guest_ip(mac).map(|ip| ip.to_string()),
+    });
+    let events = EventLog::new(machine);
+    let mut run = provisionlog::Run::start(machine)
+        .map_err(|e| output::warning(&format!("not recording the run: {}", e)))
+        .ok();
+    for provisioner in provisioners {
+        let progress = output::progress(&format!(
+            "provisioning {} with {}",
+            machine.name, provisioner.kind
+        ));
+        events.record("provisioning", Some(provisioner.kind.clone()));
+        let log = run.as_mut().and_then(|run| run.log(&provisioner.kind).ok());
+        let result = provision(
+            &provisioner.kind,
+            &provisioner.options,
+            description.clone(),
+            log.as_ref(),
+        );
+        if let Some(run) = &mut run {
+            let _ = run.finished(result.as_ref().err().map(|e| e.to_string()));
+        }
+        result?;
+        progress.finish(&format!(
+            "provisioned {} with {}",
+            machine.name, provisioner.kind
+        ));
+    }
+    if let Some(run) = run {
+        let _ = run.succeeded();
+    }
+    Ok(())
+}
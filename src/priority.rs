@@ -0,0 +1,106 @@
+//! Keeping a machine from starving the rest of the host.
+//!
+//! The QoS class applies to the machine's dispatch queue and, for
+//! `background`, to the processes running the guest as a whole. Those
+//! processes are also reniced, and a CPU limit is enforced by pausing them
+//! with SIGSTOP whenever they've used more than their share.
+
+use crate::config::{HostPriority, Qos};
+use crate::output;
+use crate::procinfo;
+use libc::{c_int, pid_t, qos_class_t};
+use std::thread;
+use std::time::{Duration, Instant};
+use virtualization_rs::base::{Id, NIL};
+
+/// How often the CPU limit is checked.
+const PERIOD: Duration = Duration::from_millis(100);
+
+extern "C" {
+    fn dispatch_queue_attr_make_with_qos_class(
+        attr: Id,
+        class: qos_class_t,
+        relative_priority: c_int,
+    ) -> Id;
+}
+
+/// Attributes for a serial dispatch queue running at `qos`.
+pub fn queue_attributes(qos: Qos) -> Id {
+    let class = match qos {
+        Qos::Default => return NIL,
+        Qos::Utility => qos_class_t::QOS_CLASS_UTILITY,
+        Qos::Background => qos_class_t::QOS_CLASS_BACKGROUND,
+    };
+    unsafe { dispatch_queue_attr_make_with_qos_class(NIL, class, 0) }
+}
+
+fn cpu_seconds(processes: &[pid_t]) -> f64 {
+    processes
+        .iter()
+        .filter_map(|&pid| procinfo::usage(pid))
+        .map(|u| u.cpu_seconds)
+        .sum()
+}
+
+fn signal(processes: &[pid_t], signal: c_int) {
+    for &pid in processes {
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+/// Holds `processes` to `limit` percent of one core until they exit.
+fn throttle(processes: Vec<pid_t>, limit: u32) {
+    let share = limit as f64 / 100.0;
+    thread::spawn(move || {
+        let mut last = Instant::now();
+        let mut used = cpu_seconds(&processes);
+        loop {
+            thread::sleep(PERIOD);
+            if processes.iter().all(|&pid| procinfo::usage(pid).is_none()) {
+                return;
+            }
+            let now = used.max(cpu_seconds(&processes));
+            let over = (now - used) - share * last.elapsed().as_secs_f64();
+            last = Instant::now();
+            used = now;
+            if over > 0.0 {
+                // Paused, they earn back their share of the time.
+                signal(&processes, libc::SIGSTOP);
+                thread::sleep(Duration::from_secs_f64((over / share).min(1.0)));
+                signal(&processes, libc::SIGCONT);
+            }
+        }
+    });
+}
+
+/// Applies `priority` to the freshly started processes running a guest.
+pub fn apply(priority: &HostPriority, processes: Vec<pid_t>) {
+    let nice = priority.nice.unwrap_or(match priority.qos {
+        Qos::Default => 0,
+        Qos::Utility | Qos::Background => 10,
+    });
+    for &pid in &processes {
+        let failed = (nice != 0
+            && unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as u32, nice) } != 0)
+            || (priority.qos == Qos::Background
+                && unsafe {
+                    libc::setpriority(libc::PRIO_DARWIN_PROCESS, pid as u32, libc::PRIO_DARWIN_BG)
+                } != 0);
+        if failed {
+            output::warning(&format!(
+                "could not lower the priority of process {}: {}",
+                pid,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    match priority.cpu_limit {
+        Some(0) => {
+            output::warning("ignoring a cpu_limit of 0, which would never let the guest run")
+        }
+        Some(limit) => throttle(processes, limit),
+        None => {}
+    }
+}
@@ -0,0 +1,109 @@
+//! Host resource usage of the processes running our guest.
+//!
+//! Virtualization.framework runs each guest in its own XPC service process
+//! rather than in ours. launchd still records us as responsible for it,
+//! which is how we find it, along with whatever else we've started, such as
+//! ssh, so it's picked out of those by its executable.
+
+use libc::{c_int, c_void, pid_t, proc_taskinfo};
+use std::ffi::CStr;
+use std::mem;
+
+/// The executable of the service the framework runs each guest in.
+const VM_SERVICE: &str = "/com.apple.Virtualization.VirtualMachine";
+
+#[repr(C)]
+struct MachTimebaseInfo {
+    numer: u32,
+    denom: u32,
+}
+
+extern "C" {
+    fn responsibility_get_pid_responsible_for_pid(pid: pid_t) -> pid_t;
+    fn mach_timebase_info(info: *mut MachTimebaseInfo) -> c_int;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    pub cpu_seconds: f64,
+    pub resident_bytes: u64,
+}
+
+fn all_pids() -> Vec<pid_t> {
+    let count = unsafe { libc::proc_listallpids(std::ptr::null_mut(), 0) };
+    if count <= 0 {
+        return Vec::new();
+    }
+    // Leave room for processes started between the two calls.
+    let mut pids: Vec<pid_t> = vec![0; count as usize + 64];
+    let size = (pids.len() * mem::size_of::<pid_t>()) as c_int;
+    let count = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut c_void, size) };
+    pids.truncate(count.max(0) as usize);
+    pids
+}
+
+fn executable(pid: pid_t) -> Option<String> {
+    let mut path = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len =
+        unsafe { libc::proc_pidpath(pid, path.as_mut_ptr() as *mut c_void, path.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    Some(
+        CStr::from_bytes_until_nul(&path)
+            .ok()?
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// The XPC service processes running our guests.
+pub fn vm_processes() -> Vec<pid_t> {
+    let me = unsafe { libc::getpid() };
+    all_pids()
+        .into_iter()
+        .filter(|&pid| pid != me && pid > 0)
+        .filter(|&pid| unsafe { responsibility_get_pid_responsible_for_pid(pid) } == me)
+        .filter(|&pid| executable(pid).is_some_and(|path| path.ends_with(VM_SERVICE)))
+        .collect()
+}
+
+fn ticks_to_seconds(ticks: u64) -> f64 {
+    let mut timebase = MachTimebaseInfo { numer: 0, denom: 0 };
+    unsafe {
+        mach_timebase_info(&mut timebase);
+    }
+    ticks as f64 * timebase.numer as f64 / timebase.denom.max(1) as f64 / 1e9
+}
+
+pub fn usage(pid: pid_t) -> Option<Usage> {
+    let mut info: proc_taskinfo = unsafe { mem::zeroed() };
+    let size = mem::size_of::<proc_taskinfo>() as c_int;
+    let r = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDTASKINFO,
+            0,
+            &mut info as *mut proc_taskinfo as *mut c_void,
+            size,
+        )
+    };
+    if r != size {
+        return None;
+    }
+    Some(Usage {
+        cpu_seconds: ticks_to_seconds(info.pti_total_user + info.pti_total_system),
+        resident_bytes: info.pti_resident_size,
+    })
+}
+
+/// Combined usage of every process backing our guest.
+pub fn vm_usage() -> Usage {
+    vm_processes()
+        .into_iter()
+        .filter_map(usage)
+        .fold(Usage::default(), |total, u| Usage {
+            cpu_seconds: total.cpu_seconds + u.cpu_seconds,
+            resident_bytes: total.resident_bytes + u.resident_bytes,
+        })
+}
@@ -0,0 +1,61 @@
+//! Named resource presets. Profiles are looked up in the config's own
+//! `profiles`, then `~/.vagrantx/profiles.json`, then the built-in
+//! `small`, `medium` and `large`.
+
+use crate::config::{Config, Profile};
+use crate::paths;
+use std::collections::BTreeMap;
+use std::error;
+use std::fs::File;
+
+const GIB: usize = 1024 * 1024 * 1024;
+
+fn builtin(name: &str) -> Option<Profile> {
+    let (cpu_count, memory_size) = match name {
+        "small" => (1, GIB),
+        "medium" => (2, 4 * GIB),
+        "large" => (4, 8 * GIB),
+        _ => return None,
+    };
+    Some(Profile {
+        cpu_count: Some(cpu_count),
+        memory_size: Some(memory_size),
+        disk_size: None,
+    })
+}
+
+fn global() -> Result<BTreeMap<String, Profile>, Box<dyn error::Error>> {
+    let path = paths::vagrantx_home().join("profiles.json");
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let file = File::open(&path)?;
+    serde_json::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Applies `name`, or the config's own `profile` if no name is given.
+pub fn apply(config: &mut Config, name: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+    let name = match name.or(config.profile.as_deref()) {
+        Some(name) => name.to_string(),
+        None => return Ok(()),
+    };
+
+    let profile = match config.profiles.get(&name) {
+        Some(profile) => profile.clone(),
+        None => global()?
+            .remove(&name)
+            .or_else(|| builtin(&name))
+            .ok_or_else(|| format!("unknown profile {}", name))?,
+    };
+
+    if let Some(cpu_count) = profile.cpu_count {
+        config.cpu_count = cpu_count;
+    }
+    if let Some(memory_size) = profile.memory_size {
+        config.memory_size = memory_size;
+    }
+    if let Some(disk_size) = profile.disk_size {
+        config.disk_size = Some(disk_size);
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! `vagrantx provision`: running a machine's provisioners again while `up`
+//! runs it, and the `pre-provision` snapshot that lets a run that broke the
+//! machine be undone.
+
+use crate::config::{self, Config};
+use crate::events::EventLog;
+use crate::halt;
+use crate::machine::Machine;
+use crate::network;
+use crate::output;
+use crate::plugins;
+use crate::snapshot::{self, Snapshots};
+use crate::ssh::Session;
+use crate::status;
+use std::error;
+use std::path::Path;
+use std::time::Duration;
+
+/// The snapshot taken before provisioning.
+pub const SNAPSHOT: &str = "pre-provision";
+
+/// Saves the machine's disks as the `pre-provision` snapshot, replacing the
+/// one from the last run. The machine has to be halted with its lock held,
+/// or have flushed its writes.
+pub fn snapshot(config: &Config, machine: &Machine) -> Result<(), Box<dyn error::Error>> {
+    let snapshots = Snapshots::unlocked(config, machine)?;
+    if snapshot::names(machine).iter().any(|name| name == SNAPSHOT) {
+        snapshots.delete(SNAPSHOT)?;
+    }
+    snapshots.save(SNAPSHOT, Some("taken before provisioning".to_string()))
+}
+
+/// Has the guest write out its page cache, so a copy of its disks taken
+/// while it runs is no worse than one after a power cut.
+fn flush(config: &Config, machine: &Machine) -> Result<(), Box<dyn error::Error>> {
+    let mut session = Session::new(config, machine)?;
+    session.options.push("BatchMode=yes".to_string());
+    let status = session
+        .command()
+        .args(["--", "sudo -n sync"])
+        .status()
+        .map_err(|e| format!("could not run ssh: {}", e))?;
+    if !status.success() {
+        return Err(format!("could not flush the guest's disks ({})", status).into());
+    }
+    Ok(())
+}
+
+/// Runs the provisioners of the machine `config_file` defines against it.
+/// With `rollback_on_failure`, a failed run stops the machine and restores
+/// the disks it had before.
+pub fn provision(
+    config_file: &Path,
+    rollback_on_failure: bool,
+) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    if config.provisioners.is_empty() {
+        return Err(format!("{} has no provisioners", config_file.display()).into());
+    }
+    if status::published(&machine).is_none() {
+        return Err(format!(
+            "{} is not running; vagrantx up provisions it as it starts",
+            machine.name
+        )
+        .into());
+    }
+    let mac = network::saved_mac_address(&machine)
+        .ok_or_else(|| format!("{} is not running", machine.name))?;
+
+    let events = EventLog::new(&machine).notifying(&config.notify);
+    if rollback_on_failure || config.snapshot_before_provision {
+        if let Err(e) = flush(&config, &machine) {
+            output::warning(&format!("{}, so the snapshot may lose recent writes", e));
+        }
+        snapshot(&config, &machine)?;
+    }
+
+    match plugins::provision_all(&config.provisioners, &machine, config_file, &mac) {
+        Ok(()) => {
+            events.record("provisioned", None);
+            Ok(())
+        }
+        Err(e) => {
+            events.record("unprovisioned", Some(e.to_string()));
+            if rollback_on_failure {
+                output::message(&format!(
+                    "rolling {} back to how it was before provisioning",
+                    machine.name
+                ));
+                // Its disks are about to be replaced, so there's no point
+                // shutting the guest down cleanly.
+                halt::stop(&machine, true, Duration::ZERO)?;
+                Snapshots::open(&config_file.to_path_buf())?.restore(SNAPSHOT)?;
+                output::message(&format!("start {} again with vagrantx up", machine.name));
+            }
+            Err(format!("could not provision {}: {}", machine.name, e).into())
+        }
+    }
+}
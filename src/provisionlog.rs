@@ -0,0 +1,228 @@
+//! A record of each provisioning run, so one that failed can still be
+//! looked into once the terminal has scrolled past it.
+//!
+//! Every run gets a directory under the machine's `provision/`, named for
+//! when it started, holding `run.json` and each provisioner's full output:
+//!
+//! ```text
+//! provision/2022-06-01T12:00:01Z/run.json
+//! provision/2022-06-01T12:00:01Z/1-ansible.log
+//! provision/2022-06-01T12:00:01Z/2-shell.log
+//! ```
+
+use crate::events;
+use crate::machine::Machine;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+
+/// How many runs to keep; older ones are removed as new ones start.
+const KEEP: usize = 20;
+
+const RECORD_FILE: &str = "run.json";
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    started: String,
+    /// Unset until the run is over, or if `up` never got to say.
+    #[serde(default)]
+    ok: Option<bool>,
+    duration_secs: f64,
+    steps: Vec<Step>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Step {
+    provisioner: String,
+    /// The file its output is in, beside `run.json`.
+    log: String,
+    duration_secs: f64,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+fn dir(machine: &Machine) -> PathBuf {
+    machine.dir.join("provision")
+}
+
+/// The machine's runs, oldest first.
+fn runs(machine: &Machine) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir(machine))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().join(RECORD_FILE).is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    // Timestamps sort as they read.
+    names.sort();
+    names
+}
+
+fn read(machine: &Machine, name: &str) -> Result<Record, Box<dyn error::Error>> {
+    let path = dir(machine).join(name).join(RECORD_FILE);
+    let data = fs::read(&path).map_err(|_| format!("{} has no run {}", machine.name, name))?;
+    Ok(serde_json::from_slice(&data)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?)
+}
+
+/// A provisioning run as it happens.
+pub struct Run {
+    dir: PathBuf,
+    started: Instant,
+    step_started: Instant,
+    record: Record,
+}
+
+impl Run {
+    /// Starts recording a run, removing the oldest past `KEEP`.
+    pub fn start(machine: &Machine) -> Result<Run, Box<dyn error::Error>> {
+        let started = events::timestamp(SystemTime::now());
+        let mut name = started.clone();
+        let mut n = 1;
+        while dir(machine).join(&name).exists() {
+            n += 1;
+            name = format!("{}-{}", started, n);
+        }
+        let run_dir = dir(machine).join(&name);
+        fs::create_dir_all(&run_dir)?;
+
+        let old = runs(machine);
+        for name in &old[..old.len().saturating_sub(KEEP)] {
+            let _ = fs::remove_dir_all(dir(machine).join(name));
+        }
+
+        let run = Run {
+            dir: run_dir,
+            started: Instant::now(),
+            step_started: Instant::now(),
+            record: Record {
+                started,
+                ok: None,
+                duration_secs: 0.0,
+                steps: Vec::new(),
+            },
+        };
+        run.save()?;
+        Ok(run)
+    }
+
+    fn save(&self) -> Result<(), Box<dyn error::Error>> {
+        let path = self.dir.join(RECORD_FILE);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec_pretty(&self.record)?)?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    /// A file for the output of the next provisioner, `kind`.
+    pub fn log(&mut self, kind: &str) -> Result<File, Box<dyn error::Error>> {
+        let log = format!("{}-{}.log", self.record.steps.len() + 1, kind);
+        let file = File::create(self.dir.join(&log))?;
+        self.record.steps.push(Step {
+            provisioner: kind.to_string(),
+            log,
+            duration_secs: 0.0,
+            error: None,
+        });
+        self.step_started = Instant::now();
+        self.save()?;
+        Ok(file)
+    }
+
+    /// Records how the provisioner last given a log went.
+    pub fn finished(&mut self, error: Option<String>) -> Result<(), Box<dyn error::Error>> {
+        let failed = error.is_some();
+        if let Some(step) = self.record.steps.last_mut() {
+            step.duration_secs = self.step_started.elapsed().as_secs_f64();
+            step.error = error;
+        }
+        self.record.duration_secs = self.started.elapsed().as_secs_f64();
+        if failed {
+            self.record.ok = Some(false);
+        }
+        self.save()
+    }
+
+    /// Records that every provisioner ran.
+    pub fn succeeded(mut self) -> Result<(), Box<dyn error::Error>> {
+        self.record.ok = Some(true);
+        self.record.duration_secs = self.started.elapsed().as_secs_f64();
+        self.save()
+    }
+}
+
+fn outcome(record: &Record) -> &'static str {
+    match record.ok {
+        Some(true) => "ok",
+        Some(false) => "failed",
+        None => "unfinished",
+    }
+}
+
+fn seconds(secs: f64) -> String {
+    format!("{:.1}s", secs)
+}
+
+/// Lists the machine's provisioning runs, oldest first.
+pub fn list(machine: &Machine) -> Result<(), Box<dyn error::Error>> {
+    let names = runs(machine);
+    if names.is_empty() {
+        return Err(format!("{} has never been provisioned", machine.name).into());
+    }
+    println!(
+        "{:<24} {:<10} {:>9} PROVISIONERS",
+        "RUN", "RESULT", "DURATION"
+    );
+    for name in names {
+        let record = read(machine, &name)?;
+        let provisioners: Vec<&str> = record
+            .steps
+            .iter()
+            .map(|step| step.provisioner.as_str())
+            .collect();
+        println!(
+            "{:<24} {:<10} {:>9} {}",
+            name,
+            outcome(&record),
+            seconds(record.duration_secs),
+            provisioners.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Prints a run in full: how each provisioner went and all it printed. The
+/// most recent run, without `name`.
+pub fn show(machine: &Machine, name: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => runs(machine)
+            .pop()
+            .ok_or_else(|| format!("{} has never been provisioned", machine.name))?,
+    };
+    let record = read(machine, &name)?;
+    println!(
+        "run {}: {} after {}",
+        name,
+        outcome(&record),
+        seconds(record.duration_secs)
+    );
+    for step in &record.steps {
+        println!();
+        match &step.error {
+            Some(error) => println!(
+                "== {} failed after {}: {}",
+                step.provisioner,
+                seconds(step.duration_secs),
+                error
+            ),
+            None => println!("== {} ({})", step.provisioner, seconds(step.duration_secs)),
+        }
+        let log = fs::read(dir(machine).join(&name).join(&step.log)).unwrap_or_default();
+        print!("{}", String::from_utf8_lossy(&log));
+    }
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//! Finding and removing what vagrantx has left behind: machines no config
+//! defines any more, launchd agents for projects that have gone, disks and
+//! snapshots nothing will boot from, the remains of interrupted operations
+//! and downloads and, if asked, boxes no config uses.
+//!
+//! Machines are only found in the project being pruned, since nothing
+//! records where else they are. A machine that's in use is left alone, as
+//! is any machine a config that doesn't load might define. Nothing is
+//! removed until the list of what would be has been confirmed.
+
+use crate::autostart::{self, Agent};
+use crate::boxes;
+use crate::config::{self, Config};
+use crate::lock::{self, LockError, MachineLock};
+use crate::machine::Machine;
+use crate::output;
+use crate::paths;
+use crate::snapshot;
+use crate::stats::format_bytes;
+use libc::isatty;
+use std::error;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Anything touched more recently than this may belong to an operation
+/// that's still going.
+const INTERRUPTED_AGE: Duration = Duration::from_secs(60 * 60);
+
+struct Orphan {
+    path: PathBuf,
+    reason: String,
+    /// Set when it's a launchd agent, which has to be unloaded too.
+    agent: Option<Agent>,
+}
+
+impl Orphan {
+    fn new(path: PathBuf, reason: impl Into<String>) -> Orphan {
+        Orphan {
+            path,
+            reason: reason.into(),
+            agent: None,
+        }
+    }
+
+    fn remove(&self) -> Result<(), Box<dyn error::Error>> {
+        match &self.agent {
+            Some(agent) => autostart::remove(agent)?,
+            None if self.path.is_dir() => fs::remove_dir_all(&self.path)?,
+            None => fs::remove_file(&self.path)?,
+        }
+        Ok(())
+    }
+}
+
+/// Space `path` takes up on disk, counting every file under it.
+fn disk_usage(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    let mut total = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            total += disk_usage(&entry.path());
+        }
+    }
+    total
+}
+
+fn entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|e| e.path()).collect())
+        .unwrap_or_default();
+    entries.sort();
+    entries
+}
+
+/// The configs in `project`: every JSON file there that loads as one.
+pub fn configs(project: &Path) -> Vec<(PathBuf, Config)> {
+    entries(project)
+        .into_iter()
+        .filter(|path| path.is_file() && path.extension() == Some("json".as_ref()))
+        .filter_map(|path| {
+            let config = config::load_config(&path).ok()?;
+            Some((path, config))
+        })
+        .collect()
+}
+
+/// The JSON files in `project` that don't load as configs, with why. Any
+/// of them may still define a machine, once it's fixed or its variables
+/// are set.
+fn unloadable(project: &Path) -> Vec<(PathBuf, String)> {
+    entries(project)
+        .into_iter()
+        .filter(|path| path.is_file() && path.extension() == Some("json".as_ref()))
+        .filter_map(|path| {
+            let e = config::load_config(&path).err()?;
+            Some((path, e.to_string()))
+        })
+        .collect()
+}
+
+/// Asks on the terminal whether to go ahead. Without one, there's nobody
+/// to ask, so it's up to `--yes`.
+fn confirm(count: usize) -> Result<bool, Box<dyn error::Error>> {
+    if unsafe { isatty(0) } != 1 {
+        return Err("not removing anything without a terminal to confirm on; pass --yes".into());
+    }
+    print!("remove {} item(s)? [y/N] ", count);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Staging copies of boxes and snapshots, and disks half restored.
+fn interrupted(dir: &Path, orphans: &mut Vec<Orphan>) {
+    for path in entries(dir) {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let old = fs::symlink_metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age > INTERRUPTED_AGE);
+        if old && (name.ends_with(".partial") || name.ends_with(".restoring")) {
+            orphans.push(Orphan::new(path, "left by an interrupted operation"));
+        }
+    }
+}
+
+fn machine_orphans(machine: &Machine, config: &Config, orphans: &mut Vec<Orphan>) {
+    interrupted(&machine.dir, orphans);
+
+    let boots_root_disk = config.box_name.is_some() && config.boot.disks.is_empty();
+    if !boots_root_disk && machine.root_disk().exists() {
+        orphans.push(Orphan::new(
+            machine.root_disk(),
+            "the config no longer boots from it",
+        ));
+    }
+
+    let snapshots = machine.dir.join("snapshots");
+    interrupted(&snapshots, orphans);
+    let complete = snapshot::names(machine);
+    for path in entries(&snapshots) {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if path.is_dir() && !name.starts_with('.') && !complete.contains(&name) {
+            orphans.push(Orphan::new(path, "snapshot was never finished"));
+        }
+    }
+}
+
+/// Locks `machine` for pruning, or returns `None` if something else is
+/// using it.
+fn lock_idle(machine: &Machine) -> Result<Option<MachineLock>, Box<dyn error::Error>> {
+    match lock::acquire(machine, "prune", false) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(LockError::Held { .. }) => {
+            println!("skipping {}, which is in use", machine.name);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn prune(
+    project: &Path,
+    dry_run: bool,
+    yes: bool,
+    prune_boxes: bool,
+) -> Result<(), Box<dyn error::Error>> {
+    let configs = configs(project);
+    let unloadable = unloadable(project);
+    for (path, e) in &unloadable {
+        output::warning(&format!(
+            "{} does not load, so machines it may define are kept: {}",
+            path.display(),
+            e
+        ));
+    }
+    let mut orphans = Vec::new();
+    let mut locks = Vec::new();
+
+    for dir in entries(&project.join(".vagrantx").join("machines")) {
+        if !dir.is_dir() {
+            continue;
+        }
+        let defined = configs.iter().find(|(config_file, config)| {
+            Machine::new(config_file, config.name.as_deref()).dir == dir
+        });
+        let machine = Machine {
+            name: dir.file_name().unwrap().to_string_lossy().into_owned(),
+            dir: dir.clone(),
+        };
+        match lock_idle(&machine)? {
+            Some(lock) => locks.push(lock),
+            None => continue,
+        }
+        match defined {
+            Some((_, config)) => machine_orphans(&machine, config, &mut orphans),
+            None if !unloadable.is_empty() => {}
+            None => orphans.push(Orphan::new(
+                dir,
+                format!("no config in {} defines it", project.display()),
+            )),
+        }
+    }
+
+    let agents = autostart::agents();
+    let mut used_boxes: Vec<String> = configs
+        .iter()
+        .filter_map(|(_, config)| config.box_name.clone())
+        .collect();
+    for agent in agents {
+        match &agent.config_file {
+            Some(config_file) if config_file.exists() => {
+                if let Ok(config) = config::load_config(config_file) {
+                    used_boxes.extend(config.box_name);
+                }
+            }
+            _ => {
+                let reason = match &agent.config_file {
+                    Some(config_file) => format!("{} no longer exists", config_file.display()),
+                    None => "it doesn't name a config".to_string(),
+                };
+                orphans.push(Orphan {
+                    path: agent.plist.clone(),
+                    reason,
+                    agent: Some(agent),
+                });
+            }
+        }
+    }
+
+    interrupted(&paths::boxes_dir(), &mut orphans);
+    interrupted(&paths::cache_dir(), &mut orphans);
+    if prune_boxes {
+        for name in boxes::list()? {
+            if !used_boxes.contains(&name) {
+                orphans.push(Orphan::new(
+                    boxes::box_dir(&name),
+                    "no config here or started at login uses it",
+                ));
+            }
+        }
+    }
+
+    if orphans.is_empty() {
+        println!("nothing to prune");
+        return Ok(());
+    }
+    let sizes: Vec<u64> = orphans.iter().map(|o| disk_usage(&o.path)).collect();
+    for (orphan, size) in orphans.iter().zip(&sizes) {
+        println!(
+            "would remove {} ({}; {})",
+            orphan.path.display(),
+            format_bytes(*size as f64),
+            orphan.reason
+        );
+    }
+    println!(
+        "would free {}",
+        format_bytes(sizes.iter().sum::<u64>() as f64)
+    );
+    if dry_run {
+        return Ok(());
+    }
+    if !yes && !confirm(orphans.len())? {
+        println!("nothing removed");
+        return Ok(());
+    }
+
+    let mut freed = 0;
+    for (orphan, size) in orphans.iter().zip(sizes) {
+        if let Err(e) = orphan.remove() {
+            println!("could not remove {}: {}", orphan.path.display(), e);
+            continue;
+        }
+        freed += size;
+        println!("removed {}", orphan.path.display());
+    }
+    println!("freed {}", format_bytes(freed as f64));
+    Ok(())
+}
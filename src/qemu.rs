@@ -0,0 +1,251 @@
+//! Runs machines under QEMU, for guests Virtualization.framework can't run.
+//!
+//! The guest gets the same virtio devices as it would under the framework,
+//! so a box boots unchanged, except that networking is QEMU's user-mode
+//! NAT: the guest isn't reachable from the host and never shows up in the
+//! host's DHCP leases, so TCP readiness probes can't find it.
+
+use crate::backend::{Backend, Exit};
+use crate::config::{BootFrom, Config, ResolvedBoot};
+use crate::console::Console;
+use crate::machine::Machine;
+use crate::network;
+use crate::vm::VmError;
+use libc::pid_t;
+use std::env::consts::ARCH;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How long a freshly spawned QEMU has to fail before we call it started.
+const START_GRACE: Duration = Duration::from_millis(500);
+
+fn error(description: String) -> VmError {
+    VmError {
+        domain: "qemu".to_string(),
+        code: 0,
+        description,
+    }
+}
+
+pub struct Qemu {
+    command: Mutex<Command>,
+    child: Mutex<Option<Child>>,
+    monitor: PathBuf,
+}
+
+/// The host's hypervisor, for QEMU: HVF on macOS, KVM on Linux where
+/// `/dev/kvm` can be opened. None where there's neither, leaving TCG.
+fn accelerator() -> Option<&'static str> {
+    if cfg!(target_os = "macos") {
+        return Some("hvf");
+    }
+    let kvm = OpenOptions::new().read(true).write(true).open("/dev/kvm");
+    (cfg!(target_os = "linux") && kvm.is_ok()).then_some("kvm")
+}
+
+impl Qemu {
+    pub fn new(
+        config: &Config,
+        arch: &str,
+        boot: &ResolvedBoot,
+        cpu_count: usize,
+        memory_size: usize,
+        console: &Console,
+        machine: &Machine,
+    ) -> Qemu {
+        let binary = config
+            .qemu
+            .binary
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("qemu-system-{}", arch)));
+        let monitor = machine.dir.join("qemu-monitor.sock");
+
+        let mut command = Command::new(binary);
+        command
+            .args(["-nodefaults", "-no-user-config", "-display", "none"])
+            .args(["-machine", if arch == "x86_64" { "q35" } else { "virt" }]);
+        // Hardware acceleration only works for guests of the host's own
+        // architecture; anything else is emulated.
+        match accelerator().filter(|_| arch == ARCH) {
+            Some(accel) => command.args(["-accel", accel, "-cpu", "host"]),
+            None => command.args(["-accel", "tcg", "-cpu", "max"]),
+        };
+        command
+            .args(["-smp", &cpu_count.to_string()])
+            .args(["-m", &format!("{}M", memory_size / (1024 * 1024))]);
+        match (&boot.kernel, &boot.initrd) {
+            (Some(kernel), Some(initrd)) => {
+                command
+                    .arg("-kernel")
+                    .arg(kernel)
+                    .arg("-initrd")
+                    .arg(initrd)
+                    .args(["-append", &boot.command_line]);
+            }
+            // virt has no firmware of its own; QEMU ships EDK2's. q35's
+            // SeaBIOS boots CDs and disks as it is.
+            _ if arch != "x86_64" => {
+                command.args(["-bios", "edk2-aarch64-code.fd"]);
+            }
+            _ => {}
+        }
+        // Boot order is by bootindex, lowest first.
+        let cdrom_first = boot.from == BootFrom::Cdrom;
+        let disk_index = |i: usize| i + usize::from(cdrom_first);
+        for (i, disk) in boot.disks.iter().enumerate() {
+            command
+                .arg("-drive")
+                .arg(format!(
+                    "file={},if=none,id=disk{},format=raw",
+                    disk.display().to_string().replace(',', ",,"),
+                    i
+                ))
+                .arg("-device")
+                .arg(format!(
+                    "virtio-blk-pci,drive=disk{},bootindex={}",
+                    i,
+                    disk_index(i)
+                ));
+        }
+        if let Some(cdrom) = &boot.cdrom {
+            let index = if cdrom_first { 0 } else { boot.disks.len() };
+            command
+                .args(["-device", "virtio-scsi-pci,id=scsi0"])
+                .arg("-drive")
+                .arg(format!(
+                    "file={},if=none,id=cd0,media=cdrom,readonly=on",
+                    cdrom.display().to_string().replace(',', ",,")
+                ))
+                .arg("-device")
+                .arg(format!("scsi-cd,bus=scsi0.0,drive=cd0,bootindex={}", index));
+        }
+        if let Some(ignition) = &boot.ignition {
+            // Fedora CoreOS and Flatcar look under different names.
+            for name in ["opt/com.coreos/config", "opt/org.flatcar-linux/config"] {
+                command.arg("-fw_cfg").arg(format!(
+                    "name={},file={}",
+                    name,
+                    ignition.display().to_string().replace(',', ",,")
+                ));
+            }
+        }
+        if let Some(seed) = &boot.seed {
+            command.arg("-drive").arg(format!(
+                "file={},if=virtio,format=raw,readonly=on",
+                seed.display().to_string().replace(',', ",,")
+            ));
+        }
+        let mut nic = format!(
+            "virtio-net-pci,netdev=net0,mac={}",
+            network::mac_address(machine)
+        );
+        if let Some(mtu) = config.nic.mtu {
+            nic.push_str(&format!(",host_mtu={}", mtu));
+        }
+        if config.nic.checksum_offload == Some(false) {
+            nic.push_str(",csum=off,guest_csum=off");
+        }
+        if config.nic.tso == Some(false) {
+            nic.push_str(",host_tso4=off,host_tso6=off,guest_tso4=off,guest_tso6=off");
+        }
+        command
+            .args(["-netdev", "user,id=net0"])
+            .args(["-device", &nic])
+            .args(["-device", "virtio-rng-pci"])
+            .args(["-device", "virtio-balloon-pci"])
+            // hvc0, as with the framework's virtio console.
+            .args(["-device", "virtio-serial-pci"])
+            .args(["-chardev", "stdio,id=console,signal=off"])
+            .args(["-device", "virtconsole,chardev=console"])
+            .arg("-monitor")
+            .arg(format!("unix:{},server=on,wait=off", monitor.display()))
+            .stdin(console.reader())
+            .stdout(console.writer());
+
+        Qemu {
+            command: Mutex::new(command),
+            child: Mutex::new(None),
+            monitor,
+        }
+    }
+}
+
+impl Backend for Qemu {
+    fn start(&self) -> Result<(), VmError> {
+        let mut child = self
+            .command
+            .lock()
+            .unwrap()
+            .spawn()
+            .map_err(|e| error(format!("could not run QEMU: {}", e)))?;
+
+        thread::sleep(START_GRACE);
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(error(format!("QEMU exited during startup ({})", status)));
+        }
+        *self.child.lock().unwrap() = Some(child);
+        Ok(())
+    }
+
+    fn poll(&self) -> (&'static str, Option<Exit>) {
+        let mut child = self.child.lock().unwrap();
+        match child.as_mut().map(|c| c.try_wait()) {
+            None => ("stopped", Some(Exit::Stopped)),
+            Some(Ok(None)) => ("running", None),
+            Some(Ok(Some(status))) if status.success() => ("stopped", Some(Exit::Stopped)),
+            Some(_) => ("error", Some(Exit::Error)),
+        }
+    }
+
+    fn balloon_target(&self) -> Option<u64> {
+        None
+    }
+
+    fn processes(&self) -> Vec<pid_t> {
+        match self.child.lock().unwrap().as_ref() {
+            Some(child) => vec![child.id() as pid_t],
+            None => Vec::new(),
+        }
+    }
+
+    fn request_stop(&self) -> Result<(), VmError> {
+        let mut monitor = UnixStream::connect(&self.monitor)
+            .map_err(|e| error(format!("could not reach the QEMU monitor: {}", e)))?;
+        monitor
+            .write_all(b"system_powerdown\n")
+            .map_err(|e| error(format!("could not ask QEMU to stop: {}", e)))
+    }
+
+    fn force_stop(&self) -> Result<(), VmError> {
+        let mut monitor = UnixStream::connect(&self.monitor)
+            .map_err(|e| error(format!("could not reach the QEMU monitor: {}", e)))?;
+        monitor
+            .write_all(b"quit\n")
+            .map_err(|e| error(format!("could not stop QEMU: {}", e)))
+    }
+
+    fn set_balloon_target(&self, bytes: u64) -> Result<(), VmError> {
+        let mut monitor = UnixStream::connect(&self.monitor)
+            .map_err(|e| error(format!("could not reach the QEMU monitor: {}", e)))?;
+        monitor
+            .write_all(format!("balloon {}\n", bytes >> 20).as_bytes())
+            .map_err(|e| error(format!("could not resize the balloon: {}", e)))
+    }
+}
+
+/// Unlike a framework machine, QEMU would outlive us, so take it down with
+/// this boot.
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.get_mut().unwrap().as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::config::Readiness;
+use crate::console::ConsoleOutput;
+use crate::network;
+use crate::phases;
+use regex::bytes::Regex;
+use std::error;
+use std::fmt;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many console lines to show when a probe fails.
+const DIAGNOSTIC_LINES: usize = 20;
+
+#[derive(Debug)]
+pub struct ReadinessError {
+    reason: String,
+    console: Vec<String>,
+    phase: Option<Option<&'static str>>,
+}
+
+impl fmt::Display for ReadinessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "machine did not become ready: {}", self.reason)?;
+        match (self.phase, self.phase.and_then(phases::next)) {
+            (Some(Some(phase)), Some(next)) => write!(
+                f,
+                "\nboot got as far as \"{}\" but never reached \"{}\"",
+                phase, next
+            )?,
+            (Some(None), Some(next)) => write!(f, "\nboot never reached \"{}\"", next)?,
+            _ => {}
+        }
+        if !self.console.is_empty() {
+            write!(f, "\nlast console output:")?;
+            for line in &self.console {
+                write!(f, "\n    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ReadinessError {}
+
+impl ReadinessError {
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Notes how far the boot got, for a more pointed message.
+    pub fn at_phase(mut self, phase: Option<&'static str>) -> ReadinessError {
+        self.phase = Some(phase);
+        self
+    }
+}
+
+/// A compiled readiness probe. Compiling happens before boot so a bad
+/// pattern is reported straight away rather than after the timeout.
+pub struct Probe {
+    console: Option<Regex>,
+    tcp_port: Option<u16>,
+    timeout: Duration,
+}
+
+impl Probe {
+    pub fn new(readiness: &Readiness) -> Result<Probe, regex::Error> {
+        Ok(Probe {
+            console: readiness.console.as_deref().map(Regex::new).transpose()?,
+            tcp_port: readiness.tcp_port,
+            timeout: Duration::from_secs(readiness.timeout),
+        })
+    }
+
+    /// Waits for every configured check to pass, console first. Only
+    /// console output past `booted_at` counts, so an earlier boot's can't
+    /// pass for this one's.
+    pub fn wait(
+        &self,
+        console: &ConsoleOutput,
+        booted_at: usize,
+        mac: &str,
+    ) -> Result<(), ReadinessError> {
+        let started = Instant::now();
+        let fail = |reason: String| ReadinessError {
+            reason,
+            console: console.tail(DIAGNOSTIC_LINES),
+            phase: None,
+        };
+
+        if let Some(pattern) = &self.console {
+            if console.expect(pattern, booted_at, self.timeout).is_none() {
+                return Err(fail(format!(
+                    "console never printed /{}/ within {}s",
+                    pattern,
+                    self.timeout.as_secs()
+                )));
+            }
+        }
+
+        if let Some(port) = self.tcp_port {
+            let mut ip = None;
+            while started.elapsed() < self.timeout {
+                ip = network::guest_ip(mac);
+                if let Some(ip) = ip {
+                    let addr = SocketAddr::from((ip, port));
+                    if TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok() {
+                        return Ok(());
+                    }
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+
+            return Err(fail(match ip {
+                Some(ip) => format!(
+                    "port {} on {} did not accept connections within {}s",
+                    port,
+                    ip,
+                    self.timeout.as_secs()
+                ),
+                None => format!(
+                    "guest never obtained a DHCP lease for {} within {}s",
+                    mac,
+                    self.timeout.as_secs()
+                ),
+            }));
+        }
+
+        Ok(())
+    }
+}
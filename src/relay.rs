@@ -0,0 +1,350 @@
+//! Relaying host ports to the guest.
+//!
+//! Each process relaying ports for a machine registers what it's serving
+//! in `forwards/<pid>.json` under the machine's directory, so `vagrantx
+//! port` can list every forward without talking to anything. A registration
+//! whose process has gone is ignored and cleaned up.
+//!
+//! `up` relays the config's `forwarded_ports`; `vagrantx tunnel` relays ad
+//! hoc ones from its own process, for as long as it runs.
+
+use crate::backend;
+use crate::config::{self, BackendKind, PortForward};
+use crate::machine::Machine;
+use crate::network;
+use crate::output;
+use libc::{
+    kill, pthread_sigmask, sigaddset, sigemptyset, sigset_t, sigwait, ESRCH, SIGINT, SIGTERM,
+    SIG_BLOCK,
+};
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// One forward as a registration records it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardState {
+    pub address: String,
+    pub guest_port: u16,
+    /// Connections currently open.
+    pub active: u64,
+    /// Connections ever accepted.
+    pub total: u64,
+    /// Bytes relayed either way, recorded about once a second.
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+/// The forwards one process is serving.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Registration {
+    pub pid: u32,
+    /// `up` for the config's forwards, `tunnel` for ad hoc ones.
+    pub source: String,
+    pub forwards: Vec<ForwardState>,
+}
+
+fn registry_dir(machine: &Machine) -> PathBuf {
+    machine.dir.join("forwards")
+}
+
+/// Relays ports to one machine for as long as it's kept.
+pub struct Relay {
+    path: PathBuf,
+    registration: Arc<Mutex<Registration>>,
+    mac: String,
+}
+
+impl Relay {
+    pub fn new(machine: &Machine, mac: &str, source: &str) -> Relay {
+        let pid = process::id();
+        Relay {
+            path: registry_dir(machine).join(format!("{}.json", pid)),
+            registration: Arc::new(Mutex::new(Registration {
+                pid,
+                source: source.to_string(),
+                forwards: Vec::new(),
+            })),
+            mac: mac.to_string(),
+        }
+    }
+
+    /// Starts relaying `forward`. The guest's address is looked up for each
+    /// connection, so a guest that comes back with a new one is still
+    /// reached.
+    pub fn add(&self, forward: &PortForward) -> io::Result<()> {
+        let listener = TcpListener::bind((forward.address.as_str(), forward.host))?;
+        let index = {
+            let mut registration = self.registration.lock().unwrap();
+            registration.forwards.push(ForwardState {
+                address: listener.local_addr()?.to_string(),
+                guest_port: forward.guest,
+                active: 0,
+                total: 0,
+                bytes: 0,
+            });
+            registration.forwards.len() - 1
+        };
+        save(&self.path, &self.registration);
+
+        let bytes = Arc::new(AtomicU64::new(0));
+        {
+            let path = self.path.clone();
+            let registration = self.registration.clone();
+            let bytes = bytes.clone();
+            thread::spawn(move || {
+                let mut recorded = 0;
+                // The file goes when the relay is dropped.
+                while path.exists() {
+                    thread::sleep(Duration::from_secs(1));
+                    let now = bytes.load(Ordering::Relaxed);
+                    if now != recorded {
+                        update(&path, &registration, |f| f[index].bytes = now);
+                        recorded = now;
+                    }
+                }
+            });
+        }
+
+        let path = self.path.clone();
+        let registration = self.registration.clone();
+        let mac = self.mac.clone();
+        let guest_port = forward.guest;
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let ip = match network::guest_ip(&mac) {
+                    Some(ip) => ip,
+                    None => continue,
+                };
+                let guest = match TcpStream::connect_timeout(
+                    &SocketAddr::from((ip, guest_port)),
+                    Duration::from_secs(5),
+                ) {
+                    Ok(guest) => guest,
+                    Err(_) => continue,
+                };
+
+                update(&path, &registration, |f| {
+                    f[index].active += 1;
+                    f[index].total += 1;
+                });
+                let path = path.clone();
+                let registration = registration.clone();
+                let bytes = bytes.clone();
+                thread::spawn(move || {
+                    pipe(client, guest, &bytes);
+                    update(&path, &registration, |f| f[index].active -= 1);
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn save(path: &PathBuf, registration: &Mutex<Registration>) {
+    let registration = registration.lock().unwrap();
+    let result = fs::create_dir_all(path.parent().unwrap()).and_then(|()| {
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(&*registration)?)?;
+        fs::rename(&partial, path)
+    });
+    if let Err(e) = result {
+        output::warning(&format!("could not record forwards: {}", e));
+    }
+}
+
+fn update(
+    path: &PathBuf,
+    registration: &Mutex<Registration>,
+    change: impl FnOnce(&mut Vec<ForwardState>),
+) {
+    change(&mut registration.lock().unwrap().forwards);
+    save(path, registration);
+}
+
+/// Copies from `from` to `to` until `from` is done, counting into `bytes`.
+fn copy(mut from: TcpStream, mut to: TcpStream, bytes: &AtomicU64) {
+    let mut buf = [0u8; 16384];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if to.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+                bytes.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        }
+    }
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+/// Copies bytes both ways until both sides are done.
+fn pipe(client: TcpStream, guest: TcpStream, bytes: &Arc<AtomicU64>) {
+    let (client_reader, guest_reader) = match (client.try_clone(), guest.try_clone()) {
+        (Ok(c), Ok(g)) => (c, g),
+        _ => return,
+    };
+    let upstream_bytes = bytes.clone();
+    let upstream = thread::spawn(move || copy(client_reader, guest, &upstream_bytes));
+    copy(guest_reader, client, bytes);
+    let _ = upstream.join();
+}
+
+/// Every live registration for `machine`.
+pub fn registrations(machine: &Machine) -> Result<Vec<Registration>, Box<dyn error::Error>> {
+    let dir = registry_dir(machine);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut registrations = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        let registration: Registration = match fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+        {
+            Some(registration) => registration,
+            None => continue,
+        };
+        if unsafe { kill(registration.pid as i32, 0) } != 0
+            && io::Error::last_os_error().raw_os_error() == Some(ESRCH)
+        {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        registrations.push(registration);
+    }
+    registrations.sort_by_key(|r| r.pid);
+    Ok(registrations)
+}
+
+/// Prints every forward to `machine`, as a table or as JSON.
+pub fn list(machine: &Machine, json: bool) -> Result<(), Box<dyn error::Error>> {
+    let registrations = registrations(machine)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&registrations)?);
+        return Ok(());
+    }
+    if registrations.iter().all(|r| r.forwards.is_empty()) {
+        println!("no ports are forwarded to {}", machine.name);
+        return Ok(());
+    }
+    println!(
+        "{:<24} {:<6} {:<8} {:>6} {:>6}",
+        "LISTEN", "GUEST", "SOURCE", "ACTIVE", "TOTAL"
+    );
+    for registration in &registrations {
+        for forward in &registration.forwards {
+            println!(
+                "{:<24} {:<6} {:<8} {:>6} {:>6}",
+                forward.address,
+                forward.guest_port,
+                registration.source,
+                forward.active,
+                forward.total
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Blocks SIGINT and SIGTERM for this thread and any it goes on to start,
+/// so they queue for `wait_for_termination` rather than killing the
+/// process with the relay still registered. Has to come before the first
+/// thread is started, which would otherwise take them.
+fn block_termination() -> sigset_t {
+    unsafe {
+        let mut signals = MaybeUninit::uninit();
+        sigemptyset(signals.as_mut_ptr());
+        let mut signals = signals.assume_init();
+        sigaddset(&mut signals, SIGINT);
+        sigaddset(&mut signals, SIGTERM);
+        pthread_sigmask(SIG_BLOCK, &signals, ptr::null_mut());
+        signals
+    }
+}
+
+/// Blocks until one of the `signals` `block_termination` blocked arrives.
+fn wait_for_termination(signals: &sigset_t) {
+    let mut signal = 0;
+    unsafe { sigwait(signals, &mut signal) };
+}
+
+/// Forwards `forwards` to the machine `config_file` defines until
+/// interrupted or closed with `tunnel close`.
+pub fn tunnel(config_file: &Path, forwards: &[PortForward]) -> Result<(), Box<dyn error::Error>> {
+    let signals = block_termination();
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    if backend::select(&config)? == BackendKind::Qemu {
+        return Err("machines run under QEMU are not reachable over the network".into());
+    }
+    let mac = network::saved_mac_address(&machine)
+        .ok_or_else(|| format!("{} is not running", machine.name))?;
+    if network::guest_ip(&mac).is_none() {
+        return Err(format!("{} has no IP address; is it running?", machine.name).into());
+    }
+
+    let relay = Relay::new(&machine, &mac, "tunnel");
+    for forward in forwards {
+        relay
+            .add(forward)
+            .map_err(|e| format!("could not forward port {}: {}", forward.host, e))?;
+    }
+    for forward in &relay.registration.lock().unwrap().forwards {
+        println!(
+            "forwarding {} to port {}",
+            forward.address, forward.guest_port
+        );
+    }
+    println!("press Ctrl-C to close");
+    wait_for_termination(&signals);
+    Ok(())
+}
+
+/// Closes the tunnels to `machine` serving any of `ports`, or all of them if
+/// `ports` is empty. A tunnel serving several ports closes all of them.
+pub fn close(machine: &Machine, ports: &[u16]) -> Result<(), Box<dyn error::Error>> {
+    let mut closed = 0;
+    for registration in registrations(machine)? {
+        if registration.source != "tunnel" {
+            continue;
+        }
+        let serves = |port: &u16| {
+            registration.forwards.iter().any(|f| {
+                f.address
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|a| a.port() == *port)
+            })
+        };
+        if ports.is_empty() || ports.iter().any(serves) {
+            unsafe { kill(registration.pid as i32, SIGTERM) };
+            closed += 1;
+        }
+    }
+    if closed == 0 {
+        return Err(format!("no matching tunnels to {}", machine.name).into());
+    }
+    println!("closed {} tunnel(s)", closed);
+    Ok(())
+}
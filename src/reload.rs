@@ -0,0 +1,186 @@
+//! `vagrantx reload`: bringing a running machine in line with its edited
+//! config.
+//!
+//! `up` records the config it started the machine with as `running.json`.
+//! Reloading shows what's changed since, and applies what it can while the
+//! machine runs: a memory size no bigger than it booted with, through the
+//! balloon device, and synced folders, by syncing them again. Anything else has `up` shut the
+//! guest down and start over with the new config.
+
+use crate::config::{self, Config};
+use crate::control;
+use crate::machine::Machine;
+use crate::output;
+use crate::profiles;
+use crate::rsync;
+use crate::stats;
+use crate::status;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings that are only read when they're used, so they never need the
+/// machine restarted.
+const READ_WHEN_USED: &[&str] = &[
+    "halt_timeout",
+    "depends_on",
+    "provisioners",
+    "snapshot_before_provision",
+    "profile",
+    "profiles",
+];
+
+#[derive(Serialize, Deserialize)]
+struct Running {
+    /// The `--profile` given to `up`, if any.
+    profile: Option<String>,
+    config: Value,
+}
+
+fn running_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("running.json")
+}
+
+/// Records the config `up` is starting the machine with.
+pub fn record(machine: &Machine, config: &Config, profile: Option<&str>) {
+    let result = serde_json::to_value(config)
+        .map_err(|e| e.to_string())
+        .and_then(|config| {
+            let running = Running {
+                profile: profile.map(str::to_string),
+                config,
+            };
+            fs::write(
+                running_path(machine),
+                serde_json::to_vec_pretty(&running).unwrap(),
+            )
+            .map_err(|e| e.to_string())
+        });
+    if let Err(e) = result {
+        output::warning(&format!(
+            "vagrantx reload will not be able to tell what changed: {}",
+            e
+        ));
+    }
+}
+
+/// One setting that differs, by its path in the config.
+struct Change {
+    path: String,
+    old: Option<Value>,
+    new: Option<Value>,
+}
+
+impl Change {
+    /// The top-level setting it's part of.
+    fn key(&self) -> &str {
+        self.path.split(['.', '[']).next().unwrap_or(&self.path)
+    }
+
+    fn describe(&self) -> String {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => format!("~ {}: {} -> {}", self.path, old, new),
+            (None, Some(new)) => format!("+ {}: {}", self.path, new),
+            (Some(old), None) => format!("- {}: {}", self.path, old),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// Collects the differences between `old` and `new` under `path`. Objects
+/// and arrays are compared member by member, so an added disk shows up as
+/// one addition.
+fn diff(path: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                diff(&child(key), old.get(key), new.get(key), changes);
+            }
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for i in 0..old.len().max(new.len()) {
+                diff(&format!("{}[{}]", path, i), old.get(i), new.get(i), changes);
+            }
+        }
+        (old, new) if old == new => {}
+        // A setting that's null on one side is as good as missing.
+        (Some(Value::Null), None) | (None, Some(Value::Null)) => {}
+        (old, new) => changes.push(Change {
+            path: path.to_string(),
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+    }
+}
+
+/// Shows how the config of the machine `config_file` defines has changed
+/// since `up` started it, and unless `dry_run`, applies the changes.
+pub fn reload(config_file: &Path, dry_run: bool) -> Result<(), Box<dyn error::Error>> {
+    let mut config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let published = status::published(&machine)
+        .ok_or_else(|| format!("{} is not running; vagrantx up starts it", machine.name))?;
+    let running: Running = serde_json::from_slice(&fs::read(running_path(&machine))?)
+        .map_err(|e| format!("could not read {}: {}", running_path(&machine).display(), e))?;
+    profiles::apply(&mut config, running.profile.as_deref())?;
+
+    let mut changes = Vec::new();
+    diff(
+        "",
+        Some(&running.config),
+        Some(&serde_json::to_value(&config)?),
+        &mut changes,
+    );
+    if changes.is_empty() {
+        output::message(&format!("{} is already running its config", machine.name));
+        return Ok(());
+    }
+    for change in &changes {
+        output::message(&change.describe());
+    }
+
+    // The balloon can only hand back memory the machine booted with.
+    let balloon = config.memory_size <= published.memory_size;
+    let restart = changes.iter().any(|change| match change.key() {
+        "memory_size" => !balloon,
+        "synced_folders" => false,
+        key => !READ_WHEN_USED.contains(&key),
+    });
+    if dry_run {
+        return Ok(());
+    }
+
+    if restart {
+        output::message(&format!("restarting {} to apply the changes", machine.name));
+        control::request(&machine, "reload")?;
+        return Ok(());
+    }
+    if changes.iter().any(|change| change.key() == "memory_size") {
+        control::request(&machine, &format!("balloon {}", config.memory_size))?;
+        output::message(&format!(
+            "gave {} {} of memory",
+            machine.name,
+            stats::format_bytes(config.memory_size as f64)
+        ));
+    }
+    if changes
+        .iter()
+        .any(|change| change.key() == "synced_folders")
+    {
+        rsync::sync_all(&config, &machine)?;
+    }
+    // What's running now is the new config.
+    record(&machine, &config, running.profile.as_deref());
+    Ok(())
+}
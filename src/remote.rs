@@ -0,0 +1,104 @@
+//! Runs commands against another Mac over SSH.
+//!
+//! The project directory is copied to `~/.vagrantx/remote/<dir>-<hash>` on
+//! the remote host with rsync, leaving its `.vagrantx` state alone, and the
+//! same command line is then run there under `ssh -t` with the config path
+//! rewritten. The remote side needs vagrantx on the `PATH` that ssh's
+//! non-interactive shell sees.
+
+use crate::paths;
+use std::env;
+use std::error;
+use std::ffi::OsString;
+use std::fs::canonicalize;
+use std::path::Path;
+use std::process::Command;
+
+/// Quotes `s` for the POSIX shell ssh runs the remote command in.
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@,".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Our own arguments, minus `--host`.
+fn forwarded_args() -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut iter = env::args_os().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--host" {
+            iter.next();
+        } else if !arg.to_string_lossy().starts_with("--host=") {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// Copies the project holding `config_file` to `host`, returning the
+/// config's path there, relative to the remote home directory.
+fn sync(host: &str, config_file: &Path) -> Result<String, Box<dyn error::Error>> {
+    let config_file = canonicalize(config_file)?;
+    let project = config_file.parent().unwrap();
+    let hash = paths::fnv1a(project.as_os_str().to_string_lossy().as_bytes());
+    let remote_dir = format!(
+        ".vagrantx/remote/{}-{:08x}",
+        project
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        hash as u32
+    );
+
+    let status = Command::new("rsync")
+        .arg("-az")
+        .arg("--delete")
+        .arg("--exclude=/.vagrantx/")
+        .arg(format!(
+            "--rsync-path=mkdir -p {} && rsync",
+            shell_quote(&remote_dir)
+        ))
+        .arg(format!("{}/", project.display()))
+        .arg(format!("{}:{}/", host, remote_dir))
+        .status()?;
+    if !status.success() {
+        return Err(format!("could not copy {} to {}", project.display(), host).into());
+    }
+
+    Ok(format!(
+        "{}/{}",
+        remote_dir,
+        config_file.file_name().unwrap().to_string_lossy()
+    ))
+}
+
+/// Runs this invocation on `host` instead, returning the remote exit code.
+/// `config_file` is the command's config, if it takes one.
+pub fn run(host: &str, config_file: Option<&Path>) -> Result<i32, Box<dyn error::Error>> {
+    let remote_config = match config_file {
+        Some(config_file) => Some(sync(host, config_file)?),
+        None => None,
+    };
+
+    let mut command = vec!["vagrantx".to_string()];
+    for arg in forwarded_args() {
+        match (&remote_config, config_file) {
+            (Some(remote), Some(local)) if Path::new(&arg) == local => {
+                command.push(shell_quote(remote))
+            }
+            _ => command.push(shell_quote(&arg.to_string_lossy())),
+        }
+    }
+
+    let status = Command::new("ssh")
+        .arg("-t")
+        .arg(host)
+        .arg(command.join(" "))
+        .status()?;
+    Ok(status.code().unwrap_or(1))
+}
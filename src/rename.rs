@@ -0,0 +1,126 @@
+//! Giving a machine a new name without losing its state.
+//!
+//! The name appears in the machine directory's path, the config, the
+//! launchd agent's label and the tags on its `/etc/hosts` entries, so each
+//! of those moves with it. The directory is moved first and moved back if
+//! the config can't be rewritten, so a failure leaves the machine as it was.
+
+use crate::autostart;
+use crate::config;
+use crate::events::EventLog;
+use crate::global;
+use crate::hosts;
+use crate::knownhosts;
+use crate::lock;
+use crate::machine::Machine;
+use regex::Regex;
+use std::error;
+use std::fs;
+use std::path::Path;
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// `contents` with the config's top-level `name` set to `new`. Everything
+/// else, comments included, is left alone.
+fn set_name(contents: &str, old: Option<&str>, new: &str) -> Result<String, String> {
+    let quoted = serde_json::to_string(new).unwrap();
+    if let Some(old) = old {
+        let pattern = format!(
+            r#""name"(\s*):(\s*){}"#,
+            regex::escape(&serde_json::to_string(old).unwrap())
+        );
+        let re = Regex::new(&pattern).unwrap();
+        if re.find_iter(contents).count() != 1 {
+            return Err("could not find the machine's name in the config to change".to_string());
+        }
+        return Ok(re
+            .replace(contents, |c: &regex::Captures| {
+                format!(r#""name"{}:{}{}"#, &c[1], &c[2], quoted)
+            })
+            .into_owned());
+    }
+    let brace = contents
+        .find('{')
+        .ok_or("the config is not a JSON object")?;
+    Ok(format!(
+        "{}\n  \"name\": {},{}",
+        &contents[..=brace],
+        quoted,
+        &contents[brace + 1..]
+    ))
+}
+
+pub fn rename(config_file: &Path, new_name: &str) -> Result<(), Box<dyn error::Error>> {
+    if !valid_name(new_name) {
+        return Err(format!(
+            "{} is not a valid machine name; use letters, numbers, '-', '_' and '.'",
+            new_name
+        )
+        .into());
+    }
+    if global::name_of(config_file).is_some() {
+        return Err(
+            "a machine made with up --name is named by its directory; make a new one instead"
+                .into(),
+        );
+    }
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let old = Machine::new(config_file, config.name.as_deref());
+    let new = Machine::new(config_file, Some(new_name));
+    if new.name == old.name {
+        return Ok(());
+    }
+    if new.dir.exists() {
+        return Err(format!("there is already a machine named {}", new.name).into());
+    }
+
+    // Held on the lock file inside the directory, so it moves with it.
+    let _lock = lock::acquire(&old, "rename", false)?;
+    let contents = fs::read_to_string(config_file)?;
+    let renamed = set_name(&contents, config.name.as_deref(), &new.name)?;
+
+    // Entries are only left behind by a machine that didn't stop cleanly,
+    // and the tag depends on the directory, which is about to move.
+    if !config.hostnames.is_empty() {
+        hosts::update(&old, &config.hostnames, None)?;
+    }
+    let autostart = autostart::enabled(config_file);
+    if autostart {
+        autostart::disable(config_file)?;
+    }
+
+    if old.dir.exists() {
+        fs::create_dir_all(new.dir.parent().unwrap())?;
+        fs::rename(&old.dir, &new.dir)?;
+    }
+    let partial = config_file.with_extension("partial");
+    if let Err(e) = fs::write(&partial, &renamed).and_then(|()| fs::rename(&partial, config_file)) {
+        let _ = fs::remove_file(&partial);
+        if new.dir.exists() {
+            fs::rename(&new.dir, &old.dir)?;
+        }
+        if autostart {
+            autostart::enable(config_file)?;
+        }
+        return Err(format!("could not update {}: {}", config_file.display(), e).into());
+    }
+
+    if autostart {
+        autostart::enable(config_file)?;
+    }
+    // Keys are recorded by name. A seeded one is recorded again on the next
+    // up; any other is learned again.
+    let _ = knownhosts::forget(&old);
+    if new.dir.exists() {
+        EventLog::new(&new).record("renamed", Some(format!("from {}", old.name)));
+    }
+    println!("renamed {} to {}", old.name, new.name);
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Checks requested CPU and memory against what Virtualization.framework
+//! allows and what the host actually has, so a bad value fails with a
+//! specific message rather than an opaque validation error.
+
+use crate::output;
+use libc::{c_void, sysctlbyname};
+use objc::{class, msg_send, sel, sel_impl};
+use std::error;
+use std::fmt;
+use std::mem;
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct ResourceError(Vec<String>);
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid resources: {}", self.0.join("; "))
+    }
+}
+
+impl error::Error for ResourceError {}
+
+impl ResourceError {
+    pub fn problems(&self) -> &[String] {
+        &self.0
+    }
+}
+
+pub struct Limits {
+    pub min_cpu_count: usize,
+    pub max_cpu_count: usize,
+    pub min_memory_size: u64,
+    pub max_memory_size: u64,
+}
+
+pub fn sysctl_u64(name: &str) -> Option<u64> {
+    let name = std::ffi::CString::new(name).unwrap();
+    let mut value: u64 = 0;
+    let mut size = mem::size_of::<u64>();
+    let r = unsafe {
+        sysctlbyname(
+            name.as_ptr(),
+            &mut value as *mut u64 as *mut c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if r != 0 {
+        return None;
+    }
+    // Integer sysctls such as hw.ncpu are only 32 bits wide.
+    if size == mem::size_of::<u32>() {
+        value &= u32::MAX as u64;
+    }
+    Some(value)
+}
+
+pub fn host_cpu_count() -> Option<usize> {
+    sysctl_u64("hw.ncpu").map(|n| n as usize)
+}
+
+pub fn host_memory_size() -> Option<u64> {
+    sysctl_u64("hw.memsize")
+}
+
+/// The framework's allowed range, narrowed to what the host can back.
+pub fn limits() -> Limits {
+    let (min_cpu_count, max_cpu_count, min_memory_size, max_memory_size) = unsafe {
+        let class = class!(VZVirtualMachineConfiguration);
+        let min_cpu: usize = msg_send![class, minimumAllowedCPUCount];
+        let max_cpu: usize = msg_send![class, maximumAllowedCPUCount];
+        let min_mem: u64 = msg_send![class, minimumAllowedMemorySize];
+        let max_mem: u64 = msg_send![class, maximumAllowedMemorySize];
+        (min_cpu, max_cpu, min_mem, max_mem)
+    };
+
+    Limits {
+        min_cpu_count,
+        max_cpu_count: host_cpu_count().map_or(max_cpu_count, |n| n.min(max_cpu_count)),
+        min_memory_size,
+        max_memory_size: host_memory_size().map_or(max_memory_size, |n| n.min(max_memory_size)),
+    }
+}
+
+/// What the host can back, for hypervisors with no limits of their own.
+pub fn host_limits() -> Limits {
+    Limits {
+        min_cpu_count: 1,
+        max_cpu_count: host_cpu_count().unwrap_or(usize::MAX),
+        min_memory_size: 128 * MIB,
+        max_memory_size: host_memory_size().unwrap_or(u64::MAX),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes.is_multiple_of(1024 * MIB) {
+        format!("{} GiB", bytes / (1024 * MIB))
+    } else {
+        format!("{} MiB", bytes / MIB)
+    }
+}
+
+/// Returns the CPU count and memory size to configure. Out-of-range values
+/// are an error unless `clamp` is set, in which case they're pulled into
+/// range with a warning.
+pub fn check(
+    cpu_count: usize,
+    memory_size: usize,
+    limits: &Limits,
+    clamp: bool,
+) -> Result<(usize, usize), ResourceError> {
+    let mut problems = Vec::new();
+    let mut warn = |message: String| {
+        if clamp {
+            output::warning(&message);
+        } else {
+            problems.push(message);
+        }
+    };
+
+    let mut cpus = cpu_count;
+    if cpus < limits.min_cpu_count {
+        warn(format!(
+            "cpu_count {} is below the minimum of {}",
+            cpus, limits.min_cpu_count
+        ));
+        cpus = limits.min_cpu_count;
+    } else if cpus > limits.max_cpu_count {
+        warn(format!(
+            "cpu_count {} exceeds the {} CPUs available on this host",
+            cpus, limits.max_cpu_count
+        ));
+        cpus = limits.max_cpu_count;
+    }
+
+    let mut memory = memory_size as u64;
+    if !memory.is_multiple_of(MIB) {
+        warn(format!(
+            "memory_size {} is not a multiple of 1 MiB",
+            memory_size
+        ));
+        memory -= memory % MIB;
+    }
+    if memory < limits.min_memory_size {
+        warn(format!(
+            "memory_size {} is below the minimum of {}",
+            format_size(memory),
+            format_size(limits.min_memory_size)
+        ));
+        memory = limits.min_memory_size;
+    } else if memory > limits.max_memory_size {
+        warn(format!(
+            "memory_size {} exceeds the {} available on this host",
+            format_size(memory),
+            format_size(limits.max_memory_size)
+        ));
+        memory = limits.max_memory_size - limits.max_memory_size % MIB;
+    }
+
+    if !problems.is_empty() {
+        return Err(ResourceError(problems));
+    }
+    Ok((cpus, memory as usize))
+}
@@ -0,0 +1,53 @@
+use crate::backend::Exit;
+use crate::config::RestartPolicy;
+use crate::console::ConsoleOutput;
+use regex::bytes::Regex;
+use std::time::Duration;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A guest that stays up this long is considered healthy again, and the
+/// next failure starts the backoff over.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+impl RestartPolicy {
+    pub fn should_restart(&self, exit: &Exit) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => matches!(exit, Exit::Error),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// Exponential delay between restarts of a guest that keeps failing.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            next: INITIAL_BACKOFF,
+        }
+    }
+
+    /// How long to wait before restarting a guest that ran for `uptime`.
+    pub fn delay(&mut self, uptime: Duration) -> Duration {
+        if uptime >= STABLE_UPTIME {
+            self.next = INITIAL_BACKOFF;
+        }
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+/// Whether the guest restarted itself rather than powering off, going by
+/// what the kernel and systemd print on the console as they go down.
+pub fn rebooted(output: &ConsoleOutput, booted_at: usize) -> bool {
+    let pattern =
+        Regex::new(r"reboot: Restarting system|systemd-shutdown\[1\]: Rebooting").unwrap();
+    output.expect(&pattern, booted_at, Duration::ZERO).is_some()
+}
@@ -0,0 +1,141 @@
+//! Synced folders: host directories copied into the guest with rsync over
+//! SSH, by `up` once the guest is ready and by `vagrantx rsync` on demand.
+//!
+//! It's a one-way copy, so changes made in the guest are overwritten on the
+//! next sync. `vagrantx rsync --watch` syncs again whenever FSEvents reports
+//! a change on the host.
+
+use crate::config::{self, Config, SyncedFolder};
+use crate::fsevents;
+use crate::machine::Machine;
+use crate::output;
+use crate::remote::shell_quote;
+use crate::ssh::Session;
+use std::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a guest that's ready on the console has to start answering SSH.
+const SSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for more changes before syncing.
+const LATENCY: Duration = Duration::from_millis(300);
+
+/// A session for syncing, waiting for a machine that's just booted to get
+/// its address.
+fn session(config: &Config, machine: &Machine) -> Result<Session, Box<dyn error::Error>> {
+    let mut session = Session::wait(config, machine, SSH_TIMEOUT)?;
+    session.options.push("BatchMode=yes".to_string());
+    session.options.push("ConnectTimeout=5".to_string());
+    Ok(session)
+}
+
+/// Copies `folder` to the guest, retrying while SSH isn't up yet.
+fn sync(session: &Session, folder: &SyncedFolder) -> Result<(), Box<dyn error::Error>> {
+    if !folder.host.is_dir() {
+        return Err(format!("{} is not a directory", folder.host.display()).into());
+    }
+    let guest = shell_quote(&folder.guest);
+    let mut rsync = Command::new("rsync");
+    rsync
+        .args(["-az", "--delete"])
+        .arg("-e")
+        .arg(session.shell_command())
+        // The guest directory may be somewhere only root can create.
+        .arg(format!(
+            "--rsync-path=sudo -n mkdir -p {} && sudo -n chown {} {} && rsync",
+            guest,
+            shell_quote(&session.username),
+            guest
+        ));
+    for pattern in &folder.exclude {
+        rsync.arg(format!("--exclude={}", pattern));
+    }
+    rsync
+        .arg(format!("{}/", folder.host.display()))
+        .arg(session.remote(&format!("{}/", folder.guest.trim_end_matches('/'))));
+
+    let deadline = Instant::now() + SSH_TIMEOUT;
+    loop {
+        let status = rsync
+            .status()
+            .map_err(|e| format!("could not run rsync: {}", e))?;
+        if status.success() {
+            return Ok(());
+        }
+        // 255 is ssh failing to connect rather than the copy failing.
+        if status.code() != Some(255) || Instant::now() > deadline {
+            return Err(format!(
+                "could not sync {} to {} ({})",
+                folder.host.display(),
+                folder.guest,
+                status
+            )
+            .into());
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+}
+
+/// Copies every synced folder `config` has to the guest.
+pub fn sync_all(config: &Config, machine: &Machine) -> Result<(), Box<dyn error::Error>> {
+    let session = session(config, machine)?;
+    for folder in &config.synced_folders {
+        output::message(&format!(
+            "syncing {} to {}",
+            folder.host.display(),
+            folder.guest
+        ));
+        sync(&session, folder)?;
+    }
+    Ok(())
+}
+
+/// Syncs the folders of the machine `config_file` defines, and with
+/// `watch`, keeps syncing them as they change until interrupted.
+pub fn rsync(config_file: &Path, watch: bool) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    if config.synced_folders.is_empty() {
+        return Err(format!("{} has no synced_folders", config_file.display()).into());
+    }
+    sync_all(&config, &machine)?;
+    if !watch {
+        return Ok(());
+    }
+
+    // FSEvents reports paths with symlinks resolved, e.g. /private/var.
+    let folders: Vec<(PathBuf, SyncedFolder)> = config
+        .synced_folders
+        .iter()
+        .map(|folder| {
+            let dir = fs::canonicalize(&folder.host)
+                .map_err(|e| format!("could not watch {}: {}", folder.host.display(), e))?;
+            Ok((dir, folder.clone()))
+        })
+        .collect::<Result<_, String>>()?;
+    let dirs: Vec<PathBuf> = folders.iter().map(|(dir, _)| dir.clone()).collect();
+    let session = session(&config, &machine)?;
+
+    output::message("watching for changes; press Ctrl-C to stop");
+    fsevents::watch(&dirs, LATENCY, move |paths| {
+        for (dir, folder) in &folders {
+            if !paths.iter().any(|path| path.starts_with(dir)) {
+                continue;
+            }
+            match sync(&session, folder) {
+                Ok(()) => output::message(&format!(
+                    "synced {} to {}",
+                    folder.host.display(),
+                    folder.guest
+                )),
+                // The guest may be restarting; the next change tries again.
+                Err(e) => output::warning(&e.to_string()),
+            }
+        }
+    })?;
+    Ok(())
+}
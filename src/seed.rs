@@ -0,0 +1,189 @@
+//! A per-machine SSH key, authorized in the guest through cloud-init.
+//!
+//! The first `up` generates an ed25519 key in the machine directory. Every
+//! boot then attaches a small read-only ISO labelled `cidata`, which
+//! cloud-init's NoCloud source picks up, authorizing the key for the
+//! default user and for `ssh.username` if that's set, and giving the guest
+//! a host key the project's known_hosts already has. A `static_ip` and a
+//! `nic.mtu` are set up through the seed's network-config. Guests without
+//! cloud-init just ignore the disk.
+
+use crate::boxes;
+use crate::config::{Config, Nic};
+use crate::knownhosts;
+use crate::machine::Machine;
+use crate::network;
+use crate::paths;
+use std::error;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+pub fn key_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("id_ed25519")
+}
+
+/// The machine's public key, generating the pair if there isn't one yet.
+pub fn ensure_key(machine: &Machine) -> Result<String, Box<dyn error::Error>> {
+    let key = key_path(machine);
+    let public = key.with_extension("pub");
+    if !key.exists() {
+        fs::create_dir_all(&machine.dir)?;
+        let _ = fs::remove_file(&public);
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C"])
+            .arg(format!("vagrantx-{}", machine.name))
+            .arg("-f")
+            .arg(&key)
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|e| format!("could not run ssh-keygen: {}", e))?;
+        if !status.success() {
+            return Err(format!("could not generate an SSH key ({})", status).into());
+        }
+    }
+    Ok(fs::read_to_string(&public)?.trim().to_string())
+}
+
+fn user_data(
+    public_key: Option<&str>,
+    username: Option<&str>,
+    host_key: &(String, String),
+) -> String {
+    // JSON strings are valid YAML, which saves quoting by hand.
+    let mut data = format!(
+        "#cloud-config\nssh_keys:\n  ed25519_private: {}\n  ed25519_public: {}\n",
+        serde_json::to_string(&host_key.0).unwrap(),
+        serde_json::to_string(&host_key.1).unwrap()
+    );
+    let key = match public_key {
+        Some(key) => serde_json::to_string(key).unwrap(),
+        None => return data,
+    };
+    data.push_str(&format!("ssh_authorized_keys:\n  - {}\n", key));
+    if let Some(username) = username {
+        data.push_str(&format!(
+            "users:\n  - default\n  - name: {}\n    sudo: \"ALL=(ALL) NOPASSWD:ALL\"\n    shell: /bin/bash\n    ssh_authorized_keys:\n      - {}\n",
+            serde_json::to_string(username).unwrap(),
+            key
+        ));
+    }
+    data
+}
+
+/// A version 2 network config for the NIC with `mac`: `static_ip` on the
+/// NAT network, or DHCP as usual, and `nic`'s MTU. None if there's nothing
+/// to change from what the guest would do anyway.
+fn network_config(mac: &str, static_ip: Option<Ipv4Addr>, nic: &Nic) -> Option<String> {
+    if static_ip.is_none() && nic.mtu.is_none() {
+        return None;
+    }
+    let mut config = format!(
+        "version: 2\nethernets:\n  nat:\n    match:\n      macaddress: \"{}\"\n",
+        mac
+    );
+    match static_ip {
+        Some(ip) => {
+            let (gateway, prefix) = network::nat_network();
+            config.push_str(&format!(
+                "    addresses: [\"{}/{}\"]\n    routes:\n      - to: 0.0.0.0/0\n        via: {}\n    nameservers:\n      addresses: [\"{}\"]\n",
+                ip, prefix, gateway, gateway
+            ));
+        }
+        None => config.push_str("    dhcp4: true\n"),
+    }
+    if let Some(mtu) = nic.mtu {
+        config.push_str(&format!("    mtu: {}\n", mtu));
+    }
+    Some(config)
+}
+
+/// Writes the seed disk for the next boot, returning its path.
+fn build(
+    machine: &Machine,
+    public_key: Option<&str>,
+    username: Option<&str>,
+    host_key: &(String, String),
+    network: Option<String>,
+) -> Result<PathBuf, Box<dyn error::Error>> {
+    let staging = machine.dir.join("seed");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    // cloud-init only runs its first-boot modules, and only applies the
+    // network config, once per instance ID, so this changes whenever
+    // either does.
+    let instance = paths::fnv1a(
+        format!(
+            "{}\n{}\n{}",
+            public_key.unwrap_or_default(),
+            host_key.1,
+            network.as_deref().unwrap_or_default()
+        )
+        .as_bytes(),
+    );
+    fs::write(
+        staging.join("meta-data"),
+        format!(
+            "instance-id: {}-{:08x}\nlocal-hostname: {}\n",
+            machine.name, instance as u32, machine.name
+        ),
+    )?;
+    fs::write(
+        staging.join("user-data"),
+        user_data(public_key, username, host_key),
+    )?;
+    if let Some(network) = &network {
+        fs::write(staging.join("network-config"), network)?;
+    }
+
+    let iso = machine.dir.join("seed.iso");
+    let _ = fs::remove_file(&iso);
+    let output = Command::new("hdiutil")
+        .args(["makehybrid", "-quiet", "-iso", "-joliet"])
+        .args(["-default-volume-name", "cidata", "-o"])
+        .arg(&iso)
+        .arg(&staging)
+        .output()
+        .map_err(|e| format!("could not run hdiutil: {}", e))?;
+    let _ = fs::remove_dir_all(&staging);
+    if !output.status.success() {
+        return Err(format!(
+            "could not build the cloud-init seed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(iso)
+}
+
+/// The seed disk `config`'s machine should boot with this time, if any.
+pub fn prepare(
+    config: &Config,
+    machine: &Machine,
+) -> Result<Option<PathBuf>, Box<dyn error::Error>> {
+    let authorize = config.ssh.generate_key && config.ssh.identity_file.is_none();
+    let network = network_config(
+        &network::mac_address(machine),
+        config.static_ip,
+        &config.nic,
+    );
+    if !authorize && network.is_none() {
+        return Ok(None);
+    }
+    let public_key = authorize.then(|| ensure_key(machine)).transpose()?;
+    let username = config.ssh.username.clone().or_else(|| {
+        let name = config.box_name.as_ref()?;
+        Some(boxes::load(name).ok()?.ssh?.username)
+    });
+    let host_key = knownhosts::ensure_host_key(machine)?;
+    Ok(Some(build(
+        machine,
+        public_key.as_deref(),
+        username.as_deref(),
+        &host_key,
+        network,
+    )?))
+}
@@ -0,0 +1,346 @@
+//! `vagrantx config get` and `set`: reading and changing one setting of a
+//! config from the command line.
+//!
+//! A setting is named by its path, e.g. `memory_size`, `qemu.arch` or
+//! `forwarded_ports[0].host`. Setting one rewrites only the text of its
+//! value, leaving the rest of the file, comments and layout included, as it
+//! was, and the result has to load as a config before it replaces the
+//! original.
+
+use crate::config;
+use serde_json::Value;
+use std::error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let invalid = || format!("{} is not a setting path like qemu.arch or disks[0]", path);
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (key, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if key.is_empty() && segments.is_empty() {
+            return Err(invalid());
+        }
+        if !key.is_empty() {
+            segments.push(Segment::Key(key.to_string()));
+        }
+        while !rest.is_empty() {
+            let end = rest.find(']').ok_or_else(invalid)?;
+            let index = rest[1..end].parse().map_err(|_| invalid())?;
+            segments.push(Segment::Index(index));
+            rest = &rest[end + 1..];
+            if !rest.is_empty() && !rest.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(segments)
+}
+
+/// Where a value is in the config's text, and where its members are.
+struct Node {
+    start: usize,
+    end: usize,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    /// Members with the offset each one's key starts at.
+    Object(Vec<(String, usize, Node)>),
+    Array(Vec<Node>),
+    Scalar,
+}
+
+/// Just enough of a JSON parser to find where each value is in the text,
+/// skipping the `//` comments configs allow.
+struct Scanner<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn skip_space(&mut self) {
+        while self.pos < self.text.len() {
+            match self.text[self.pos] {
+                b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+                b'/' if self.text.get(self.pos + 1) == Some(&b'/') => {
+                    while self.pos < self.text.len() && self.text[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_space();
+        if self.text.get(self.pos) != Some(&byte) {
+            return Err(format!(
+                "expected '{}' at byte {} of the config",
+                byte as char, self.pos
+            ));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn string_end(&self, start: usize) -> Result<usize, String> {
+        let mut pos = start + 1;
+        while pos < self.text.len() {
+            match self.text[pos] {
+                b'\\' => pos += 2,
+                b'"' => return Ok(pos + 1),
+                _ => pos += 1,
+            }
+        }
+        Err("unterminated string in the config".to_string())
+    }
+
+    fn value(&mut self) -> Result<Node, String> {
+        self.skip_space();
+        let start = self.pos;
+        let kind = match self.text.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_space();
+                if self.text.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        self.skip_space();
+                        let key_start = self.pos;
+                        if self.text.get(key_start) != Some(&b'"') {
+                            return Err(format!(
+                                "expected a key at byte {} of the config",
+                                key_start
+                            ));
+                        }
+                        self.pos = self.string_end(key_start)?;
+                        let key: String = serde_json::from_slice(&self.text[key_start..self.pos])
+                            .map_err(|e| e.to_string())?;
+                        self.expect(b':')?;
+                        members.push((key, key_start, self.value()?));
+                        self.skip_space();
+                        match self.text.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            _ => {
+                                self.expect(b'}')?;
+                                break;
+                            }
+                        }
+                    }
+                }
+                NodeKind::Object(members)
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elements = Vec::new();
+                self.skip_space();
+                if self.text.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        elements.push(self.value()?);
+                        self.skip_space();
+                        match self.text.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            _ => {
+                                self.expect(b']')?;
+                                break;
+                            }
+                        }
+                    }
+                }
+                NodeKind::Array(elements)
+            }
+            Some(b'"') => {
+                self.pos = self.string_end(start)?;
+                NodeKind::Scalar
+            }
+            Some(_) => {
+                while self.pos < self.text.len() && !b",}] \t\r\n/".contains(&self.text[self.pos]) {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(format!("expected a value at byte {} of the config", start));
+                }
+                NodeKind::Scalar
+            }
+            None => return Err("the config ends early".to_string()),
+        };
+        Ok(Node {
+            start,
+            end: self.pos,
+            kind,
+        })
+    }
+}
+
+/// The whitespace a line of `text` starts with, for the line holding `pos`.
+fn indentation(text: &str, pos: usize) -> &str {
+    let line_start = text[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[line_start..].split('\n').next().unwrap_or("");
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// `contents` with `member` added to the object spanning `start..end`:
+/// after its `last` member's value, indented like its key, or on a line of
+/// its own in an empty object.
+fn insert(
+    contents: &str,
+    (start, end): (usize, usize),
+    last: Option<(usize, usize)>,
+    member: &str,
+) -> String {
+    match last {
+        Some((key_start, value_end)) => format!(
+            "{},\n{}{}{}",
+            &contents[..value_end],
+            indentation(contents, key_start),
+            member,
+            &contents[value_end..]
+        ),
+        None => {
+            let indent = indentation(contents, start);
+            let open = start + 1;
+            let close = end - 1;
+            if contents[open..close].trim().is_empty() {
+                format!(
+                    "{}\n{}  {}\n{}{}",
+                    &contents[..open],
+                    indent,
+                    member,
+                    indent,
+                    &contents[close..]
+                )
+            } else {
+                // Only comments; leave them after the new member.
+                format!(
+                    "{}\n{}  {}{}",
+                    &contents[..open],
+                    indent,
+                    member,
+                    &contents[open..]
+                )
+            }
+        }
+    }
+}
+
+/// `segments` as a value to put in place of the ones that don't exist yet.
+fn nest(segments: &[Segment], value: Value) -> Result<Value, String> {
+    let mut value = value;
+    for segment in segments.iter().rev() {
+        match segment {
+            Segment::Key(key) => {
+                let mut object = serde_json::Map::new();
+                object.insert(key.clone(), value);
+                value = Value::Object(object);
+            }
+            Segment::Index(_) => return Err("there is no such list entry to set".to_string()),
+        }
+    }
+    Ok(value)
+}
+
+/// `contents` with the setting at `segments` set to `value`.
+fn set_text(contents: &str, segments: &[Segment], value: Value) -> Result<String, String> {
+    let mut scanner = Scanner {
+        text: contents.as_bytes(),
+        pos: 0,
+    };
+    let mut node = scanner.value()?;
+    for (depth, segment) in segments.iter().enumerate() {
+        let span = (node.start, node.end);
+        let next = match (node.kind, segment) {
+            (NodeKind::Object(members), Segment::Key(key)) => {
+                let last = members
+                    .last()
+                    .map(|(_, key_start, child)| (*key_start, child.end));
+                match members.into_iter().find(|(k, _, _)| k == key) {
+                    Some((_, _, child)) => child,
+                    None => {
+                        let member = format!(
+                            "{}: {}",
+                            serde_json::to_string(key).unwrap(),
+                            serde_json::to_string(&nest(&segments[depth + 1..], value)?).unwrap()
+                        );
+                        return Ok(insert(contents, span, last, &member));
+                    }
+                }
+            }
+            (NodeKind::Array(elements), Segment::Index(index)) => {
+                let len = elements.len();
+                elements
+                    .into_iter()
+                    .nth(*index)
+                    .ok_or_else(|| format!("there are only {} entries to set", len))?
+            }
+            (_, Segment::Key(_)) => return Err("that setting has no settings inside it".into()),
+            (_, Segment::Index(_)) => return Err("that setting is not a list".to_string()),
+        };
+        node = next;
+    }
+    Ok(format!(
+        "{}{}{}",
+        &contents[..node.start],
+        serde_json::to_string(&value).unwrap(),
+        &contents[node.end..]
+    ))
+}
+
+/// Prints the setting at `path` as the machine would use it, defaults
+/// included: strings as they are, anything else as JSON.
+pub fn get(config_file: &Path, path: &str) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let mut value = serde_json::to_value(&config)?;
+    for segment in parse_path(path)? {
+        value = match (value, &segment) {
+            (Value::Object(mut object), Segment::Key(key)) => object
+                .remove(key)
+                .ok_or_else(|| format!("there is no setting {}", path))?,
+            (Value::Array(mut array), Segment::Index(index)) if *index < array.len() => {
+                array.swap_remove(*index)
+            }
+            _ => return Err(format!("there is no setting {}", path).into()),
+        };
+    }
+    match value {
+        Value::String(s) => println!("{}", s),
+        value => println!("{}", serde_json::to_string_pretty(&value)?),
+    }
+    Ok(())
+}
+
+/// Sets the setting at `path` to `value`, which is taken as JSON if it
+/// parses as JSON and as a string otherwise.
+pub fn set(config_file: &Path, path: &str, value: &str) -> Result<(), Box<dyn error::Error>> {
+    let segments = parse_path(path)?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    let contents = fs::read_to_string(config_file)?;
+    let updated = set_text(&contents, &segments, value)
+        .map_err(|e| format!("could not set {}: {}", path, e))?;
+
+    // Beside the config, so its includes are found as they would be.
+    let partial = config_file.with_extension("partial");
+    fs::write(&partial, &updated)?;
+    if let Err(e) = config::load_config(&partial) {
+        let _ = fs::remove_file(&partial);
+        return Err(format!(
+            "{} would not be a valid config: {}",
+            config_file.display(),
+            e
+        )
+        .into());
+    }
+    fs::rename(&partial, config_file)?;
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//! Disk snapshots of a halted machine, kept as a tree.
+//!
+//! Each snapshot lives in `snapshots/<name>` under the machine directory,
+//! holding a copy of every disk and a `snapshot.json` naming its parent.
+//! `snapshots/current` names the snapshot the disks were last saved as or
+//! restored from, which becomes the parent of the next one; restoring an
+//! older snapshot and saving again therefore starts a new branch.
+
+use crate::config::{self, Config};
+use crate::events::{self, EventLog};
+use crate::lock::{self, MachineLock};
+use crate::machine::Machine;
+use crate::output;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+const METADATA_FILE: &str = "snapshot.json";
+const CURRENT_FILE: &str = "current";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    pub name: String,
+    #[serde(default)]
+    pub parent: Option<String>,
+    pub created: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Where each saved disk is restored to, in boot order.
+    pub disks: Vec<PathBuf>,
+}
+
+pub struct Snapshots {
+    machine: Machine,
+    dir: PathBuf,
+    disks: Vec<PathBuf>,
+    _lock: Option<MachineLock>,
+}
+
+fn disk_file(index: usize) -> String {
+    format!("disk{}.img", index)
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != CURRENT_FILE
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// The names of a machine's snapshots, without locking it.
+pub fn names(machine: &Machine) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(machine.dir.join("snapshots"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|e| e.path().join(METADATA_FILE).is_file())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+impl Snapshots {
+    /// Opens the machine's snapshots, locking the machine so it can't be
+    /// started while its disks are being copied.
+    pub fn open(config_file: &PathBuf) -> Result<Snapshots, Box<dyn error::Error>> {
+        let config = config::load_config(config_file)?;
+        let machine = Machine::new(config_file, config.name.as_deref());
+        let lock = lock::acquire(&machine, "snapshot", false)?;
+        let disks = config.resolve_boot(&machine)?.disks;
+        Ok(Snapshots {
+            dir: machine.dir.join("snapshots"),
+            machine,
+            disks,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Opens the machine's snapshots without locking it, for when its lock
+    /// is already held: by this process, or by the `up` running it.
+    pub fn unlocked(
+        config: &Config,
+        machine: &Machine,
+    ) -> Result<Snapshots, Box<dyn error::Error>> {
+        Ok(Snapshots {
+            dir: machine.dir.join("snapshots"),
+            machine: machine.clone(),
+            disks: config.resolve_boot(machine)?.disks,
+            _lock: None,
+        })
+    }
+
+    fn load(&self, name: &str) -> Result<Snapshot, Box<dyn error::Error>> {
+        if !valid_name(name) {
+            return Err(format!("invalid snapshot name {}", name).into());
+        }
+        let file = File::open(self.dir.join(name).join(METADATA_FILE))
+            .map_err(|_| format!("{} has no snapshot named {}", self.machine.name, name))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    fn store(&self, snapshot: &Snapshot) -> Result<(), Box<dyn error::Error>> {
+        let file = File::create(self.dir.join(&snapshot.name).join(METADATA_FILE))?;
+        serde_json::to_writer_pretty(file, snapshot)?;
+        Ok(())
+    }
+
+    fn current(&self) -> Option<String> {
+        fs::read_to_string(self.dir.join(CURRENT_FILE))
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| self.dir.join(s).join(METADATA_FILE).is_file())
+    }
+
+    fn set_current(&self, name: Option<&str>) -> Result<(), Box<dyn error::Error>> {
+        let path = self.dir.join(CURRENT_FILE);
+        match name {
+            Some(name) => fs::write(path, name)?,
+            None if path.exists() => fs::remove_file(path)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Every snapshot, oldest first.
+    pub fn all(&self) -> Result<Vec<Snapshot>, Box<dyn error::Error>> {
+        let mut snapshots = Vec::new();
+        if self.dir.exists() {
+            for entry in fs::read_dir(&self.dir)? {
+                let entry = entry?;
+                if entry.path().join(METADATA_FILE).is_file() {
+                    snapshots.push(self.load(&entry.file_name().to_string_lossy())?);
+                }
+            }
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    pub fn save(
+        &self,
+        name: &str,
+        description: Option<String>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        if !valid_name(name) {
+            return Err(format!("invalid snapshot name {}", name).into());
+        }
+        let dir = self.dir.join(name);
+        if dir.exists() {
+            return Err(format!("snapshot {} already exists", name).into());
+        }
+
+        let staging = self.dir.join(format!(".{}.partial", name));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+        for (i, disk) in self.disks.iter().enumerate() {
+            // A clone on APFS, so this costs almost nothing up front.
+            fs::copy(disk, staging.join(disk_file(i)))?;
+        }
+        fs::rename(&staging, &dir)?;
+
+        self.store(&Snapshot {
+            name: name.to_string(),
+            parent: self.current(),
+            created: events::timestamp(SystemTime::now()),
+            description,
+            disks: self.disks.clone(),
+        })?;
+        self.set_current(Some(name))?;
+        EventLog::new(&self.machine).record("snapshotted", Some(name.to_string()));
+        output::message(&format!("saved snapshot {} of {}", name, self.machine.name));
+        Ok(())
+    }
+
+    pub fn restore(&self, name: &str) -> Result<(), Box<dyn error::Error>> {
+        let snapshot = self.load(name)?;
+        for (i, disk) in snapshot.disks.iter().enumerate() {
+            // Copy beside the disk first so a failure can't leave it half
+            // overwritten.
+            let partial = disk.with_extension("restoring");
+            fs::copy(self.dir.join(name).join(disk_file(i)), &partial)?;
+            fs::rename(&partial, disk)?;
+        }
+        self.set_current(Some(name))?;
+        EventLog::new(&self.machine).record("restored", Some(name.to_string()));
+        output::message(&format!(
+            "restored {} to snapshot {}",
+            self.machine.name, name
+        ));
+        Ok(())
+    }
+
+    /// Deletes a snapshot. Its children are reattached to its parent.
+    pub fn delete(&self, name: &str) -> Result<(), Box<dyn error::Error>> {
+        let snapshot = self.load(name)?;
+        for mut child in self.all()? {
+            if child.parent.as_deref() == Some(name) {
+                child.parent = snapshot.parent.clone();
+                self.store(&child)?;
+            }
+        }
+        if self.current().as_deref() == Some(name) {
+            self.set_current(snapshot.parent.as_deref())?;
+        }
+        fs::remove_dir_all(self.dir.join(name))?;
+        output::message(&format!(
+            "deleted snapshot {} of {}",
+            name, self.machine.name
+        ));
+        Ok(())
+    }
+
+    fn line(&self, snapshot: &Snapshot, current: Option<&str>) -> String {
+        let marker = if current == Some(snapshot.name.as_str()) {
+            " *"
+        } else {
+            ""
+        };
+        match &snapshot.description {
+            Some(description) => format!(
+                "{}{}  {}  {}",
+                snapshot.name, marker, snapshot.created, description
+            ),
+            None => format!("{}{}  {}", snapshot.name, marker, snapshot.created),
+        }
+    }
+
+    fn print_children(
+        &self,
+        snapshots: &[Snapshot],
+        parent: Option<&str>,
+        prefix: &str,
+        current: Option<&str>,
+    ) {
+        // Snapshots whose parent has gone missing are shown as roots.
+        let is_root = |s: &Snapshot| match &s.parent {
+            None => true,
+            Some(p) => !snapshots.iter().any(|other| &other.name == p),
+        };
+        let children: Vec<&Snapshot> = snapshots
+            .iter()
+            .filter(|s| match parent {
+                None => is_root(s),
+                Some(_) => s.parent.as_deref() == parent,
+            })
+            .collect();
+        for (i, child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let (branch, indent) = match (parent, last) {
+                (None, _) => ("", ""),
+                (Some(_), true) => ("└── ", "    "),
+                (Some(_), false) => ("├── ", "│   "),
+            };
+            println!("{}{}{}", prefix, branch, self.line(child, current));
+            self.print_children(
+                snapshots,
+                Some(&child.name),
+                &format!("{}{}", prefix, indent),
+                current,
+            );
+        }
+    }
+
+    /// Prints every snapshot, marking the one the disks descend from with
+    /// `*`. With `tree`, children are shown under their parents.
+    pub fn list(&self, tree: bool) -> Result<(), Box<dyn error::Error>> {
+        let snapshots = self.all()?;
+        let current = self.current();
+        if tree {
+            self.print_children(&snapshots, None, "", current.as_deref());
+        } else {
+            for snapshot in &snapshots {
+                println!("{}", self.line(snapshot, current.as_deref()));
+            }
+        }
+        Ok(())
+    }
+}
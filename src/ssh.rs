@@ -0,0 +1,196 @@
+//! Logging in to a running machine with the host's OpenSSH client.
+
+use crate::backend;
+use crate::boxes;
+use crate::config::{self, BackendKind, Config};
+use crate::knownhosts;
+use crate::machine::Machine;
+use crate::network;
+use crate::output;
+use crate::remote::shell_quote;
+use crate::seed;
+use std::env;
+use std::error;
+use std::net::Ipv4Addr;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Everything needed to reach a machine over SSH.
+pub struct Session {
+    pub host: Ipv4Addr,
+    pub username: String,
+    pub identity_file: Option<PathBuf>,
+    pub forward_agent: bool,
+    pub options: Vec<String>,
+    /// The project's known_hosts, and the name the machine's key is in it
+    /// under.
+    pub known_hosts: PathBuf,
+    pub host_key_alias: String,
+}
+
+impl Session {
+    /// How to reach the machine `config` describes, from the config's `ssh`
+    /// settings, then the machine's generated key, then the box's
+    /// credentials.
+    pub fn new(config: &Config, machine: &Machine) -> Result<Session, Box<dyn error::Error>> {
+        if backend::select(config)? == BackendKind::Qemu {
+            return Err("machines run under QEMU are not reachable over the network".into());
+        }
+        let credentials = match &config.box_name {
+            Some(name) => boxes::load(name)?.ssh,
+            None => None,
+        };
+
+        let username = config
+            .ssh
+            .username
+            .clone()
+            .or_else(|| credentials.as_ref().map(|c| c.username.clone()))
+            .ok_or("no SSH username; set ssh.username in the config")?;
+        let identity_file = config
+            .ssh
+            .identity_file
+            .clone()
+            .or_else(|| {
+                let key = seed::key_path(machine);
+                (config.ssh.generate_key && key.exists()).then_some(key)
+            })
+            .or_else(|| credentials.and_then(|c| c.private_key));
+
+        let mac = network::saved_mac_address(machine)
+            .ok_or_else(|| format!("{} is not running", machine.name))?;
+        let host = network::guest_ip(&mac)
+            .ok_or_else(|| format!("{} has no IP address; is it running?", machine.name))?;
+
+        Ok(Session {
+            host,
+            username,
+            identity_file,
+            forward_agent: config.ssh.forward_agent,
+            options: config.ssh.options.clone(),
+            known_hosts: knownhosts::path(machine),
+            host_key_alias: knownhosts::alias(machine),
+        })
+    }
+
+    /// Like `new`, but gives a machine that's just booted up to `timeout`
+    /// to get its address, for whatever runs straight after boot.
+    pub fn wait(
+        config: &Config,
+        machine: &Machine,
+        timeout: Duration,
+    ) -> Result<Session, Box<dyn error::Error>> {
+        if backend::select(config)? == BackendKind::Qemu {
+            return Session::new(config, machine);
+        }
+        let deadline = Instant::now() + timeout;
+        let mac = network::saved_mac_address(machine)
+            .ok_or_else(|| format!("{} is not running", machine.name))?;
+        loop {
+            let has_ip = network::guest_ip(&mac).is_some();
+            match Session::new(config, machine) {
+                Ok(session) => return Ok(session),
+                // Anything else is wrong with the config, which waiting
+                // won't fix.
+                Err(e) if has_ip || Instant::now() > deadline => return Err(e),
+                Err(_) => thread::sleep(Duration::from_secs(2)),
+            }
+        }
+    }
+
+    /// The options ssh and scp need for reaching the machine.
+    fn client_args(&self) -> Vec<String> {
+        // Keys are checked by machine rather than by address, which the
+        // next machine to boot may be given. A key not yet known is one the
+        // machine was made with since it was last forgotten.
+        let mut args: Vec<String> = vec![
+            "-o".to_string(),
+            format!("UserKnownHostsFile={}", self.known_hosts.display()),
+            "-o".to_string(),
+            format!("HostKeyAlias={}", self.host_key_alias),
+            "-o".to_string(),
+            "StrictHostKeyChecking=accept-new".to_string(),
+            "-o".to_string(),
+            "LogLevel=ERROR".to_string(),
+        ];
+        if let Some(identity_file) = &self.identity_file {
+            args.push("-i".to_string());
+            args.push(identity_file.to_string_lossy().into_owned());
+            args.extend(["-o".to_string(), "IdentitiesOnly=yes".to_string()]);
+        }
+        if self.forward_agent {
+            args.extend(["-o".to_string(), "ForwardAgent=yes".to_string()]);
+        }
+        for option in &self.options {
+            args.push("-o".to_string());
+            args.push(option.clone());
+        }
+        args
+    }
+
+    /// `program` (ssh or scp) with the options for reaching the machine.
+    fn client(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        command.args(self.client_args());
+        command
+    }
+
+    /// The ssh command line for rsync's `-e`, which runs it through a shell.
+    pub fn shell_command(&self) -> String {
+        let mut line = String::from("ssh");
+        for arg in self.client_args() {
+            line.push(' ');
+            line.push_str(&shell_quote(&arg));
+        }
+        line
+    }
+
+    /// An `ssh` invocation logging in to the machine, to add a command to.
+    pub fn command(&self) -> Command {
+        let mut command = self.client("ssh");
+        command.arg(format!("{}@{}", self.username, self.host));
+        command
+    }
+
+    /// An `scp` invocation, to add paths to.
+    pub fn scp(&self) -> Command {
+        self.client("scp")
+    }
+
+    /// `path` on the guest, as scp and rsync name it.
+    pub fn remote(&self, path: &str) -> String {
+        format!("{}@{}:{}", self.username, self.host, path)
+    }
+}
+
+/// Logs in to the machine `config_file` defines, or runs `remote_command`
+/// there. Only returns if ssh can't be run.
+pub fn ssh(
+    config_file: &Path,
+    forward_agent: bool,
+    identity_file: Option<PathBuf>,
+    options: Vec<String>,
+    remote_command: &[String],
+) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let mut session = Session::new(&config, &machine)?;
+
+    session.forward_agent |= forward_agent;
+    if identity_file.is_some() {
+        session.identity_file = identity_file;
+    }
+    session.options.extend(options);
+    if session.forward_agent && env::var_os("SSH_AUTH_SOCK").is_none() {
+        output::warning("SSH_AUTH_SOCK is not set, so there is no agent to forward");
+    }
+
+    let mut command = session.command();
+    if !remote_command.is_empty() {
+        command.arg("--").args(remote_command);
+    }
+    Err(format!("could not run ssh: {}", command.exec()).into())
+}
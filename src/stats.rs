@@ -0,0 +1,185 @@
+//! Host resources each running machine is using, from what its `up`
+//! publishes and what the host reports about the processes behind it.
+
+use crate::config;
+use crate::machine::Machine;
+use crate::procinfo;
+use crate::relay;
+use crate::status;
+use serde::Serialize;
+use std::error;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rates are measured over at least this long.
+const SAMPLE: Duration = Duration::from_secs(1);
+
+struct Watched {
+    machine: Machine,
+    disks: Vec<PathBuf>,
+}
+
+/// One machine's counters at a moment.
+struct Sample {
+    at: Instant,
+    state: String,
+    cpu_seconds: f64,
+    resident_bytes: u64,
+    memory_size: usize,
+    balloon_target: Option<u64>,
+    disk_bytes: u64,
+    network_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub machine: String,
+    pub state: String,
+    /// Of one host core, so a busy two-CPU guest can show 200.
+    pub cpu_percent: f64,
+    pub resident_bytes: u64,
+    /// Guest memory less what the balloon has taken back.
+    pub balloon_bytes: Option<u64>,
+    /// Space the disk images actually take up on the host.
+    pub disk_bytes: u64,
+    pub disk_bytes_per_second: f64,
+    /// Through forwarded ports.
+    pub network_bytes_per_second: f64,
+}
+
+impl Watched {
+    fn sample(&self) -> Sample {
+        let published = status::published(&self.machine);
+        let (cpu_seconds, resident_bytes) = published
+            .iter()
+            .flat_map(|p| p.processes.iter())
+            .filter_map(|&pid| procinfo::usage(pid))
+            .fold((0.0, 0), |(cpu, resident), u| {
+                (cpu + u.cpu_seconds, resident + u.resident_bytes)
+            });
+        // Images are sparse, so count the blocks allocated to them rather
+        // than their length.
+        let disk_bytes = self
+            .disks
+            .iter()
+            .filter_map(|disk| fs::metadata(disk).ok())
+            .map(|m| m.blocks() * 512)
+            .sum();
+        let network_bytes = relay::registrations(&self.machine)
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|r| r.forwards.iter())
+            .map(|f| f.bytes)
+            .sum();
+        Sample {
+            at: Instant::now(),
+            state: published
+                .as_ref()
+                .map_or_else(|| "stopped".to_string(), |p| p.state.clone()),
+            cpu_seconds,
+            resident_bytes,
+            memory_size: published.as_ref().map_or(0, |p| p.memory_size),
+            balloon_target: published.and_then(|p| p.balloon_target),
+            disk_bytes,
+            network_bytes,
+        }
+    }
+}
+
+fn measure(machine: &Machine, before: &Sample, after: &Sample) -> Stats {
+    let seconds = after.at.duration_since(before.at).as_secs_f64().max(0.001);
+    let rate = |before: u64, after: u64| after.saturating_sub(before) as f64 / seconds;
+    Stats {
+        machine: machine.name.clone(),
+        state: after.state.clone(),
+        cpu_percent: (after.cpu_seconds - before.cpu_seconds).max(0.0) / seconds * 100.0,
+        resident_bytes: after.resident_bytes,
+        balloon_bytes: after
+            .balloon_target
+            .map(|target| (after.memory_size as u64).saturating_sub(target)),
+        disk_bytes: after.disk_bytes,
+        disk_bytes_per_second: rate(before.disk_bytes, after.disk_bytes),
+        network_bytes_per_second: rate(before.network_bytes, after.network_bytes),
+    }
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", value as u64, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn print_table(rows: &[Stats]) {
+    println!(
+        "{:<16} {:<10} {:>7} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        "MACHINE", "STATE", "CPU%", "MEMORY", "BALLOON", "DISK", "DISK/S", "NET/S"
+    );
+    for row in rows {
+        println!(
+            "{:<16} {:<10} {:>7.1} {:>11} {:>11} {:>11} {:>11} {:>11}",
+            row.machine,
+            row.state,
+            row.cpu_percent,
+            format_bytes(row.resident_bytes as f64),
+            row.balloon_bytes
+                .map_or_else(|| "-".to_string(), |b| format_bytes(b as f64)),
+            format_bytes(row.disk_bytes as f64),
+            format_bytes(row.disk_bytes_per_second),
+            format_bytes(row.network_bytes_per_second),
+        );
+    }
+}
+
+/// Prints stats for each machine in `configs`, once or, with `watch`,
+/// every `interval` until interrupted.
+pub fn stats(
+    configs: &[PathBuf],
+    watch: bool,
+    interval: Duration,
+    json: bool,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut watched = Vec::new();
+    for config_file in configs {
+        let config = config::load_config(config_file)?;
+        let machine = Machine::new(config_file, config.name.as_deref());
+        let disks = config.preview_boot(&machine)?.disks;
+        watched.push(Watched { machine, disks });
+    }
+
+    let mut before: Vec<Sample> = watched.iter().map(Watched::sample).collect();
+    thread::sleep(SAMPLE);
+    loop {
+        let after: Vec<Sample> = watched.iter().map(Watched::sample).collect();
+        let rows: Vec<Stats> = watched
+            .iter()
+            .zip(before.iter().zip(&after))
+            .map(|(w, (before, after))| measure(&w.machine, before, after))
+            .collect();
+        if json {
+            println!("{}", serde_json::to_string(&rows)?);
+        } else {
+            if watch {
+                // Redraw in place, like top.
+                print!("\x1b[H\x1b[2J");
+            }
+            print_table(&rows);
+        }
+        if !watch {
+            return Ok(());
+        }
+        before = after;
+        thread::sleep(interval.max(SAMPLE));
+    }
+}
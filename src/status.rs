@@ -0,0 +1,107 @@
+use crate::machine::Machine;
+use libc::{kill, pid_t, ESRCH};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// What `up` knows about its machine, shared with anything reporting on it
+/// from another thread.
+pub struct Status {
+    pub name: String,
+    pub state: &'static str,
+    pub started: Option<Instant>,
+    pub cpu_count: usize,
+    pub memory_size: usize,
+    pub balloon_target: Option<u64>,
+    pub boots: u64,
+    /// How far the current boot has got; see `phases`.
+    pub boot_phase: Option<&'static str>,
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+impl Status {
+    pub fn new(name: &str, cpu_count: usize, memory_size: usize) -> SharedStatus {
+        Arc::new(Mutex::new(Status {
+            name: name.to_string(),
+            state: "stopped",
+            started: None,
+            cpu_count,
+            memory_size,
+            balloon_target: None,
+            boots: 0,
+            boot_phase: None,
+        }))
+    }
+
+    pub fn uptime_seconds(&self) -> f64 {
+        match (self.state, self.started) {
+            ("stopped" | "error", _) | (_, None) => 0.0,
+            (_, Some(started)) => started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// What `up` publishes about its machine in `status.json`, for commands
+/// run from other processes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Published {
+    pub pid: u32,
+    pub state: String,
+    pub memory_size: usize,
+    pub balloon_target: Option<u64>,
+    /// The host processes running the guest.
+    pub processes: Vec<pid_t>,
+}
+
+fn published_path(machine: &Machine) -> PathBuf {
+    machine.dir.join("status.json")
+}
+
+pub fn publish(machine: &Machine, status: &Status, processes: &[pid_t]) {
+    let published = Published {
+        pid: process::id(),
+        state: status.state.to_string(),
+        memory_size: status.memory_size,
+        balloon_target: status.balloon_target,
+        processes: processes.to_vec(),
+    };
+    let path = published_path(machine);
+    let partial = path.with_extension("partial");
+    let _ = serde_json::to_vec(&published)
+        .map_err(io::Error::from)
+        .and_then(|data| fs::write(&partial, data))
+        .and_then(|()| fs::rename(&partial, &path));
+}
+
+/// Removes what `up` published about its machine when dropped, so the
+/// machine isn't taken for running once `up` has gone.
+pub struct Publication(PathBuf);
+
+impl Publication {
+    pub fn new(machine: &Machine) -> Publication {
+        Publication(published_path(machine))
+    }
+}
+
+impl Drop for Publication {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// What the `up` running `machine` last published, if one is running.
+pub fn published(machine: &Machine) -> Option<Published> {
+    let published: Published =
+        serde_json::from_slice(&fs::read(published_path(machine)).ok()?).ok()?;
+    if unsafe { kill(published.pid as pid_t, 0) } != 0
+        && io::Error::last_os_error().raw_os_error() == Some(ESRCH)
+    {
+        return None;
+    }
+    Some(published)
+}
@@ -0,0 +1,63 @@
+//! Setting the guest's clock from the host's, which it drifts away from
+//! while the machine is paused or the host sleeps.
+
+use crate::config::{self, Config};
+use crate::events::EventLog;
+use crate::machine::Machine;
+use crate::output;
+use crate::ssh::Session;
+use std::error;
+use std::path::Path;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Steps the clock with chrony if the guest has it, since otherwise it
+/// takes its time slewing back, and falls back to setting the date.
+fn script() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!(
+        "sudo -n sh -c 'chronyc -a makestep >/dev/null 2>&1 || date -u -s @{} >/dev/null'",
+        now
+    )
+}
+
+/// Syncs the clock of the machine `session` logs in to.
+pub fn sync(session: &Session) -> Result<(), Box<dyn error::Error>> {
+    let status = session
+        .command()
+        .args(["--", &script()])
+        .status()
+        .map_err(|e| format!("could not run ssh: {}", e))?;
+    if !status.success() {
+        return Err(format!("could not set the guest's clock ({})", status).into());
+    }
+    Ok(())
+}
+
+/// Syncs the clock of a machine `up` is running, in the background. Does
+/// nothing for a machine there's no way to log in to.
+pub fn sync_running(config: &Config, machine: &Machine, events: &EventLog) {
+    let mut session = match Session::new(config, machine) {
+        Ok(session) => session,
+        Err(_) => return,
+    };
+    // Nobody's there to answer a prompt.
+    session.options.push("BatchMode=yes".to_string());
+    let events = events.clone();
+    thread::spawn(move || match sync(&session) {
+        Ok(()) => events.record("timesynced", None),
+        Err(e) => output::warning(&e.to_string()),
+    });
+}
+
+/// `vagrantx timesync`.
+pub fn timesync(config_file: &Path) -> Result<(), Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    sync(&Session::new(&config, &machine)?)?;
+    println!("synced {}'s clock", machine.name);
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! `vagrantx push` and `pull`: copying files to and from a running machine.
+//!
+//! There's no guest agent to carry them over vsock, so they go over SSH with
+//! scp, and need the guest's network and SSH server up.
+
+use crate::config;
+use crate::machine::Machine;
+use crate::ssh::Session;
+use std::error;
+use std::path::Path;
+use std::process::Command;
+
+fn session(config_file: &Path) -> Result<Session, Box<dyn error::Error>> {
+    let config = config::load_config(&config_file.to_path_buf())?;
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let mut session = Session::new(&config, &machine)?;
+    session.options.push("BatchMode=yes".to_string());
+    Ok(session)
+}
+
+fn run(mut scp: Command) -> Result<(), Box<dyn error::Error>> {
+    let status = scp
+        .status()
+        .map_err(|e| format!("could not run scp: {}", e))?;
+    if !status.success() {
+        return Err(format!("scp failed ({})", status).into());
+    }
+    Ok(())
+}
+
+/// Copies `host_path` to `guest_path`, recursively for a directory.
+pub fn push(
+    config_file: &Path,
+    host_path: &Path,
+    guest_path: &str,
+) -> Result<(), Box<dyn error::Error>> {
+    let session = session(config_file)?;
+    let mut scp = session.scp();
+    if host_path.is_dir() {
+        scp.arg("-r");
+    }
+    scp.arg("--").arg(host_path).arg(session.remote(guest_path));
+    run(scp)
+}
+
+/// Copies `guest_path` to `host_path`; `recursive` is needed for a
+/// directory, since there's no telling from here.
+pub fn pull(
+    config_file: &Path,
+    guest_path: &str,
+    host_path: &Path,
+    recursive: bool,
+) -> Result<(), Box<dyn error::Error>> {
+    let session = session(config_file)?;
+    let mut scp = session.scp();
+    if recursive {
+        scp.arg("-r");
+    }
+    scp.arg("--").arg(session.remote(guest_path)).arg(host_path);
+    run(scp)
+}
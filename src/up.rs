@@ -0,0 +1,455 @@
+use crate::api;
+use crate::arch;
+use crate::backend::{self, Exit};
+use crate::config::{self, BackendKind, BootFrom};
+use crate::console::{self, Console};
+use crate::control::{self, Control};
+use crate::error::Error;
+use crate::events::EventLog;
+use crate::expect::Script;
+use crate::guestenv;
+use crate::health;
+use crate::hosts;
+use crate::ignition;
+use crate::knownhosts;
+use crate::lock;
+use crate::machine::Machine;
+use crate::metrics;
+use crate::network;
+use crate::output;
+use crate::phases;
+use crate::plugins;
+use crate::priority;
+use crate::profiles;
+use crate::provision;
+use crate::readiness;
+use crate::relay::Relay;
+use crate::reload;
+use crate::resources;
+use crate::restart::{self, Backoff};
+use crate::rsync;
+use crate::seed;
+use crate::status::{self, Status};
+use crate::timesync;
+use libc::isatty;
+use std::env;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A pause between polls this much longer than the interval means the host
+/// was asleep.
+const HOST_SLEEP_GAP: Duration = Duration::from_secs(30);
+
+pub fn up(
+    config_file: &PathBuf,
+    force_unlock: bool,
+    profile: Option<&str>,
+    boot_from: Option<BootFrom>,
+) -> Result<(), Error> {
+    let mut config = config::load_config(config_file)?;
+    profiles::apply(&mut config, profile).map_err(|e| Error::Config(e.to_string()))?;
+    if let Some(from) = boot_from {
+        config.boot.from = from;
+    }
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let _lock = lock::acquire(&machine, "up", force_unlock)?;
+    // Taking the lock makes the machine's directory, but only booting it
+    // gives it a MAC address.
+    let created = network::saved_mac_address(&machine).is_none();
+    output::message(&format!("starting {}", machine.name));
+
+    arch::check(&config).map_err(Error::Config)?;
+    let kind = backend::select(&config).map_err(Error::Config)?;
+    if kind == BackendKind::Qemu && arch::is_foreign(&config) {
+        output::warning(&format!(
+            "{} is an {} machine, so QEMU emulates it, which is much slower than running natively",
+            machine.name,
+            arch::guest(&config)
+        ));
+    }
+    if kind == BackendKind::Qemu && config.platform.is_some() {
+        output::warning("platform settings only apply to Virtualization.framework");
+    }
+
+    let (cpu_count, memory_size) = resources::check(
+        config.cpu_count,
+        config.memory_size,
+        &backend::limits(kind),
+        config.clamp_resources,
+    )
+    .map_err(|e| Error::Config(e.to_string()))?;
+
+    let probe = config
+        .readiness
+        .as_ref()
+        .map(readiness::Probe::new)
+        .transpose()
+        .map_err(|e| Error::Config(format!("invalid readiness console pattern: {}", e)))?;
+
+    let mut health_checks = health::checks(&config).map_err(Error::Config)?;
+    if kind == BackendKind::Qemu && !health_checks.is_empty() {
+        output::warning("health checks only run under Virtualization.framework");
+        health_checks.clear();
+    }
+
+    let script = config
+        .console_script
+        .as_ref()
+        .map(|path| Script::load(path))
+        .transpose()
+        .map_err(|e| Error::Config(e.to_string()))?;
+
+    let events = EventLog::new(&machine).notifying(&config.notify);
+    reload::record(&machine, &config, profile);
+    if created {
+        // Whatever key an earlier machine by this name had, this one's
+        // is new.
+        if let Err(e) = knownhosts::forget(&machine) {
+            output::warning(&format!("could not forget the old host key: {}", e));
+        }
+    }
+    if let Some(ip) = config.static_ip {
+        network::check_static_ip(ip).map_err(Error::Config)?;
+        if kind == BackendKind::Qemu {
+            output::warning("static_ip only applies to Virtualization.framework");
+        }
+    }
+    network::check_nic(&config.nic, kind == BackendKind::Qemu).map_err(Error::Config)?;
+    if kind != BackendKind::Qemu
+        && (config.nic.checksum_offload.is_some() || config.nic.tso.is_some())
+    {
+        output::warning("nic.checksum_offload and nic.tso only apply to QEMU");
+    }
+    network::record_static_ip(
+        &machine,
+        config.static_ip.filter(|_| kind != BackendKind::Qemu),
+    )?;
+    let mut boot = config.resolve_boot(&machine)?;
+    boot.seed = seed::prepare(&config, &machine)?;
+    if created {
+        events.record("created", None);
+    }
+    // Taken while the machine is still halted, so its disks are consistent.
+    let snapshotted = config.snapshot_before_provision && !config.provisioners.is_empty();
+    if snapshotted {
+        provision::snapshot(&config, &machine)
+            .map_err(|e| format!("could not snapshot {}: {}", machine.name, e))?;
+    }
+    let mac = network::mac_address(&machine);
+
+    let status = Status::new(&machine.name, cpu_count, memory_size);
+    if let Some(address) = &config.metrics_address {
+        metrics::serve(address, status.clone(), &machine)
+            .map_err(|e| format!("could not serve metrics on {}: {}", address, e))?;
+    }
+
+    let manage_hosts = !config.hostnames.is_empty() && kind != BackendKind::Qemu;
+    // Dropped, and the entries removed, however up returns.
+    let _hosts = manage_hosts.then(|| {
+        hosts::authorize();
+        hosts::watch(&machine, &mac, config.hostnames.clone())
+    });
+    if !manage_hosts && !config.hostnames.is_empty() {
+        output::warning("hostnames only apply to Virtualization.framework");
+    }
+    if kind == BackendKind::Qemu && !config.guest_env.is_empty() {
+        output::warning("guest_env only applies to Virtualization.framework");
+    }
+    if kind == BackendKind::Qemu && !config.synced_folders.is_empty() {
+        output::warning("synced_folders only apply to Virtualization.framework");
+    }
+
+    // Nobody can type into a machine started in the background.
+    let console = Console::open(
+        config.serial,
+        &config.console_sinks,
+        unsafe { isatty(0) } == 1,
+    )?;
+    if let Some(path) = console.pty_path() {
+        fs::write(
+            console::pty_path(&machine),
+            path.to_string_lossy().as_bytes(),
+        )?;
+        output::message(&format!(
+            "{}'s console is on {}; screen {} attaches to it",
+            machine.name,
+            path.display(),
+            path.display()
+        ));
+    }
+    if config.console_sinks.socket {
+        if let Err(e) = console.serve(&console::socket_path(&machine)) {
+            output::warning(&format!(
+                "vagrantx console will not be able to attach: {}",
+                e
+            ));
+        }
+    }
+    let (sender, requests) = mpsc::channel();
+    if let Err(e) = control::serve(&machine, sender.clone()) {
+        output::warning(&format!("vagrantx halt will not be able to stop it: {}", e));
+    }
+    if let Some(address) = &config.api_address {
+        api::serve(
+            address,
+            config_file,
+            &machine,
+            status.clone(),
+            console.output().clone(),
+            sender,
+        )
+        .map_err(|e| format!("could not serve the API on {}: {}", address, e))?;
+    }
+    let relay = Relay::new(&machine, &mac, "up");
+    if kind == BackendKind::Qemu && !config.forwarded_ports.is_empty() {
+        output::warning("forwarded_ports only apply to Virtualization.framework");
+    } else {
+        for forward in &config.forwarded_ports {
+            relay
+                .add(forward)
+                .map_err(|e| format!("could not forward port {}: {}", forward.host, e))?;
+        }
+    }
+    let mut backoff = Backoff::new();
+    let mut stop_requested = false;
+    let mut reload_requested = false;
+    // Restarts bring back a machine that was already provisioned.
+    let mut provisioned = false;
+    // Kept across restarts, which republish as they start.
+    let _publication = status::Publication::new(&machine);
+
+    loop {
+        // Only the first boot gets Ignition.
+        let mut boot = boot.clone();
+        ignition::prepare(&config, &machine, kind, &mut boot)
+            .map_err(|e| Error::Config(e.to_string()))?;
+        let vm = match backend::create(
+            kind,
+            &config,
+            &boot,
+            cpu_count,
+            memory_size,
+            &console,
+            &machine,
+        ) {
+            Ok(vm) => vm,
+            Err(e) => {
+                events.record_error("errored", &e);
+                return Err(e.into());
+            }
+        };
+
+        let started = Instant::now();
+        let booted_at = console.output().position();
+        let boot_number = {
+            let mut status = status.lock().unwrap();
+            status.state = "starting";
+            status.started = Some(started);
+            status.boots += 1;
+            status.boot_phase = None;
+            status.boots
+        };
+        let exit = match vm.start() {
+            Ok(()) => {
+                events.record("started", None);
+                let processes = vm.processes();
+                // Published straight away so that halt can stop a machine
+                // still booting.
+                status::publish(&machine, &status.lock().unwrap(), &processes);
+                priority::apply(&config.host_priority, processes.clone());
+                phases::watch(
+                    console.output().clone(),
+                    status.clone(),
+                    boot_number,
+                    booted_at,
+                );
+                if let Some(script) = &script {
+                    if let Err(e) =
+                        script.run(console.output(), booted_at, &mut |data| console.send(data))
+                    {
+                        events.record("unready", Some(e.to_string()));
+                        return Err(Error::NotReady(e.to_string()));
+                    }
+                }
+                match &probe {
+                    Some(probe) => {
+                        let waiting =
+                            output::progress(&format!("waiting for {} to be ready", machine.name));
+                        match probe.wait(console.output(), booted_at, &mac) {
+                            Ok(()) => {
+                                events.record("ready", None);
+                                waiting.finish(&format!("{} is ready", machine.name));
+                            }
+                            Err(e) => {
+                                events.record("unready", Some(e.reason().to_string()));
+                                let phase = status.lock().unwrap().boot_phase;
+                                return Err(e.at_phase(phase).into());
+                            }
+                        }
+                    }
+                    // Without a probe, started is as ready as it gets.
+                    None => events.record("ready", Some("no readiness probe".to_string())),
+                }
+                ignition::applied(&config, &machine);
+                if !config.guest_env.is_empty() && kind != BackendKind::Qemu {
+                    if let Err(e) = guestenv::inject(&config, &machine) {
+                        events.record("unprovisioned", Some(e.to_string()));
+                        return Err(format!(
+                            "could not pass the environment to {}: {}",
+                            machine.name, e
+                        )
+                        .into());
+                    }
+                }
+                if !config.synced_folders.is_empty() && kind != BackendKind::Qemu {
+                    if let Err(e) = rsync::sync_all(&config, &machine) {
+                        events.record("unprovisioned", Some(e.to_string()));
+                        return Err(
+                            format!("could not sync folders to {}: {}", machine.name, e).into()
+                        );
+                    }
+                }
+                if !provisioned && !config.provisioners.is_empty() {
+                    if let Err(e) =
+                        plugins::provision_all(&config.provisioners, &machine, config_file, &mac)
+                    {
+                        events.record("unprovisioned", Some(e.to_string()));
+                        if snapshotted {
+                            output::message(&format!(
+                                "vagrantx snapshot restore {} {} undoes the run",
+                                config_file.display(),
+                                provision::SNAPSHOT
+                            ));
+                        }
+                        return Err(format!("could not provision {}: {}", machine.name, e).into());
+                    }
+                    events.record("provisioned", None);
+                }
+                provisioned = true;
+                // Dropped, and the checks stopped, once the machine exits.
+                let _health = (!health_checks.is_empty()).then(|| {
+                    health::Monitor::start(health_checks.clone(), &config, &machine, &mac, &events)
+                });
+                let mut published = false;
+                let mut last_state = "";
+                let mut last_poll = SystemTime::now();
+                loop {
+                    let (state, exit) = vm.poll();
+                    // The monotonic clock stops while the host sleeps, but the
+                    // wall clock doesn't.
+                    let slept = SystemTime::now()
+                        .duration_since(last_poll)
+                        .is_ok_and(|gap| gap > HOST_SLEEP_GAP);
+                    let resumed = matches!(last_state, "paused" | "resuming") && state == "running";
+                    if config.time_sync && (slept || resumed) {
+                        timesync::sync_running(&config, &machine, &events);
+                    }
+                    last_state = state;
+                    last_poll = SystemTime::now();
+                    let balloon_target = vm.balloon_target();
+                    {
+                        let mut status = status.lock().unwrap();
+                        if (status.state, status.balloon_target) != (state, balloon_target)
+                            || !published
+                        {
+                            status.state = state;
+                            status.balloon_target = balloon_target;
+                            status::publish(&machine, &status, &processes);
+                            published = true;
+                        }
+                    }
+                    if let Some(exit) = exit {
+                        break match exit {
+                            Exit::Stopped
+                                if !stop_requested
+                                    && restart::rebooted(console.output(), booted_at) =>
+                            {
+                                Exit::Rebooted
+                            }
+                            exit => exit,
+                        };
+                    }
+                    while let Ok(request) = requests.try_recv() {
+                        let (reply, result, reason) = match request {
+                            Control::Stop(reply, reason) => (reply, vm.request_stop(), reason),
+                            Control::ForceStop(reply, reason) => (reply, vm.force_stop(), reason),
+                            Control::Reload(reply) => {
+                                let result = vm.request_stop();
+                                reload_requested = result.is_ok();
+                                (reply, result, "to reload its config")
+                            }
+                            Control::SetBalloon(reply, bytes) => {
+                                let _ = reply.send(vm.set_balloon_target(bytes));
+                                continue;
+                            }
+                        };
+                        if result.is_ok() {
+                            stop_requested = true;
+                            events.record("stopping", Some(reason.to_string()));
+                        }
+                        let _ = reply.send(result);
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+            Err(e) => {
+                events.record_error("errored", &e);
+                output::message(&format!("could not start {}: {}", machine.name, e));
+                status.lock().unwrap().state = "error";
+                Exit::Error
+            }
+        };
+
+        match exit {
+            Exit::Stopped => events.record("stopped", None),
+            Exit::Error => events.record("crashed", None),
+            Exit::Rebooted => events.record("rebooted", None),
+        }
+
+        // Starting over from scratch picks up every change, with the same
+        // terminal and process.
+        if reload_requested {
+            events.record("reloading", None);
+            output::message(&format!("restarting {} with its new config", machine.name));
+            let exe = env::current_exe().map_err(|e| format!("could not restart: {}", e))?;
+            // exec skips our destructors, and the new process makes the
+            // terminal raw again from how it finds it.
+            console.restore_terminal();
+            let e = Command::new(exe).args(env::args_os().skip(1)).exec();
+            return Err(format!("could not restart {}: {}", machine.name, e).into());
+        }
+
+        // A guest that restarts itself expects to come straight back, with
+        // the same console and forwarded ports, whatever the restart policy.
+        if let Exit::Rebooted = exit {
+            output::message(&format!("{} rebooted, starting it again", machine.name));
+            continue;
+        }
+
+        // A stop asked for over the API is final, whatever the restart policy.
+        if stop_requested || !config.restart.should_restart(&exit) {
+            return match exit {
+                Exit::Error => Err(Error::Crashed(format!("{} crashed", machine.name))),
+                _ => Ok(()),
+            };
+        }
+
+        let delay = backoff.delay(started.elapsed());
+        events.record("restarting", Some(format!("in {}s", delay.as_secs())));
+        output::message(&format!(
+            "{} {}, restarting in {}s",
+            machine.name,
+            match exit {
+                Exit::Error => "crashed",
+                _ => "stopped",
+            },
+            delay.as_secs()
+        ));
+        thread::sleep(delay);
+    }
+}
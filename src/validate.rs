@@ -0,0 +1,321 @@
+//! Checks a config as thoroughly as possible without starting anything.
+
+use crate::arch;
+use crate::backend;
+use crate::boxes;
+use crate::cache;
+use crate::config::{self, BackendKind, BootFrom, Config};
+use crate::console::Console;
+use crate::expect;
+use crate::health;
+use crate::machine::Machine;
+use crate::network;
+use crate::notify;
+use crate::platform;
+use crate::plugins;
+use crate::profiles;
+use crate::readiness;
+use crate::resources;
+use crate::vm;
+use std::env;
+use std::error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+struct Problem {
+    line: Option<usize>,
+    message: String,
+}
+
+/// Collects problems, pointing each at the first line of the config that
+/// mentions what it's about.
+struct Report<'a> {
+    config_file: &'a Path,
+    contents: String,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Report<'a> {
+    fn line_of(&self, needle: &str) -> Option<usize> {
+        self.contents
+            .lines()
+            .position(|l| l.contains(needle))
+            .map(|i| i + 1)
+    }
+
+    /// Reports a problem with the config key `key`.
+    fn key(&mut self, key: &str, message: String) {
+        let line = self.line_of(&format!("\"{}\"", key));
+        self.problems.push(Problem { line, message });
+    }
+
+    fn print(&self) {
+        for problem in &self.problems {
+            match problem.line {
+                Some(line) => println!(
+                    "{}:{}: {}",
+                    self.config_file.display(),
+                    line,
+                    problem.message
+                ),
+                None => println!("{}: {}", self.config_file.display(), problem.message),
+            }
+        }
+    }
+
+    fn file(&mut self, key: &str, path: &Path) {
+        // Downloaded when the machine boots.
+        if path.to_str().is_some_and(cache::is_url) {
+            return;
+        }
+        if !path.is_file() {
+            self.key(key, format!("{} does not exist", path.display()));
+        }
+    }
+
+    fn address(&mut self, key: &str, address: &Option<String>) {
+        if let Some(address) = address {
+            if address.parse::<SocketAddr>().is_err() {
+                self.key(key, format!("{} is not an address and port", address));
+            }
+        }
+    }
+}
+
+fn on_path(binary: &Path) -> bool {
+    if binary.components().count() > 1 {
+        return binary.is_file();
+    }
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(binary).is_file()))
+        .unwrap_or(false)
+}
+
+fn check(report: &mut Report, config: &Config, machine: &Machine) -> Option<BackendKind> {
+    let kind = match backend::select(config) {
+        Ok(kind) => Some(kind),
+        Err(e) => {
+            report.key("backend", e);
+            None
+        }
+    };
+
+    if let Err(e) = arch::check(config) {
+        report.key("arch", e);
+    }
+
+    let limits = backend::limits(kind.unwrap_or(BackendKind::Qemu));
+    if let Err(e) = resources::check(config.cpu_count, config.memory_size, &limits, false) {
+        for problem in e.problems() {
+            let key = problem.split_whitespace().next().unwrap_or("cpu_count");
+            report.key(key, problem.clone());
+        }
+    }
+
+    if let Some(readiness) = &config.readiness {
+        if let Err(e) = readiness::Probe::new(readiness) {
+            report.key("console", format!("invalid readiness pattern: {}", e));
+        }
+    }
+
+    if let Some(script) = &config.console_script {
+        if let Err(e) = expect::Script::load(script) {
+            report.key("console_script", e.to_string());
+        }
+    }
+
+    if let Some(name) = &config.box_name {
+        if let Err(e) = boxes::load(name) {
+            report.key("box", e.to_string());
+        }
+    }
+    if let Some(kernel) = &config.boot.kernel {
+        report.file("kernel", kernel);
+    }
+    if let Some(initrd) = &config.boot.initrd {
+        report.file("initrd", initrd);
+    }
+    for disk in &config.boot.disks {
+        report.file("disks", disk);
+    }
+    if let Some(ip) = config.static_ip {
+        if let Err(e) = network::check_static_ip(ip) {
+            report.key("static_ip", e);
+        }
+    }
+    if let Err(e) = health::checks(config) {
+        report.key("health_checks", e);
+    }
+    if let Err(e) = network::check_nic(&config.nic, kind == Some(BackendKind::Qemu)) {
+        report.key("nic", e);
+    }
+    match &config.boot.cdrom {
+        Some(cdrom) => report.file("cdrom", cdrom),
+        None if config.boot.from == BootFrom::Cdrom => report.key(
+            "from",
+            "booting from the cdrom needs boot.cdrom".to_string(),
+        ),
+        None => {}
+    }
+    for disk in &config.additional_disks {
+        report.file("additional_disks", disk);
+    }
+
+    if let Some(platform) = &config.platform {
+        if let Err(e) = platform::check(platform) {
+            report.key("platform", e.to_string());
+        }
+    }
+
+    if let Some(nice) = config.host_priority.nice {
+        if !(0..=20).contains(&nice) {
+            report.key(
+                "nice",
+                format!("nice must be between 0 and 20, not {}", nice),
+            );
+        }
+    }
+    if config.host_priority.cpu_limit == Some(0) {
+        report.key("cpu_limit", "cpu_limit must be more than 0".to_string());
+    }
+
+    if let Some(ignition) = &config.ignition {
+        report.file("ignition", ignition);
+    }
+    if let Some(secrets_file) = &config.guest_env.secrets_file {
+        report.file("secrets_file", secrets_file);
+    }
+    for folder in &config.synced_folders {
+        if !folder.host.is_dir() {
+            report.key(
+                "synced_folders",
+                format!("{} is not a directory", folder.host.display()),
+            );
+        }
+        if !folder.guest.starts_with('/') {
+            report.key(
+                "synced_folders",
+                format!("{} is not an absolute guest path", folder.guest),
+            );
+        }
+    }
+
+    for event in &config.notify {
+        if !notify::EVENTS.contains(&event.as_str()) {
+            report.key(
+                "notify",
+                format!(
+                    "{} is not an event; expected one of {}",
+                    event,
+                    notify::EVENTS.join(", ")
+                ),
+            );
+        }
+    }
+
+    report.address("metrics_address", &config.metrics_address);
+    report.address("api_address", &config.api_address);
+
+    if !config.provisioners.is_empty() {
+        let plugins = plugins::discover();
+        for provisioner in &config.provisioners {
+            let provided = plugins.iter().any(|p| {
+                p.description
+                    .provisioners
+                    .iter()
+                    .any(|n| n == &provisioner.kind)
+            });
+            if !provided {
+                let line = report.line_of(&format!("\"{}\"", provisioner.kind));
+                report.problems.push(Problem {
+                    line,
+                    message: format!("no plugin provides the {} provisioner", provisioner.kind),
+                });
+            }
+        }
+    }
+
+    if report.problems.is_empty() {
+        if let Err(e) = config.preview_boot(machine) {
+            report.key("boot", e.to_string());
+        }
+    }
+    kind
+}
+
+/// Validates `config_file`, printing every problem found. Returns whether
+/// it's fine.
+pub fn validate(config_file: &PathBuf) -> Result<bool, Box<dyn error::Error>> {
+    let contents = fs::read_to_string(config_file)?;
+    let mut config = match config::load_config(config_file) {
+        Ok(config) => config,
+        Err(e) => {
+            // serde_json's messages already say where in the file they are.
+            println!("{}", e);
+            return Ok(false);
+        }
+    };
+
+    let mut report = Report {
+        config_file,
+        contents,
+        problems: Vec::new(),
+    };
+    if let Err(e) = profiles::apply(&mut config, None) {
+        report.key("profile", e.to_string());
+    }
+
+    let machine = Machine::new(config_file, config.name.as_deref());
+    let kind = check(&mut report, &config, &machine);
+
+    // Only a config that's otherwise sound is worth handing to the
+    // hypervisor, which stops at the first thing it doesn't like.
+    match (report.problems.is_empty(), kind) {
+        (true, Some(BackendKind::Qemu)) => {
+            let arch = arch::guest(&config);
+            let binary = config
+                .qemu
+                .binary
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(format!("qemu-system-{}", arch)));
+            if !on_path(&binary) {
+                report.key("qemu", format!("{} was not found", binary.display()));
+            }
+        }
+        (true, Some(_)) => {
+            // Building a configuration persists a MAC address and machine
+            // identifier, so give it somewhere disposable to put them.
+            let scratch = Machine {
+                name: machine.name.clone(),
+                dir: env::temp_dir().join(format!("vagrantx-validate-{}", std::process::id())),
+            };
+            let result = config.preview_boot(&machine).map(|boot| {
+                vm::build_configuration(
+                    &config,
+                    &boot,
+                    config.cpu_count,
+                    config.memory_size,
+                    &Console::buffered(),
+                    &scratch,
+                )
+            });
+            let _ = fs::remove_dir_all(&scratch.dir);
+            match result {
+                Ok(Err(e)) => report.problems.push(Problem {
+                    line: None,
+                    message: format!("Virtualization.framework rejected the configuration: {}", e),
+                }),
+                Err(e) => report.key("boot", e.to_string()),
+                Ok(Ok(_)) => {}
+            }
+        }
+        _ => {}
+    }
+
+    report.print();
+    if report.problems.is_empty() {
+        println!("{} is valid", config_file.display());
+    }
+    Ok(report.problems.is_empty())
+}
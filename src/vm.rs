@@ -0,0 +1,407 @@
+use crate::backend::{Backend, Exit};
+use crate::config::{BootFrom, Config, Qos, ResolvedBoot};
+use crate::console::Console;
+use crate::firmware::{self, EfiBootLoader, StorageDevice};
+use crate::machine::Machine;
+use crate::network;
+use crate::platform;
+use crate::priority;
+use crate::procinfo;
+use block::{Block, ConcreteBlock};
+use libc::{c_void, pid_t};
+use objc::rc::StrongPtr;
+use objc::runtime::{BOOL, YES};
+use objc::{msg_send, sel, sel_impl};
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fmt;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use virtualization_rs::virtualization::boot_loader;
+use virtualization_rs::{
+    base::{dispatch_async, dispatch_queue_create, dispatch_sync, Id, NSError, NSString, NIL},
+    virtualization::{
+        boot_loader::{VZBootLoader, VZLinuxBootLoaderBuilder},
+        entropy_device::VZVirtioEntropyDeviceConfiguration,
+        memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
+        network_device::{VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration},
+        storage_device::{
+            VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+        },
+        virtual_machine::{
+            VZVirtualMachine, VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder,
+            VZVirtualMachineState,
+        },
+    },
+};
+
+/// A path as the framework takes it. `ResolvedBoot` paths are already
+/// absolute.
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn build_boot_loader(
+    kernel: &Path,
+    initrd: &Path,
+    cmd_line: &str,
+) -> boot_loader::VZLinuxBootLoader {
+    VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path_string(kernel))
+        .initial_ramdisk_url(path_string(initrd))
+        .command_line(cmd_line)
+        .build()
+}
+
+enum BootLoader {
+    Linux(boot_loader::VZLinuxBootLoader),
+    Efi(EfiBootLoader),
+}
+
+impl VZBootLoader for BootLoader {
+    fn id(&self) -> Id {
+        match self {
+            BootLoader::Linux(loader) => loader.id(),
+            BootLoader::Efi(loader) => loader.id(),
+        }
+    }
+}
+
+/// The machine's storage, in the order the firmware tries it: the install
+/// media first when booting from it, and after the disks otherwise. The
+/// seed always comes last.
+fn build_storage_devices(boot: &ResolvedBoot) -> Result<Vec<StorageDevice>, NSError> {
+    let mut devices = Vec::with_capacity(boot.disks.len() + 2);
+    let cdrom = boot
+        .cdrom
+        .as_deref()
+        .map(StorageDevice::install_media)
+        .transpose()?;
+    let (first, last) = match boot.from {
+        BootFrom::Cdrom => (cdrom, None),
+        _ => (None, cdrom),
+    };
+    devices.extend(first);
+    let disks = boot
+        .disks
+        .iter()
+        .map(|disk| (disk.as_path(), false))
+        .chain(boot.seed.as_deref().map(|seed| (seed, true)));
+    for (disk, read_only) in disks {
+        let block_attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path_string(disk))
+            .read_only(read_only)
+            .build()?;
+        let block_device = VZVirtioBlockDeviceConfiguration::new(block_attachment);
+        devices.push(StorageDevice::block(block_device));
+    }
+    // Before the seed, which is only ever read by cloud-init.
+    if let Some(cdrom) = last {
+        let at = devices.len() - usize::from(boot.seed.is_some());
+        devices.insert(at, cdrom);
+    }
+    Ok(devices)
+}
+
+/// Builds and validates a fresh configuration. Devices can only belong to
+/// one virtual machine, so every boot needs its own.
+pub fn build_configuration(
+    config: &Config,
+    boot: &ResolvedBoot,
+    cpu_count: usize,
+    memory_size: usize,
+    console: &Console,
+    machine: &Machine,
+) -> Result<VZVirtualMachineConfiguration, VmError> {
+    let entropy = VZVirtioEntropyDeviceConfiguration::new();
+    let memory_balloon = VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new();
+
+    let network_attachment = VZNATNetworkDeviceAttachment::new();
+    let mut network_device = VZVirtioNetworkDeviceConfiguration::new(network_attachment);
+    let mac_address = network::vz_mac_address(&network::mac_address(machine));
+    network_device.set_mac_address(mac_address);
+
+    let boot_loader = match (&boot.kernel, &boot.initrd) {
+        (Some(kernel), Some(initrd)) => {
+            BootLoader::Linux(build_boot_loader(kernel, initrd, &boot.command_line))
+        }
+        _ if !firmware::supported() => {
+            return Err(VmError {
+                domain: "vagrantx.firmware".to_string(),
+                code: 0,
+                description: "booting through firmware requires macOS 13 or later".to_string(),
+            })
+        }
+        _ => BootLoader::Efi(EfiBootLoader::new(machine).map_err(|e| VmError::from_ns_error(&e))?),
+    };
+    let storage_devices = build_storage_devices(boot).map_err(|e| VmError::from_ns_error(&e))?;
+
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(cpu_count)
+        .memory_size(memory_size)
+        .entropy_devices(vec![entropy])
+        .memory_balloon_devices(vec![memory_balloon])
+        .network_devices(vec![network_device])
+        .serial_ports(vec![console.serial_port()])
+        .storage_devices(storage_devices)
+        .build();
+
+    if let Some(platform) = &config.platform {
+        platform::apply(&conf, platform, machine).map_err(|e| VmError {
+            domain: "vagrantx.platform".to_string(),
+            code: 0,
+            description: e.to_string(),
+        })?;
+    }
+
+    conf.validate_with_error()
+        .map_err(|e| VmError::from_ns_error(&e))?;
+    Ok(conf)
+}
+
+/// The parts of an NSError worth keeping once it's left the machine's queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmError {
+    pub domain: String,
+    pub code: isize,
+    pub description: String,
+}
+
+impl VmError {
+    pub fn from_ns_error(error: &NSError) -> VmError {
+        let domain = unsafe { NSString(StrongPtr::retain(msg_send![*error.0, domain])) };
+        VmError {
+            domain: domain.as_str().to_string(),
+            code: error.code(),
+            description: error.localized_description().as_str().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} {})", self.description, self.domain, self.code)
+    }
+}
+
+impl error::Error for VmError {}
+
+/// A virtual machine and the dispatch queue it must be driven from.
+pub struct Vm {
+    vm: VZVirtualMachine,
+    queue: Id,
+}
+
+impl Vm {
+    pub fn new(conf: VZVirtualMachineConfiguration, name: &str, qos: Qos) -> Vm {
+        let label = std::ffi::CString::new(format!("vagrantx.{}", name)).unwrap();
+        let queue =
+            unsafe { dispatch_queue_create(label.as_ptr(), priority::queue_attributes(qos)) };
+        Vm {
+            vm: VZVirtualMachine::new(conf, queue),
+            queue,
+        }
+    }
+
+    /// Runs `f` on the machine's queue, which is the only place the
+    /// framework allows it to be touched, and returns its result.
+    fn on_queue<T: 'static>(&self, f: impl Fn(&VZVirtualMachine) -> T + 'static) -> Option<T> {
+        let result = Arc::new(Mutex::new(None));
+        let slot = result.clone();
+        let vm = self.vm.clone();
+        let block = ConcreteBlock::new(move || {
+            *slot.lock().unwrap() = Some(f(&vm));
+        });
+        let block = block.copy();
+        let block: &Block<(), ()> = &block;
+        unsafe {
+            dispatch_sync(self.queue, block as *const Block<(), ()> as *mut c_void);
+        }
+
+        let value = result.lock().unwrap().take();
+        value
+    }
+
+    fn state(&self) -> VZVirtualMachineState {
+        self.on_queue(|vm| unsafe { vm.state() })
+            .unwrap_or(VZVirtualMachineState::Other)
+    }
+}
+
+impl Backend for Vm {
+    /// Starts the machine, blocking until the framework reports whether it
+    /// managed to.
+    fn start(&self) -> Result<(), VmError> {
+        let (tx, rx) = mpsc::channel();
+        let vm = self.vm.clone();
+        let dispatch_block = ConcreteBlock::new(move || {
+            let tx = tx.clone();
+            let completion_handler = ConcreteBlock::new(move |err: Id| {
+                let result = if err == NIL {
+                    Ok(())
+                } else {
+                    let error = unsafe { NSError(StrongPtr::retain(err)) };
+                    Err(VmError::from_ns_error(&error))
+                };
+                let _ = tx.send(result);
+            });
+            let completion_handler = completion_handler.copy();
+            let completion_handler: &Block<(Id,), ()> = &completion_handler;
+            vm.start_with_completion_handler(completion_handler);
+        });
+        let dispatch_block = dispatch_block.copy();
+        let dispatch_block: &Block<(), ()> = &dispatch_block;
+        unsafe {
+            dispatch_async(self.queue, dispatch_block);
+        }
+
+        rx.recv().unwrap_or_else(|_| {
+            Err(VmError {
+                domain: "vagrantx".to_string(),
+                code: 0,
+                description: "start was never acknowledged".to_string(),
+            })
+        })
+    }
+
+    /// How much memory the balloon device is currently targeting, which is
+    /// what the guest is actually allowed to use.
+    fn balloon_target(&self) -> Option<u64> {
+        self.on_queue(|vm| unsafe {
+            let devices: Id = msg_send![machine_id(vm), memoryBalloonDevices];
+            let device: Id = msg_send![devices, firstObject];
+            if device == NIL {
+                None
+            } else {
+                let target: u64 = msg_send![device, targetVirtualMachineMemorySize];
+                Some(target)
+            }
+        })
+        .flatten()
+    }
+
+    /// Asks the guest to shut down, as if its power button were pressed.
+    fn request_stop(&self) -> Result<(), VmError> {
+        self.on_queue(|vm| unsafe {
+            let id = machine_id(vm);
+            let can_stop: BOOL = msg_send![id, canRequestStop];
+            if can_stop != YES {
+                return Err(VmError {
+                    domain: "vagrantx".to_string(),
+                    code: 0,
+                    description: "the machine can't be asked to stop in its current state"
+                        .to_string(),
+                });
+            }
+            let mut error: Id = NIL;
+            let stopped: BOOL = msg_send![id, requestStopWithError: &mut error];
+            if stopped == YES {
+                Ok(())
+            } else {
+                Err(VmError::from_ns_error(&NSError(StrongPtr::retain(error))))
+            }
+        })
+        .unwrap_or_else(|| {
+            Err(VmError {
+                domain: "vagrantx".to_string(),
+                code: 0,
+                description: "stop request was never acknowledged".to_string(),
+            })
+        })
+    }
+
+    /// Stops the machine without asking the guest. Needs macOS 12.
+    fn force_stop(&self) -> Result<(), VmError> {
+        self.on_queue(|vm| unsafe {
+            let id = machine_id(vm);
+            let supported: BOOL =
+                msg_send![id, respondsToSelector: sel!(stopWithCompletionHandler:)];
+            if supported != YES {
+                return Err(VmError {
+                    domain: "vagrantx".to_string(),
+                    code: 0,
+                    description: "forcing a machine off needs macOS 12 or later".to_string(),
+                });
+            }
+            let can_stop: BOOL = msg_send![id, canStop];
+            if can_stop != YES {
+                return Err(VmError {
+                    domain: "vagrantx".to_string(),
+                    code: 0,
+                    description: "the machine can't be stopped in its current state".to_string(),
+                });
+            }
+            // poll sees the machine stop; there's nothing to do on completion.
+            let completion_handler = ConcreteBlock::new(|_err: Id| {});
+            let completion_handler = completion_handler.copy();
+            let completion_handler: &Block<(Id,), ()> = &completion_handler;
+            let _: () = msg_send![id, stopWithCompletionHandler: completion_handler];
+            Ok(())
+        })
+        .unwrap_or_else(|| {
+            Err(VmError {
+                domain: "vagrantx".to_string(),
+                code: 0,
+                description: "stop was never acknowledged".to_string(),
+            })
+        })
+    }
+
+    fn set_balloon_target(&self, bytes: u64) -> Result<(), VmError> {
+        // The framework wants a whole number of megabytes.
+        let bytes = bytes & !((1 << 20) - 1);
+        self.on_queue(move |vm| unsafe {
+            let devices: Id = msg_send![machine_id(vm), memoryBalloonDevices];
+            let device: Id = msg_send![devices, firstObject];
+            if device == NIL {
+                return Err(VmError {
+                    domain: "vagrantx".to_string(),
+                    code: 0,
+                    description: "the machine has no balloon device".to_string(),
+                });
+            }
+            let _: () = msg_send![device, setTargetVirtualMachineMemorySize: bytes];
+            Ok(())
+        })
+        .unwrap_or_else(|| {
+            Err(VmError {
+                domain: "vagrantx".to_string(),
+                code: 0,
+                description: "the balloon change was never acknowledged".to_string(),
+            })
+        })
+    }
+
+    fn processes(&self) -> Vec<pid_t> {
+        procinfo::vm_processes()
+    }
+
+    fn poll(&self) -> (&'static str, Option<Exit>) {
+        let state = self.state();
+        let exit = match state {
+            VZVirtualMachineState::VZVirtualMachineStateStopped => Some(Exit::Stopped),
+            VZVirtualMachineState::VZVirtualMachineStateError => Some(Exit::Error),
+            _ => None,
+        };
+        (state_name(&state), exit)
+    }
+}
+
+/// The Objective-C object behind a machine; see `platform::configuration_id`.
+fn machine_id(vm: &VZVirtualMachine) -> Id {
+    unsafe { **(vm as *const VZVirtualMachine as *const StrongPtr) }
+}
+
+fn state_name(state: &VZVirtualMachineState) -> &'static str {
+    match state {
+        VZVirtualMachineState::VZVirtualMachineStateStopped => "stopped",
+        VZVirtualMachineState::VZVirtualMachineStateRunning => "running",
+        VZVirtualMachineState::VZVirtualMachineStatePaused => "paused",
+        VZVirtualMachineState::VZVirtualMachineStateError => "error",
+        VZVirtualMachineState::VZVirtualMachineStateStarting => "starting",
+        VZVirtualMachineState::VZVirtualMachineStatePausing => "pausing",
+        VZVirtualMachineState::VZVirtualMachineStateResuming => "resuming",
+        VZVirtualMachineState::Other => "unknown",
+    }
+}